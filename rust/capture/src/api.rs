@@ -2,8 +2,10 @@ use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use uuid::Uuid;
 
 use crate::token::InvalidTokenReason;
+use crate::v0_request::DataType;
 
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum CaptureResponseCode {
@@ -18,6 +20,101 @@ pub struct CaptureResponse {
     pub quota_limited: Option<Vec<String>>,
 }
 
+/// Response of the v1 batch endpoint: events are validated individually, and the
+/// response reports the outcome for every event of the batch, by batch index.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CaptureV1Response {
+    pub status: CaptureResponseCode,
+    pub accepted: Vec<usize>,
+    pub rejected: Vec<RejectedEvent>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub quota_limited: Vec<String>,
+}
+
+/// Response of the import endpoint, once the whole body has been processed.
+/// Imports can hold millions of lines, so accepted lines are reported as inclusive
+/// ranges of line numbers, and only the first rejected lines are listed.
+#[derive(Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ImportSummary {
+    pub accepted_count: usize,
+    pub accepted: Vec<(usize, usize)>,
+    pub rejected_count: usize,
+    pub rejected: Vec<RejectedLine>,
+
+    /// Set if the body could not be read to the end, lines after the error were not imported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RejectedLine {
+    pub line: usize,
+    pub reason: String,
+    pub retryable: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RejectedEvent {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<Uuid>,
+    pub reason: String,
+    pub retryable: bool,
+}
+
+/// Response of the validation endpoint: how capture would have processed the payload,
+/// without producing it. Errors are reported here instead of failing the request.
+#[derive(Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ValidationReport {
+    /// Detected compression of the body: `none`, `base64` for forms, or the algorithm name
+    pub compression: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    pub historical_migration: bool,
+    pub events: Vec<EventValidation>,
+
+    /// Set if the request would have been rejected as a whole
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ValidationError>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct EventValidation {
+    pub index: usize,
+    pub event: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distinct_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_type: Option<DataType>,
+    /// Kafka key of the event, before overflow and routing rules are applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partition_key: Option<String>,
+    /// Size of the produced message payload, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ValidationError>,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ValidationError {
+    pub reason: String,
+    pub message: String,
+}
+
+impl From<&CaptureError> for ValidationError {
+    fn from(err: &CaptureError) -> Self {
+        ValidationError {
+            reason: err.reason().to_string(),
+            message: err.to_string(),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum CaptureError {
     #[error("failed to decode request: {0}")]
@@ -51,8 +148,12 @@ pub enum CaptureError {
 
     #[error("transient error, please retry")]
     RetryableSinkError,
+    #[error("privacy settings are not loaded yet, please retry")]
+    PrivacySettingsUnavailable,
     #[error("maximum event size exceeded")]
     EventTooBig,
+    #[error("request body is too large")]
+    PayloadTooLarge,
     #[error("invalid event could not be processed")]
     NonRetryableSinkError,
 
@@ -63,6 +164,44 @@ pub enum CaptureError {
     RateLimited,
 }
 
+impl CaptureError {
+    /// Short machine-readable reason, used in the v1 per-event results.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            CaptureError::RequestDecodingError(_) => "request_decoding_error",
+            CaptureError::RequestParsingError(_) => "request_parsing_error",
+            CaptureError::EmptyBatch => "empty_batch",
+            CaptureError::MissingEventName => "missing_event_name",
+            CaptureError::EmptyDistinctId => "empty_distinct_id",
+            CaptureError::MissingDistinctId => "missing_distinct_id",
+            CaptureError::MissingSnapshotData => "missing_snapshot_data",
+            CaptureError::MissingSessionId => "missing_session_id",
+            CaptureError::MissingWindowId => "missing_window_id",
+            CaptureError::InvalidSessionId => "invalid_session_id",
+            CaptureError::NoTokenError => "no_token",
+            CaptureError::MultipleTokensError => "multiple_tokens",
+            CaptureError::TokenValidationError(_) => "token_validation",
+            CaptureError::RetryableSinkError => "retryable_sink_error",
+            CaptureError::PrivacySettingsUnavailable => "privacy_settings_unavailable",
+            CaptureError::EventTooBig => "event_too_big",
+            CaptureError::PayloadTooLarge => "payload_too_large",
+            CaptureError::NonRetryableSinkError => "sink_error",
+            CaptureError::BillingLimit => "quota_limited",
+            CaptureError::RateLimited => "rate_limited",
+        }
+    }
+
+    /// Whether the client can expect the same payload to be accepted later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            CaptureError::RetryableSinkError
+                | CaptureError::PrivacySettingsUnavailable
+                | CaptureError::RateLimited
+        )
+    }
+}
+
 impl IntoResponse for CaptureError {
     fn into_response(self) -> Response {
         match self {
@@ -83,7 +222,11 @@ impl IntoResponse for CaptureError {
             | CaptureError::MultipleTokensError
             | CaptureError::TokenValidationError(_) => (StatusCode::UNAUTHORIZED, self.to_string()),
 
-            CaptureError::RetryableSinkError => (StatusCode::SERVICE_UNAVAILABLE, self.to_string()),
+            CaptureError::RetryableSinkError | CaptureError::PrivacySettingsUnavailable => {
+                (StatusCode::SERVICE_UNAVAILABLE, self.to_string())
+            }
+
+            CaptureError::PayloadTooLarge => (StatusCode::PAYLOAD_TOO_LARGE, self.to_string()),
 
             CaptureError::BillingLimit | CaptureError::RateLimited => {
                 (StatusCode::TOO_MANY_REQUESTS, self.to_string())
@@ -0,0 +1,752 @@
+/// Incremental decoding of request bodies.
+///
+/// Instead of buffering the whole body, decompressing it into a new buffer and copying it
+/// into a String before parsing, the body chunks are forwarded as they arrive to a blocking
+/// task, that decompresses and deserializes them in a single pass. The decompressed size is
+/// checked while reading, so that oversized payloads are rejected before they are fully
+/// received. Peak memory per request is bounded by the channel depth and the parsed events.
+use std::io::{self, BufRead, BufReader, Read};
+
+use axum::body::Body;
+use axum::http::HeaderMap;
+use bytes::{Buf, Bytes};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use futures::StreamExt;
+use http_body_util::LengthLimitError;
+use serde::de::DeserializeOwned;
+use tokio::sync::mpsc;
+use tracing::instrument;
+
+use crate::api::CaptureError;
+use crate::lz64::{self, Lz64Error};
+use crate::prometheus::report_dropped_events;
+use crate::v0_request::{Compression, GZIP_MAGIC_NUMBERS};
+
+/// How many body chunks can be buffered while the decoder is busy
+const BODY_CHUNKS_IN_FLIGHT: usize = 8;
+
+pub static ZSTD_MAGIC_NUMBERS: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Buffer size of the brotli decoder, as recommended by the crate
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+/// Returns the compression announced by the client, either in the `compression` query
+/// param, or in the Content-Encoding header.
+pub fn compression_hint(query: Option<Compression>, headers: &HeaderMap) -> Option<Compression> {
+    query
+        .filter(|c| *c != Compression::Unsupported)
+        .or_else(|| {
+            headers
+                .get("content-encoding")
+                .and_then(|v| v.to_str().ok())
+                .and_then(Compression::from_content_encoding)
+        })
+}
+
+/// Streams the request body into the decoder, and returns the deserialized payload.
+/// Stops reading the body as soon as the decoder fails, to reject bad requests early.
+#[instrument(skip_all)]
+pub async fn decode_body<T>(
+    body: Body,
+    hint: Option<Compression>,
+    limit: usize,
+) -> Result<T, CaptureError>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(BODY_CHUNKS_IN_FLIGHT);
+    let decoder = tokio::task::spawn_blocking(move || {
+        decode_json::<T, _>(ChannelReader::new(rx), hint, limit)
+    });
+
+    let over_limit = forward_body(body, tx).await;
+
+    let decoded = decoder.await.map_err(|e| {
+        tracing::error!("body decoding task failed: {}", e);
+        CaptureError::RequestDecodingError(String::from("failed to decode body"))
+    })?;
+    if over_limit {
+        // The decoder only saw a read error, report it as the body limit it is
+        report_dropped_events("payload_too_large", 1);
+        return Err(CaptureError::PayloadTooLarge);
+    }
+    decoded
+}
+
+/// Buffers a whole body, for payloads that can't be decoded incrementally.
+pub async fn read_body(body: Body, limit: usize) -> Result<Bytes, CaptureError> {
+    axum::body::to_bytes(body, limit).await.map_err(|e| {
+        if is_length_limit_error(&e) {
+            report_dropped_events("payload_too_large", 1);
+            return CaptureError::PayloadTooLarge;
+        }
+        tracing::error!("failed to read body: {}", e);
+        CaptureError::RequestDecodingError(String::from("failed to read body"))
+    })
+}
+
+/// Upper bound of the size of a form body holding a payload of `limit` bytes in its `data`
+/// field: base64 inflates the payload by 4/3, and url-encoding `+` and `/` adds a bit more.
+/// The decoded payload is then checked against `limit` itself.
+pub fn form_body_limit(limit: usize) -> usize {
+    limit.saturating_mul(2)
+}
+
+/// Whether reading a body failed because it is larger than the body limit of its route.
+/// Bodies without a Content-Length are only checked against the limit while being read.
+fn is_length_limit_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if err.is::<LengthLimitError>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Forwards the body chunks to a blocking reader, until the body ends or the reader is dropped.
+/// Returns whether the body was cut short because it is over the body limit.
+async fn forward_body(body: Body, tx: mpsc::Sender<io::Result<Bytes>>) -> bool {
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        match chunk {
+            Ok(chunk) => {
+                // Sending fails if the decoder returned early, no need to read the rest of the body
+                if tx.send(Ok(chunk)).await.is_err() {
+                    return false;
+                }
+            }
+            Err(e) => {
+                let over_limit = is_length_limit_error(&e);
+                tx.send(Err(io::Error::other(e))).await.ok();
+                return over_limit;
+            }
+        }
+    }
+    false
+}
+
+/// Item of a newline-delimited JSON body, see `stream_ndjson`.
+#[derive(Debug)]
+pub enum NdjsonItem<T> {
+    /// A non-empty line, numbered from 1, and its deserialized value
+    Line(usize, Result<T, CaptureError>),
+    /// The body could not be read or decompressed, no more lines will follow
+    Failed(CaptureError),
+}
+
+/// Decodes a newline-delimited JSON body of arbitrary length, one line at a time.
+///
+/// Lines are deserialized in a blocking task and sent through a bounded channel: if the
+/// caller does not keep up, the body is not read further, applying backpressure to the
+/// client. Only gzip, zstd and deflate compressions are supported, as they can be detected.
+/// Lines larger than `line_limit` bytes once decompressed are skipped with `EventTooBig`.
+pub fn stream_ndjson<T>(
+    body: Body,
+    line_limit: usize,
+    lines_in_flight: usize,
+) -> mpsc::Receiver<NdjsonItem<T>>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    let (body_tx, body_rx) = mpsc::channel(BODY_CHUNKS_IN_FLIGHT);
+    let (tx, rx) = mpsc::channel(lines_in_flight);
+    tokio::spawn(forward_body(body, body_tx));
+    tokio::task::spawn_blocking(move || {
+        let mut reader = ChannelReader::new(body_rx);
+        let (head, filled) = match read_head(&mut reader) {
+            Ok(head) => head,
+            Err(err) => {
+                tx.blocking_send(NdjsonItem::Failed(err)).ok();
+                return;
+            }
+        };
+        let head = &head[..filled];
+        let reader = Read::chain(head, reader);
+        let (reader, encoding): (Box<dyn Read>, &str) = match detect_compression(head, None) {
+            Some(Compression::Gzip) => (Box::new(GzDecoder::new(reader)), "gzip"),
+            Some(Compression::Deflate) => (Box::new(ZlibDecoder::new(reader)), "deflate"),
+            Some(Compression::Zstd) => match zstd::stream::read::Decoder::new(reader) {
+                Ok(decoder) => (Box::new(decoder), "zstd"),
+                Err(e) => {
+                    tracing::error!("failed to create zstd decoder: {}", e);
+                    tx.blocking_send(NdjsonItem::Failed(CaptureError::RequestDecodingError(
+                        String::from("invalid zstd data"),
+                    )))
+                    .ok();
+                    return;
+                }
+            },
+            _ => (Box::new(reader), "plain"),
+        };
+        read_ndjson_lines(BufReader::new(reader), encoding, line_limit, &tx);
+    });
+    rx
+}
+
+fn read_ndjson_lines<T: DeserializeOwned, R: BufRead>(
+    mut reader: R,
+    encoding: &str,
+    line_limit: usize,
+    tx: &mpsc::Sender<NdjsonItem<T>>,
+) {
+    let failed = |e: io::Error| {
+        tracing::error!("failed to read {} body: {}", encoding, e);
+        NdjsonItem::Failed(CaptureError::RequestDecodingError(format!(
+            "invalid {} data",
+            encoding
+        )))
+    };
+
+    let mut buffer = Vec::new();
+    let mut number = 0;
+    loop {
+        buffer.clear();
+        number += 1;
+        let read = (&mut reader)
+            .take(line_limit as u64 + 1)
+            .read_until(b'\n', &mut buffer);
+        let item = match read {
+            Ok(0) => return,
+            Ok(read) if read > line_limit && buffer.last() != Some(&b'\n') => {
+                match skip_line(&mut reader) {
+                    Ok(()) => NdjsonItem::Line(number, Err(CaptureError::EventTooBig)),
+                    Err(e) => failed(e),
+                }
+            }
+            Ok(_) => {
+                let line = buffer.trim_ascii();
+                if line.is_empty() {
+                    continue;
+                }
+                NdjsonItem::Line(number, serde_json::from_slice(line).map_err(Into::into))
+            }
+            Err(e) => failed(e),
+        };
+        let stop = matches!(item, NdjsonItem::Failed(_));
+        // Sending fails if the caller stopped reading lines
+        if tx.blocking_send(item).is_err() || stop {
+            return;
+        }
+    }
+}
+
+/// Consumes the reader until the end of the current line.
+fn skip_line<R: BufRead>(reader: &mut R) -> io::Result<()> {
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            return Ok(());
+        }
+        match available.iter().position(|b| *b == b'\n') {
+            Some(position) => {
+                reader.consume(position + 1);
+                return Ok(());
+            }
+            None => {
+                let len = available.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+/// Detects the payload compression and deserializes the payload while decompressing it.
+/// Fails with EventTooBig if the decompressed payload is larger than `limit` bytes.
+///
+/// A sizable portion of clients send a wrong compression hint, so we peek at the payload's
+/// magic bytes to detect gzip, zstd and deflate. Brotli and lz64 payloads can't be detected
+/// that way, so we only decode them if the client told us so, and then trust the hint: some
+/// of their payloads start with what looks like a zlib header.
+pub fn decode_json<T: DeserializeOwned, R: Read>(
+    mut reader: R,
+    hint: Option<Compression>,
+    limit: usize,
+) -> Result<T, CaptureError> {
+    let (head, filled) = read_head(&mut reader)?;
+    let head = &head[..filled];
+    let reader = Read::chain(head, reader);
+
+    match detect_compression(head, hint) {
+        Some(Compression::Gzip) => {
+            parse_limited(LimitedReader::new(GzDecoder::new(reader), limit), "gzip")
+        }
+        Some(Compression::Zstd) => {
+            let decoder = zstd::stream::read::Decoder::new(reader).map_err(|e| {
+                tracing::error!("failed to create zstd decoder: {}", e);
+                CaptureError::RequestDecodingError(String::from("invalid zstd data"))
+            })?;
+            parse_limited(LimitedReader::new(decoder, limit), "zstd")
+        }
+        Some(Compression::Deflate) => parse_limited(
+            LimitedReader::new(ZlibDecoder::new(reader), limit),
+            "deflate",
+        ),
+        Some(Compression::Brotli) => parse_limited(
+            LimitedReader::new(brotli::Decompressor::new(reader, BROTLI_BUFFER_SIZE), limit),
+            "brotli",
+        ),
+        Some(Compression::Lz64) => parse_lz64(LimitedReader::new(reader, limit), limit),
+        Some(Compression::Unsupported) | None => {
+            parse_limited(LimitedReader::new(reader, limit), "plain")
+        }
+    }
+}
+
+/// Reads the first bytes of the payload, to detect its compression.
+fn read_head<R: Read>(reader: &mut R) -> Result<([u8; 4], usize), CaptureError> {
+    let mut head = [0u8; 4];
+    let mut filled = 0;
+    while filled < head.len() {
+        match reader.read(&mut head[filled..]) {
+            Ok(0) => break,
+            Ok(got) => filled += got,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                tracing::error!("failed to read body: {}", e);
+                return Err(CaptureError::RequestDecodingError(String::from(
+                    "failed to read body",
+                )));
+            }
+        }
+    }
+    Ok((head, filled))
+}
+
+/// Returns the compression of a payload from its first bytes, see `decode_json`.
+pub(crate) fn detect_compression(head: &[u8], hint: Option<Compression>) -> Option<Compression> {
+    if let Some(hint @ (Compression::Brotli | Compression::Lz64)) = hint {
+        Some(hint)
+    } else if head.starts_with(&GZIP_MAGIC_NUMBERS) {
+        Some(Compression::Gzip)
+    } else if head.starts_with(&ZSTD_MAGIC_NUMBERS) {
+        Some(Compression::Zstd)
+    } else if is_zlib_header(head) {
+        Some(Compression::Deflate)
+    } else {
+        None
+    }
+}
+
+/// Deflate payloads are sent with a zlib header: the first byte holds the compression
+/// method (8 for deflate), and the first two bytes are a multiple of 31.
+/// No valid JSON payload starts with such a sequence.
+fn is_zlib_header(head: &[u8]) -> bool {
+    match head {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
+
+/// lz-string is not a streaming format: read the whole payload before decompressing it.
+fn parse_lz64<T: DeserializeOwned, R: Read>(
+    mut reader: LimitedReader<R>,
+    limit: usize,
+) -> Result<T, CaptureError> {
+    let mut compressed = String::new();
+    if let Err(e) = reader.read_to_string(&mut compressed) {
+        if reader.exceeded {
+            tracing::error!("lz64 payload limit reached");
+            report_dropped_events("event_too_big", 1);
+            return Err(CaptureError::EventTooBig);
+        }
+        tracing::error!("failed to read lz64 body: {}", e);
+        return Err(CaptureError::RequestDecodingError(String::from(
+            "invalid lz64 data",
+        )));
+    }
+
+    let payload = decompress_lz64(&compressed, limit)?;
+    Ok(serde_json::from_str(&payload)?)
+}
+
+/// Decompresses a lz64 payload, mapping errors to the matching CaptureError.
+pub fn decompress_lz64(data: &str, limit: usize) -> Result<String, CaptureError> {
+    match lz64::decompress_from_base64(data, limit) {
+        // lz-string decodes some invalid payloads to an empty string
+        Ok(decompressed) if decompressed.is_empty() && !data.trim().is_empty() => Err(
+            CaptureError::RequestDecodingError(String::from("invalid lz64 data")),
+        ),
+        result => result.map_err(|e| match e {
+            Lz64Error::TooLarge => {
+                tracing::error!("lz64 decompression limit reached");
+                report_dropped_events("event_too_big", 1);
+                CaptureError::EventTooBig
+            }
+            Lz64Error::InvalidData => {
+                CaptureError::RequestDecodingError(String::from("invalid lz64 data"))
+            }
+        }),
+    }
+}
+
+fn parse_limited<T: DeserializeOwned, R: Read>(
+    mut reader: LimitedReader<R>,
+    encoding: &str,
+) -> Result<T, CaptureError> {
+    match serde_json::from_reader(BufReader::new(&mut reader)) {
+        Ok(parsed) => Ok(parsed),
+        Err(_) if reader.exceeded => {
+            tracing::error!("{} decompression limit reached", encoding);
+            report_dropped_events("event_too_big", 1);
+            Err(CaptureError::EventTooBig)
+        }
+        Err(e) if e.is_io() => {
+            tracing::error!("failed to read {} body: {}", encoding, e);
+            Err(CaptureError::RequestDecodingError(format!(
+                "invalid {} data",
+                encoding
+            )))
+        }
+        Err(e) => Err(CaptureError::RequestParsingError(e)),
+    }
+}
+
+/// Fails reads once more than `limit` bytes have been read from the inner reader.
+struct LimitedReader<R> {
+    inner: R,
+    remaining: usize,
+    exceeded: bool,
+}
+
+impl<R> LimitedReader<R> {
+    fn new(inner: R, limit: usize) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+            exceeded: false,
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let got = self.inner.read(buf)?;
+        match self.remaining.checked_sub(got) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                Ok(got)
+            }
+            None => {
+                self.exceeded = true;
+                Err(io::Error::other("payload size limit reached"))
+            }
+        }
+    }
+}
+
+/// Blocking reader over the body chunks sent by `decode_body`.
+struct ChannelReader {
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+    current: Bytes,
+}
+
+impl ChannelReader {
+    fn new(rx: mpsc::Receiver<io::Result<Bytes>>) -> Self {
+        Self {
+            rx,
+            current: Bytes::new(),
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.current.has_remaining() {
+            match self.rx.blocking_recv() {
+                None => return Ok(0),
+                Some(Ok(chunk)) => self.current = chunk,
+                Some(Err(e)) => return Err(e),
+            }
+        }
+        let got = buf.len().min(self.current.len());
+        buf[..got].copy_from_slice(&self.current[..got]);
+        self.current.advance(got);
+        Ok(got)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use axum::body::Body;
+    use axum::http::{HeaderMap, HeaderValue};
+    use bytes::Bytes;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use serde_json::json;
+
+    use http_body_util::Limited;
+
+    use super::{
+        compression_hint, decode_body, decompress_lz64, detect_compression, is_zlib_header,
+        read_body, stream_ndjson, NdjsonItem,
+    };
+    use crate::api::CaptureError;
+    use crate::lz64::tests::BATCH_PAYLOAD as LZ64_PAYLOAD;
+    use crate::v0_request::{Compression, RawRequest};
+
+    fn chunked_body(payload: Vec<u8>, chunk_size: usize) -> Body {
+        let chunks: Vec<Result<Bytes, io::Error>> = payload
+            .chunks(chunk_size)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        Body::from_stream(futures::stream::iter(chunks))
+    }
+
+    fn gzip(payload: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(payload).expect("failed to compress");
+        encoder.finish().expect("failed to compress")
+    }
+
+    fn deflate(payload: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(payload).expect("failed to compress");
+        encoder.finish().expect("failed to compress")
+    }
+
+    fn zstd(payload: &[u8]) -> Vec<u8> {
+        zstd::stream::encode_all(payload, 0).expect("failed to compress")
+    }
+
+    fn brotli(payload: &[u8]) -> Vec<u8> {
+        let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+        encoder.write_all(payload).expect("failed to compress");
+        encoder.into_inner()
+    }
+
+    #[tokio::test]
+    async fn decode_chunked_bodies() {
+        let payload = json!({
+            "api_key": "my_token",
+            "batch": [{"event": "e1", "distinct_id": "id1"}, {"event": "e2", "distinct_id": "id2"}]
+        })
+        .to_string()
+        .into_bytes();
+
+        // Chunks are smaller than the magic numbers, to exercise the peeking logic
+        for (body, hint) in [
+            (chunked_body(payload.clone(), 1), None),
+            (chunked_body(payload.clone(), 1024), None),
+            (chunked_body(gzip(&payload), 2), None),
+            (chunked_body(gzip(&payload), 1024), None),
+            (chunked_body(zstd(&payload), 3), None),
+            (chunked_body(deflate(&payload), 1), None),
+            (
+                chunked_body(brotli(&payload), 16),
+                Some(Compression::Brotli),
+            ),
+            // Wrong hints are ignored for formats we can detect
+            (chunked_body(gzip(&payload), 16), Some(Compression::Zstd)),
+            (chunked_body(payload.clone(), 16), Some(Compression::Gzip)),
+        ] {
+            let request: RawRequest = decode_body(body, hint, 2048)
+                .await
+                .expect("failed to decode");
+            let token = request
+                .extract_and_verify_token()
+                .expect("failed to extract token");
+            assert_eq!("my_token", token);
+            assert_eq!(2, request.events().len());
+        }
+    }
+
+    #[tokio::test]
+    async fn decode_lz64_bodies() {
+        let request: RawRequest = decode_body(
+            chunked_body(LZ64_PAYLOAD.as_bytes().to_vec(), 64),
+            Some(Compression::Lz64),
+            4096,
+        )
+        .await
+        .expect("failed to decode");
+        let events = request.events();
+        assert_eq!(1, events.len());
+        assert_eq!("🤓", events[0].event);
+
+        assert!(matches!(
+            decode_body::<RawRequest>(Body::from("foo"), Some(Compression::Lz64), 4096).await,
+            Err(CaptureError::RequestDecodingError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn decode_lz64_bodies_looking_like_zlib() {
+        // lz-string of "N€", its first two bytes are a valid zlib header
+        let payload = "HKGoIg==";
+        assert!(is_zlib_header(payload.as_bytes()));
+        assert_eq!(
+            Some(Compression::Deflate),
+            detect_compression(payload.as_bytes(), None)
+        );
+        assert_eq!(
+            Some(Compression::Lz64),
+            detect_compression(payload.as_bytes(), Some(Compression::Lz64))
+        );
+        assert_eq!(
+            "N€",
+            decompress_lz64(payload, 4096).expect("failed to decompress")
+        );
+
+        // Decompressed as lz64, then rejected for not being JSON, instead of failing to inflate
+        assert!(matches!(
+            decode_body::<RawRequest>(Body::from(payload), Some(Compression::Lz64), 4096).await,
+            Err(CaptureError::RequestParsingError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_payloads_over_limit() {
+        let payload =
+            json!({"event": "e", "distinct_id": "id", "properties": {"big": "a".repeat(4096)}})
+                .to_string()
+                .into_bytes();
+
+        for (body, hint) in [
+            (chunked_body(payload.clone(), 128), None),
+            (chunked_body(gzip(&payload), 128), None),
+            (chunked_body(zstd(&payload), 128), None),
+            (chunked_body(deflate(&payload), 128), None),
+            (
+                chunked_body(brotli(&payload), 128),
+                Some(Compression::Brotli),
+            ),
+            (
+                chunked_body(LZ64_PAYLOAD.as_bytes().to_vec(), 128),
+                Some(Compression::Lz64),
+            ),
+        ] {
+            assert!(matches!(
+                decode_body::<RawRequest>(body, hint, 512).await,
+                Err(CaptureError::EventTooBig)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_bodies_over_limit() {
+        let payload = json!({"event": "e", "distinct_id": "id"})
+            .to_string()
+            .into_bytes();
+
+        // Chunked bodies have no content-length, they only hit the limit while being read
+        let limited = Body::new(Limited::new(chunked_body(payload.clone(), 8), 16));
+        assert!(matches!(
+            decode_body::<RawRequest>(limited, None, 2048).await,
+            Err(CaptureError::PayloadTooLarge)
+        ));
+
+        assert!(matches!(
+            read_body(chunked_body(payload.clone(), 8), 16).await,
+            Err(CaptureError::PayloadTooLarge)
+        ));
+        assert_eq!(
+            read_body(chunked_body(payload.clone(), 8), 2048)
+                .await
+                .expect("failed to read body"),
+            Bytes::from(payload)
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_payloads() {
+        // Valid gzip header, followed by a deflate block of reserved type
+        let bad_gzip = [
+            &[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff][..],
+            &[0xff; 16][..],
+        ]
+        .concat();
+        assert!(matches!(
+            decode_body::<RawRequest>(chunked_body(bad_gzip, 4), None, 2048).await,
+            Err(CaptureError::RequestDecodingError(_))
+        ));
+
+        assert!(matches!(
+            decode_body::<RawRequest>(Body::from("{\"event\": "), None, 2048).await,
+            Err(CaptureError::RequestParsingError(_))
+        ));
+
+        let failing: Vec<Result<Bytes, io::Error>> = vec![
+            Ok(Bytes::from("{\"event\": ")),
+            Err(io::Error::other("connection reset")),
+        ];
+        assert!(matches!(
+            decode_body::<RawRequest>(
+                Body::from_stream(futures::stream::iter(failing)),
+                None,
+                2048
+            )
+            .await,
+            Err(CaptureError::RequestDecodingError(_))
+        ));
+    }
+
+    #[test]
+    fn read_compression_hint() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, compression_hint(None, &headers));
+        assert_eq!(
+            None,
+            compression_hint(Some(Compression::Unsupported), &headers)
+        );
+
+        headers.insert("content-encoding", HeaderValue::from_static("br"));
+        assert_eq!(Some(Compression::Brotli), compression_hint(None, &headers));
+        // The query param takes precedence over the header
+        assert_eq!(
+            Some(Compression::Lz64),
+            compression_hint(Some(Compression::Lz64), &headers)
+        );
+
+        headers.insert("content-encoding", HeaderValue::from_static("identity"));
+        assert_eq!(None, compression_hint(None, &headers));
+    }
+
+    async fn collect_lines(body: Body, line_limit: usize) -> Vec<NdjsonItem<serde_json::Value>> {
+        let mut rx = stream_ndjson(body, line_limit, 2);
+        let mut items = Vec::new();
+        while let Some(item) = rx.recv().await {
+            items.push(item);
+        }
+        items
+    }
+
+    #[tokio::test]
+    async fn stream_ndjson_lines() {
+        let long = format!("{{\"event\": \"{}\"}}", "x".repeat(100));
+        let payload = format!("{{\"event\": \"e1\"}}\n\n{long}\nnot json\r\n{{\"event\": \"e5\"}}");
+
+        for body in [
+            payload.clone().into_bytes(),
+            gzip(payload.as_bytes()),
+            zstd(payload.as_bytes()),
+            deflate(payload.as_bytes()),
+        ] {
+            let items = collect_lines(chunked_body(body, 7), 64).await;
+            assert_eq!(4, items.len());
+            assert!(matches!(&items[0], NdjsonItem::Line(1, Ok(v)) if v["event"] == "e1"));
+            assert!(matches!(
+                &items[1],
+                NdjsonItem::Line(3, Err(CaptureError::EventTooBig))
+            ));
+            assert!(matches!(
+                &items[2],
+                NdjsonItem::Line(4, Err(CaptureError::RequestParsingError(_)))
+            ));
+            assert!(matches!(&items[3], NdjsonItem::Line(5, Ok(v)) if v["event"] == "e5"));
+        }
+    }
+
+    #[tokio::test]
+    async fn stop_ndjson_on_invalid_compression() {
+        let mut body = gzip(b"{\"event\": \"e1\"}\n{\"event\": \"e2\"}");
+        body.truncate(body.len() / 2);
+        let items = collect_lines(Body::from(body), 64).await;
+        assert!(matches!(
+            items.last(),
+            Some(NdjsonItem::Failed(CaptureError::RequestDecodingError(_)))
+        ));
+    }
+}
@@ -0,0 +1,337 @@
+/// Bot and crawler filtering.
+///
+/// Requests are classified from their user agent, matched against a list of well-known
+/// crawlers, and from their client IP, matched against the crawler ranges of the
+/// `BOT_IP_RANGES` setting. What we do with bot traffic depends on the team: its events
+/// are either dropped, or tagged with the `$bot` and `$bot_name` properties, so that they
+/// can be filtered out in queries. Teams opt in through the `@posthog/capture-bot-filtering`
+/// Redis hash, holding `drop` or `tag` per token, the `*` token applying to all teams.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+use metrics::gauge;
+use serde::Deserialize;
+use serde_json::Value;
+use time::Duration;
+use tokio::sync::RwLock;
+use tokio::task;
+use tokio::time::interval;
+
+use crate::redis::Client;
+use crate::v0_request::RawEvent;
+
+pub const BOT_FILTERING_CACHE_KEY: &str = "@posthog/capture-bot-filtering";
+
+/// Settings under this token apply to teams without their own settings
+const ANY_TOKEN: &str = "*";
+
+/// Lowercase user agent fragments of well-known crawlers, and the name we tag them with.
+/// Specific crawlers come first, generic fragments last.
+const BOT_USER_AGENTS: &[(&str, &str)] = &[
+    ("googlebot", "googlebot"),
+    ("adsbot-google", "googlebot"),
+    ("mediapartners-google", "googlebot"),
+    ("bingbot", "bingbot"),
+    ("bingpreview", "bingbot"),
+    ("yandexbot", "yandexbot"),
+    ("baiduspider", "baiduspider"),
+    ("duckduckbot", "duckduckbot"),
+    ("slurp", "yahoo"),
+    ("applebot", "applebot"),
+    ("facebookexternalhit", "facebook"),
+    ("twitterbot", "twitterbot"),
+    ("linkedinbot", "linkedinbot"),
+    ("slackbot", "slackbot"),
+    ("discordbot", "discordbot"),
+    ("ahrefsbot", "ahrefsbot"),
+    ("semrushbot", "semrushbot"),
+    ("mj12bot", "mj12bot"),
+    ("dotbot", "dotbot"),
+    ("petalbot", "petalbot"),
+    ("bytespider", "bytespider"),
+    ("gptbot", "gptbot"),
+    ("ccbot", "ccbot"),
+    ("headlesschrome", "headless_chrome"),
+    ("phantomjs", "phantomjs"),
+    ("crawler", "crawler"),
+    ("spider", "crawler"),
+    ("bot/", "crawler"),
+];
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BotAction {
+    Drop,
+    Tag,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BotMatch {
+    pub action: BotAction,
+    pub name: String,
+}
+
+impl BotMatch {
+    /// Tags the event as bot traffic.
+    pub fn tag(&self, event: &mut RawEvent) {
+        event
+            .properties
+            .insert(String::from("$bot"), Value::Bool(true));
+        event
+            .properties
+            .insert(String::from("$bot_name"), Value::String(self.name.clone()));
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct IpRange {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    fn parse(value: &str) -> Option<IpRange> {
+        let (network, prefix) = match value.split_once('/') {
+            Some((network, prefix)) => (network.parse().ok()?, prefix.parse().ok()?),
+            None => {
+                let network: IpAddr = value.parse().ok()?;
+                (network, if network.is_ipv4() { 32 } else { 128 })
+            }
+        };
+        match network {
+            IpAddr::V4(_) if prefix <= 32 => Some(IpRange { network, prefix }),
+            IpAddr::V6(_) if prefix <= 128 => Some(IpRange { network, prefix }),
+            _ => None,
+        }
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BotFilter {
+    ip_ranges: Arc<Vec<(IpRange, String)>>,
+    actions: Arc<RwLock<HashMap<String, BotAction>>>,
+}
+
+impl BotFilter {
+    /// Create a new BotFilter, with the crawler IP ranges as a comma-separated list of
+    /// `name=cidr` values, `googlebot=66.249.64.0/19` for example.
+    pub fn new(ip_ranges: &str) -> anyhow::Result<BotFilter> {
+        let mut ranges = Vec::new();
+        for value in ip_ranges.split(',') {
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            let (name, range) = match value.split_once('=') {
+                Some((name, range)) => (name.trim(), range.trim()),
+                None => anyhow::bail!("bot IP range {value} has no name"),
+            };
+            match IpRange::parse(range) {
+                Some(range) => ranges.push((range, name.to_string())),
+                None => anyhow::bail!("invalid bot IP range {value}"),
+            }
+        }
+        Ok(BotFilter {
+            ip_ranges: Arc::new(ranges),
+            actions: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    /// Starts loading the team settings from Redis in the background.
+    pub fn load_from_redis(
+        &self,
+        interval_duration: Duration,
+        redis: Arc<dyn Client + Send + Sync>,
+        redis_key_prefix: Option<String>,
+    ) {
+        let actions = Arc::clone(&self.actions);
+        let key = format!(
+            "{}{BOT_FILTERING_CACHE_KEY}",
+            redis_key_prefix.unwrap_or_default()
+        );
+        let interval_duration =
+            StdDuration::from_nanos(interval_duration.whole_nanoseconds() as u64);
+
+        task::spawn(async move {
+            let mut interval = interval(interval_duration);
+            loop {
+                match redis.hgetall(key.clone()).await {
+                    Ok(values) => {
+                        let mut updated = HashMap::with_capacity(values.len());
+                        for (token, value) in values {
+                            match serde_json::from_value(Value::String(value.clone())) {
+                                Ok(action) => {
+                                    updated.insert(token, action);
+                                }
+                                Err(_) => {
+                                    tracing::warn!("invalid bot filtering for {token}: {value}")
+                                }
+                            }
+                        }
+                        gauge!("capture_bot_filtering_loaded_tokens").set(updated.len() as f64);
+                        *actions.write().await = updated;
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to update bot filtering from Redis: {:?}", e);
+                    }
+                }
+
+                interval.tick().await;
+            }
+        });
+    }
+
+    /// Returns how to handle the request if it comes from a bot, and the team filters bots.
+    pub async fn check(
+        &self,
+        token: &str,
+        user_agent: Option<&str>,
+        client_ip: &str,
+    ) -> Option<BotMatch> {
+        let action = {
+            let actions = self.actions.read().await;
+            *actions.get(token).or_else(|| actions.get(ANY_TOKEN))?
+        };
+        let name = self.classify(user_agent, client_ip)?;
+        Some(BotMatch { action, name })
+    }
+
+    /// Returns the bot name if the user agent or client IP belong to a known crawler.
+    fn classify(&self, user_agent: Option<&str>, client_ip: &str) -> Option<String> {
+        if let Some(user_agent) = user_agent {
+            let user_agent = user_agent.to_ascii_lowercase();
+            if let Some((_, name)) = BOT_USER_AGENTS
+                .iter()
+                .find(|(fragment, _)| user_agent.contains(fragment))
+            {
+                return Some(name.to_string());
+            }
+        }
+        let ip: IpAddr = client_ip.parse().ok()?;
+        self.ip_ranges
+            .iter()
+            .find(|(range, _)| range.contains(&ip))
+            .map(|(_, name)| name.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use time::Duration;
+
+    use crate::bots::{BotAction, BotFilter, BotMatch, IpRange, BOT_FILTERING_CACHE_KEY};
+    use crate::redis::MockRedisClient;
+    use crate::v0_request::RawEvent;
+
+    const CHROME: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36";
+    const GOOGLEBOT: &str =
+        "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+
+    #[test]
+    fn match_ip_ranges() {
+        let range = IpRange::parse("66.249.64.0/19").unwrap();
+        assert!(range.contains(&"66.249.66.1".parse().unwrap()));
+        assert!(!range.contains(&"66.249.96.1".parse().unwrap()));
+        assert!(!range.contains(&"::1".parse().unwrap()));
+
+        let range = IpRange::parse("2001:4860:4801::/48").unwrap();
+        assert!(range.contains(&"2001:4860:4801:10::1".parse().unwrap()));
+        assert!(!range.contains(&"2001:4860:4802::1".parse().unwrap()));
+
+        assert!(IpRange::parse("10.0.0.1")
+            .unwrap()
+            .contains(&"10.0.0.1".parse().unwrap()));
+        assert!(IpRange::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(&"1.2.3.4".parse().unwrap()));
+        assert_eq!(None, IpRange::parse("10.0.0.0/33"));
+        assert_eq!(None, IpRange::parse("nope/8"));
+    }
+
+    #[test]
+    fn classify_requests() {
+        let filter = BotFilter::new("googlebot=66.249.64.0/19, internal=10.0.0.0/8")
+            .expect("invalid ranges");
+
+        assert_eq!(None, filter.classify(Some(CHROME), "1.2.3.4"));
+        assert_eq!(None, filter.classify(None, "unknown"));
+        assert_eq!(
+            Some("googlebot".to_string()),
+            filter.classify(Some(GOOGLEBOT), "1.2.3.4")
+        );
+        assert_eq!(
+            Some("crawler".to_string()),
+            filter.classify(Some("SomeCrawler/1.0"), "1.2.3.4")
+        );
+        assert_eq!(
+            Some("internal".to_string()),
+            filter.classify(Some(CHROME), "10.1.2.3")
+        );
+
+        assert!(BotFilter::new("").is_ok());
+        assert!(BotFilter::new("66.249.64.0/19").is_err());
+        assert!(BotFilter::new("googlebot=nope").is_err());
+    }
+
+    #[tokio::test]
+    async fn apply_team_settings() {
+        let client = MockRedisClient::new().hgetall_ret(
+            BOT_FILTERING_CACHE_KEY,
+            HashMap::from([
+                ("dropping".to_string(), "drop".to_string()),
+                ("*".to_string(), "tag".to_string()),
+            ]),
+        );
+        let filter = BotFilter::new("").expect("invalid ranges");
+        filter.load_from_redis(Duration::seconds(1), Arc::new(client), None);
+
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        assert_eq!(
+            Some(BotMatch {
+                action: BotAction::Drop,
+                name: "googlebot".to_string()
+            }),
+            filter.check("dropping", Some(GOOGLEBOT), "1.2.3.4").await
+        );
+        assert_eq!(
+            Some(BotAction::Tag),
+            filter
+                .check("other", Some(GOOGLEBOT), "1.2.3.4")
+                .await
+                .map(|bot| bot.action)
+        );
+        assert_eq!(None, filter.check("other", Some(CHROME), "1.2.3.4").await);
+
+        let mut event = RawEvent::default();
+        BotMatch {
+            action: BotAction::Tag,
+            name: "googlebot".to_string(),
+        }
+        .tag(&mut event);
+        assert_eq!(
+            json!({"$bot": true, "$bot_name": "googlebot"}),
+            json!(event.properties)
+        );
+    }
+}
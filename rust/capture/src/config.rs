@@ -20,6 +20,62 @@ impl std::str::FromStr for CaptureMode {
     }
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub enum TeeDestination {
+    Kafka,
+    File,
+    Print,
+}
+
+impl std::str::FromStr for TeeDestination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_ref() {
+            "kafka" => Ok(TeeDestination::Kafka),
+            "file" => Ok(TeeDestination::File),
+            "print" => Ok(TeeDestination::Print),
+            _ => Err(format!("Unknown tee destination: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DebugCaptureDestination {
+    Kafka,
+    File,
+}
+
+impl std::str::FromStr for DebugCaptureDestination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_ref() {
+            "kafka" => Ok(DebugCaptureDestination::Kafka),
+            "file" => Ok(DebugCaptureDestination::File),
+            _ => Err(format!("Unknown debug capture destination: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DedupBackend {
+    Memory,
+    Redis,
+}
+
+impl std::str::FromStr for DedupBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_ref() {
+            "memory" => Ok(DedupBackend::Memory),
+            "redis" => Ok(DedupBackend::Redis),
+            _ => Err(format!("Unknown dedup backend: {s}")),
+        }
+    }
+}
+
 #[derive(Envconfig, Clone)]
 pub struct Config {
     #[envconfig(default = "false")]
@@ -42,9 +98,115 @@ pub struct Config {
 
     pub overflow_forced_keys: Option<String>, // Coma-delimited keys
 
+    #[envconfig(default = "false")]
+    pub overflow_redis_enabled: bool, // Share overflow counts across replicas through Redis
+
+    #[envconfig(default = "false")]
+    pub rate_limit_enabled: bool,
+
+    #[envconfig(default = "100")]
+    pub rate_limit_per_token_per_second: NonZeroU32, // Requests per second, can be overridden from Redis
+
+    #[envconfig(default = "1000")]
+    pub rate_limit_per_token_burst: NonZeroU32,
+
+    pub rate_limit_per_distinct_id_per_second: Option<NonZeroU32>, // Events per second, disabled if unset
+
+    #[envconfig(default = "100")]
+    pub rate_limit_per_distinct_id_burst: NonZeroU32,
+
+    pub rate_limit_global_per_second: Option<NonZeroU32>, // Requests per second, disabled if unset
+
+    #[envconfig(default = "10000")]
+    pub rate_limit_global_burst: NonZeroU32,
+
+    #[envconfig(default = "1000")]
+    pub import_events_per_second: NonZeroU32, // Pod-wide production rate of the import endpoint
+
+    pub dedup_backend: Option<DedupBackend>, // Deduplicate events by uuid, disabled if unset
+
+    #[envconfig(default = "60")]
+    pub dedup_window_secs: u64,
+
+    #[envconfig(default = "1000000")]
+    pub dedup_memory_max_entries: usize, // Per window in the memory backend, uuids are not deduplicated past it
+
     #[envconfig(nested = true)]
     pub kafka: KafkaConfig,
 
+    #[envconfig(default = "false")]
+    pub routing_enabled: bool, // Route events to topics with rules from Redis and ROUTING_RULES
+
+    pub routing_rules: Option<String>, // JSON object of token to rules, see routing.rs
+
+    #[envconfig(default = "false")]
+    pub privacy_enabled: bool, // Apply the per-team privacy settings from Redis, see privacy.rs
+
+    #[envconfig(default = "false")]
+    pub bot_filtering_enabled: bool, // Drop or tag bot traffic for teams opted in in Redis, see bots.rs
+
+    #[envconfig(
+        default = "googlebot=66.249.64.0/19,bingbot=157.55.39.0/24,bingbot=207.46.13.0/24,bingbot=40.77.167.0/24"
+    )]
+    pub bot_ip_ranges: String, // Coma-delimited name=cidr crawler ranges
+
+    #[envconfig(default = "false")]
+    pub load_shedding_enabled: bool, // Shed traffic as the Kafka producer queue fills up, see shedding.rs
+
+    #[envconfig(default = "0.6")]
+    pub load_shedding_low_priority_watermark: f64, // Queue usage from which historical and overflow events are dropped
+
+    #[envconfig(default = "0.85")]
+    pub load_shedding_reject_watermark: f64, // Queue usage from which requests are rejected with a 503
+
+    // The three shutdown steps must fit in the termination grace period, 30s on Kubernetes
+    #[envconfig(default = "5")]
+    pub shutdown_drain_delay_secs: u64, // Keep serving while not-ready on shutdown, for load balancers to catch up
+
+    #[envconfig(default = "15")]
+    pub shutdown_requests_timeout_secs: u64, // Maximum wait for in-flight requests on shutdown
+
+    #[envconfig(default = "5")]
+    pub shutdown_flush_timeout_secs: u64, // Maximum wait for queued Kafka deliveries on shutdown
+
+    #[envconfig(default = "false")]
+    pub spool_enabled: bool, // Spool events to disk when Kafka is unavailable
+
+    #[envconfig(default = "/var/lib/capture/spool")]
+    pub spool_path: String,
+
+    #[envconfig(default = "1024")]
+    pub spool_max_mib: u64, // Maximum disk usage of the spool in mebibytes
+
+    pub tee_destination: Option<TeeDestination>, // Mirror a sample of the events, disabled if unset
+
+    #[envconfig(default = "0.01")]
+    pub tee_sample_rate: f64,
+
+    pub tee_tokens: Option<String>, // Coma-delimited tokens to mirror, all tokens if unset
+
+    pub tee_kafka_hosts: Option<String>, // Secondary cluster, using the same topics as the primary
+
+    #[envconfig(default = "capture-tee.jsonl")]
+    pub tee_file_path: String,
+
+    pub debug_capture_destination: Option<DebugCaptureDestination>, // Record raw requests of the tokens listed in Redis, disabled if unset
+
+    #[envconfig(default = "0.1")]
+    pub debug_capture_sample_rate: f64,
+
+    #[envconfig(default = "512")]
+    pub debug_capture_max_body_kib: usize, // Recorded bodies are truncated to this size, and to fit in Kafka messages
+
+    #[envconfig(default = "capture_debug_requests")]
+    pub debug_capture_kafka_topic: String,
+
+    #[envconfig(default = "capture-debug.jsonl")]
+    pub debug_capture_file_path: String,
+
+    #[envconfig(default = "100")]
+    pub debug_capture_file_max_mib: u64, // The file is rotated to `{path}.1` at this size
+
     #[envconfig(default = "1.0")]
     pub otel_sampling_rate: f64,
 
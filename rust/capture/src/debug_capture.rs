@@ -0,0 +1,578 @@
+/// Raw request recording, to debug what the SDKs of a team actually send.
+///
+/// Operators list the tokens to debug in the `@posthog/capture-debug-tokens` Redis hash,
+/// holding the unix timestamp until which to record requests for each token, at most one
+/// day ahead. While tokens are listed, the first `DEBUG_CAPTURE_MAX_BODY_KIB` of request
+/// bodies are copied as they are streamed to the decoders, and once the token of the request
+/// is known, a sample of the requests of listed tokens is recorded with their headers, query,
+/// compressed body and outcome, to a Kafka topic or to rotating local files. Endpoints taking
+/// the token outside of the body only copy the bodies of listed tokens. Requests failing
+/// before we decode their token cannot be recorded.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration as StdDuration;
+
+use axum::body::Body;
+use axum::http::{HeaderMap, Method, Uri};
+use base64::Engine;
+use futures::StreamExt;
+use metrics::{counter, gauge};
+use rand::Rng;
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use rdkafka::ClientConfig;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, RwLock, Semaphore};
+use tokio::task;
+use tokio::time::interval;
+
+use crate::api::CaptureError;
+use crate::config::{Config, DebugCaptureDestination};
+use crate::redis::Client;
+use crate::router;
+
+pub const DEBUG_CAPTURE_CACHE_KEY: &str = "@posthog/capture-debug-tokens";
+
+/// Tokens cannot be debugged for longer than this, so that recordings always stop
+const MAX_DEBUG_DURATION: Duration = Duration::days(1);
+/// Recordings are written in background tasks, skip requests if too many are in flight
+const MAX_RECORDINGS_IN_FLIGHT: usize = 100;
+/// Credentials are never recorded
+const REDACTED_HEADERS: &[&str] = &["authorization", "cookie"];
+/// Room left in Kafka messages for the record fields other than the body
+const RECORD_OVERHEAD_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Serialize)]
+pub struct DebugRecord {
+    pub token: String,
+    pub received_at: String,
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: HashMap<String, String>,
+    pub body: String, // base64-encoded, as sent by the client
+    pub body_size: usize,
+    pub body_truncated: bool,
+    pub outcome: String,
+    pub error: Option<String>,
+}
+
+/// Appends lines to a file, moving it to `{path}.1` when it reaches `max_bytes`.
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    async fn open(path: impl AsRef<Path>, max_bytes: u64) -> std::io::Result<RotatingFile> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        let size = file.metadata().await?.len();
+        Ok(RotatingFile {
+            path,
+            max_bytes,
+            file,
+            size,
+        })
+    }
+
+    async fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_bytes {
+            self.file.flush().await?;
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            tokio::fs::rename(&self.path, rotated).await?;
+            *self = RotatingFile::open(&self.path, self.max_bytes).await?;
+        }
+        self.file.write_all(line).await?;
+        self.file.write_all(b"\n").await?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+enum DebugWriter {
+    Kafka {
+        producer: FutureProducer,
+        topic: String,
+    },
+    File(Mutex<RotatingFile>),
+}
+
+impl DebugWriter {
+    async fn write(&self, record: &DebugRecord) -> anyhow::Result<()> {
+        let payload = serde_json::to_vec(record)?;
+        match self {
+            DebugWriter::Kafka { producer, topic } => {
+                // Not waiting for the delivery, records are best effort
+                drop(
+                    producer
+                        .send_result(FutureRecord::to(topic).key(&record.token).payload(&payload))
+                        .map_err(|(e, _)| e)?,
+                );
+            }
+            DebugWriter::File(file) => file.lock().await.write_line(&payload).await?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct DebugCapture {
+    writer: Arc<DebugWriter>,
+    sample_rate: f64,
+    max_body_bytes: usize,
+    tokens: Arc<RwLock<HashMap<String, OffsetDateTime>>>,
+    in_flight: Arc<Semaphore>,
+}
+
+impl DebugCapture {
+    pub async fn new(
+        destination: &DebugCaptureDestination,
+        config: &Config,
+    ) -> anyhow::Result<DebugCapture> {
+        let writer = match destination {
+            DebugCaptureDestination::Kafka => {
+                let mut client_config = ClientConfig::new();
+                client_config
+                    .set("bootstrap.servers", &config.kafka.kafka_hosts)
+                    .set("compression.codec", &config.kafka.kafka_compression_codec)
+                    .set(
+                        "message.max.bytes",
+                        config.kafka.kafka_producer_message_max_bytes.to_string(),
+                    );
+                if config.kafka.kafka_tls {
+                    client_config
+                        .set("security.protocol", "ssl")
+                        .set("enable.ssl.certificate.verification", "false");
+                }
+                DebugWriter::Kafka {
+                    producer: client_config.create()?,
+                    topic: config.debug_capture_kafka_topic.clone(),
+                }
+            }
+            DebugCaptureDestination::File => DebugWriter::File(Mutex::new(
+                RotatingFile::open(
+                    &config.debug_capture_file_path,
+                    config.debug_capture_file_max_mib * 1024 * 1024,
+                )
+                .await?,
+            )),
+        };
+        let mut max_body_bytes = config.debug_capture_max_body_kib * 1024;
+        if let DebugWriter::Kafka { .. } = writer {
+            max_body_bytes = max_body_bytes.min(max_body_for_message(
+                config.kafka.kafka_producer_message_max_bytes as usize,
+            ));
+        }
+        Ok(DebugCapture::with_writer(
+            writer,
+            config.debug_capture_sample_rate,
+            max_body_bytes,
+        ))
+    }
+
+    fn with_writer(writer: DebugWriter, sample_rate: f64, max_body_bytes: usize) -> DebugCapture {
+        DebugCapture {
+            writer: Arc::new(writer),
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            max_body_bytes,
+            tokens: Arc::new(RwLock::new(HashMap::new())),
+            in_flight: Arc::new(Semaphore::new(MAX_RECORDINGS_IN_FLIGHT)),
+        }
+    }
+
+    /// Delivers the records still queued, on shutdown.
+    pub async fn flush(&self, timeout: StdDuration) -> anyhow::Result<()> {
+        match self.writer.as_ref() {
+            DebugWriter::Kafka { producer, .. } => {
+                let producer = producer.clone();
+                task::spawn_blocking(move || producer.flush(timeout)).await??;
+            }
+            DebugWriter::File(file) => file.lock().await.file.flush().await?,
+        }
+        Ok(())
+    }
+
+    /// Starts loading the debugged tokens from Redis in the background.
+    pub fn load_from_redis(
+        &self,
+        interval_duration: Duration,
+        redis: Arc<dyn Client + Send + Sync>,
+        redis_key_prefix: Option<String>,
+    ) {
+        let tokens = Arc::clone(&self.tokens);
+        let key = format!(
+            "{}{DEBUG_CAPTURE_CACHE_KEY}",
+            redis_key_prefix.unwrap_or_default()
+        );
+        let interval_duration =
+            StdDuration::from_nanos(interval_duration.whole_nanoseconds() as u64);
+
+        task::spawn(async move {
+            let mut interval = interval(interval_duration);
+            loop {
+                match redis.hgetall(key.clone()).await {
+                    Ok(values) => {
+                        let max_until = OffsetDateTime::now_utc() + MAX_DEBUG_DURATION;
+                        let mut updated = HashMap::with_capacity(values.len());
+                        for (token, value) in values {
+                            match value
+                                .parse()
+                                .ok()
+                                .and_then(|ts| OffsetDateTime::from_unix_timestamp(ts).ok())
+                            {
+                                Some(until) => {
+                                    updated.insert(token, until.min(max_until));
+                                }
+                                None => {
+                                    tracing::warn!("invalid debug capture for {token}: {value}")
+                                }
+                            }
+                        }
+                        gauge!("capture_debug_capture_loaded_tokens").set(updated.len() as f64);
+                        *tokens.write().await = updated;
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to update debug tokens from Redis: {:?}", e);
+                    }
+                }
+
+                interval.tick().await;
+            }
+        });
+    }
+
+    /// Whether some tokens are currently debugged, and request bodies must be buffered.
+    pub async fn is_active(&self) -> bool {
+        let now = OffsetDateTime::now_utc();
+        self.tokens.read().await.values().any(|until| *until > now)
+    }
+
+    /// Whether this token is currently debugged.
+    async fn is_debugged(&self, token: &str) -> bool {
+        self.tokens
+            .read()
+            .await
+            .get(token)
+            .is_some_and(|until| *until > OffsetDateTime::now_utc())
+    }
+
+    /// Whether this request of this token must be recorded.
+    async fn is_sampled(&self, token: &str) -> bool {
+        self.is_debugged(token).await
+            && self.sample_rate > 0.0
+            && rand::thread_rng().gen_bool(self.sample_rate)
+    }
+
+    /// Copies the start of the body while it is read, if the request might be recorded, for
+    /// `DebugRequest::finish` to record it once we know its token and outcome. The body is
+    /// still streamed, so that the body limits and decoders behave the same.
+    pub async fn buffer(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        body: Body,
+        token: Option<&str>,
+    ) -> (Body, Option<DebugRequest>) {
+        let recorded = match token {
+            Some(token) => self.is_debugged(token).await,
+            None => self.is_active().await,
+        };
+        if !recorded {
+            return (body, None);
+        }
+
+        let copy = Arc::new(StdMutex::new(BodyCopy::default()));
+        let body = {
+            let copy = copy.clone();
+            let max_body_bytes = self.max_body_bytes;
+            Body::from_stream(body.into_data_stream().map(move |chunk| {
+                if let Ok(chunk) = &chunk {
+                    let mut copy = copy.lock().unwrap();
+                    let room = max_body_bytes.saturating_sub(copy.bytes.len());
+                    copy.bytes
+                        .extend_from_slice(&chunk[..chunk.len().min(room)]);
+                    copy.size += chunk.len();
+                }
+                chunk
+            }))
+        };
+        let request = DebugRequest {
+            capture: self.clone(),
+            received_at: OffsetDateTime::now_utc(),
+            method: method.to_string(),
+            uri: uri.clone(),
+            headers: headers.clone(),
+            body: copy,
+            token: token.map(String::from),
+        };
+        (body, Some(request))
+    }
+}
+
+/// Largest body that fits in a Kafka message once base64-encoded in a record.
+fn max_body_for_message(message_max_bytes: usize) -> usize {
+    message_max_bytes.saturating_sub(RECORD_OVERHEAD_BYTES) / 4 * 3
+}
+
+/// Wraps the body of the request for debug capture, if enabled. `token` is the token of the
+/// request if it is known before reading the body.
+pub async fn debug_request(
+    state: &router::State,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: Body,
+    token: Option<&str>,
+) -> (Body, Option<DebugRequest>) {
+    match &state.debug_capture {
+        None => (body, None),
+        Some(debug_capture) => {
+            debug_capture
+                .buffer(method, uri, headers, body, token)
+                .await
+        }
+    }
+}
+
+/// The start of a request body, and the size of the body read.
+#[derive(Default)]
+struct BodyCopy {
+    bytes: Vec<u8>,
+    size: usize,
+}
+
+/// A buffered request, recorded when finished if its token is debugged.
+pub struct DebugRequest {
+    capture: DebugCapture,
+    received_at: OffsetDateTime,
+    method: String,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Arc<StdMutex<BodyCopy>>,
+    token: Option<String>,
+}
+
+impl DebugRequest {
+    pub fn set_token(&mut self, token: &str) {
+        self.token = Some(token.to_string());
+    }
+
+    /// Records the request in the background if it is sampled.
+    pub fn finish<T>(self, result: &Result<T, CaptureError>) {
+        let Some(token) = self.token.clone() else {
+            return;
+        };
+        let (outcome, error) = match result {
+            Ok(_) => (String::from("ok"), None),
+            Err(err) => (err.reason().to_string(), Some(err.to_string())),
+        };
+        let Ok(permit) = self.capture.in_flight.clone().try_acquire_owned() else {
+            counter!("capture_debug_requests_skipped_total").increment(1);
+            return;
+        };
+
+        tokio::spawn(async move {
+            let capture = self.capture.clone();
+            if !capture.is_sampled(&token).await {
+                return;
+            }
+            let record = self.into_record(token, outcome, error);
+            match capture.writer.write(&record).await {
+                Ok(()) => counter!("capture_debug_requests_recorded_total").increment(1),
+                Err(e) => tracing::warn!("failed to record debug request: {:?}", e),
+            }
+            drop(permit);
+        });
+    }
+
+    fn into_record(self, token: String, outcome: String, error: Option<String>) -> DebugRecord {
+        let body = std::mem::take(&mut *self.body.lock().unwrap());
+        let headers = self
+            .headers
+            .iter()
+            .filter(|(name, _)| !REDACTED_HEADERS.contains(&name.as_str()))
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).to_string(),
+                )
+            })
+            .collect();
+        DebugRecord {
+            token,
+            received_at: self
+                .received_at
+                .format(&Rfc3339)
+                .expect("failed to format timestamp"),
+            method: self.method,
+            path: self.uri.path().to_string(),
+            query: self.uri.query().map(String::from),
+            headers,
+            body: base64::engine::general_purpose::STANDARD.encode(&body.bytes),
+            body_size: body.size,
+            body_truncated: body.size > body.bytes.len(),
+            outcome,
+            error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use axum::http::{HeaderMap, Method, Uri};
+    use time::{Duration, OffsetDateTime};
+    use tokio::sync::Mutex;
+    use uuid::Uuid;
+
+    use crate::api::CaptureError;
+    use base64::Engine;
+
+    use crate::debug_capture::{
+        max_body_for_message, DebugCapture, DebugWriter, RotatingFile, DEBUG_CAPTURE_CACHE_KEY,
+        RECORD_OVERHEAD_BYTES,
+    };
+    use crate::redis::MockRedisClient;
+
+    fn temp_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("capture-debug-{}.jsonl", Uuid::now_v7()))
+    }
+
+    #[tokio::test]
+    async fn rotate_files() {
+        let path = temp_path();
+        let mut file = RotatingFile::open(&path, 10).await.unwrap();
+        file.write_line(b"first").await.unwrap();
+        file.write_line(b"second").await.unwrap();
+        file.write_line(b"third").await.unwrap();
+
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+        assert_eq!("third\n", tokio::fs::read_to_string(&path).await.unwrap());
+        assert_eq!(
+            "second\n",
+            tokio::fs::read_to_string(&rotated).await.unwrap()
+        );
+        tokio::fs::remove_file(&path).await.ok();
+        tokio::fs::remove_file(&rotated).await.ok();
+    }
+
+    #[tokio::test]
+    async fn record_debugged_tokens() {
+        let path = temp_path();
+        let file = RotatingFile::open(&path, 1024 * 1024).await.unwrap();
+        let capture = DebugCapture::with_writer(DebugWriter::File(Mutex::new(file)), 1.0, 1024);
+
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let client = MockRedisClient::new().hgetall_ret(
+            DEBUG_CAPTURE_CACHE_KEY,
+            HashMap::from([
+                ("debugged".to_string(), (now + 3600).to_string()),
+                ("expired".to_string(), (now - 3600).to_string()),
+            ]),
+        );
+        assert!(!capture.is_active().await);
+        capture.load_from_redis(Duration::seconds(1), Arc::new(client), None);
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+        assert!(capture.is_active().await);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/json".parse().unwrap());
+        headers.insert("cookie", "secret".parse().unwrap());
+        for token in ["debugged", "expired"] {
+            let (body, request) = capture
+                .buffer(
+                    &Method::POST,
+                    &Uri::from_static("/e/?ver=1.2.3"),
+                    &headers,
+                    vec![b'a'; 2000].into(),
+                    None,
+                )
+                .await;
+            let mut request = request.expect("request not buffered");
+            // The body is streamed through untouched, and copied up to the limit
+            let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+            assert_eq!(2000, body.len());
+            request.set_token(token);
+            request.finish::<()>(&Err(CaptureError::MissingDistinctId));
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        let content = tokio::fs::read_to_string(&path).await.unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(1, lines.len());
+        assert_eq!("debugged", lines[0]["token"]);
+        assert_eq!("/e/", lines[0]["path"]);
+        assert_eq!("ver=1.2.3", lines[0]["query"]);
+        assert_eq!("missing_distinct_id", lines[0]["outcome"]);
+        assert_eq!("application/json", lines[0]["headers"]["content-type"]);
+        assert!(lines[0]["headers"].get("cookie").is_none());
+        assert_eq!(2000, lines[0]["body_size"]);
+        assert_eq!(true, lines[0]["body_truncated"]);
+        assert_eq!(1368, lines[0]["body"].as_str().unwrap().len()); // 1024 bytes in base64
+        tokio::fs::remove_file(&path).await.ok();
+    }
+
+    #[tokio::test]
+    async fn only_buffer_known_tokens_if_debugged() {
+        let path = temp_path();
+        let file = RotatingFile::open(&path, 1024 * 1024).await.unwrap();
+        let capture = DebugCapture::with_writer(DebugWriter::File(Mutex::new(file)), 1.0, 1024);
+
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let client = MockRedisClient::new().hgetall_ret(
+            DEBUG_CAPTURE_CACHE_KEY,
+            HashMap::from([("debugged".to_string(), (now + 3600).to_string())]),
+        );
+        capture.load_from_redis(Duration::seconds(1), Arc::new(client), None);
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        let uri = Uri::from_static("/i/v0/import?token=other");
+        let (_, request) = capture
+            .buffer(
+                &Method::POST,
+                &uri,
+                &HeaderMap::new(),
+                "{}".into(),
+                Some("other"),
+            )
+            .await;
+        assert!(request.is_none());
+        let (_, request) = capture
+            .buffer(
+                &Method::POST,
+                &uri,
+                &HeaderMap::new(),
+                "{}".into(),
+                Some("debugged"),
+            )
+            .await;
+        assert!(request.is_some());
+        tokio::fs::remove_file(&path).await.ok();
+    }
+
+    #[test]
+    fn fit_records_in_kafka_messages() {
+        let max_body = max_body_for_message(1_000_000);
+        let encoded = base64::engine::general_purpose::STANDARD.encode(vec![0; max_body]);
+        assert!(encoded.len() + RECORD_OVERHEAD_BYTES <= 1_000_000);
+    }
+}
@@ -0,0 +1,305 @@
+/// Drops exact duplicates of recent events, sent again by SDKs retrying after a network timeout.
+///
+/// Events are identified by their `(token, uuid)` pair. Only events with a client-provided uuid
+/// can be deduplicated, as capture generates a new uuid for the others.
+///
+/// Seen pairs are stored in time buckets as wide as the deduplication window, and we look them up
+/// in the current and previous buckets. Repeats are detected if they arrive within the window,
+/// and might be detected up to twice the window after the first event.
+///
+/// The in-memory backend only deduplicates on the current pod, while the Redis backend is shared
+/// across pods. Both fail open: events are not deduplicated if Redis is unavailable, or once the
+/// in-memory bucket is full.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use metrics::counter;
+use time::OffsetDateTime;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::redis::Client;
+
+pub const DEDUP_CACHE_KEY: &str = "@posthog/capture-dedup/";
+
+#[async_trait]
+pub trait Deduplicator {
+    /// Records the uuids as seen for the token, and returns whether each of them is a duplicate.
+    /// Repeats inside the same call are reported as duplicates too.
+    async fn check(&self, token: &str, uuids: &[Uuid]) -> Vec<bool>;
+
+    /// Forgets uuids recorded by `check`, for events that were rejected afterwards, so that
+    /// retries from the client, or fixed versions of invalid events, are not dropped.
+    async fn forget(&self, token: &str, uuids: &[Uuid]);
+}
+
+/// Returns whether each event is a duplicate, events without a uuid are never duplicates.
+pub async fn find_duplicates(
+    deduplicator: &(dyn Deduplicator + Send + Sync),
+    token: &str,
+    uuids: &[Option<Uuid>],
+) -> Vec<bool> {
+    let known: Vec<Uuid> = uuids.iter().flatten().copied().collect();
+    if known.is_empty() {
+        return vec![false; uuids.len()];
+    }
+    let mut found = deduplicator.check(token, &known).await.into_iter();
+    uuids
+        .iter()
+        .map(|uuid| uuid.is_some() && found.next().unwrap_or(false))
+        .collect()
+}
+
+/// Flags the repeats inside one call, that would not be found in the buckets yet.
+fn repeats_in_call(uuids: &[Uuid]) -> Vec<bool> {
+    let mut seen = HashSet::with_capacity(uuids.len());
+    uuids.iter().map(|uuid| !seen.insert(uuid)).collect()
+}
+
+/// Tokens are stored as hashes to keep entries small, a collision between two tokens would only
+/// matter if they also sent the same uuid.
+fn token_hash(token: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    token.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Time bucket and the `(token hash, uuid)` pairs seen in it
+type Bucket = (i64, HashSet<(u64, Uuid)>);
+
+pub struct MemoryDeduplicator {
+    window_secs: i64,
+    max_entries: usize, // Per bucket, new uuids are not recorded past it
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+impl MemoryDeduplicator {
+    pub fn new(window_secs: u64, max_entries: usize) -> Self {
+        MemoryDeduplicator {
+            window_secs: window_secs.max(1) as i64,
+            max_entries,
+            buckets: Mutex::new(VecDeque::with_capacity(2)),
+        }
+    }
+
+    fn check_at(&self, now: i64, token: &str, uuids: &[Uuid]) -> Vec<bool> {
+        let bucket = now / self.window_secs;
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Drop the buckets we don't need to look at anymore
+        while buckets.front().is_some_and(|(b, _)| *b < bucket - 1) {
+            buckets.pop_front();
+        }
+        if !matches!(buckets.back(), Some((b, _)) if *b == bucket) {
+            buckets.push_back((bucket, HashSet::new()));
+        }
+
+        let token = token_hash(token);
+        let repeats = repeats_in_call(uuids);
+        let mut duplicates = Vec::with_capacity(uuids.len());
+        let mut dropped = 0;
+        for (uuid, repeated) in uuids.iter().zip(repeats) {
+            let key = (token, *uuid);
+            let seen = repeated || buckets.iter().any(|(_, set)| set.contains(&key));
+            if let Some((_, current)) = buckets.back_mut() {
+                if current.len() < self.max_entries {
+                    current.insert(key);
+                } else if !seen {
+                    dropped += 1;
+                }
+            }
+            duplicates.push(seen);
+        }
+        if dropped > 0 {
+            counter!("capture_dedup_full_total").increment(dropped);
+        }
+        duplicates
+    }
+}
+
+#[async_trait]
+impl Deduplicator for MemoryDeduplicator {
+    async fn check(&self, token: &str, uuids: &[Uuid]) -> Vec<bool> {
+        self.check_at(OffsetDateTime::now_utc().unix_timestamp(), token, uuids)
+    }
+
+    async fn forget(&self, token: &str, uuids: &[Uuid]) {
+        let token = token_hash(token);
+        let mut buckets = self.buckets.lock().unwrap();
+        for uuid in uuids {
+            let key = (token, *uuid);
+            for (_, set) in buckets.iter_mut() {
+                set.remove(&key);
+            }
+        }
+    }
+}
+
+pub struct RedisDeduplicator {
+    redis: Arc<dyn Client + Send + Sync>,
+    window_secs: i64,
+    key_prefix: String,
+}
+
+impl RedisDeduplicator {
+    pub fn new(
+        redis: Arc<dyn Client + Send + Sync>,
+        window_secs: u64,
+        redis_key_prefix: Option<String>,
+    ) -> Self {
+        RedisDeduplicator {
+            redis,
+            window_secs: window_secs.max(1) as i64,
+            key_prefix: format!("{}{DEDUP_CACHE_KEY}", redis_key_prefix.unwrap_or_default()),
+        }
+    }
+
+    fn bucket_key(&self, token: &str, bucket: i64) -> String {
+        format!("{}{token}/{bucket}", self.key_prefix)
+    }
+
+    async fn check_at(&self, now: i64, token: &str, uuids: &[Uuid]) -> Vec<bool> {
+        let bucket = now / self.window_secs;
+        let current = self.bucket_key(token, bucket);
+        let lookback = vec![current.clone(), self.bucket_key(token, bucket - 1)];
+        let members = uuids.iter().map(Uuid::to_string).collect();
+
+        let found = match self
+            .redis
+            .sadd_checked(current, lookback, members, 2 * self.window_secs as u64)
+            .await
+        {
+            Ok(found) => found,
+            Err(e) => {
+                tracing::error!("failed to check for duplicates in Redis: {:?}", e);
+                counter!("capture_dedup_errors_total").increment(1);
+                vec![false; uuids.len()]
+            }
+        };
+
+        repeats_in_call(uuids)
+            .into_iter()
+            .zip(found)
+            .map(|(repeated, found)| repeated || found)
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Deduplicator for RedisDeduplicator {
+    #[instrument(skip_all, fields(events = uuids.len()))]
+    async fn check(&self, token: &str, uuids: &[Uuid]) -> Vec<bool> {
+        self.check_at(OffsetDateTime::now_utc().unix_timestamp(), token, uuids)
+            .await
+    }
+
+    #[instrument(skip_all, fields(events = uuids.len()))]
+    async fn forget(&self, token: &str, uuids: &[Uuid]) {
+        // The uuids were recorded in the current bucket, that might have rotated since
+        let bucket = OffsetDateTime::now_utc().unix_timestamp() / self.window_secs;
+        let members: Vec<String> = uuids.iter().map(Uuid::to_string).collect();
+        for key in [
+            self.bucket_key(token, bucket),
+            self.bucket_key(token, bucket - 1),
+        ] {
+            if let Err(e) = self.redis.srem(key, members.clone()).await {
+                tracing::error!("failed to forget duplicates in Redis: {:?}", e);
+                counter!("capture_dedup_errors_total").increment(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use uuid::Uuid;
+
+    use crate::dedup::{find_duplicates, Deduplicator, MemoryDeduplicator, RedisDeduplicator};
+    use crate::redis::MockRedisClient;
+
+    #[test]
+    fn memory_dedup_in_window() {
+        let dedup = MemoryDeduplicator::new(60, 1000);
+        let (one, two) = (Uuid::now_v7(), Uuid::now_v7());
+
+        assert_eq!(
+            vec![false, false, true],
+            dedup.check_at(0, "t", &[one, two, one])
+        );
+        assert_eq!(vec![true], dedup.check_at(30, "t", &[two]));
+        // Keys are scoped by token
+        assert_eq!(vec![false], dedup.check_at(30, "other", &[two]));
+        // Still found in the previous bucket
+        assert_eq!(vec![true], dedup.check_at(90, "t", &[one]));
+        // Expired, the repeat at 90 is in the bucket at 60 though
+        assert_eq!(vec![false], dedup.check_at(150, "t", &[two]));
+        assert_eq!(vec![true], dedup.check_at(150, "t", &[one]));
+        assert_eq!(vec![false], dedup.check_at(300, "t", &[one]));
+    }
+
+    #[test]
+    fn memory_dedup_fails_open_when_full() {
+        let dedup = MemoryDeduplicator::new(60, 2);
+        let (one, two, three) = (Uuid::now_v7(), Uuid::now_v7(), Uuid::now_v7());
+
+        assert_eq!(
+            vec![false, false, false],
+            dedup.check_at(0, "t", &[one, two, three])
+        );
+        // The first uuids are still found, the one past the cap was not recorded
+        assert_eq!(
+            vec![true, true, false],
+            dedup.check_at(30, "t", &[one, two, three])
+        );
+        // The next bucket has room again
+        assert_eq!(vec![false], dedup.check_at(60, "t", &[three]));
+        assert_eq!(vec![true], dedup.check_at(90, "t", &[three]));
+    }
+
+    #[tokio::test]
+    async fn forget_failed_events() {
+        let memory = MemoryDeduplicator::new(60, 1000);
+        let redis = RedisDeduplicator::new(Arc::new(MockRedisClient::new()), 60, None);
+        let dedups: [&(dyn Deduplicator + Send + Sync); 2] = [&memory, &redis];
+        for dedup in dedups {
+            let (one, two) = (Uuid::now_v7(), Uuid::now_v7());
+            assert_eq!(vec![false, false], dedup.check("t", &[one, two]).await);
+            dedup.forget("t", &[one]).await;
+            assert_eq!(vec![false, true], dedup.check("t", &[one, two]).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn only_check_known_uuids() {
+        let dedup = MemoryDeduplicator::new(60, 1000);
+        let one = Uuid::now_v7();
+        assert_eq!(
+            vec![false, false, false],
+            find_duplicates(&dedup, "t", &[Some(one), None, None]).await
+        );
+        assert_eq!(
+            vec![false, true],
+            find_duplicates(&dedup, "t", &[None, Some(one)]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn redis_dedup_in_window() {
+        let dedup = RedisDeduplicator::new(Arc::new(MockRedisClient::new()), 60, None);
+        let (one, two) = (Uuid::now_v7(), Uuid::now_v7());
+
+        assert_eq!(
+            vec![false, false, true],
+            dedup.check_at(0, "t", &[one, two, one]).await
+        );
+        assert_eq!(vec![true], dedup.check_at(30, "t", &[two]).await);
+        assert_eq!(vec![false], dedup.check_at(30, "other", &[two]).await);
+        assert_eq!(vec![true], dedup.check_at(90, "t", &[one]).await);
+        assert_eq!(vec![false], dedup.check_at(150, "t", &[two]).await);
+    }
+}
@@ -0,0 +1,303 @@
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::{MatchedPath, Query, State};
+use axum::http::{HeaderMap, Method, Uri};
+use axum::{debug_handler, Json};
+use axum_client_ip::InsecureClientIp;
+use futures::future::join_all;
+use metrics::counter;
+use serde::Deserialize;
+use tokio::time::Instant;
+use tracing::instrument;
+
+use crate::api::{CaptureError, ImportSummary, RejectedLine};
+use crate::body::{stream_ndjson, NdjsonItem};
+use crate::debug_capture::debug_request;
+use crate::limiters::redis::QuotaResource;
+use crate::privacy::PrivacySettings;
+use crate::prometheus::report_dropped_events;
+use crate::router;
+use crate::sinks::Event;
+use crate::token::validate_token;
+use crate::v0_endpoint::{privacy_settings, process_single_event};
+use crate::v0_request::{ProcessingContext, RawEvent};
+
+/// Decompressed size limit of a line, same as the single event endpoint
+const IMPORT_LINE_MAX_BYTES: usize = 2 * 1024 * 1024;
+/// How many decoded lines can be buffered while we are producing
+const IMPORT_LINES_IN_FLIGHT: usize = 1000;
+/// Lines are validated and produced in chunks of up to this size
+const IMPORT_CHUNK_SIZE: usize = 100;
+/// Stop producing while the producer queue is fuller than this, to leave room for live traffic
+const IMPORT_MAX_QUEUE_USAGE: f64 = 0.5;
+/// Fail the chunk as retryable if the producer queue does not drain in time
+const IMPORT_QUEUE_WAIT: Duration = Duration::from_secs(30);
+const IMPORT_QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Only the first rejected lines are listed in the summary
+const IMPORT_MAX_REJECTED_LINES: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    token: Option<String>,
+}
+
+/// Bulk import endpoint for historical migrations.
+///
+/// The body is a newline-delimited list of events, optionally compressed with gzip, zstd or
+/// deflate, and of arbitrary length: it is decoded while streamed, and events are produced
+/// to the historical topic as they are read. Production is paced by a pod-wide events per
+/// second budget, and paused while the producer queue is busy, which slows down the body
+/// reads and the client. Once the body is processed, we return the accepted and rejected
+/// line numbers, so that the client can retry the rejected lines.
+#[instrument(skip_all, fields(path, token, user_agent, lines))]
+#[debug_handler]
+#[allow(clippy::too_many_arguments)]
+pub async fn import(
+    state: State<router::State>,
+    ip: InsecureClientIp,
+    query: Query<ImportQuery>,
+    headers: HeaderMap,
+    method: Method,
+    uri: Uri,
+    path: MatchedPath,
+    body: Body,
+) -> Result<Json<ImportSummary>, CaptureError> {
+    // Imports can be huge, only the start of the bodies of debugged tokens is kept
+    let (body, debug) = match query.token.as_deref() {
+        Some(token) => debug_request(&state, &method, &uri, &headers, body, Some(token)).await,
+        None => (body, None),
+    };
+    let result = handle_import(state, ip, query, headers, path, body).await;
+    if let Some(debug) = debug {
+        debug.finish(&result);
+    }
+    result
+}
+
+async fn handle_import(
+    state: State<router::State>,
+    InsecureClientIp(ip): InsecureClientIp,
+    Query(query): Query<ImportQuery>,
+    headers: HeaderMap,
+    path: MatchedPath,
+    body: Body,
+) -> Result<Json<ImportSummary>, CaptureError> {
+    let user_agent = headers
+        .get("user-agent")
+        .map_or("unknown", |v| v.to_str().unwrap_or("unknown"));
+    tracing::Span::current().record("user_agent", user_agent);
+    tracing::Span::current().record("path", path.as_str().trim_end_matches('/'));
+
+    let token = query.token.ok_or(CaptureError::NoTokenError)?;
+    validate_token(&token)?;
+    tracing::Span::current().record("token", &token);
+
+    if let Some(rate_limiter) = &state.rate_limiter {
+        if rate_limiter.check_token(&token).await.is_err() {
+            return Err(CaptureError::RateLimited);
+        }
+    }
+
+    let mut context = ProcessingContext {
+        lib_version: None,
+        sent_at: None,
+        token,
+        now: state.timesource.current_time(),
+        client_ip: ip.to_string(),
+        historical_migration: true,
+    };
+
+    // Lines of resources over quota are rejected, other lines of the import go through
+    let quota_limited = state
+        .billing_limiter
+        .limited_resources(&context.token)
+        .await;
+    let privacy = privacy_settings(&state, &mut context).await?;
+
+    let mut lines = stream_ndjson::<RawEvent>(body, IMPORT_LINE_MAX_BYTES, IMPORT_LINES_IN_FLIGHT);
+    let mut summary = ImportSummary::default();
+    let mut chunk = Vec::with_capacity(IMPORT_CHUNK_SIZE);
+    while let Some(item) = lines.recv().await {
+        // Don't wait for a full chunk if the client is slower than us
+        let mut next = Some(item);
+        while let Some(item) = next.take() {
+            match item {
+                NdjsonItem::Line(number, event) => chunk.push((number, event)),
+                NdjsonItem::Failed(err) => {
+                    tracing::warn!("stopping import: {}", err);
+                    summary.error = Some(err.to_string());
+                }
+            }
+            if chunk.len() < IMPORT_CHUNK_SIZE {
+                next = lines.try_recv().ok();
+            }
+        }
+        import_chunk(
+            &state,
+            &context,
+            &quota_limited,
+            privacy.as_deref(),
+            std::mem::take(&mut chunk),
+            &mut summary,
+        )
+        .await;
+    }
+
+    tracing::Span::current().record("lines", summary.accepted_count + summary.rejected_count);
+    if summary.rejected_count > 0 {
+        tracing::log::warn!(
+            "rejected {} out of {} lines",
+            summary.rejected_count,
+            summary.accepted_count + summary.rejected_count
+        );
+    }
+    Ok(Json(summary))
+}
+
+async fn import_chunk(
+    state: &router::State,
+    context: &ProcessingContext,
+    quota_limited: &[QuotaResource],
+    privacy: Option<&PrivacySettings>,
+    lines: Vec<(usize, Result<RawEvent, CaptureError>)>,
+    summary: &mut ImportSummary,
+) {
+    if lines.is_empty() {
+        return;
+    }
+    counter!("capture_events_received_total").increment(lines.len() as u64);
+    counter!("capture_import_lines_received_total").increment(lines.len() as u64);
+
+    let mut outcomes = Vec::with_capacity(lines.len());
+    let mut events = Vec::with_capacity(lines.len());
+    for (number, event) in lines {
+        let result = event
+            .and_then(|event| match &event.token {
+                Some(token) if *token != context.token => Err(CaptureError::MultipleTokensError),
+                _ => Ok(event),
+            })
+            .map(|mut event| {
+                if let Some(settings) = privacy {
+                    settings.apply(&mut event);
+                }
+                event
+            })
+            .and_then(|event| {
+                match state
+                    .billing_limiter
+                    .resource(&event, context.historical_migration)
+                {
+                    Some(resource) if quota_limited.contains(&resource) => {
+                        Err(CaptureError::BillingLimit)
+                    }
+                    _ => Ok(event),
+                }
+            })
+            .and_then(|event| process_single_event(&event, context));
+        match result {
+            Ok(event) => events.push((number, event)),
+            Err(err) => {
+                let cause = match err {
+                    CaptureError::BillingLimit => "over_quota",
+                    _ => err.reason(),
+                };
+                report_dropped_events(cause, 1);
+                outcomes.push((number, Err(err)));
+            }
+        }
+    }
+
+    match wait_for_queue(state.sink.as_ref()).await {
+        Err(_) => outcomes.extend(
+            events
+                .into_iter()
+                .map(|(number, _)| (number, Err(CaptureError::RetryableSinkError))),
+        ),
+        Ok(()) => {
+            for _ in &events {
+                state.import_limiter.until_ready().await;
+            }
+            // join_all polls the futures in order, events are enqueued in line order
+            let sink = &state.sink;
+            outcomes.extend(
+                join_all(
+                    events
+                        .into_iter()
+                        .map(|(number, event)| async move { (number, sink.send(event).await) }),
+                )
+                .await,
+            );
+        }
+    }
+
+    outcomes.sort_unstable_by_key(|(number, _)| *number);
+    for (number, result) in outcomes {
+        match result {
+            Ok(()) => summary.accept(number),
+            Err(err) => summary.reject(number, &err),
+        }
+    }
+}
+
+/// Waits for the producer queue to have room for the historical traffic.
+async fn wait_for_queue(sink: &(dyn Event + Send + Sync)) -> Result<(), CaptureError> {
+    let deadline = Instant::now() + IMPORT_QUEUE_WAIT;
+    while sink
+        .queue_usage()
+        .is_some_and(|usage| usage > IMPORT_MAX_QUEUE_USAGE)
+    {
+        if Instant::now() >= deadline {
+            tracing::warn!("producer queue did not drain, failing import chunk");
+            return Err(CaptureError::RetryableSinkError);
+        }
+        counter!("capture_import_queue_waits_total").increment(1);
+        tokio::time::sleep(IMPORT_QUEUE_POLL_INTERVAL).await;
+    }
+    Ok(())
+}
+
+impl ImportSummary {
+    /// Records an accepted line, lines must be recorded in increasing order.
+    fn accept(&mut self, line: usize) {
+        self.accepted_count += 1;
+        match self.accepted.last_mut() {
+            Some((_, last)) if *last + 1 == line => *last = line,
+            _ => self.accepted.push((line, line)),
+        }
+    }
+
+    fn reject(&mut self, line: usize, err: &CaptureError) {
+        self.rejected_count += 1;
+        if self.rejected.len() < IMPORT_MAX_REJECTED_LINES {
+            self.rejected.push(RejectedLine {
+                line,
+                reason: err.reason().to_string(),
+                retryable: err.is_retryable(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{CaptureError, ImportSummary};
+
+    #[test]
+    fn summarize_lines_as_ranges() {
+        let mut summary = ImportSummary::default();
+        for line in [1, 2, 3, 5, 6, 9] {
+            summary.accept(line);
+        }
+        summary.reject(4, &CaptureError::MissingDistinctId);
+        summary.reject(7, &CaptureError::RetryableSinkError);
+
+        assert_eq!(6, summary.accepted_count);
+        assert_eq!(vec![(1, 3), (5, 6), (9, 9)], summary.accepted);
+        assert_eq!(2, summary.rejected_count);
+        assert_eq!(4, summary.rejected[0].line);
+        assert_eq!("missing_distinct_id", summary.rejected[0].reason);
+        assert!(!summary.rejected[0].retryable);
+        assert!(summary.rejected[1].retryable);
+    }
+}
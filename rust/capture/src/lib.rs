@@ -1,9 +1,19 @@
 pub mod api;
+pub mod body;
+pub mod bots;
 pub mod config;
+pub mod debug_capture;
+pub mod dedup;
+pub mod import_endpoint;
 pub mod limiters;
+pub mod lz64;
+pub mod privacy;
 pub mod prometheus;
 pub mod redis;
 pub mod router;
+pub mod routing;
+pub mod segment_endpoint;
+pub mod segment_request;
 pub mod server;
 pub mod sinks;
 pub mod test_endpoint;
@@ -12,3 +22,6 @@ pub mod token;
 pub mod utils;
 pub mod v0_endpoint;
 pub mod v0_request;
+pub mod v1_endpoint;
+pub mod v1_request;
+pub mod validate_endpoint;
@@ -0,0 +1,159 @@
+use std::sync::Arc;
+
+use time::Duration;
+
+use crate::config::CaptureMode;
+use crate::limiters::redis::{QuotaResource, RedisLimiter, QUOTA_LIMITER_CACHE_KEY};
+use crate::redis::Client;
+use crate::v0_request::{DataType, RawEvent};
+
+/// Billing limits of all the resources ingested in a capture mode.
+///
+/// Events, exceptions and recordings are billed separately, and a team over one of these
+/// quotas can still send the others: limits are applied per event, depending on the
+/// resource its data type counts against. Heatmaps and ingestion warnings are not billed.
+#[derive(Clone)]
+pub struct BillingLimiter {
+    capture_mode: CaptureMode,
+    limiters: Vec<(QuotaResource, RedisLimiter)>,
+}
+
+impl BillingLimiter {
+    pub fn new(
+        capture_mode: CaptureMode,
+        interval: Duration,
+        redis: Arc<dyn Client + Send + Sync>,
+        redis_key_prefix: Option<String>,
+    ) -> anyhow::Result<BillingLimiter> {
+        let resources = match capture_mode {
+            CaptureMode::Events => vec![QuotaResource::Events, QuotaResource::Exceptions],
+            CaptureMode::Recordings => vec![QuotaResource::Recordings],
+        };
+        let limiters = resources
+            .into_iter()
+            .map(|resource| {
+                let limiter = RedisLimiter::new(
+                    interval,
+                    redis.clone(),
+                    QUOTA_LIMITER_CACHE_KEY.to_string(),
+                    redis_key_prefix.clone(),
+                    resource,
+                )?;
+                Ok((resource, limiter))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(BillingLimiter {
+            capture_mode,
+            limiters,
+        })
+    }
+
+    /// Returns the resources the token is over quota for.
+    pub async fn limited_resources(&self, token: &str) -> Vec<QuotaResource> {
+        let mut limited = Vec::new();
+        for (resource, limiter) in &self.limiters {
+            if limiter.is_limited(token).await {
+                limited.push(*resource);
+            }
+        }
+        limited
+    }
+
+    /// Returns the resource an event counts against, if it is billed.
+    pub fn resource(&self, event: &RawEvent, historical_migration: bool) -> Option<QuotaResource> {
+        match self.capture_mode {
+            CaptureMode::Recordings => Some(QuotaResource::Recordings),
+            CaptureMode::Events => match event.data_type(historical_migration) {
+                DataType::AnalyticsMain | DataType::AnalyticsHistorical => {
+                    Some(QuotaResource::Events)
+                }
+                DataType::ExceptionMain => Some(QuotaResource::Exceptions),
+                DataType::SnapshotMain => Some(QuotaResource::Recordings),
+                DataType::HeatmapMain | DataType::ClientIngestionWarning => None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use time::Duration;
+
+    use crate::config::CaptureMode;
+    use crate::limiters::billing::BillingLimiter;
+    use crate::limiters::redis::QuotaResource;
+    use crate::redis::MockRedisClient;
+    use crate::v0_request::RawEvent;
+
+    fn event(name: &str) -> RawEvent {
+        RawEvent {
+            event: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn limit_resources_separately() {
+        let client = MockRedisClient::new()
+            .zrangebyscore_ret("@posthog/quota-limits/events", vec![String::from("a")])
+            .zrangebyscore_ret("@posthog/quota-limits/exceptions", vec![String::from("b")]);
+        let limiter = BillingLimiter::new(
+            CaptureMode::Events,
+            Duration::seconds(1),
+            Arc::new(client),
+            None,
+        )
+        .expect("Failed to create billing limiter");
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        assert_eq!(
+            vec![QuotaResource::Events],
+            limiter.limited_resources("a").await
+        );
+        assert_eq!(
+            vec![QuotaResource::Exceptions],
+            limiter.limited_resources("b").await
+        );
+        assert!(limiter.limited_resources("c").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn map_events_to_resources() {
+        let client = Arc::new(MockRedisClient::new());
+        let events = BillingLimiter::new(
+            CaptureMode::Events,
+            Duration::seconds(1),
+            client.clone(),
+            None,
+        )
+        .expect("Failed to create billing limiter");
+        let recordings =
+            BillingLimiter::new(CaptureMode::Recordings, Duration::seconds(1), client, None)
+                .expect("Failed to create billing limiter");
+
+        assert_eq!(
+            Some(QuotaResource::Events),
+            events.resource(&event("$pageview"), false)
+        );
+        assert_eq!(
+            Some(QuotaResource::Events),
+            events.resource(&event("$pageview"), true)
+        );
+        assert_eq!(
+            Some(QuotaResource::Exceptions),
+            events.resource(&event("$exception"), false)
+        );
+        assert_eq!(None, events.resource(&event("$$heatmap"), false));
+        assert_eq!(
+            None,
+            events.resource(&event("$$client_ingestion_warning"), false)
+        );
+        assert_eq!(
+            Some(QuotaResource::Recordings),
+            recordings.resource(&event("$snapshot"), false)
+        );
+    }
+}
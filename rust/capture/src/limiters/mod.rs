@@ -1,2 +1,5 @@
+pub mod billing;
 pub mod overflow;
+pub mod rate;
 pub mod redis;
+pub mod shedding;
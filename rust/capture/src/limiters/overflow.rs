@@ -7,19 +7,55 @@
 /// constraint when bursts are detected. When that happens, the excess traffic will be
 /// spread across all partitions and be processed by the overflow consumer, without
 /// strict ordering guarantees.
-use std::collections::HashSet;
+///
+/// Each replica limits the keys it sees locally. As a hot key is spread across all replicas,
+/// it only overflows once every replica sees a burst on its own. Replicas can optionally
+/// share their counts through Redis: local counts are added to per-key counters in
+/// time buckets, and keys whose cluster-wide count goes over the bucket quota are overflowed
+/// on all replicas until the end of the next bucket. Only keys with enough local traffic
+/// are reported, to keep the Redis load bounded, so counts are approximate. If Redis is
+/// unavailable, we keep relying on the local limits.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::num::NonZeroU32;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 
 use governor::{clock, state::keyed::DefaultKeyedStateStore, Quota, RateLimiter};
-use metrics::gauge;
+use metrics::{counter, gauge};
 use rand::Rng;
+use time::OffsetDateTime;
+
+use crate::limiters::redis::OVERFLOW_COUNTER_CACHE_KEY;
+use crate::redis::Client;
+
+/// Width of the shared counter buckets
+const SHARED_BUCKET_SECS: i64 = 5;
+/// Local counts are reported to Redis at this interval
+const SHARED_SYNC_INTERVAL_MILLIS: u64 = 1000;
+/// Local counts are sharded to reduce lock contention
+const SHARED_COUNT_SHARDS: usize = 16;
+/// Keys are reported once their local count reaches this fraction of the bucket quota
+const SHARED_REPORT_FRACTION: u64 = 100;
 
 // See: https://docs.rs/governor/latest/governor/_guide/index.html#usage-in-multiple-threads
 #[derive(Clone)]
 pub struct OverflowLimiter {
     limiter: Arc<RateLimiter<String, DefaultKeyedStateStore<String>, clock::DefaultClock>>,
     forced_keys: HashSet<String>,
+    shared: Option<Arc<SharedCounts>>,
+    bucket_quota: u64,
+}
+
+/// Counts shared with the other replicas through Redis.
+struct SharedCounts {
+    redis: Arc<dyn Client + Send + Sync>,
+    key_prefix: String,
+    /// Counts not reported yet, for the current bucket
+    local: Vec<Mutex<HashMap<String, u64>>>,
+    local_bucket: Mutex<i64>,
+    /// Keys overflowing cluster-wide, with the last bucket they overflow in
+    hot_keys: RwLock<HashMap<String, i64>>,
 }
 
 impl OverflowLimiter {
@@ -35,11 +71,61 @@ impl OverflowLimiter {
         OverflowLimiter {
             limiter,
             forced_keys,
+            shared: None,
+            bucket_quota: u64::from(per_second.get()) * SHARED_BUCKET_SECS as u64
+                + u64::from(burst.get()),
         }
     }
 
+    /// Shares the counts with other replicas through Redis, `sync_shared_counts`
+    /// needs to be spawned in a separate task.
+    pub fn with_redis(
+        mut self,
+        redis: Arc<dyn Client + Send + Sync>,
+        redis_key_prefix: Option<String>,
+    ) -> Self {
+        self.shared = Some(Arc::new(SharedCounts {
+            redis,
+            key_prefix: format!(
+                "{}{OVERFLOW_COUNTER_CACHE_KEY}",
+                redis_key_prefix.unwrap_or_default()
+            ),
+            local: (0..SHARED_COUNT_SHARDS)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            local_bucket: Mutex::new(0),
+            hot_keys: RwLock::new(HashMap::new()),
+        }));
+        self
+    }
+
     pub fn is_limited(&self, key: &String) -> bool {
-        self.forced_keys.contains(key) || self.limiter.check_key(key).is_err()
+        if self.forced_keys.contains(key) {
+            return true;
+        }
+        if let Some(shared) = &self.shared {
+            shared.record(key);
+            if shared.hot_keys.read().unwrap().contains_key(key) {
+                return true;
+            }
+        }
+        self.limiter.check_key(key).is_err()
+    }
+
+    /// Reports the local counts to Redis every second, and updates the keys overflowing
+    /// cluster-wide. Needs to be spawned in a separate task.
+    pub async fn sync_shared_counts(&self) {
+        let Some(shared) = &self.shared else {
+            return;
+        };
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(
+            SHARED_SYNC_INTERVAL_MILLIS,
+        ));
+        loop {
+            interval.tick().await;
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            shared.sync_at(now, self.bucket_quota).await;
+        }
     }
 
     /// Reports the number of tracked keys to prometheus every 10 seconds,
@@ -70,10 +156,86 @@ impl OverflowLimiter {
     }
 }
 
+impl SharedCounts {
+    fn shard(&self, key: &str) -> &Mutex<HashMap<String, u64>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.local[hasher.finish() as usize % self.local.len()]
+    }
+
+    fn record(&self, key: &str) {
+        let mut counts = self.shard(key).lock().unwrap();
+        match counts.get_mut(key) {
+            Some(count) => *count += 1,
+            None => {
+                counts.insert(key.to_string(), 1);
+            }
+        }
+    }
+
+    async fn sync_at(&self, now: i64, bucket_quota: u64) {
+        let bucket = now / SHARED_BUCKET_SECS;
+        let min_report = (bucket_quota / SHARED_REPORT_FRACTION).max(1);
+
+        let new_bucket = {
+            let mut local_bucket = self.local_bucket.lock().unwrap();
+            let new_bucket = *local_bucket != bucket;
+            *local_bucket = bucket;
+            new_bucket
+        };
+        let mut increments = Vec::new();
+        for shard in &self.local {
+            let mut counts = shard.lock().unwrap();
+            counts.retain(|key, count| {
+                if *count >= min_report {
+                    increments.push((key.clone(), *count as i64));
+                    return false;
+                }
+                // Forget the small counts once their bucket is over
+                !new_bucket
+            });
+        }
+
+        if !increments.is_empty() {
+            let keys: Vec<String> = increments.iter().map(|(key, _)| key.clone()).collect();
+            match self
+                .redis
+                .hincrby_many(
+                    format!("{}{bucket}", self.key_prefix),
+                    increments,
+                    2 * SHARED_BUCKET_SECS as u64,
+                )
+                .await
+            {
+                Ok(totals) => {
+                    let mut hot_keys = self.hot_keys.write().unwrap();
+                    for (key, total) in keys.into_iter().zip(totals) {
+                        if total as u64 > bucket_quota {
+                            hot_keys.insert(key, bucket + 1);
+                        }
+                    }
+                }
+                Err(e) => {
+                    // Local limits still apply
+                    tracing::error!("failed to share overflow counts in Redis: {:?}", e);
+                    counter!("capture_overflow_sync_errors_total").increment(1);
+                }
+            }
+        }
+
+        let mut hot_keys = self.hot_keys.write().unwrap();
+        hot_keys.retain(|_, last_bucket| *last_bucket >= bucket);
+        gauge!("partition_limits_shared_hot_keys").set(hot_keys.len() as f64);
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::limiters::overflow::OverflowLimiter;
+    use crate::limiters::overflow::{OverflowLimiter, SHARED_BUCKET_SECS};
+    use crate::redis::MockRedisClient;
     use std::num::NonZeroU32;
+    use std::sync::Arc;
+    use time::OffsetDateTime;
 
     #[tokio::test]
     async fn low_limits() {
@@ -124,4 +286,59 @@ mod tests {
         // Two is limited on the second event
         assert!(limiter.is_limited(&key_two));
     }
+
+    #[tokio::test]
+    async fn share_counts_in_redis() {
+        let redis = Arc::new(MockRedisClient::new());
+        let new_limiter = || {
+            OverflowLimiter::new(
+                NonZeroU32::new(10).unwrap(),
+                NonZeroU32::new(1000).unwrap(),
+                None,
+            )
+            .with_redis(redis.clone(), None)
+        };
+        let (one, two) = (new_limiter(), new_limiter());
+        let key = String::from("token:id");
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+
+        // Each replica is under its local limits, but not the cluster
+        for _ in 0..600 {
+            assert!(!one.is_limited(&key));
+            assert!(!two.is_limited(&key));
+        }
+        one.shared
+            .as_ref()
+            .unwrap()
+            .sync_at(now, one.bucket_quota)
+            .await;
+        assert!(!one.is_limited(&key));
+        two.shared
+            .as_ref()
+            .unwrap()
+            .sync_at(now, two.bucket_quota)
+            .await;
+        assert!(two.is_limited(&key));
+        assert!(!two.is_limited(&String::from("token:other")));
+
+        // Replicas learn about the hot keys they report
+        for _ in 0..10 {
+            one.is_limited(&key);
+        }
+        one.shared
+            .as_ref()
+            .unwrap()
+            .sync_at(now, one.bucket_quota)
+            .await;
+        assert!(one.is_limited(&key));
+
+        // Until the end of the next bucket
+        let later = now + 2 * SHARED_BUCKET_SECS;
+        one.shared
+            .as_ref()
+            .unwrap()
+            .sync_at(later, one.bucket_quota)
+            .await;
+        assert!(!one.is_limited(&key));
+    }
 }
@@ -0,0 +1,324 @@
+/// Protects capture and the ingestion pipeline against misbehaving clients, by limiting the
+/// number of requests accepted per token, and optionally the number of events per distinct_id.
+///
+/// Unlike the billing limiter, this is enforced locally on every pod: limits are expressed
+/// per pod, and the budget of a token spreads with the number of replicas. A global budget
+/// can also be configured, as a last line of defense for the pod itself.
+///
+/// The default per-token quota can be overridden for some tokens, by setting a value in the
+/// `@posthog/capture-rate-limits/tokens` Redis hash. Values are either `per_second` or
+/// `per_second:burst`. Overrides are refreshed in a background task, and we keep the last known
+/// values if Redis is unavailable.
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+use governor::clock::{Clock, DefaultClock};
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::state::{InMemoryState, NotKeyed};
+use governor::{Quota, RateLimiter};
+use metrics::gauge;
+use rand::Rng;
+use time::Duration;
+use tokio::sync::RwLock;
+use tokio::task;
+use tokio::time::interval;
+use tracing::instrument;
+
+use crate::redis::Client;
+
+pub const RATE_LIMITER_CACHE_KEY: &str = "@posthog/capture-rate-limits/";
+
+type KeyedLimiter = RateLimiter<String, DefaultKeyedStateStore<String>, DefaultClock>;
+type DirectLimiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock>;
+type Overrides = HashMap<String, (Quota, Arc<DirectLimiter>)>;
+
+#[derive(Clone)]
+pub struct TokenRateLimiter {
+    per_token: Arc<KeyedLimiter>,
+    per_distinct_id: Option<Arc<KeyedLimiter>>,
+    global: Option<Arc<DirectLimiter>>,
+    overrides: Arc<RwLock<Overrides>>,
+    clock: DefaultClock,
+    redis: Arc<dyn Client + Send + Sync>,
+    key: String,
+    interval: Duration,
+}
+
+impl TokenRateLimiter {
+    /// Create a new TokenRateLimiter, and start loading the per-token overrides from Redis.
+    ///
+    /// `per_token` is a number of requests, `per_distinct_id` a number of events.
+    pub fn new(
+        interval: Duration,
+        redis: Arc<dyn Client + Send + Sync>,
+        redis_key_prefix: Option<String>,
+        per_token: Quota,
+        per_distinct_id: Option<Quota>,
+        global: Option<Quota>,
+    ) -> anyhow::Result<TokenRateLimiter> {
+        let key_prefix = redis_key_prefix.unwrap_or_default();
+
+        let limiter = TokenRateLimiter {
+            per_token: Arc::new(RateLimiter::dashmap(per_token)),
+            per_distinct_id: per_distinct_id.map(|quota| Arc::new(RateLimiter::dashmap(quota))),
+            global: global.map(|quota| Arc::new(RateLimiter::direct(quota))),
+            overrides: Arc::new(RwLock::new(HashMap::new())),
+            clock: DefaultClock::default(),
+            redis,
+            key: format!("{key_prefix}{RATE_LIMITER_CACHE_KEY}tokens"),
+            interval,
+        };
+
+        limiter.spawn_background_update();
+
+        Ok(limiter)
+    }
+
+    fn spawn_background_update(&self) {
+        let overrides = Arc::clone(&self.overrides);
+        let redis = Arc::clone(&self.redis);
+        let interval_duration = StdDuration::from_nanos(self.interval.whole_nanoseconds() as u64);
+        let key = self.key.clone();
+
+        task::spawn(async move {
+            let mut interval = interval(interval_duration);
+            loop {
+                match redis.hgetall(key.clone()).await {
+                    Ok(values) => {
+                        let mut overrides_lock = overrides.write().await;
+                        let mut updated = HashMap::with_capacity(values.len());
+                        for (token, value) in values {
+                            let Some(quota) = parse_quota(&value) else {
+                                tracing::warn!("invalid rate limit override for {token}: {value}");
+                                continue;
+                            };
+                            // Keep the current state if the quota did not change
+                            let limiter = match overrides_lock.remove(&token) {
+                                Some((current, limiter)) if current == quota => limiter,
+                                _ => Arc::new(RateLimiter::direct(quota)),
+                            };
+                            updated.insert(token, (quota, limiter));
+                        }
+                        gauge!(
+                            "capture_rate_limits_loaded_overrides",
+                            "cache_key" => key.clone(),
+                        )
+                        .set(updated.len() as f64);
+
+                        *overrides_lock = updated;
+                    }
+                    Err(e) => {
+                        tracing::error!(
+                            "Failed to update rate limit overrides from Redis: {:?}",
+                            e
+                        );
+                    }
+                }
+
+                interval.tick().await;
+            }
+        });
+    }
+
+    /// Checks the token and global budgets for a new request.
+    /// Returns how long the client should wait before retrying if the request is limited.
+    #[instrument(skip_all, fields(token = token))]
+    pub async fn check_token(&self, token: &str) -> Result<(), StdDuration> {
+        let result = match self.overrides.read().await.get(token) {
+            Some((_, limiter)) => limiter.check(),
+            None => self.per_token.check_key(&token.to_string()),
+        };
+        result.map_err(|not_until| not_until.wait_time_from(self.clock.now()))?;
+
+        if let Some(global) = &self.global {
+            global
+                .check()
+                .map_err(|not_until| not_until.wait_time_from(self.clock.now()))?;
+        }
+        Ok(())
+    }
+
+    /// Checks the per-distinct_id budget for one event, if enabled.
+    pub fn check_distinct_id(&self, token: &str, distinct_id: &str) -> Result<(), StdDuration> {
+        match &self.per_distinct_id {
+            None => Ok(()),
+            Some(limiter) => limiter
+                .check_key(&format!("{token}:{distinct_id}"))
+                .map_err(|not_until| not_until.wait_time_from(self.clock.now())),
+        }
+    }
+
+    /// Reports the number of tracked keys to prometheus every 10 seconds,
+    /// needs to be spawned in a separate task.
+    pub async fn report_metrics(&self) {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            gauge!("rate_limits_token_count").set(self.per_token.len() as f64);
+            if let Some(limiter) = &self.per_distinct_id {
+                gauge!("rate_limits_distinct_id_count").set(limiter.len() as f64);
+            }
+        }
+    }
+
+    /// Clean up the rate limiter state, once per minute. Ensure we don't use more memory than
+    /// necessary.
+    pub async fn clean_state(&self) {
+        // Spread the cleanups of replicas, see OverflowLimiter::clean_state
+        let interval_secs = rand::thread_rng().gen_range(60..70);
+
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(interval_secs));
+        loop {
+            interval.tick().await;
+
+            self.per_token.retain_recent();
+            self.per_token.shrink_to_fit();
+            if let Some(limiter) = &self.per_distinct_id {
+                limiter.retain_recent();
+                limiter.shrink_to_fit();
+            }
+        }
+    }
+}
+
+/// Parses a `per_second` or `per_second:burst` override value.
+fn parse_quota(value: &str) -> Option<Quota> {
+    let (per_second, burst) = match value.split_once(':') {
+        None => (value, None),
+        Some((per_second, burst)) => (per_second, Some(burst)),
+    };
+    let per_second: NonZeroU32 = per_second.trim().parse().ok()?;
+    let quota = Quota::per_second(per_second);
+    match burst {
+        None => Some(quota),
+        Some(burst) => Some(quota.allow_burst(burst.trim().parse().ok()?)),
+    }
+}
+
+/// Formats a wait duration as a `Retry-After` header value, in whole seconds.
+pub fn retry_after_secs(wait: StdDuration) -> u64 {
+    let secs = wait.as_secs();
+    if wait.subsec_nanos() > 0 || secs == 0 {
+        secs + 1
+    } else {
+        secs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::num::NonZeroU32;
+    use std::sync::Arc;
+    use std::time::Duration as StdDuration;
+
+    use governor::Quota;
+    use time::Duration;
+
+    use crate::limiters::rate::{
+        parse_quota, retry_after_secs, TokenRateLimiter, RATE_LIMITER_CACHE_KEY,
+    };
+    use crate::redis::MockRedisClient;
+
+    fn quota(per_second: u32, burst: u32) -> Quota {
+        Quota::per_second(NonZeroU32::new(per_second).unwrap())
+            .allow_burst(NonZeroU32::new(burst).unwrap())
+    }
+
+    fn limiter(
+        client: MockRedisClient,
+        per_distinct_id: Option<Quota>,
+        global: Option<Quota>,
+    ) -> TokenRateLimiter {
+        TokenRateLimiter::new(
+            Duration::seconds(1),
+            Arc::new(client),
+            None,
+            quota(1, 2),
+            per_distinct_id,
+            global,
+        )
+        .expect("Failed to create rate limiter")
+    }
+
+    #[tokio::test]
+    async fn limit_per_token() {
+        let limiter = limiter(MockRedisClient::new(), None, None);
+
+        assert!(limiter.check_token("one").await.is_ok());
+        assert!(limiter.check_token("one").await.is_ok());
+        let wait = limiter
+            .check_token("one")
+            .await
+            .expect_err("should be limited");
+        assert!(wait <= StdDuration::from_secs(1));
+
+        // Other tokens have their own budget, and distinct_ids are not limited
+        assert!(limiter.check_token("two").await.is_ok());
+        assert!(limiter.check_distinct_id("one", "id").is_ok());
+    }
+
+    #[tokio::test]
+    async fn limit_per_distinct_id() {
+        let limiter = limiter(MockRedisClient::new(), Some(quota(1, 1)), None);
+
+        assert!(limiter.check_distinct_id("token", "one").is_ok());
+        assert!(limiter.check_distinct_id("token", "one").is_err());
+        assert!(limiter.check_distinct_id("token", "two").is_ok());
+        assert!(limiter.check_distinct_id("other_token", "one").is_ok());
+    }
+
+    #[tokio::test]
+    async fn limit_globally() {
+        let limiter = limiter(MockRedisClient::new(), None, Some(quota(1, 3)));
+
+        assert!(limiter.check_token("one").await.is_ok());
+        assert!(limiter.check_token("two").await.is_ok());
+        assert!(limiter.check_token("three").await.is_ok());
+        assert!(limiter.check_token("four").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn override_from_redis() {
+        let client = MockRedisClient::new().hgetall_ret(
+            &format!("{RATE_LIMITER_CACHE_KEY}tokens"),
+            HashMap::from([
+                ("big".to_string(), "10:5".to_string()),
+                ("invalid".to_string(), "nope".to_string()),
+            ]),
+        );
+        let limiter = limiter(client, None, None);
+
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        for _ in 0..5 {
+            assert!(limiter.check_token("big").await.is_ok());
+        }
+        assert!(limiter.check_token("big").await.is_err());
+
+        // Invalid overrides are ignored, the default quota applies
+        assert!(limiter.check_token("invalid").await.is_ok());
+        assert!(limiter.check_token("invalid").await.is_ok());
+        assert!(limiter.check_token("invalid").await.is_err());
+    }
+
+    #[test]
+    fn parse_override_values() {
+        assert_eq!(Some(quota(10, 10)), parse_quota("10"));
+        assert_eq!(Some(quota(10, 50)), parse_quota("10:50"));
+        assert_eq!(Some(quota(10, 50)), parse_quota(" 10 : 50 "));
+        assert_eq!(None, parse_quota("0"));
+        assert_eq!(None, parse_quota("10:"));
+        assert_eq!(None, parse_quota("ten"));
+    }
+
+    #[test]
+    fn round_up_retry_after() {
+        assert_eq!(1, retry_after_secs(StdDuration::ZERO));
+        assert_eq!(1, retry_after_secs(StdDuration::from_millis(200)));
+        assert_eq!(2, retry_after_secs(StdDuration::from_secs(2)));
+        assert_eq!(3, retry_after_secs(StdDuration::from_millis(2001)));
+    }
+}
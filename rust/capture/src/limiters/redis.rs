@@ -33,10 +33,13 @@ use crate::redis::Client;
 // todo: fetch from env
 pub const QUOTA_LIMITER_CACHE_KEY: &str = "@posthog/quota-limits/";
 pub const OVERFLOW_LIMITER_CACHE_KEY: &str = "@posthog/capture-overflow/";
+// Shared event counts of the analytics OverflowLimiter, see limiters::overflow
+pub const OVERFLOW_COUNTER_CACHE_KEY: &str = "@posthog/capture-overflow-counts/";
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum QuotaResource {
     Events,
+    Exceptions,
     // due to historical reasons we use different suffixes for quota limits and overflow
     // hopefully we can unify these in the future
     Recordings,
@@ -47,6 +50,7 @@ impl QuotaResource {
     pub fn as_str(&self) -> &'static str {
         match self {
             Self::Events => "events",
+            Self::Exceptions => "exceptions",
             Self::Recordings => "recordings",
             Self::Replay => "replay",
         }
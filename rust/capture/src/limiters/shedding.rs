@@ -0,0 +1,92 @@
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use metrics::{counter, gauge};
+
+use crate::api::CaptureError;
+use crate::router;
+
+/// Admission control driven by the producer queue usage.
+///
+/// rdkafka buffers messages in memory until brokers ack them: when brokers slow down, the
+/// queue fills up, and once it is full every request waits for a slot until it times out.
+/// To degrade gracefully instead, we shed load in two steps as the queue usage rises:
+///
+/// 1. Past the low priority watermark, historical and overflow events are dropped by the
+///    sink, keeping the queue for live traffic. The rest of their batch goes through.
+/// 2. Past the reject watermark, new requests are answered with a 503 before we read
+///    their body, so that clients retry later or on another pod.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoadShedding {
+    low_priority_watermark: f64,
+    reject_watermark: f64,
+}
+
+impl LoadShedding {
+    pub fn new(low_priority_watermark: f64, reject_watermark: f64) -> anyhow::Result<Self> {
+        if !(0.0 < low_priority_watermark
+            && low_priority_watermark <= reject_watermark
+            && reject_watermark <= 1.0)
+        {
+            anyhow::bail!(
+                "invalid load shedding watermarks {low_priority_watermark} and {reject_watermark}"
+            );
+        }
+        Ok(LoadShedding {
+            low_priority_watermark,
+            reject_watermark,
+        })
+    }
+
+    /// Whether historical and overflow events should be shed at this queue usage.
+    pub fn shed_low_priority(&self, queue_usage: Option<f64>) -> bool {
+        queue_usage.is_some_and(|usage| usage >= self.low_priority_watermark)
+    }
+
+    /// Whether new requests should be rejected at this queue usage.
+    pub fn reject(&self, queue_usage: Option<f64>) -> bool {
+        queue_usage.is_some_and(|usage| usage >= self.reject_watermark)
+    }
+}
+
+/// Rejects requests with a 503 while the producer queue is past the reject watermark.
+pub async fn shed_load(
+    State(state): State<router::State>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let usage = state.sink.queue_usage();
+    if let Some(usage) = usage {
+        gauge!("capture_kafka_producer_queue_usage").set(usage);
+    }
+    if state
+        .load_shedding
+        .is_some_and(|shedding| shedding.reject(usage))
+    {
+        counter!("capture_requests_shed_total").increment(1);
+        return CaptureError::RetryableSinkError.into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::limiters::shedding::LoadShedding;
+
+    #[test]
+    fn shed_by_watermark() {
+        let shedding = LoadShedding::new(0.6, 0.9).expect("invalid watermarks");
+
+        assert!(!shedding.shed_low_priority(None));
+        assert!(!shedding.shed_low_priority(Some(0.5)));
+        assert!(shedding.shed_low_priority(Some(0.6)));
+        assert!(!shedding.reject(Some(0.8)));
+        assert!(shedding.reject(Some(0.9)));
+        assert!(!shedding.reject(None));
+
+        assert!(LoadShedding::new(0.0, 0.9).is_err());
+        assert!(LoadShedding::new(0.9, 0.6).is_err());
+        assert!(LoadShedding::new(0.6, 1.5).is_err());
+        assert!(LoadShedding::new(1.0, 1.0).is_ok());
+    }
+}
@@ -0,0 +1,194 @@
+/// Decompression of lz-string payloads, as sent by posthog-js with `compression=lz64`.
+///
+/// This is a port of LZString.decompressFromBase64, with an output size limit checked while
+/// decompressing, so that we don't blow up on decompression bombs.
+use thiserror::Error;
+
+const BASE64_ALPHABET: &[u8; 65] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/=";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum Lz64Error {
+    #[error("invalid lz64 data")]
+    InvalidData,
+    #[error("decompressed payload is too large")]
+    TooLarge,
+}
+
+struct BitReader<'a> {
+    input: &'a [u8],
+    value: u32,
+    position: u32,
+    index: usize,
+}
+
+impl<'a> BitReader<'a> {
+    const RESET_VALUE: u32 = 32;
+
+    fn new(input: &'a [u8]) -> Result<Self, Lz64Error> {
+        let mut reader = BitReader {
+            input,
+            value: 0,
+            position: Self::RESET_VALUE,
+            index: 1,
+        };
+        reader.value = reader.char_value(0)?;
+        Ok(reader)
+    }
+
+    fn char_value(&self, index: usize) -> Result<u32, Lz64Error> {
+        match self.input.get(index) {
+            // Reading past the end yields zeroes, like the reference implementation
+            None => Ok(0),
+            Some(c) => BASE64_ALPHABET
+                .iter()
+                .position(|a| a == c)
+                .map(|v| v as u32)
+                .ok_or(Lz64Error::InvalidData),
+        }
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<u32, Lz64Error> {
+        let mut bits = 0;
+        for shift in 0..count {
+            let bit = self.value & self.position;
+            self.position >>= 1;
+            if self.position == 0 {
+                self.position = Self::RESET_VALUE;
+                self.value = self.char_value(self.index)?;
+                self.index += 1;
+            }
+            if bit > 0 {
+                bits |= 1 << shift;
+            }
+        }
+        Ok(bits)
+    }
+}
+
+/// Decompresses a lz-string base64 payload, failing if the output exceeds `limit` bytes.
+pub fn decompress_from_base64(input: &str, limit: usize) -> Result<String, Lz64Error> {
+    // Form-encoded payloads might have their + characters decoded to spaces
+    let input: Vec<u8> = input
+        .bytes()
+        .map(|c| if c == b' ' { b'+' } else { c })
+        .collect();
+    if input.is_empty() {
+        return Ok(String::new());
+    }
+
+    let mut reader = BitReader::new(&input)?;
+    let mut dictionary: Vec<Vec<u16>> = vec![vec![], vec![], vec![]];
+    let mut enlarge_in: u32 = 4;
+    let mut num_bits: u32 = 3;
+    let mut output: Vec<u16> = Vec::new();
+
+    let first = match reader.read_bits(2)? {
+        0 => reader.read_bits(8)? as u16,
+        1 => reader.read_bits(16)? as u16,
+        _ => return Ok(String::new()),
+    };
+    let mut previous = vec![first];
+    dictionary.push(previous.clone());
+    output.push(first);
+
+    loop {
+        if reader.index > input.len() {
+            return Err(Lz64Error::InvalidData);
+        }
+
+        let mut code = reader.read_bits(num_bits)? as usize;
+        match code {
+            0 | 1 => {
+                let width = if code == 0 { 8 } else { 16 };
+                dictionary.push(vec![reader.read_bits(width)? as u16]);
+                code = dictionary.len() - 1;
+                enlarge_in -= 1;
+            }
+            2 => break,
+            _ => {}
+        }
+
+        if enlarge_in == 0 {
+            enlarge_in = 1 << num_bits;
+            num_bits += 1;
+        }
+
+        let entry = if let Some(entry) = dictionary.get(code) {
+            entry.clone()
+        } else if code == dictionary.len() {
+            let mut entry = previous.clone();
+            entry.push(previous[0]);
+            entry
+        } else {
+            return Err(Lz64Error::InvalidData);
+        };
+
+        output.extend_from_slice(&entry);
+        // Each UTF-16 unit is at least one byte once re-encoded as UTF-8
+        if output.len() > limit {
+            return Err(Lz64Error::TooLarge);
+        }
+
+        let mut new_entry = previous;
+        new_entry.push(entry[0]);
+        dictionary.push(new_entry);
+        enlarge_in -= 1;
+        previous = entry;
+
+        if enlarge_in == 0 {
+            enlarge_in = 1 << num_bits;
+            num_bits += 1;
+        }
+    }
+
+    String::from_utf16(&output).map_err(|_| Lz64Error::InvalidData)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{decompress_from_base64, Lz64Error};
+
+    // Batch sent by posthog-js, holding an emoji in the event name, from the django capture tests
+    pub(crate) const BATCH_PAYLOAD: &str = "NoKABBYN4EQKYDc4DsAuMBcYaD4NwyLswA0MADgE4D2JcZqAlnAM6bQwAkFzWMAsgIYBjMAHkAymAAaRdgCNKAd0Y0WMAMIALSgFs40tgICuZMilQB9IwBsV61KhIYA9I4CMAJgDsAOgAMvry4YABw+oY4AJnBaFHrqnOjc7t5+foEhoXokfKjqyHw6KhFRMcRschSKNGZIZIx0FMgsQQBspYwCJihm6nB0AOa2LC4+AKw+bR1wXfJ04TlDzSGllnQyKvJwa8ur1TR1DSou/j56dMhKtGaz6wBeAJ4GQagALPJ8buo3I8iLevQFWBczVGIxGAGYPABONxeMGQlzEcJ0Rj0ZACczXbg3OCQgBCyFxAlxAE1iQBBADSAC0ANYAVT4NIAKmDRC4eAA5AwAMUYABkAJIAcQMPCouOeZCCAFotAA1cLNeR6SIIOgCOBXcKHDwjSFBNyQnzA95BZ7SnxuAQjFwuABmYKCAg8bh8MqBYLgzRcIzc0pcfDgfD4Pn9uv1huNPhkwxGegMFy1KmxeIJRNJlNpDOZrPZXN5gpFYpIEqlsoVStOyDo9D4ljMJjtNBMZBsdgcziSxwCwVCPkclgofTOAH5kHAAB6oAC8jirNbodYbcCbxjOfTM4QoWj4Z0Onm7aT70hI8TiG5q+0aiQCzV80nUfEYZkYlkENLMGxkcQoNJYdrrJRSkEegkDMJtsiMTU7TfPouDAUBIGwED6nOaUDAnaVXWGdwYBAABdYhUF/FAVGpKkqTgAUSDuAQ+QACWlVAKQoGQ+VxABRJk3A5YQ+g8eQ+gAKW5NwKQARwAET5EY7gAdTpMwPFQKllQAX2ICg7TtJQEjAMFQmeNSCKAA==";
+
+    #[test]
+    fn decompress_lz64_payloads() {
+        let decompressed =
+            decompress_from_base64(BATCH_PAYLOAD, 4096).expect("failed to decompress");
+        let parsed: serde_json::Value =
+            serde_json::from_str(&decompressed).expect("failed to parse");
+        assert_eq!("🤓", parsed[0]["event"]);
+        assert_eq!(
+            "ze9BnBcBYYAKZkUakT3S1MNuFsLIGuMopB4r8-mVd6w",
+            parsed[0]["properties"]["distinct_id"]
+        );
+
+        // Form decoding can replace + characters by spaces
+        assert_eq!(
+            Ok(decompressed),
+            decompress_from_base64(&BATCH_PAYLOAD.replace('+', " "), 4096)
+        );
+        assert_eq!(Ok("".to_string()), decompress_from_base64("", 4096));
+    }
+
+    #[test]
+    fn reject_invalid_payloads() {
+        assert_eq!(
+            Err(Lz64Error::InvalidData),
+            decompress_from_base64("not base64!", 4096)
+        );
+        assert_eq!(
+            Err(Lz64Error::InvalidData),
+            decompress_from_base64(&BATCH_PAYLOAD[..64], 4096)
+        );
+    }
+
+    #[test]
+    fn enforce_size_limit() {
+        assert_eq!(
+            Err(Lz64Error::TooLarge),
+            decompress_from_base64(BATCH_PAYLOAD, 512)
+        );
+    }
+}
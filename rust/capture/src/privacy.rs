@@ -0,0 +1,359 @@
+/// Per-team privacy settings, applied to events before they reach the sink.
+///
+/// Teams can have the client IP dropped or truncated, and event properties stripped or
+/// hashed with a team salt, so that personal data never reaches Kafka. Settings are set by
+/// the main app in the `@posthog/capture-privacy-settings` Redis hash, holding a JSON object
+/// per token. They are loaded in the background, and we keep the last known values if Redis
+/// is unavailable. Until the first load succeeds, requests are rejected with a retryable
+/// error and the pod is not ready, as we can't tell which teams need their data scrubbed.
+/// Requests of teams whose settings can't be parsed are rejected the same way.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+use metrics::gauge;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use time::Duration;
+use tokio::sync::RwLock;
+use tokio::task;
+use tokio::time::interval;
+
+use crate::api::CaptureError;
+use crate::redis::Client;
+use crate::v0_request::{ProcessingContext, RawEvent};
+
+pub const PRIVACY_SETTINGS_CACHE_KEY: &str = "@posthog/capture-privacy-settings";
+
+/// Property holding the client IP, if the SDK sets it
+const IP_PROPERTY: &str = "$ip";
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpHandling {
+    #[default]
+    Keep,
+    Drop,
+    /// Zero the last octet of IPv4 addresses, and the last 80 bits of IPv6 addresses
+    Truncate,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct PrivacySettings {
+    #[serde(default)]
+    pub ip: IpHandling,
+    /// Properties removed from the events, `$set` and `$set_once` included, nested or not
+    #[serde(default)]
+    pub strip_properties: Vec<String>,
+    /// Properties replaced by the SHA-256 of the salt and their value
+    #[serde(default)]
+    pub hash_properties: Vec<String>,
+    #[serde(default)]
+    pub salt: String,
+}
+
+impl PrivacySettings {
+    /// Applies the IP settings to the request context.
+    pub fn apply_context(&self, context: &mut ProcessingContext) {
+        context.client_ip = match self.ip {
+            IpHandling::Keep => return,
+            IpHandling::Drop => String::new(),
+            IpHandling::Truncate => truncate_ip(&context.client_ip),
+        };
+    }
+
+    /// Strips and hashes the event properties, and applies the IP settings to `$ip`.
+    pub fn apply(&self, event: &mut RawEvent) {
+        // Some SDKs send person properties nested in the event properties
+        for key in ["$set", "$set_once"] {
+            if let Some(Value::Object(properties)) = event.properties.get_mut(key) {
+                for key in &self.strip_properties {
+                    properties.remove(key);
+                }
+                for key in &self.hash_properties {
+                    if let Some(value) = properties.get_mut(key) {
+                        *value = Value::String(self.hash(value));
+                    }
+                }
+            }
+        }
+
+        let properties = [
+            Some(&mut event.properties),
+            event.set.as_mut(),
+            event.set_once.as_mut(),
+        ];
+        for properties in properties.into_iter().flatten() {
+            for key in &self.strip_properties {
+                properties.remove(key);
+            }
+            for key in &self.hash_properties {
+                if let Some(value) = properties.get_mut(key) {
+                    *value = Value::String(self.hash(value));
+                }
+            }
+        }
+
+        match self.ip {
+            IpHandling::Keep => {}
+            IpHandling::Drop => {
+                event.properties.remove(IP_PROPERTY);
+            }
+            IpHandling::Truncate => {
+                if let Some(Value::String(ip)) = event.properties.get_mut(IP_PROPERTY) {
+                    *ip = truncate_ip(ip);
+                }
+            }
+        }
+    }
+
+    fn hash(&self, value: &Value) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt.as_bytes());
+        match value {
+            Value::String(value) => hasher.update(value.as_bytes()),
+            value => hasher.update(value.to_string().as_bytes()),
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn is_valid(&self) -> bool {
+        // Unsalted hashes of low-cardinality values can be reversed
+        self.hash_properties.is_empty() || !self.salt.is_empty()
+    }
+}
+
+/// Truncates an IP address, unparseable values are dropped.
+fn truncate_ip(ip: &str) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0]).to_string()
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let [a, b, c, ..] = ip.segments();
+            IpAddr::from([a, b, c, 0, 0, 0, 0, 0]).to_string()
+        }
+        Err(_) => String::new(),
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct PrivacyFilter {
+    settings: Arc<RwLock<HashMap<String, Option<Arc<PrivacySettings>>>>>, // None if invalid
+    loaded: Arc<AtomicBool>,
+}
+
+impl PrivacyFilter {
+    /// Starts loading the settings from Redis in the background.
+    pub fn load_from_redis(
+        &self,
+        interval_duration: Duration,
+        redis: Arc<dyn Client + Send + Sync>,
+        redis_key_prefix: Option<String>,
+    ) {
+        let settings = Arc::clone(&self.settings);
+        let loaded = Arc::clone(&self.loaded);
+        let key = format!(
+            "{}{PRIVACY_SETTINGS_CACHE_KEY}",
+            redis_key_prefix.unwrap_or_default()
+        );
+        let interval_duration =
+            StdDuration::from_nanos(interval_duration.whole_nanoseconds() as u64);
+
+        task::spawn(async move {
+            let mut interval = interval(interval_duration);
+            loop {
+                match redis.hgetall(key.clone()).await {
+                    Ok(values) => {
+                        let mut updated = HashMap::with_capacity(values.len());
+                        let mut invalid = 0;
+                        for (token, value) in values {
+                            match serde_json::from_str::<PrivacySettings>(&value) {
+                                Ok(parsed) if parsed.is_valid() => {
+                                    updated.insert(token, Some(Arc::new(parsed)));
+                                }
+                                _ => {
+                                    tracing::warn!("invalid privacy settings for {token}");
+                                    invalid += 1;
+                                    updated.insert(token, None);
+                                }
+                            }
+                        }
+                        gauge!("capture_privacy_settings_loaded_tokens")
+                            .set((updated.len() - invalid) as f64);
+                        gauge!("capture_privacy_settings_invalid_tokens").set(invalid as f64);
+                        *settings.write().await = updated;
+                        loaded.store(true, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to update privacy settings from Redis: {:?}", e);
+                    }
+                }
+
+                interval.tick().await;
+            }
+        });
+    }
+
+    /// Whether the settings were loaded from Redis at least once.
+    pub fn is_loaded(&self) -> bool {
+        self.loaded.load(Ordering::Relaxed)
+    }
+
+    /// Returns the settings of a team, if it has any.
+    pub async fn settings(
+        &self,
+        token: &str,
+    ) -> Result<Option<Arc<PrivacySettings>>, CaptureError> {
+        if !self.is_loaded() {
+            return Err(CaptureError::PrivacySettingsUnavailable);
+        }
+        match self.settings.read().await.get(token) {
+            None => Ok(None),
+            Some(Some(settings)) => Ok(Some(settings.clone())),
+            // We can't tell what to scrub, fail closed until the settings are fixed
+            Some(None) => Err(CaptureError::PrivacySettingsUnavailable),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use time::Duration;
+
+    use crate::api::CaptureError;
+    use crate::privacy::{
+        truncate_ip, IpHandling, PrivacyFilter, PrivacySettings, PRIVACY_SETTINGS_CACHE_KEY,
+    };
+    use crate::redis::MockRedisClient;
+    use crate::v0_request::RawEvent;
+
+    #[test]
+    fn truncate_addresses() {
+        assert_eq!("192.168.1.0", truncate_ip("192.168.1.42"));
+        assert_eq!(
+            "2001:db8:85a3::",
+            truncate_ip("2001:db8:85a3:8d3:1319:8a2e:370:7348")
+        );
+        assert_eq!("", truncate_ip("unknown"));
+    }
+
+    #[test]
+    fn strip_and_hash_properties() {
+        let settings = PrivacySettings {
+            ip: IpHandling::Truncate,
+            strip_properties: vec!["email".to_string()],
+            hash_properties: vec!["name".to_string(), "id".to_string()],
+            salt: "salt".to_string(),
+        };
+        let mut event: RawEvent = serde_json::from_value(json!({
+            "event": "e",
+            "properties": {"email": "a@b.c", "name": "alice", "id": 42, "$ip": "10.1.2.3"},
+            "$set": {"email": "a@b.c", "name": "alice", "plan": "free"}
+        }))
+        .unwrap();
+        settings.apply(&mut event);
+
+        let hashed = settings.hash(&json!("alice"));
+        assert_eq!(64, hashed.len());
+        assert_ne!(hashed, settings.hash(&json!("bob")));
+        assert_eq!(
+            json!({"name": hashed, "id": settings.hash(&json!(42)), "$ip": "10.1.2.0"}),
+            json!(event.properties)
+        );
+        assert_eq!(
+            json!({"name": hashed, "plan": "free"}),
+            json!(event.set.unwrap())
+        );
+    }
+
+    #[test]
+    fn strip_and_hash_nested_person_properties() {
+        let settings = PrivacySettings {
+            strip_properties: vec!["email".to_string()],
+            hash_properties: vec!["name".to_string()],
+            salt: "salt".to_string(),
+            ..Default::default()
+        };
+        let mut event: RawEvent = serde_json::from_value(json!({
+            "event": "$identify",
+            "properties": {
+                "$set": {"email": "a@b.c", "name": "alice", "plan": "free"},
+                "$set_once": {"email": "a@b.c", "first_seen": "today"}
+            }
+        }))
+        .unwrap();
+        settings.apply(&mut event);
+
+        assert_eq!(
+            json!({
+                "$set": {"name": settings.hash(&json!("alice")), "plan": "free"},
+                "$set_once": {"first_seen": "today"}
+            }),
+            json!(event.properties)
+        );
+    }
+
+    #[tokio::test]
+    async fn load_settings_from_redis() {
+        let client = MockRedisClient::new().hgetall_ret(
+            PRIVACY_SETTINGS_CACHE_KEY,
+            HashMap::from([
+                ("strict".to_string(), json!({"ip": "drop"}).to_string()),
+                (
+                    "unsalted".to_string(),
+                    json!({"hash_properties": ["email"]}).to_string(),
+                ),
+                ("invalid".to_string(), "nope".to_string()),
+            ]),
+        );
+        let filter = PrivacyFilter::default();
+        assert!(matches!(
+            filter.settings("strict").await,
+            Err(CaptureError::PrivacySettingsUnavailable)
+        ));
+        filter.load_from_redis(Duration::seconds(1), Arc::new(client), None);
+
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        assert!(filter.is_loaded());
+        assert_eq!(
+            Some(IpHandling::Drop),
+            filter.settings("strict").await.unwrap().map(|s| s.ip)
+        );
+        // Events of teams with unusable settings must not go through unscrubbed
+        assert!(matches!(
+            filter.settings("unsalted").await,
+            Err(CaptureError::PrivacySettingsUnavailable)
+        ));
+        assert!(matches!(
+            filter.settings("invalid").await,
+            Err(CaptureError::PrivacySettingsUnavailable)
+        ));
+        assert!(filter.settings("other").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn fail_closed_until_loaded() {
+        // Unknown keys fail like Redis being unavailable
+        let client = MockRedisClient::new();
+        let filter = PrivacyFilter::default();
+        filter.load_from_redis(Duration::seconds(1), Arc::new(client), None);
+
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        assert!(!filter.is_loaded());
+        assert!(matches!(
+            filter.settings("strict").await,
+            Err(CaptureError::PrivacySettingsUnavailable)
+        ));
+    }
+}
@@ -1,9 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use redis::AsyncCommands;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, RedisError};
 use tokio::time::timeout;
 
 // average for all commands is <10ms, check grafana
@@ -20,17 +22,61 @@ const REDIS_TIMEOUT_MILLISECS: u64 = 10;
 pub trait Client {
     // A very simplified wrapper, but works for our usage
     async fn zrangebyscore(&self, k: String, min: String, max: String) -> Result<Vec<String>>;
+    async fn hgetall(&self, k: String) -> Result<HashMap<String, String>>;
+    /// Adds members to the set at `k`, expiring after `ttl` seconds. Returns, for each member,
+    /// whether it was already present in one of the `lookback` sets before being added.
+    async fn sadd_checked(
+        &self,
+        k: String,
+        lookback: Vec<String>,
+        members: Vec<String>,
+        ttl: u64,
+    ) -> Result<Vec<bool>>;
+    async fn srem(&self, k: String, members: Vec<String>) -> Result<()>;
+    /// Increments fields of the hash at `k`, expiring after `ttl` seconds.
+    /// Returns the new value of each field.
+    async fn hincrby_many(
+        &self,
+        k: String,
+        increments: Vec<(String, i64)>,
+        ttl: u64,
+    ) -> Result<Vec<i64>>;
 }
 
 pub struct RedisClient {
     client: redis::Client,
+    // Shared by the commands running on every request, instead of connecting each time
+    multiplexed: tokio::sync::Mutex<Option<MultiplexedConnection>>,
 }
 
 impl RedisClient {
     pub fn new(addr: String) -> Result<RedisClient> {
         let client = redis::Client::open(addr)?;
 
-        Ok(RedisClient { client })
+        Ok(RedisClient {
+            client,
+            multiplexed: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    async fn multiplexed_connection(&self) -> Result<MultiplexedConnection> {
+        let mut multiplexed = self.multiplexed.lock().await;
+        if let Some(conn) = multiplexed.as_ref() {
+            return Ok(conn.clone());
+        }
+        let conn = self.client.get_multiplexed_tokio_connection().await?;
+        *multiplexed = Some(conn.clone());
+        Ok(conn)
+    }
+
+    /// Drops the shared connection if it is broken, the next command will open a new one.
+    async fn check_connection<T>(&self, result: std::result::Result<T, RedisError>) -> Result<T> {
+        if let Err(e) = &result {
+            if e.is_io_error() || e.is_connection_dropped() {
+                self.multiplexed.lock().await.take();
+            }
+        }
+        Ok(result?)
     }
 }
 
@@ -44,18 +90,102 @@ impl Client for RedisClient {
 
         Ok(fut?)
     }
+
+    async fn hgetall(&self, k: String) -> Result<HashMap<String, String>> {
+        let mut conn = self.client.get_async_connection().await?;
+
+        let results = conn.hgetall(k);
+        let fut = timeout(Duration::from_millis(REDIS_TIMEOUT_MILLISECS), results).await?;
+
+        Ok(fut?)
+    }
+
+    async fn sadd_checked(
+        &self,
+        k: String,
+        lookback: Vec<String>,
+        members: Vec<String>,
+        ttl: u64,
+    ) -> Result<Vec<bool>> {
+        if members.is_empty() || lookback.is_empty() {
+            return Ok(vec![false; members.len()]);
+        }
+        let mut conn = self.multiplexed_connection().await?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for member in &members {
+            for key in &lookback {
+                pipe.sismember(key, member);
+            }
+        }
+        pipe.sadd(&k, &members).ignore();
+        pipe.expire(&k, ttl as usize).ignore();
+
+        let results = pipe.query_async::<_, Vec<bool>>(&mut conn);
+        let fut = timeout(Duration::from_millis(REDIS_TIMEOUT_MILLISECS), results).await?;
+
+        Ok(self
+            .check_connection(fut)
+            .await?
+            .chunks(lookback.len())
+            .map(|found| found.iter().any(|f| *f))
+            .collect())
+    }
+
+    async fn srem(&self, k: String, members: Vec<String>) -> Result<()> {
+        if members.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.multiplexed_connection().await?;
+
+        let results = conn.srem(k, members);
+        let fut = timeout(Duration::from_millis(REDIS_TIMEOUT_MILLISECS), results).await?;
+
+        self.check_connection(fut).await
+    }
+
+    async fn hincrby_many(
+        &self,
+        k: String,
+        increments: Vec<(String, i64)>,
+        ttl: u64,
+    ) -> Result<Vec<i64>> {
+        if increments.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.client.get_async_connection().await?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (field, delta) in &increments {
+            pipe.hincr(&k, field, *delta);
+        }
+        pipe.expire(&k, ttl as usize).ignore();
+
+        let results = pipe.query_async::<_, Vec<i64>>(&mut conn);
+        let fut = timeout(Duration::from_millis(REDIS_TIMEOUT_MILLISECS), results).await?;
+
+        Ok(fut?)
+    }
 }
 
 // mockall got really annoying with async and results so I'm just gonna do my own
 #[derive(Clone)]
 pub struct MockRedisClient {
     zrangebyscore_ret: HashMap<String, Vec<String>>,
+    hgetall_ret: HashMap<String, HashMap<String, String>>,
+    sets: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+    hashes: Arc<Mutex<HashMap<String, HashMap<String, i64>>>>,
 }
 
 impl MockRedisClient {
     pub fn new() -> MockRedisClient {
         MockRedisClient {
             zrangebyscore_ret: HashMap::new(),
+            hgetall_ret: HashMap::new(),
+            sets: Arc::new(Mutex::new(HashMap::new())),
+            hashes: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -63,6 +193,11 @@ impl MockRedisClient {
         self.zrangebyscore_ret.insert(key.to_owned(), ret);
         self.clone()
     }
+
+    pub fn hgetall_ret(&mut self, key: &str, ret: HashMap<String, String>) -> Self {
+        self.hgetall_ret.insert(key.to_owned(), ret);
+        self.clone()
+    }
 }
 
 impl Default for MockRedisClient {
@@ -80,4 +215,60 @@ impl Client for MockRedisClient {
             None => Err(anyhow!("unknown key")),
         }
     }
+
+    async fn hgetall(&self, key: String) -> Result<HashMap<String, String>> {
+        match self.hgetall_ret.get(&key) {
+            Some(val) => Ok(val.clone()),
+            None => Err(anyhow!("unknown key")),
+        }
+    }
+
+    // Sets are stateful, and never expire
+    async fn sadd_checked(
+        &self,
+        key: String,
+        lookback: Vec<String>,
+        members: Vec<String>,
+        _ttl: u64,
+    ) -> Result<Vec<bool>> {
+        let mut sets = self.sets.lock().unwrap();
+        let found = members
+            .iter()
+            .map(|member| {
+                lookback
+                    .iter()
+                    .any(|k| sets.get(k).is_some_and(|set| set.contains(member)))
+            })
+            .collect();
+        sets.entry(key).or_default().extend(members);
+        Ok(found)
+    }
+
+    async fn srem(&self, key: String, members: Vec<String>) -> Result<()> {
+        if let Some(set) = self.sets.lock().unwrap().get_mut(&key) {
+            for member in members {
+                set.remove(&member);
+            }
+        }
+        Ok(())
+    }
+
+    // Hashes are stateful, and never expire
+    async fn hincrby_many(
+        &self,
+        key: String,
+        increments: Vec<(String, i64)>,
+        _ttl: u64,
+    ) -> Result<Vec<i64>> {
+        let mut hashes = self.hashes.lock().unwrap();
+        let hash = hashes.entry(key).or_default();
+        Ok(increments
+            .into_iter()
+            .map(|(field, delta)| {
+                let value = hash.entry(field).or_default();
+                *value += delta;
+                *value
+            })
+            .collect())
+    }
 }
@@ -1,19 +1,33 @@
 use std::future::ready;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use axum::extract::DefaultBodyLimit;
-use axum::http::Method;
+use axum::http::{Method, StatusCode};
 use axum::{
     routing::{get, post},
     Router,
 };
+use governor::clock::DefaultClock;
+use governor::state::{InMemoryState, NotKeyed};
+use governor::{Quota, RateLimiter};
 use health::HealthRegistry;
 use tower::limit::ConcurrencyLimitLayer;
 use tower_http::cors::{AllowHeaders, AllowOrigin, CorsLayer};
+use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::trace::TraceLayer;
 
-use crate::test_endpoint;
-use crate::{limiters::redis::RedisLimiter, redis::Client, sinks, time::TimeSource, v0_endpoint};
+use crate::bots::BotFilter;
+use crate::debug_capture::DebugCapture;
+use crate::dedup::Deduplicator;
+use crate::limiters::shedding::{shed_load, LoadShedding};
+use crate::privacy::PrivacyFilter;
+use crate::{import_endpoint, segment_endpoint, test_endpoint, validate_endpoint};
+use crate::{
+    limiters::billing::BillingLimiter, limiters::rate::TokenRateLimiter, redis::Client, sinks,
+    time::TimeSource, v0_endpoint, v1_endpoint,
+};
 
 use crate::config::CaptureMode;
 use crate::prometheus::{setup_metrics_recorder, track_metrics};
@@ -27,14 +41,37 @@ pub struct State {
     pub sink: Arc<dyn sinks::Event + Send + Sync>,
     pub timesource: Arc<dyn TimeSource + Send + Sync>,
     pub redis: Arc<dyn Client + Send + Sync>,
-    pub billing_limiter: RedisLimiter,
+    pub billing_limiter: BillingLimiter,
+    pub rate_limiter: Option<TokenRateLimiter>,
+    pub deduplicator: Option<Arc<dyn Deduplicator + Send + Sync>>,
+    pub privacy: Option<PrivacyFilter>,
+    pub bot_filter: Option<BotFilter>,
+    pub load_shedding: Option<LoadShedding>,
+    pub debug_capture: Option<DebugCapture>,
+    pub import_limiter: Arc<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>,
     pub event_size_limit: usize,
+    pub replay_message_max_bytes: usize,
 }
 
 async fn index() -> &'static str {
     "capture"
 }
 
+/// Not-ready once shutdown started, so that load balancers stop sending us traffic, and
+/// until the privacy settings are loaded, as requests are rejected without them.
+fn readiness_status(
+    readiness: &AtomicBool,
+    privacy: Option<&PrivacyFilter>,
+) -> (StatusCode, &'static str) {
+    if !readiness.load(Ordering::Relaxed) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down");
+    }
+    match privacy.map_or(true, PrivacyFilter::is_loaded) {
+        true => (StatusCode::OK, "capture"),
+        false => (StatusCode::SERVICE_UNAVAILABLE, "loading privacy settings"),
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn router<
     TZ: TimeSource + Send + Sync + 'static,
@@ -43,20 +80,39 @@ pub fn router<
 >(
     timesource: TZ,
     liveness: HealthRegistry,
+    readiness: Arc<AtomicBool>,
     sink: S,
     redis: Arc<R>,
-    billing_limiter: RedisLimiter,
+    billing_limiter: BillingLimiter,
+    rate_limiter: Option<TokenRateLimiter>,
+    deduplicator: Option<Arc<dyn Deduplicator + Send + Sync>>,
+    privacy: Option<PrivacyFilter>,
+    bot_filter: Option<BotFilter>,
+    load_shedding: Option<LoadShedding>,
+    debug_capture: Option<DebugCapture>,
+    import_events_per_second: NonZeroU32,
     metrics: bool,
     capture_mode: CaptureMode,
     concurrency_limit: Option<usize>,
     event_size_limit: usize,
+    replay_message_max_bytes: usize,
 ) -> Router {
     let state = State {
         sink: Arc::new(sink),
         timesource: Arc::new(timesource),
         redis,
         billing_limiter,
+        rate_limiter,
+        deduplicator,
+        privacy,
+        bot_filter,
+        load_shedding,
+        debug_capture,
+        import_limiter: Arc::new(RateLimiter::direct(Quota::per_second(
+            import_events_per_second,
+        ))),
         event_size_limit,
+        replay_message_max_bytes,
     };
 
     // Very permissive CORS policy, as old SDK versions
@@ -95,7 +151,15 @@ pub fn router<
                 .get(v0_endpoint::event)
                 .options(v0_endpoint::options),
         )
-        .layer(DefaultBodyLimit::max(BATCH_BODY_SIZE)); // Have to use this, rather than RequestBodyLimitLayer, because we use `Bytes` in the handler (this limit applies specifically to Bytes body types)
+        .route(
+            "/i/v1/batch",
+            post(v1_endpoint::batch).options(v0_endpoint::options),
+        )
+        .route(
+            "/i/v1/batch/",
+            post(v1_endpoint::batch).options(v0_endpoint::options),
+        )
+        .layer(RequestBodyLimitLayer::new(BATCH_BODY_SIZE)); // Handlers stream the body, DefaultBodyLimit only applies to `Bytes` body types
 
     let event_router = Router::new()
         .route(
@@ -122,11 +186,45 @@ pub fn router<
                 .get(v0_endpoint::event)
                 .options(v0_endpoint::options),
         )
-        .layer(DefaultBodyLimit::max(EVENT_BODY_SIZE));
+        .layer(RequestBodyLimitLayer::new(EVENT_BODY_SIZE));
+
+    // Streamed bodies of arbitrary length, lines are size-limited by the handler
+    let import_router = Router::new().route(
+        "/i/v0/import",
+        post(import_endpoint::import).options(v0_endpoint::options),
+    );
+
+    // Segment-compatible API, served on `/v1/{track,identify,page,screen,group,alias,batch}`
+    let segment_router = Router::new()
+        .route(
+            "/v1/:call",
+            post(segment_endpoint::call).options(v0_endpoint::options),
+        )
+        .route(
+            "/v1/:call/",
+            post(segment_endpoint::call).options(v0_endpoint::options),
+        )
+        .layer(RequestBodyLimitLayer::new(BATCH_BODY_SIZE));
+
+    // Dry-run of the v0 pipeline, reporting how payloads would be processed
+    let validate_router = Router::new()
+        .route(
+            "/i/v0/validate",
+            post(validate_endpoint::validate).options(v0_endpoint::options),
+        )
+        .route(
+            "/i/v0/validate/",
+            post(validate_endpoint::validate).options(v0_endpoint::options),
+        )
+        .layer(RequestBodyLimitLayer::new(BATCH_BODY_SIZE));
 
+    let readiness_privacy = state.privacy.clone();
     let status_router = Router::new()
         .route("/", get(index))
-        .route("/_readiness", get(index))
+        .route(
+            "/_readiness",
+            get(move || ready(readiness_status(&readiness, readiness_privacy.as_ref()))),
+        )
         .route("/_liveness", get(move || ready(liveness.get_status())));
 
     let recordings_router = Router::new()
@@ -142,12 +240,15 @@ pub fn router<
                 .get(v0_endpoint::recording)
                 .options(v0_endpoint::options),
         )
-        .layer(DefaultBodyLimit::max(RECORDING_BODY_SIZE));
+        .layer(RequestBodyLimitLayer::new(RECORDING_BODY_SIZE));
 
     let mut router = match capture_mode {
         CaptureMode::Events => Router::new()
             .merge(batch_router)
             .merge(event_router)
+            .merge(segment_router)
+            .merge(validate_router)
+            .merge(import_router)
             .merge(test_router),
         CaptureMode::Recordings => Router::new().merge(recordings_router),
     };
@@ -156,6 +257,14 @@ pub fn router<
         router = router.layer(ConcurrencyLimitLayer::new(limit));
     }
 
+    // Rejects requests before they wait for a concurrency slot or read their body
+    if load_shedding.is_some() {
+        router = router.layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            shed_load,
+        ));
+    }
+
     let router = router
         .merge(status_router)
         .layer(TraceLayer::new_for_http())
@@ -0,0 +1,255 @@
+/// Routing rules for the Kafka sink, to send the analytics events of some teams to dedicated
+/// topics without relying on overflow. Historical, replay and other data types are not routed.
+///
+/// Rules are grouped by token, the `*` token holding rules applying to all teams. For a
+/// given event, the token rules are checked in order, then the `*` rules, and the first
+/// rule matching the event name decides the topic and partition key. Event name patterns
+/// can hold one `*` wildcard, `$feature_flag_*` or `*` for example.
+///
+/// Rules come from the `ROUTING_RULES` setting, a JSON object of token to rules lists, and
+/// from the `@posthog/capture-routing-rules` Redis hash, holding a JSON list of rules per
+/// token. Redis rules replace the static rules of the same token, and we keep the last
+/// known values if Redis is unavailable.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+use metrics::gauge;
+use serde::Deserialize;
+use time::Duration;
+use tokio::sync::RwLock;
+use tokio::task;
+use tokio::time::interval;
+
+use crate::redis::Client;
+
+pub const ROUTING_RULES_CACHE_KEY: &str = "@posthog/capture-routing-rules";
+
+/// Rules under this token apply to all teams, after their own rules
+const ANY_TOKEN: &str = "*";
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PartitionKeyStrategy {
+    /// Keep the key of the event type: `token:distinct_id` for analytics events
+    #[default]
+    Default,
+    /// All the events of the team go to the same partition, except overflowing events
+    Token,
+    /// Spread the events on all partitions, without locality
+    Random,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RoutingRule {
+    #[serde(default = "any_event")]
+    pub event: String,
+    pub topic: String,
+    #[serde(default)]
+    pub partition_key: PartitionKeyStrategy,
+}
+
+fn any_event() -> String {
+    String::from("*")
+}
+
+impl RoutingRule {
+    fn matches(&self, event_name: &str) -> bool {
+        match self.event.split_once('*') {
+            None => self.event == event_name,
+            Some((prefix, suffix)) => {
+                event_name.len() >= prefix.len() + suffix.len()
+                    && event_name.starts_with(prefix)
+                    && event_name.ends_with(suffix)
+            }
+        }
+    }
+}
+
+type Rules = HashMap<String, Vec<RoutingRule>>;
+
+#[derive(Clone)]
+pub struct RoutingTable {
+    static_rules: Arc<Rules>,
+    rules: Arc<RwLock<Rules>>,
+}
+
+impl RoutingTable {
+    /// Create a new RoutingTable from the static rules, a JSON object of token to rules.
+    pub fn new(static_rules: Option<&str>) -> anyhow::Result<RoutingTable> {
+        let static_rules: Rules = match static_rules {
+            None => HashMap::new(),
+            Some(value) if value.trim().is_empty() => HashMap::new(),
+            Some(value) => serde_json::from_str(value)?,
+        };
+        for rule in static_rules.values().flatten() {
+            if rule.topic.is_empty() {
+                anyhow::bail!("routing rule for {} has an empty topic", rule.event);
+            }
+        }
+        Ok(RoutingTable {
+            rules: Arc::new(RwLock::new(static_rules.clone())),
+            static_rules: Arc::new(static_rules),
+        })
+    }
+
+    /// Starts loading rules from Redis in the background, on top of the static rules.
+    pub fn load_from_redis(
+        &self,
+        interval_duration: Duration,
+        redis: Arc<dyn Client + Send + Sync>,
+        redis_key_prefix: Option<String>,
+    ) {
+        let rules = Arc::clone(&self.rules);
+        let static_rules = Arc::clone(&self.static_rules);
+        let key = format!(
+            "{}{ROUTING_RULES_CACHE_KEY}",
+            redis_key_prefix.unwrap_or_default()
+        );
+        let interval_duration =
+            StdDuration::from_nanos(interval_duration.whole_nanoseconds() as u64);
+
+        task::spawn(async move {
+            let mut interval = interval(interval_duration);
+            loop {
+                match redis.hgetall(key.clone()).await {
+                    Ok(values) => {
+                        let mut updated = static_rules.as_ref().clone();
+                        for (token, value) in values {
+                            match parse_rules(&value) {
+                                Some(token_rules) => {
+                                    updated.insert(token, token_rules);
+                                }
+                                None => {
+                                    tracing::warn!("invalid routing rules for {token}: {value}")
+                                }
+                            }
+                        }
+                        gauge!("capture_routing_rules_loaded_tokens").set(updated.len() as f64);
+                        *rules.write().await = updated;
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to update routing rules from Redis: {:?}", e);
+                    }
+                }
+
+                interval.tick().await;
+            }
+        });
+    }
+
+    /// Returns the destination topic and partition key strategy for an event,
+    /// if a rule matches it.
+    pub async fn route(
+        &self,
+        token: &str,
+        event_name: &str,
+    ) -> Option<(String, PartitionKeyStrategy)> {
+        let rules = self.rules.read().await;
+        [token, ANY_TOKEN]
+            .iter()
+            .filter_map(|token| rules.get(*token))
+            .flatten()
+            .find(|rule| rule.matches(event_name))
+            .map(|rule| (rule.topic.clone(), rule.partition_key))
+    }
+}
+
+fn parse_rules(value: &str) -> Option<Vec<RoutingRule>> {
+    let rules: Vec<RoutingRule> = serde_json::from_str(value).ok()?;
+    match rules.iter().any(|rule| rule.topic.is_empty()) {
+        true => None,
+        false => Some(rules),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use time::Duration;
+
+    use crate::redis::MockRedisClient;
+    use crate::routing::{
+        PartitionKeyStrategy, RoutingRule, RoutingTable, ROUTING_RULES_CACHE_KEY,
+    };
+
+    fn rule(event: &str) -> RoutingRule {
+        RoutingRule {
+            event: event.to_string(),
+            topic: "topic".to_string(),
+            partition_key: PartitionKeyStrategy::Default,
+        }
+    }
+
+    #[test]
+    fn match_event_patterns() {
+        assert!(rule("*").matches("$pageview"));
+        assert!(rule("$pageview").matches("$pageview"));
+        assert!(!rule("$pageview").matches("$pageleave"));
+        assert!(rule("$feature_flag_*").matches("$feature_flag_called"));
+        assert!(!rule("$feature_flag_*").matches("$feature_flag"));
+        assert!(rule("*_clicked").matches("button_clicked"));
+        assert!(rule("a*a").matches("aa"));
+        assert!(!rule("a*a").matches("a"));
+    }
+
+    #[tokio::test]
+    async fn route_static_rules() {
+        let rules = json!({
+            "big": [
+                {"event": "$pageview", "topic": "big_pageviews", "partition_key": "random"},
+                {"topic": "big_events", "partition_key": "token"}
+            ],
+            "*": [{"event": "$web_vitals", "topic": "web_vitals"}]
+        });
+        let table = RoutingTable::new(Some(&rules.to_string())).expect("invalid rules");
+
+        assert_eq!(
+            Some(("big_pageviews".to_string(), PartitionKeyStrategy::Random)),
+            table.route("big", "$pageview").await
+        );
+        assert_eq!(
+            Some(("big_events".to_string(), PartitionKeyStrategy::Token)),
+            table.route("big", "$web_vitals").await
+        );
+        assert_eq!(
+            Some(("web_vitals".to_string(), PartitionKeyStrategy::Default)),
+            table.route("small", "$web_vitals").await
+        );
+        assert_eq!(None, table.route("small", "$pageview").await);
+
+        assert!(RoutingTable::new(None).is_ok());
+        assert!(RoutingTable::new(Some("[]")).is_err());
+        assert!(RoutingTable::new(Some(r#"{"t": [{"topic": ""}]}"#)).is_err());
+    }
+
+    #[tokio::test]
+    async fn route_redis_rules() {
+        let rules = json!({"big": [{"topic": "static"}], "other": [{"topic": "other"}]});
+        let table = RoutingTable::new(Some(&rules.to_string())).expect("invalid rules");
+        let client = MockRedisClient::new().hgetall_ret(
+            ROUTING_RULES_CACHE_KEY,
+            HashMap::from([
+                ("big".to_string(), json!([{"topic": "redis"}]).to_string()),
+                ("invalid".to_string(), "nope".to_string()),
+            ]),
+        );
+        table.load_from_redis(Duration::seconds(1), Arc::new(client), None);
+
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        // Redis rules replace the static rules of the token
+        assert_eq!(
+            Some(("redis".to_string(), PartitionKeyStrategy::Default)),
+            table.route("big", "e").await
+        );
+        assert_eq!(
+            Some(("other".to_string(), PartitionKeyStrategy::Default)),
+            table.route("other", "e").await
+        );
+        assert_eq!(None, table.route("invalid", "e").await);
+    }
+}
@@ -0,0 +1,152 @@
+use axum::body::Body;
+use axum::extract::{MatchedPath, Path, State};
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::{debug_handler, Json};
+use axum_client_ip::InsecureClientIp;
+use metrics::counter;
+use tracing::instrument;
+
+use crate::api::{CaptureError, CaptureResponse, CaptureResponseCode};
+use crate::body::{compression_hint, decode_body};
+use crate::debug_capture::debug_request;
+use crate::prometheus::report_dropped_events;
+use crate::router;
+use crate::segment_request::{
+    extract_write_key, parse_sent_at, SegmentBatch, SegmentMessage, SegmentType,
+};
+use crate::v0_endpoint::{
+    apply_limits, filter_bots, privacy_settings, quota_limited_response, send_events,
+};
+use crate::v0_request::ProcessingContext;
+
+/// Compatibility endpoints for the Segment HTTP tracking API, so that teams migrating
+/// from Segment can point their `analytics-*` libraries to capture.
+///
+/// Messages are translated to PostHog events, then processed like v0 events: billing
+/// and rate limits are not reported to the client, as these libraries retry on errors.
+async fn handle_segment(
+    state: &router::State,
+    ip: &InsecureClientIp,
+    headers: &HeaderMap,
+    path: &MatchedPath,
+    body: Body,
+    kind: Option<SegmentType>,
+) -> Result<Json<CaptureResponse>, CaptureError> {
+    let user_agent = headers
+        .get("user-agent")
+        .map_or("unknown", |v| v.to_str().unwrap_or("unknown"));
+    tracing::Span::current().record("user_agent", user_agent);
+    tracing::Span::current().record("path", path.as_str().trim_end_matches('/'));
+
+    // Authenticate before decoding, there is no token in the payload to fall back to
+    let token = extract_write_key(headers)?;
+    tracing::Span::current().record("token", &token);
+
+    let hint = compression_hint(None, headers);
+    let (events, sent_at) = match kind {
+        Some(kind) => {
+            let message = decode_body::<SegmentMessage>(body, hint, state.event_size_limit).await?;
+            let sent_at = parse_sent_at(&message.sent_at);
+            (vec![message.into_event(kind)?], sent_at)
+        }
+        None => {
+            let batch = decode_body::<SegmentBatch>(body, hint, state.event_size_limit).await?;
+            let sent_at = parse_sent_at(&batch.sent_at);
+            let count = batch.batch.len() as u64;
+            match batch.into_events() {
+                Ok(events) => (events, sent_at),
+                Err(err) => {
+                    report_dropped_events("segment_invalid_message", count);
+                    return Err(err);
+                }
+            }
+        }
+    };
+
+    tracing::Span::current().record("batch_size", events.len());
+    if events.is_empty() {
+        tracing::log::warn!("rejected empty batch");
+        return Err(CaptureError::EmptyBatch);
+    }
+    counter!("capture_events_received_total").increment(events.len() as u64);
+    counter!("capture_segment_events_received_total").increment(events.len() as u64);
+
+    let mut context = ProcessingContext {
+        lib_version: None,
+        sent_at,
+        token,
+        now: state.timesource.current_time(),
+        client_ip: ip.0.to_string(),
+        historical_migration: false,
+    };
+
+    // Bot traffic is dropped silently, like rate limited traffic
+    let events = filter_bots(state, &context, headers, events).await;
+    if events.is_empty() {
+        return Ok(Json(CaptureResponse {
+            status: CaptureResponseCode::Ok,
+            quota_limited: None,
+        }));
+    }
+
+    // Before the limits, uuids recorded for deduplication would not be forgotten otherwise
+    let privacy = privacy_settings(state, &mut context).await?;
+    let (mut events, quota_limited) = match apply_limits(state, &context, events).await {
+        Err(CaptureError::RateLimited) => (Vec::new(), Vec::new()),
+        Err(err) => return Err(err),
+        Ok(limited) => limited,
+    };
+    let ok = Json(CaptureResponse {
+        status: CaptureResponseCode::Ok,
+        quota_limited: quota_limited_response(&quota_limited),
+    });
+    if events.is_empty() {
+        return Ok(ok);
+    }
+    if let Some(settings) = privacy {
+        events.iter_mut().for_each(|event| settings.apply(event));
+    }
+
+    tracing::debug!(context=?context, events=?events, "decoded segment request");
+
+    send_events(state, &context, &events).await?;
+    Ok(ok)
+}
+
+/// Serves the `/v1/{call}` routes, `call` being a message type or `batch`.
+#[instrument(skip_all, fields(path, call, token, batch_size, user_agent))]
+#[debug_handler]
+#[allow(clippy::too_many_arguments)]
+pub async fn call(
+    state: State<router::State>,
+    ip: InsecureClientIp,
+    Path(call): Path<String>,
+    headers: HeaderMap,
+    method: Method,
+    uri: Uri,
+    path: MatchedPath,
+    body: Body,
+) -> Result<Response, CaptureError> {
+    tracing::Span::current().record("call", &call);
+    let kind = match call.as_str() {
+        "track" => Some(SegmentType::Track),
+        "identify" => Some(SegmentType::Identify),
+        "page" => Some(SegmentType::Page),
+        "screen" => Some(SegmentType::Screen),
+        "group" => Some(SegmentType::Group),
+        "alias" => Some(SegmentType::Alias),
+        "batch" => None,
+        _ => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    // Requests without a valid write key fail before their token is known, and are not recorded
+    let (body, debug) = match extract_write_key(&headers) {
+        Ok(token) => debug_request(&state, &method, &uri, &headers, body, Some(&token)).await,
+        Err(_) => (body, None),
+    };
+    let result = handle_segment(&state, &ip, &headers, &path, body, kind).await;
+    if let Some(debug) = debug {
+        debug.finish(&result);
+    }
+    Ok(result?.into_response())
+}
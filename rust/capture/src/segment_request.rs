@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+
+use axum::http::header::AUTHORIZATION;
+use axum::http::HeaderMap;
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use time::format_description::well_known::Iso8601;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::api::CaptureError;
+use crate::token::validate_token;
+use crate::v0_request::RawEvent;
+
+/// Segment does not have group types, groups are created with this type
+/// unless the traits hold a `group_type` value.
+const DEFAULT_GROUP_TYPE: &str = "company";
+
+/// Segment context fields translated to PostHog properties, as (path, property) pairs.
+/// Properties set by the message itself take precedence.
+const CONTEXT_PROPERTIES: [(&[&str], &str); 13] = [
+    (&["ip"], "$ip"),
+    (&["userAgent"], "$raw_user_agent"),
+    (&["library", "name"], "$lib"),
+    (&["library", "version"], "$lib_version"),
+    (&["page", "url"], "$current_url"),
+    (&["page", "path"], "$pathname"),
+    (&["page", "referrer"], "$referrer"),
+    (&["os", "name"], "$os"),
+    (&["os", "version"], "$os_version"),
+    (&["app", "name"], "$app_name"),
+    (&["app", "version"], "$app_version"),
+    (&["screen", "width"], "$screen_width"),
+    (&["screen", "height"], "$screen_height"),
+];
+
+/// Segment API calls, each mapped to a `/v1/{type}` route.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SegmentType {
+    Track,
+    Identify,
+    Page,
+    Screen,
+    Group,
+    Alias,
+}
+
+/// One message sent by the `analytics-*` libraries, see the Segment spec.
+/// Unknown fields like `integrations` are ignored.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SegmentMessage {
+    #[serde(rename = "type")]
+    pub kind: Option<SegmentType>,
+    pub user_id: Option<Value>,
+    pub anonymous_id: Option<Value>,
+    pub message_id: Option<String>,
+    pub timestamp: Option<String>,
+    pub sent_at: Option<String>,
+    pub event: Option<String>,
+    pub name: Option<String>,
+    pub group_id: Option<Value>,
+    pub previous_id: Option<Value>,
+    #[serde(default)]
+    pub properties: HashMap<String, Value>,
+    #[serde(default)]
+    pub traits: HashMap<String, Value>,
+    #[serde(default)]
+    pub context: Map<String, Value>,
+}
+
+/// Payload of the `/v1/batch` route, messages carry their own type.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SegmentBatch {
+    pub batch: Vec<SegmentMessage>,
+    pub sent_at: Option<String>,
+    /// Shared by all messages, merged under their own context
+    #[serde(default)]
+    pub context: Map<String, Value>,
+}
+
+/// Reads the write key from the basic auth credentials, Segment libraries send it as
+/// the username with an empty password.
+pub fn extract_write_key(headers: &HeaderMap) -> Result<String, CaptureError> {
+    let credentials = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| {
+            base64::engine::general_purpose::STANDARD
+                .decode(v.trim())
+                .ok()
+        })
+        .and_then(|v| String::from_utf8(v).ok())
+        .ok_or(CaptureError::NoTokenError)?;
+
+    let write_key = match credentials.split_once(':') {
+        Some((username, _)) => username,
+        None => credentials.as_str(),
+    };
+    validate_token(write_key)?;
+    Ok(write_key.to_string())
+}
+
+pub fn parse_sent_at(value: &Option<String>) -> Option<OffsetDateTime> {
+    value
+        .as_ref()
+        .and_then(|value| OffsetDateTime::parse(value, &Iso8601::DEFAULT).ok())
+}
+
+impl SegmentBatch {
+    /// Translates the messages, failing the whole batch if one of them is invalid.
+    pub fn into_events(self) -> Result<Vec<RawEvent>, CaptureError> {
+        let context = self.context;
+        self.batch
+            .into_iter()
+            .map(|mut message| {
+                for (key, value) in &context {
+                    if !message.context.contains_key(key) {
+                        message.context.insert(key.clone(), value.clone());
+                    }
+                }
+                let kind = message.kind.ok_or_else(|| {
+                    CaptureError::RequestDecodingError(String::from("message without a type"))
+                })?;
+                message.into_event(kind)
+            })
+            .collect()
+    }
+}
+
+impl SegmentMessage {
+    /// Translates the message into a PostHog event. `kind` comes from the route for
+    /// single messages, the `type` field is ignored for them.
+    pub fn into_event(self, kind: SegmentType) -> Result<RawEvent, CaptureError> {
+        let user_id = self.user_id.filter(|v| !v.is_null());
+        let anonymous_id = self.anonymous_id.filter(|v| !v.is_null());
+
+        let mut properties = self.properties;
+        for (path, property) in CONTEXT_PROPERTIES {
+            if properties.contains_key(property) {
+                continue;
+            }
+            let mut value = self.context.get(path[0]);
+            for key in &path[1..] {
+                value = value.and_then(|v| v.get(key));
+            }
+            if let Some(value) = value.filter(|v| !v.is_null()) {
+                properties.insert(property.to_string(), value.clone());
+            }
+        }
+        if let (Some(_), Some(anonymous_id)) = (&user_id, &anonymous_id) {
+            properties.insert("$anon_distinct_id".to_string(), anonymous_id.clone());
+        }
+
+        let mut event = RawEvent {
+            distinct_id: user_id.or(anonymous_id),
+            // Segment message ids are free-form, we can only keep them if they are uuids
+            uuid: self
+                .message_id
+                .as_deref()
+                .and_then(|id| Uuid::parse_str(id).ok()),
+            timestamp: self.timestamp,
+            ..Default::default()
+        };
+
+        let mut traits = self.traits;
+        match kind {
+            SegmentType::Track => {
+                event.event = self.event.unwrap_or_default();
+            }
+            SegmentType::Identify => {
+                event.event = "$identify".to_string();
+                // Context traits are sent along every call by analytics.js, explicit ones win
+                if let Some(Value::Object(context_traits)) = self.context.get("traits") {
+                    for (key, value) in context_traits {
+                        traits.entry(key.clone()).or_insert_with(|| value.clone());
+                    }
+                }
+                event.set = Some(traits);
+            }
+            SegmentType::Page => {
+                event.event = "$pageview".to_string();
+                for (key, property) in [
+                    ("url", "$current_url"),
+                    ("path", "$pathname"),
+                    ("referrer", "$referrer"),
+                    ("title", "$title"),
+                ] {
+                    if let Some(value) = properties.get(key).cloned() {
+                        properties.insert(property.to_string(), value);
+                    }
+                }
+                if let Some(name) = self.name {
+                    properties
+                        .entry("$title".to_string())
+                        .or_insert(name.into());
+                }
+            }
+            SegmentType::Screen => {
+                event.event = "$screen".to_string();
+                if let Some(name) = self.name {
+                    properties.insert("$screen_name".to_string(), name.into());
+                }
+            }
+            SegmentType::Group => {
+                event.event = "$groupidentify".to_string();
+                let group_key = match self.group_id {
+                    Some(Value::String(id)) if !id.is_empty() => Value::String(id),
+                    Some(Value::Number(id)) => Value::String(id.to_string()),
+                    _ => {
+                        return Err(CaptureError::RequestDecodingError(String::from(
+                            "group call without a groupId",
+                        )))
+                    }
+                };
+                let group_type = match traits.remove("group_type") {
+                    Some(Value::String(group_type)) if !group_type.is_empty() => group_type,
+                    _ => DEFAULT_GROUP_TYPE.to_string(),
+                };
+                properties.insert("$group_type".to_string(), group_type.into());
+                properties.insert("$group_key".to_string(), group_key);
+                properties.insert(
+                    "$group_set".to_string(),
+                    Value::Object(traits.into_iter().collect()),
+                );
+            }
+            SegmentType::Alias => {
+                // Segment merges previousId into userId, which becomes the distinct_id
+                event.event = "$create_alias".to_string();
+                let alias = self.previous_id.filter(|v| !v.is_null()).ok_or_else(|| {
+                    CaptureError::RequestDecodingError(String::from(
+                        "alias call without a previousId",
+                    ))
+                })?;
+                properties.insert("alias".to_string(), alias);
+            }
+        }
+
+        event.properties = properties;
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::AUTHORIZATION;
+    use axum::http::{HeaderMap, HeaderValue};
+    use base64::Engine;
+    use serde_json::{json, Value};
+    use uuid::Uuid;
+
+    use crate::api::CaptureError;
+    use crate::segment_request::{extract_write_key, SegmentBatch, SegmentMessage, SegmentType};
+    use crate::token::InvalidTokenReason;
+
+    fn message(value: Value) -> SegmentMessage {
+        serde_json::from_value(value).expect("failed to parse message")
+    }
+
+    #[test]
+    fn translate_track() {
+        let uuid = Uuid::now_v7();
+        let event = message(json!({
+            "type": "identify",
+            "userId": "user1",
+            "anonymousId": "anon1",
+            "messageId": uuid.to_string(),
+            "timestamp": "2024-01-01T00:00:00Z",
+            "event": "Order Completed",
+            "properties": {"total": 10, "$lib": "custom"},
+            "context": {
+                "ip": "1.2.3.4",
+                "library": {"name": "analytics-node", "version": "1.0.0"},
+                "page": {"url": "https://example.com"}
+            }
+        }))
+        .into_event(SegmentType::Track)
+        .expect("failed to translate");
+
+        // The route decides the type for single messages
+        assert_eq!("Order Completed", event.event);
+        assert_eq!(Some(json!("user1")), event.distinct_id);
+        assert_eq!(Some(uuid), event.uuid);
+        assert_eq!(Some("2024-01-01T00:00:00Z".to_string()), event.timestamp);
+        assert_eq!(Some(&json!(10)), event.properties.get("total"));
+        assert_eq!(
+            Some(&json!("anon1")),
+            event.properties.get("$anon_distinct_id")
+        );
+        assert_eq!(Some(&json!("1.2.3.4")), event.properties.get("$ip"));
+        assert_eq!(Some(&json!("custom")), event.properties.get("$lib"));
+        assert_eq!(Some(&json!("1.0.0")), event.properties.get("$lib_version"));
+        assert_eq!(
+            Some(&json!("https://example.com")),
+            event.properties.get("$current_url")
+        );
+        assert!(event.set.is_none());
+    }
+
+    #[test]
+    fn translate_identify_page_and_screen() {
+        let event = message(json!({
+            "anonymousId": "anon1",
+            "messageId": "ajs-next-123",
+            "traits": {"email": "a@example.com"},
+            "context": {"traits": {"email": "old@example.com", "plan": "free"}}
+        }))
+        .into_event(SegmentType::Identify)
+        .expect("failed to translate");
+        assert_eq!("$identify", event.event);
+        assert_eq!(Some(json!("anon1")), event.distinct_id);
+        assert!(event.uuid.is_none());
+        assert!(!event.properties.contains_key("$anon_distinct_id"));
+        let set = event.set.expect("missing $set");
+        assert_eq!(Some(&json!("a@example.com")), set.get("email"));
+        assert_eq!(Some(&json!("free")), set.get("plan"));
+
+        let event = message(json!({
+            "userId": "user1",
+            "name": "Pricing",
+            "properties": {"url": "https://example.com/pricing", "path": "/pricing"}
+        }))
+        .into_event(SegmentType::Page)
+        .expect("failed to translate");
+        assert_eq!("$pageview", event.event);
+        assert_eq!(
+            Some(&json!("https://example.com/pricing")),
+            event.properties.get("$current_url")
+        );
+        assert_eq!(Some(&json!("/pricing")), event.properties.get("$pathname"));
+        assert_eq!(Some(&json!("Pricing")), event.properties.get("$title"));
+
+        let event = message(json!({"userId": "user1", "name": "Home"}))
+            .into_event(SegmentType::Screen)
+            .expect("failed to translate");
+        assert_eq!("$screen", event.event);
+        assert_eq!(Some(&json!("Home")), event.properties.get("$screen_name"));
+    }
+
+    #[test]
+    fn translate_group_and_alias() {
+        let event = message(json!({
+            "userId": "user1",
+            "groupId": 42,
+            "traits": {"name": "Acme", "group_type": "organization"}
+        }))
+        .into_event(SegmentType::Group)
+        .expect("failed to translate");
+        assert_eq!("$groupidentify", event.event);
+        assert_eq!(
+            Some(&json!("organization")),
+            event.properties.get("$group_type")
+        );
+        assert_eq!(Some(&json!("42")), event.properties.get("$group_key"));
+        assert_eq!(
+            Some(&json!({"name": "Acme"})),
+            event.properties.get("$group_set")
+        );
+
+        let event =
+            message(json!({"userId": "user1", "traits": {}})).into_event(SegmentType::Group);
+        assert!(matches!(event, Err(CaptureError::RequestDecodingError(_))));
+
+        let event = message(json!({"userId": "user1", "previousId": "anon1"}))
+            .into_event(SegmentType::Alias)
+            .expect("failed to translate");
+        assert_eq!("$create_alias", event.event);
+        assert_eq!(Some(json!("user1")), event.distinct_id);
+        assert_eq!(Some(&json!("anon1")), event.properties.get("alias"));
+    }
+
+    #[test]
+    fn translate_batch() {
+        let batch: SegmentBatch = serde_json::from_value(json!({
+            "batch": [
+                {"type": "track", "event": "e1", "userId": "user1"},
+                {"type": "page", "userId": "user1", "context": {"ip": "5.6.7.8"}},
+            ],
+            "context": {"ip": "1.2.3.4"}
+        }))
+        .expect("failed to parse batch");
+        let events = batch.into_events().expect("failed to translate");
+        assert_eq!("e1", events[0].event);
+        assert_eq!(Some(&json!("1.2.3.4")), events[0].properties.get("$ip"));
+        assert_eq!("$pageview", events[1].event);
+        assert_eq!(Some(&json!("5.6.7.8")), events[1].properties.get("$ip"));
+
+        let batch: SegmentBatch =
+            serde_json::from_value(json!({"batch": [{"event": "e1", "userId": "user1"}]}))
+                .expect("failed to parse batch");
+        assert!(matches!(
+            batch.into_events(),
+            Err(CaptureError::RequestDecodingError(_))
+        ));
+    }
+
+    #[test]
+    fn extract_basic_auth_write_key() {
+        let with_auth = |credentials: &str| {
+            let mut headers = HeaderMap::new();
+            let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Basic {encoded}")).unwrap(),
+            );
+            headers
+        };
+
+        assert_eq!(
+            "phc_key",
+            extract_write_key(&with_auth("phc_key:")).expect("failed to extract")
+        );
+        assert_eq!(
+            "phc_key",
+            extract_write_key(&with_auth("phc_key")).expect("failed to extract")
+        );
+        assert!(matches!(
+            extract_write_key(&HeaderMap::new()),
+            Err(CaptureError::NoTokenError)
+        ));
+        assert!(matches!(
+            extract_write_key(&with_auth(":")),
+            Err(CaptureError::TokenValidationError(
+                InvalidTokenReason::Empty
+            ))
+        ));
+        assert!(matches!(
+            extract_write_key(&with_auth("phx_personal:")),
+            Err(CaptureError::TokenValidationError(
+                InvalidTokenReason::PersonalApiKey
+            ))
+        ));
+    }
+}
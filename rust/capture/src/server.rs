@@ -1,32 +1,48 @@
-use std::future::Future;
+use std::future::{Future, IntoFuture};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration as StdDuration;
 
+use futures::future::join_all;
+use governor::Quota;
 use health::{ComponentStatus, HealthRegistry};
 use time::Duration;
 use tokio::net::TcpListener;
 
+use crate::bots::BotFilter;
 use crate::config::CaptureMode;
-use crate::config::Config;
+use crate::config::{Config, DedupBackend, KafkaConfig, TeeDestination};
+use crate::debug_capture::DebugCapture;
+use crate::dedup::{Deduplicator, MemoryDeduplicator, RedisDeduplicator};
 
+use crate::limiters::billing::BillingLimiter;
 use crate::limiters::overflow::OverflowLimiter;
-use crate::limiters::redis::{
-    QuotaResource, RedisLimiter, OVERFLOW_LIMITER_CACHE_KEY, QUOTA_LIMITER_CACHE_KEY,
-};
+use crate::limiters::rate::TokenRateLimiter;
+use crate::limiters::redis::{QuotaResource, RedisLimiter, OVERFLOW_LIMITER_CACHE_KEY};
+use crate::limiters::shedding::LoadShedding;
+use crate::privacy::PrivacyFilter;
 use crate::redis::RedisClient;
 use crate::router;
 use crate::router::BATCH_BODY_SIZE;
+use crate::routing::RoutingTable;
+use crate::sinks::file::FileSink;
 use crate::sinks::kafka::KafkaSink;
 use crate::sinks::print::PrintSink;
+use crate::sinks::spool::{SpoolSink, SPOOL_SEGMENT_BYTES};
+use crate::sinks::tee::TeeSink;
+use crate::sinks::Event;
 
 pub async fn serve<F>(config: Config, listener: TcpListener, shutdown: F)
 where
     F: Future<Output = ()> + Send + 'static,
 {
     let liveness = HealthRegistry::new("liveness");
+    let readiness = Arc::new(AtomicBool::new(true));
 
-    let redis_client =
-        Arc::new(RedisClient::new(config.redis_url).expect("failed to create redis client"));
+    let redis_client = Arc::new(
+        RedisClient::new(config.redis_url.clone()).expect("failed to create redis client"),
+    );
 
     let replay_overflow_limiter = match config.capture_mode {
         CaptureMode::Recordings => Some(
@@ -42,27 +58,130 @@ where
         _ => None,
     };
 
-    let billing_limiter = RedisLimiter::new(
+    let rate_limiter = match config.rate_limit_enabled {
+        false => None,
+        true => {
+            let limiter = TokenRateLimiter::new(
+                Duration::seconds(5),
+                redis_client.clone(),
+                config.redis_key_prefix.clone(),
+                Quota::per_second(config.rate_limit_per_token_per_second)
+                    .allow_burst(config.rate_limit_per_token_burst),
+                config
+                    .rate_limit_per_distinct_id_per_second
+                    .map(|per_second| {
+                        Quota::per_second(per_second)
+                            .allow_burst(config.rate_limit_per_distinct_id_burst)
+                    }),
+                config.rate_limit_global_per_second.map(|per_second| {
+                    Quota::per_second(per_second).allow_burst(config.rate_limit_global_burst)
+                }),
+            )
+            .expect("failed to create rate limiter");
+            if config.export_prometheus {
+                let limiter = limiter.clone();
+                tokio::spawn(async move {
+                    limiter.report_metrics().await;
+                });
+            }
+            {
+                // Ensure that the rate limiter state does not grow unbounded
+                let limiter = limiter.clone();
+                tokio::spawn(async move {
+                    limiter.clean_state().await;
+                });
+            }
+            Some(limiter)
+        }
+    };
+
+    let deduplicator: Option<Arc<dyn Deduplicator + Send + Sync>> = match config.dedup_backend {
+        None => None,
+        Some(DedupBackend::Memory) => Some(Arc::new(MemoryDeduplicator::new(
+            config.dedup_window_secs,
+            config.dedup_memory_max_entries,
+        ))),
+        Some(DedupBackend::Redis) => Some(Arc::new(RedisDeduplicator::new(
+            redis_client.clone(),
+            config.dedup_window_secs,
+            config.redis_key_prefix.clone(),
+        ))),
+    };
+
+    let privacy = match config.privacy_enabled {
+        false => None,
+        true => {
+            let privacy = PrivacyFilter::default();
+            privacy.load_from_redis(
+                Duration::seconds(5),
+                redis_client.clone(),
+                config.redis_key_prefix.clone(),
+            );
+            Some(privacy)
+        }
+    };
+
+    let bot_filter = match config.bot_filtering_enabled {
+        false => None,
+        true => {
+            let bot_filter = BotFilter::new(&config.bot_ip_ranges).expect("invalid bot IP ranges");
+            bot_filter.load_from_redis(
+                Duration::seconds(5),
+                redis_client.clone(),
+                config.redis_key_prefix.clone(),
+            );
+            Some(bot_filter)
+        }
+    };
+
+    let load_shedding = match config.load_shedding_enabled {
+        false => None,
+        true => Some(
+            LoadShedding::new(
+                config.load_shedding_low_priority_watermark,
+                config.load_shedding_reject_watermark,
+            )
+            .expect("invalid load shedding watermarks"),
+        ),
+    };
+
+    // Debugging tools must not impact capture, don't fail startup
+    let debug_capture = match &config.debug_capture_destination {
+        None => None,
+        Some(destination) => match DebugCapture::new(destination, &config).await {
+            Ok(debug_capture) => {
+                debug_capture.load_from_redis(
+                    Duration::seconds(5),
+                    redis_client.clone(),
+                    config.redis_key_prefix.clone(),
+                );
+                Some(debug_capture)
+            }
+            Err(e) => {
+                tracing::error!("failed to start debug capture, not recording: {:?}", e);
+                None
+            }
+        },
+    };
+
+    let billing_limiter = BillingLimiter::new(
+        config.capture_mode.clone(),
         Duration::seconds(5),
         redis_client.clone(),
-        QUOTA_LIMITER_CACHE_KEY.to_string(),
-        config.redis_key_prefix,
-        match config.capture_mode {
-            CaptureMode::Events => QuotaResource::Events,
-            CaptureMode::Recordings => QuotaResource::Recordings,
-        },
+        config.redis_key_prefix.clone(),
     )
     .expect("failed to create billing limiter");
 
-    // In Recordings capture mode, we unpack a batch of events, and then pack them back up into
-    // a big blob and send to kafka all at once - so we should abort unpacking a batch if the data
-    // size crosses the kafka limit. In the Events mode, we can unpack the batch and send each
-    // event individually, so we should instead allow for some small multiple of our max compressed
-    // body size to be unpacked. If a single event is still too big, we'll drop it at kafka send time.
-    let event_max_bytes = match config.capture_mode {
-        CaptureMode::Events => BATCH_BODY_SIZE * 5, // To allow for some compression ratio, but still have a limit of 100MB.
-        CaptureMode::Recordings => config.kafka.kafka_producer_message_max_bytes as usize,
-    };
+    // We allow for some small multiple of our max compressed body size to be unpacked. In the Events
+    // mode, we send each event individually. In Recordings capture mode, we unpack a batch of events,
+    // and then pack them back up into blobs split to fit the kafka limit. If a single event is
+    // still too big, we'll drop it at kafka send time.
+    let event_max_bytes = BATCH_BODY_SIZE * 5; // To allow for some compression ratio, but still have a limit of 100MB.
+    let replay_message_max_bytes = config.kafka.kafka_producer_message_max_bytes as usize;
+
+    // Kept to flush the producers on shutdown, once the handlers are done
+    let mut producers: Vec<(&'static str, KafkaSink)> = Vec::new();
+    let debug_capture_flush = debug_capture.clone();
 
     let app = if config.print_sink {
         // Print sink is only used for local debug, don't allow a container with it to run on prod
@@ -75,13 +194,22 @@ where
         router::router(
             crate::time::SystemTime {},
             liveness,
+            readiness.clone(),
             PrintSink {},
             redis_client,
             billing_limiter,
+            rate_limiter,
+            deduplicator,
+            privacy,
+            bot_filter,
+            load_shedding,
+            debug_capture,
+            config.import_events_per_second,
             config.export_prometheus,
             config.capture_mode,
             config.concurrency_limit,
             event_max_bytes,
+            replay_message_max_bytes,
         )
     } else {
         let sink_liveness = liveness
@@ -91,11 +219,19 @@ where
         let partition = match config.overflow_enabled {
             false => None,
             true => {
-                let partition = OverflowLimiter::new(
+                let mut partition = OverflowLimiter::new(
                     config.overflow_per_second_limit,
                     config.overflow_burst_limit,
-                    config.overflow_forced_keys,
+                    config.overflow_forced_keys.clone(),
                 );
+                if config.overflow_redis_enabled {
+                    partition =
+                        partition.with_redis(redis_client.clone(), config.redis_key_prefix.clone());
+                    let partition = partition.clone();
+                    tokio::spawn(async move {
+                        partition.sync_shared_counts().await;
+                    });
+                }
                 if config.export_prometheus {
                     let partition = partition.clone();
                     tokio::spawn(async move {
@@ -112,35 +248,176 @@ where
                 Some(partition)
             }
         };
+        // Secondary sink failures must not impact the primary, don't fail startup either
+        let tee_secondary = match &config.tee_destination {
+            None => None,
+            Some(destination) => match tee_secondary(destination, &config, &mut producers).await {
+                Ok(secondary) => Some(secondary),
+                Err(e) => {
+                    tracing::error!("failed to start tee sink, not mirroring events: {:?}", e);
+                    None
+                }
+            },
+        };
+
+        let routing = match config.routing_enabled {
+            false => None,
+            true => {
+                let routing = RoutingTable::new(config.routing_rules.as_deref())
+                    .expect("invalid routing rules");
+                routing.load_from_redis(
+                    Duration::seconds(5),
+                    redis_client.clone(),
+                    config.redis_key_prefix.clone(),
+                );
+                Some(routing)
+            }
+        };
+
         let sink = KafkaSink::new(
             config.kafka,
             sink_liveness,
             partition,
             replay_overflow_limiter,
+            routing,
+            load_shedding,
         )
         .expect("failed to start Kafka sink");
+        producers.push(("main", sink.clone()));
+
+        let sink: Box<dyn Event + Send + Sync> = match config.spool_enabled {
+            false => Box::new(sink),
+            true => {
+                let spool_liveness = liveness
+                    .register("spool".to_string(), Duration::seconds(30))
+                    .await;
+                Box::new(
+                    SpoolSink::new(
+                        sink,
+                        spool_liveness,
+                        &config.spool_path,
+                        config.spool_max_mib * 1024 * 1024,
+                        SPOOL_SEGMENT_BYTES,
+                    )
+                    .expect("failed to open spool"),
+                )
+            }
+        };
+
+        let sink: Box<dyn Event + Send + Sync> = match tee_secondary {
+            None => sink,
+            Some(secondary) => Box::new(TeeSink::new(
+                sink,
+                secondary,
+                config.tee_sample_rate,
+                config.tee_tokens,
+            )),
+        };
 
         router::router(
             crate::time::SystemTime {},
             liveness,
+            readiness.clone(),
             sink,
             redis_client,
             billing_limiter,
+            rate_limiter,
+            deduplicator,
+            privacy,
+            bot_filter,
+            load_shedding,
+            debug_capture,
+            config.import_events_per_second,
             config.export_prometheus,
             config.capture_mode,
             config.concurrency_limit,
             event_max_bytes,
+            replay_message_max_bytes,
         )
     };
 
+    // On shutdown, report not-ready and keep serving for a while, so that load balancers
+    // stop sending us traffic before we stop accepting connections
+    let (stopping_tx, stopping_rx) = tokio::sync::oneshot::channel();
+    let drain_delay = StdDuration::from_secs(config.shutdown_drain_delay_secs);
+    let shutdown = {
+        let readiness = readiness.clone();
+        async move {
+            shutdown.await;
+            readiness.store(false, Ordering::Relaxed);
+            tracing::info!("not ready, draining traffic for {:?}", drain_delay);
+            tokio::time::sleep(drain_delay).await;
+            tracing::info!("closing listener, waiting for in-flight requests");
+            _ = stopping_tx.send(());
+        }
+    };
+
     // run our app with hyper
     // `axum::Server` is a re-export of `hyper::Server`
     tracing::info!("listening on {:?}", listener.local_addr().unwrap());
-    axum::serve(
+    let server = axum::serve(
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
     .with_graceful_shutdown(shutdown)
-    .await
-    .unwrap()
+    .into_future();
+
+    let requests_timeout = StdDuration::from_secs(config.shutdown_requests_timeout_secs);
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = async {
+            match stopping_rx.await {
+                Ok(()) => tokio::time::sleep(requests_timeout).await,
+                Err(_) => std::future::pending::<()>().await,
+            }
+        } => tracing::warn!("in-flight requests did not complete in {:?}", requests_timeout),
+    }
+
+    // Deliver the messages still queued in the producers, they might have been acked to clients.
+    // Producers are flushed concurrently, to all fit in the flush timeout.
+    let timeout = StdDuration::from_secs(config.shutdown_flush_timeout_secs);
+    let flush_producers = join_all(producers.into_iter().map(|(name, producer)| async move {
+        match tokio::task::spawn_blocking(move || producer.flush(timeout)).await {
+            Ok(Ok(())) => tracing::info!("flushed {} Kafka producer", name),
+            Ok(Err(e)) => tracing::error!("failed to flush {} Kafka producer: {:?}", name, e),
+            Err(e) => tracing::error!("failed to flush {} Kafka producer: {:?}", name, e),
+        }
+    }));
+    let flush_debug_capture = async {
+        if let Some(debug_capture) = debug_capture_flush {
+            if let Err(e) = debug_capture.flush(timeout).await {
+                tracing::error!("failed to flush debug capture: {:?}", e);
+            }
+        }
+    };
+    tokio::join!(flush_producers, flush_debug_capture);
+}
+
+/// Starts the secondary sink of the tee, adding its Kafka producer to the ones to flush.
+async fn tee_secondary(
+    destination: &TeeDestination,
+    config: &Config,
+    producers: &mut Vec<(&'static str, KafkaSink)>,
+) -> anyhow::Result<Box<dyn Event + Send + Sync>> {
+    Ok(match destination {
+        TeeDestination::Print => Box::new(PrintSink {}),
+        TeeDestination::File => Box::new(FileSink::new(&config.tee_file_path).await?),
+        TeeDestination::Kafka => {
+            let kafka_hosts = config
+                .tee_kafka_hosts
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("TEE_KAFKA_HOSTS must be set"))?;
+            let kafka_config = KafkaConfig {
+                kafka_hosts,
+                ..config.kafka.clone()
+            };
+            // Not registered in the liveness registry, to not restart capture on secondary issues
+            let liveness = HealthRegistry::new("tee")
+                .register("rdkafka".to_string(), Duration::seconds(30))
+                .await;
+            let sink = KafkaSink::new(kafka_config, liveness, None, None, None, None)?;
+            producers.push(("tee", sink.clone()));
+            Box::new(sink)
+        }
+    })
 }
@@ -0,0 +1,62 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use metrics::counter;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::log::error;
+
+use crate::api::CaptureError;
+use crate::sinks::Event;
+use crate::v0_request::ProcessedEvent;
+
+/// Appends events to a local file, one JSON-encoded event per line.
+/// Meant for debugging and mirroring, this sink does not rotate the file.
+pub struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub async fn new(path: impl AsRef<Path>) -> anyhow::Result<FileSink> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(FileSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+#[async_trait]
+impl Event for FileSink {
+    async fn send(&self, event: ProcessedEvent) -> Result<(), CaptureError> {
+        self.send_batch(vec![event]).await
+    }
+
+    async fn send_batch(&self, events: Vec<ProcessedEvent>) -> Result<(), CaptureError> {
+        let mut buffer = Vec::new();
+        for event in &events {
+            serde_json::to_writer(&mut buffer, event).map_err(|e| {
+                error!("failed to serialize event: {}", e);
+                CaptureError::NonRetryableSinkError
+            })?;
+            buffer.push(b'\n');
+        }
+
+        self.file
+            .lock()
+            .await
+            .write_all(&buffer)
+            .await
+            .map_err(|e| {
+                error!("failed to write events to file: {}", e);
+                CaptureError::RetryableSinkError
+            })?;
+
+        counter!("capture_file_sink_events_written_total").increment(events.len() as u64);
+        Ok(())
+    }
+}
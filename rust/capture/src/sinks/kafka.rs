@@ -9,6 +9,8 @@ use rdkafka::message::{Header, OwnedHeaders};
 use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord, Producer};
 use rdkafka::util::Timeout;
 use rdkafka::ClientConfig;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::task::JoinSet;
 use tracing::log::{debug, error, info};
@@ -17,17 +19,25 @@ use tracing::{info_span, instrument, Instrument};
 use crate::api::CaptureError;
 use crate::config::KafkaConfig;
 use crate::limiters::overflow::OverflowLimiter;
+use crate::limiters::shedding::LoadShedding;
 use crate::prometheus::report_dropped_events;
+use crate::routing::{PartitionKeyStrategy, RoutingTable};
 use crate::sinks::Event;
 
+/// Default of the `queue.buffering.max.messages` producer setting, until we get the stats
+const DEFAULT_QUEUE_MAX_MESSAGES: u64 = 100_000;
+
 struct KafkaContext {
     liveness: HealthHandle,
+    queue_max_messages: Arc<AtomicU64>, // Shared with the sink, the producer hides its context
 }
 
 impl rdkafka::ClientContext for KafkaContext {
     fn stats(&self, stats: rdkafka::Statistics) {
         // Signal liveness, as the main rdkafka loop is running and calling us
         self.liveness.report_healthy_blocking();
+        self.queue_max_messages
+            .store(stats.msg_max, Ordering::Relaxed);
 
         // Update exported metrics
         gauge!("capture_kafka_callback_queue_depth",).set(stats.replyq as f64);
@@ -110,6 +120,7 @@ impl rdkafka::ClientContext for KafkaContext {
 #[derive(Clone)]
 pub struct KafkaSink {
     producer: FutureProducer<KafkaContext>,
+    queue_max_messages: Arc<AtomicU64>,
     partition: Option<OverflowLimiter>,
     main_topic: String,
     historical_topic: String,
@@ -118,6 +129,8 @@ pub struct KafkaSink {
     heatmaps_topic: String,
     replay_overflow_limiter: Option<RedisLimiter>,
     replay_overflow_topic: String,
+    routing: Option<RoutingTable>,
+    shedding: Option<LoadShedding>,
 }
 
 impl KafkaSink {
@@ -126,6 +139,8 @@ impl KafkaSink {
         liveness: HealthHandle,
         partition: Option<OverflowLimiter>,
         replay_overflow_limiter: Option<RedisLimiter>,
+        routing: Option<RoutingTable>,
+        shedding: Option<LoadShedding>,
     ) -> anyhow::Result<KafkaSink> {
         info!("connecting to Kafka brokers at {}...", config.kafka_hosts);
 
@@ -168,8 +183,12 @@ impl KafkaSink {
         };
 
         debug!("rdkafka configuration: {:?}", client_config);
+        let queue_max_messages = Arc::new(AtomicU64::new(DEFAULT_QUEUE_MAX_MESSAGES));
         let producer: FutureProducer<KafkaContext> =
-            client_config.create_with_context(KafkaContext { liveness })?;
+            client_config.create_with_context(KafkaContext {
+                liveness,
+                queue_max_messages: queue_max_messages.clone(),
+            })?;
 
         // Ping the cluster to make sure we can reach brokers, fail after 10 seconds
         drop(producer.client().fetch_metadata(
@@ -180,6 +199,7 @@ impl KafkaSink {
 
         Ok(KafkaSink {
             producer,
+            queue_max_messages,
             partition,
             main_topic: config.kafka_topic,
             historical_topic: config.kafka_historical_topic,
@@ -188,15 +208,26 @@ impl KafkaSink {
             heatmaps_topic: config.kafka_heatmaps_topic,
             replay_overflow_topic: config.kafka_replay_overflow_topic,
             replay_overflow_limiter,
+            routing,
+            shedding,
         })
     }
 
-    pub fn flush(&self) -> Result<(), KafkaError> {
-        // TODO: hook it up on shutdown
-        self.producer.flush(Duration::new(30, 0))
+    /// Blocks until the queued messages are delivered, or the timeout is reached.
+    pub fn flush(&self, timeout: Duration) -> Result<(), KafkaError> {
+        info!(
+            "flushing {} queued messages to Kafka",
+            self.producer.in_flight_count()
+        );
+        self.producer.flush(timeout)
     }
 
-    async fn kafka_send(&self, event: ProcessedEvent) -> Result<DeliveryFuture, CaptureError> {
+    /// Enqueues the event in the producer. Returns None if it was shed to keep the queue for
+    /// live traffic: shed events are dropped, and the rest of their batch goes through.
+    async fn kafka_send(
+        &self,
+        event: ProcessedEvent,
+    ) -> Result<Option<DeliveryFuture>, CaptureError> {
         let (event, metadata) = (event.event, event.metadata);
 
         let payload = serde_json::to_string(&event).map_err(|e| {
@@ -208,9 +239,12 @@ impl KafkaSink {
         let data_type = metadata.data_type;
         let event_key = event.key();
         let session_id = metadata.session_id.clone();
+        let event_name = metadata.event_name;
 
         drop(event); // Events can be EXTREMELY memory hungry
 
+        // Historical and overflow traffic can wait, they are shed first under queue pressure
+        let mut low_priority = data_type == DataType::AnalyticsHistorical;
         let (topic, partition_key): (&str, Option<&str>) = match data_type {
             DataType::AnalyticsHistorical => (&self.historical_topic, Some(event_key.as_str())), // We never trigger overflow on historical events
             DataType::AnalyticsMain => {
@@ -220,6 +254,7 @@ impl KafkaSink {
                     Some(partition) => partition.is_limited(&event_key),
                 };
                 if is_limited {
+                    low_priority = true;
                     (&self.main_topic, None) // Analytics overflow goes to the main topic without locality
                 } else {
                     (&self.main_topic, Some(event_key.as_str()))
@@ -241,6 +276,7 @@ impl KafkaSink {
                 };
 
                 if is_overflowing {
+                    low_priority = true;
                     (&self.replay_overflow_topic, Some(session_id))
                 } else {
                     (&self.main_topic, Some(session_id))
@@ -248,6 +284,36 @@ impl KafkaSink {
             }
         };
 
+        if low_priority
+            && self
+                .shedding
+                .is_some_and(|shedding| shedding.shed_low_priority(self.queue_usage()))
+        {
+            report_dropped_events("load_shedding", 1);
+            return Ok(None);
+        }
+
+        // Routing rules only override the topic of live analytics events
+        let route = match (&self.routing, data_type) {
+            (Some(routing), DataType::AnalyticsMain) => routing.route(&token, &event_name).await,
+            _ => None,
+        };
+        let (topic, partition_key) = match &route {
+            None => (topic, partition_key),
+            Some((routed_topic, strategy)) => {
+                counter!("capture_events_routed_total", "topic" => routed_topic.clone())
+                    .increment(1);
+                match strategy {
+                    PartitionKeyStrategy::Default => (routed_topic.as_str(), partition_key),
+                    // Overflowing events stay unkeyed, to keep spreading their load
+                    PartitionKeyStrategy::Token => {
+                        (routed_topic.as_str(), partition_key.map(|_| token.as_str()))
+                    }
+                    PartitionKeyStrategy::Random => (routed_topic.as_str(), None),
+                }
+            }
+        };
+
         match self.producer.send_result(FutureRecord {
             topic,
             payload: Some(&payload),
@@ -259,7 +325,7 @@ impl KafkaSink {
                 value: Some(&token),
             })),
         }) {
-            Ok(ack) => Ok(ack),
+            Ok(ack) => Ok(Some(ack)),
             Err((e, _)) => match e.rdkafka_error_code() {
                 Some(RDKafkaErrorCode::MessageSizeTooLarge) => {
                     report_dropped_events("kafka_message_size", 1);
@@ -306,7 +372,11 @@ impl KafkaSink {
 impl Event for KafkaSink {
     #[instrument(skip_all)]
     async fn send(&self, event: ProcessedEvent) -> Result<(), CaptureError> {
-        let ack = self.kafka_send(event).await?;
+        // Single events are sent by endpoints reporting per-event results, fail shed events
+        // for the client to retry them, as batches only drop them
+        let Some(ack) = self.kafka_send(event).await? else {
+            return Err(CaptureError::RetryableSinkError);
+        };
         histogram!("capture_event_batch_size").record(1.0);
         Self::process_ack(ack)
             .instrument(info_span!("ack_wait_one"))
@@ -319,7 +389,9 @@ impl Event for KafkaSink {
         let batch_size = events.len();
         for event in events {
             // We await kafka_send to get events in the producer queue sequentially
-            let ack = self.kafka_send(event).await?;
+            let Some(ack) = self.kafka_send(event).await? else {
+                continue;
+            };
 
             // Then stash the returned DeliveryFuture, waiting concurrently for the write ACKs from brokers.
             set.spawn(Self::process_ack(ack));
@@ -349,6 +421,12 @@ impl Event for KafkaSink {
         histogram!("capture_event_batch_size").record(batch_size as f64);
         Ok(())
     }
+
+    fn queue_usage(&self) -> Option<f64> {
+        // The queue limit comes from the stats, but the stats are too old for the depth
+        let max = self.queue_max_messages.load(Ordering::Relaxed);
+        Some(self.producer.in_flight_count() as f64 / max.max(1) as f64)
+    }
 }
 
 #[cfg(test)]
@@ -356,7 +434,9 @@ mod tests {
     use crate::api::CaptureError;
     use crate::config;
     use crate::limiters::overflow::OverflowLimiter;
+    use crate::limiters::shedding::LoadShedding;
     use crate::sinks::kafka::KafkaSink;
+    use crate::sinks::test_utils::event;
     use crate::sinks::Event;
     use crate::utils::uuid_v7;
     use crate::v0_request::{DataType, ProcessedEvent, ProcessedEventMetadata};
@@ -364,14 +444,19 @@ mod tests {
     use health::HealthRegistry;
     use rand::distributions::Alphanumeric;
     use rand::Rng;
+    use rdkafka::consumer::{BaseConsumer, Consumer};
     use rdkafka::mocking::MockCluster;
     use rdkafka::producer::DefaultProducerContext;
     use rdkafka::types::{RDKafkaApiKey, RDKafkaRespErr};
+    use rdkafka::{ClientConfig, Message};
     use std::num::NonZeroU32;
+    use std::time::{Duration as StdDuration, Instant};
     use time::Duration;
+    use uuid::Uuid;
 
     async fn start_on_mocked_sink(
         message_max_bytes: Option<u32>,
+        shedding: Option<LoadShedding>,
     ) -> (MockCluster<'static, DefaultProducerContext>, KafkaSink) {
         let registry = HealthRegistry::new("liveness");
         let handle = registry
@@ -401,7 +486,8 @@ mod tests {
             kafka_metadata_max_age_ms: 60000,
             kafka_producer_max_retries: 2,
         };
-        let sink = KafkaSink::new(config, handle, limiter, None).expect("failed to create sink");
+        let sink = KafkaSink::new(config, handle, limiter, None, None, shedding)
+            .expect("failed to create sink");
         (cluster, sink)
     }
 
@@ -410,7 +496,7 @@ mod tests {
         // Uses a mocked Kafka broker that allows injecting write errors, to check error handling.
         // We test different cases in a single test to amortize the startup cost of the producer.
 
-        let (cluster, sink) = start_on_mocked_sink(Some(3000000)).await;
+        let (cluster, sink) = start_on_mocked_sink(Some(3000000), None).await;
         let event: CapturedEvent = CapturedEvent {
             uuid: uuid_v7(),
             distinct_id: "id1".to_string(),
@@ -424,6 +510,7 @@ mod tests {
         let metadata = ProcessedEventMetadata {
             data_type: DataType::AnalyticsMain,
             session_id: None,
+            event_name: "event".to_string(),
         };
 
         let event = ProcessedEvent {
@@ -544,4 +631,80 @@ mod tests {
             Ok(()) => panic!("should have errored"),
         };
     }
+
+    #[tokio::test]
+    async fn kafka_sink_sheds_low_priority_events() {
+        // Shed low priority events as soon as one message is in flight
+        let shedding = LoadShedding::new(f64::MIN_POSITIVE, 1.0).unwrap();
+        let (cluster, sink) = start_on_mocked_sink(None, Some(shedding)).await;
+        let live = event("token1");
+        let mut historical = event("token1");
+        historical.metadata.data_type = DataType::AnalyticsHistorical;
+
+        // Wait for producer to be healthy, to keep kafka_message_timeout_ms short and tests faster
+        for _ in 0..20 {
+            if sink.send(live.clone()).await.is_ok() {
+                break;
+            }
+        }
+
+        // Keep the first message in flight for a retry, the historical event after it is shed
+        // and the rest of the batch goes through
+        let (first, last) = (event("token1"), event("token1"));
+        let err = [RDKafkaRespErr::RD_KAFKA_RESP_ERR_BROKER_NOT_AVAILABLE; 1];
+        cluster.request_errors(RDKafkaApiKey::Produce, &err);
+        sink.send_batch(vec![first.clone(), historical.clone(), last.clone()])
+            .await
+            .expect("failed to send batch with shed events");
+
+        // Single events are failed instead, for per-event endpoints to report them
+        cluster.request_errors(RDKafkaApiKey::Produce, &err);
+        let (live_result, historical_result) =
+            tokio::join!(sink.send(live.clone()), sink.send(historical.clone()));
+        live_result.expect("failed to send live event");
+        match historical_result {
+            Err(CaptureError::RetryableSinkError) => {}
+            result => panic!("unexpected result for a shed event: {:?}", result),
+        }
+
+        let produced = produced_uuids(&cluster, &[first.event.uuid, last.event.uuid]);
+        assert!(produced.contains(&first.event.uuid));
+        assert!(produced.contains(&last.event.uuid));
+        assert!(!produced.contains(&historical.event.uuid));
+    }
+
+    /// Consumes the events produced to the mocked topics, until the expected ones are found.
+    fn produced_uuids(
+        cluster: &MockCluster<'static, DefaultProducerContext>,
+        expected: &[Uuid],
+    ) -> Vec<Uuid> {
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .set("group.id", "kafka_sink_tests")
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .expect("failed to create consumer");
+        consumer
+            .subscribe(&[
+                "events_plugin_ingestion",
+                "events_plugin_ingestion_historical",
+            ])
+            .expect("failed to subscribe");
+
+        // Keep polling for a bit after finding the expected events, to catch unexpected ones
+        let mut uuids = vec![];
+        let mut deadline = Instant::now() + StdDuration::from_secs(10);
+        while Instant::now() < deadline {
+            if let Some(Ok(message)) = consumer.poll(StdDuration::from_millis(100)) {
+                let event: CapturedEvent =
+                    serde_json::from_slice(message.payload().expect("empty payload"))
+                        .expect("invalid payload");
+                uuids.push(event.uuid);
+                if expected.iter().all(|uuid| uuids.contains(uuid)) {
+                    deadline = deadline.min(Instant::now() + StdDuration::from_secs(1));
+                }
+            }
+        }
+        uuids
+    }
 }
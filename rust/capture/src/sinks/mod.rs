@@ -2,11 +2,35 @@ use async_trait::async_trait;
 
 use crate::{api::CaptureError, v0_request::ProcessedEvent};
 
+pub mod file;
 pub mod kafka;
 pub mod print;
+pub mod spool;
+pub mod tee;
+#[cfg(test)]
+pub(crate) mod test_utils;
 
 #[async_trait]
 pub trait Event {
     async fn send(&self, event: ProcessedEvent) -> Result<(), CaptureError>;
     async fn send_batch(&self, events: Vec<ProcessedEvent>) -> Result<(), CaptureError>;
+
+    /// Fraction of the producer queue in use, between 0 and 1, for sinks that have one.
+    /// Used by bulk endpoints to slow down before the queue is full.
+    fn queue_usage(&self) -> Option<f64> {
+        None
+    }
+}
+
+#[async_trait]
+impl Event for Box<dyn Event + Send + Sync> {
+    async fn send(&self, event: ProcessedEvent) -> Result<(), CaptureError> {
+        self.as_ref().send(event).await
+    }
+    async fn send_batch(&self, events: Vec<ProcessedEvent>) -> Result<(), CaptureError> {
+        self.as_ref().send_batch(events).await
+    }
+    fn queue_usage(&self) -> Option<f64> {
+        self.as_ref().queue_usage()
+    }
 }
@@ -0,0 +1,608 @@
+/// Durable on-disk spool in front of another sink, usually the KafkaSink.
+///
+/// When the inner sink keeps failing with retryable errors (producer queue full, brokers
+/// down or too slow to ACK), events are appended to a local write-ahead log instead of
+/// failing the request, as most SDKs never retry. Isolated failures are returned to the
+/// client, the spool only kicks in after `SPOOL_FAILURE_THRESHOLD` consecutive ones.
+/// A background task replays the log in order once the inner sink accepts events again.
+/// While the spool is not empty, new events are appended to it too, so that they are not
+/// produced before older events.
+///
+/// The log is split in segment files holding one JSON-encoded event per line, and segments
+/// are deleted once fully replayed. Delivery is at-least-once: events might be produced
+/// twice if a batch fails halfway, or if capture restarts while replaying a segment.
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use health::HealthHandle;
+use metrics::{counter, gauge};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tracing::instrument;
+use tracing::log::{error, info, warn};
+
+use crate::api::CaptureError;
+use crate::prometheus::report_dropped_events;
+use crate::sinks::Event;
+use crate::v0_request::ProcessedEvent;
+
+pub const SPOOL_SEGMENT_BYTES: u64 = 16 * 1024 * 1024;
+pub const SPOOL_FAILURE_THRESHOLD: u32 = 3;
+const SEGMENT_EXTENSION: &str = "wal";
+const REPLAY_BATCH_SIZE: usize = 100;
+const REPLAY_IDLE_INTERVAL: Duration = Duration::from_secs(1);
+const REPLAY_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct SpooledEvent {
+    spooled_at: i64,
+    event: ProcessedEvent,
+}
+
+/// Borrowing counterpart of `SpooledEvent`, to serialize events without cloning them.
+#[derive(Serialize)]
+struct SpooledEventRef<'a> {
+    spooled_at: i64,
+    event: &'a ProcessedEvent,
+}
+
+struct Segment {
+    id: u64,
+    events: u64,
+    bytes: u64,
+    oldest: i64,
+}
+
+struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    segments: VecDeque<Segment>,
+    writer: Option<File>, // Open on the last segment, if it still accepts writes
+    next_id: u64,
+    total_events: u64,
+    total_bytes: u64,
+    depth: Arc<AtomicU64>, // Mirrors total_events, readable without locking the spool
+}
+
+impl Spool {
+    /// Loads the segments left over by a previous run, they will be replayed first.
+    fn open(dir: PathBuf, max_bytes: u64, segment_bytes: u64) -> anyhow::Result<Spool> {
+        fs::create_dir_all(&dir)?;
+
+        let mut ids: Vec<u64> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION))
+            .filter_map(|path| path.file_stem()?.to_str()?.parse().ok())
+            .collect();
+        ids.sort_unstable();
+
+        let mut spool = Spool {
+            dir,
+            max_bytes,
+            segment_bytes,
+            segments: VecDeque::with_capacity(ids.len()),
+            writer: None,
+            next_id: ids.last().map_or(0, |id| id + 1),
+            total_events: 0,
+            total_bytes: 0,
+            depth: Arc::new(AtomicU64::new(0)),
+        };
+
+        for id in ids {
+            let content = fs::read(spool.segment_path(id))?;
+            let oldest = content
+                .split(|c| *c == b'\n')
+                .next()
+                .and_then(|line| serde_json::from_slice::<SpooledEvent>(line).ok())
+                .map_or_else(
+                    || OffsetDateTime::now_utc().unix_timestamp(),
+                    |e| e.spooled_at,
+                );
+            let segment = Segment {
+                id,
+                events: content.iter().filter(|c| **c == b'\n').count() as u64,
+                bytes: content.len() as u64,
+                oldest,
+            };
+            spool.total_events += segment.events;
+            spool.total_bytes += segment.bytes;
+            spool.segments.push_back(segment);
+        }
+
+        if spool.total_events > 0 {
+            info!("found {} events to replay in the spool", spool.total_events);
+        }
+        spool.report_depth();
+
+        Ok(spool)
+    }
+
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{id:020}.{SEGMENT_EXTENSION}"))
+    }
+
+    /// Appends the events to the last segment, with blocking writes: call from a blocking task.
+    fn append(&mut self, events: &[ProcessedEvent]) -> Result<(), CaptureError> {
+        let spooled_at = OffsetDateTime::now_utc().unix_timestamp();
+        let mut buffer = Vec::new();
+        for event in events {
+            serde_json::to_writer(&mut buffer, &SpooledEventRef { spooled_at, event }).map_err(
+                |e| {
+                    error!("failed to serialize event: {}", e);
+                    CaptureError::NonRetryableSinkError
+                },
+            )?;
+            buffer.push(b'\n');
+        }
+
+        if self.total_bytes + buffer.len() as u64 > self.max_bytes {
+            report_dropped_events("spool_full", events.len() as u64);
+            return Err(CaptureError::RetryableSinkError);
+        }
+
+        let rotate = match (&self.writer, self.segments.back()) {
+            (Some(_), Some(segment)) => segment.bytes >= self.segment_bytes,
+            _ => true,
+        };
+        if rotate {
+            let id = self.next_id;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.segment_path(id))
+                .map_err(|e| {
+                    error!("failed to create spool segment: {}", e);
+                    CaptureError::RetryableSinkError
+                })?;
+            self.next_id += 1;
+            self.writer = Some(file);
+            self.segments.push_back(Segment {
+                id,
+                events: 0,
+                bytes: 0,
+                oldest: spooled_at,
+            });
+        }
+
+        let (Some(writer), Some(segment)) = (self.writer.as_mut(), self.segments.back_mut()) else {
+            return Err(CaptureError::RetryableSinkError);
+        };
+        // Spooling is only expected during Kafka incidents, so we can afford to sync every write
+        if let Err(e) = writer.write_all(&buffer).and_then(|_| writer.sync_data()) {
+            error!("failed to write to spool: {}", e);
+            // The segment might hold a partial line now, stop writing to it
+            self.writer = None;
+            return Err(CaptureError::RetryableSinkError);
+        }
+
+        segment.events += events.len() as u64;
+        segment.bytes += buffer.len() as u64;
+        self.total_events += events.len() as u64;
+        self.total_bytes += buffer.len() as u64;
+        counter!("capture_spool_events_written_total").increment(events.len() as u64);
+        self.report_depth();
+
+        Ok(())
+    }
+
+    /// Returns the oldest segment to replay, closing it for writes if needed.
+    fn seal_oldest(&mut self) -> Option<(u64, PathBuf)> {
+        let oldest = self.segments.front()?.id;
+        if self.segments.len() == 1 {
+            self.writer = None;
+        }
+        Some((oldest, self.segment_path(oldest)))
+    }
+
+    fn complete(&mut self, id: u64) {
+        if self.segments.front().map(|segment| segment.id) != Some(id) {
+            return;
+        }
+        if let Err(e) = fs::remove_file(self.segment_path(id)) {
+            error!("failed to remove spool segment {}: {}", id, e);
+        }
+        if let Some(segment) = self.segments.pop_front() {
+            self.total_events -= segment.events;
+            self.total_bytes -= segment.bytes;
+        }
+        self.report_depth();
+    }
+
+    fn report_depth(&self) {
+        self.depth.store(self.total_events, Ordering::Relaxed);
+        gauge!("capture_spool_depth_events").set(self.total_events as f64);
+        gauge!("capture_spool_depth_bytes").set(self.total_bytes as f64);
+        self.report_age();
+    }
+
+    /// Refreshed by the replay task too, the age keeps growing while nothing is written.
+    fn report_age(&self) {
+        let age = self.segments.front().map_or(0, |segment| {
+            OffsetDateTime::now_utc().unix_timestamp() - segment.oldest
+        });
+        gauge!("capture_spool_oldest_event_age_seconds").set(age as f64);
+    }
+}
+
+pub struct SpoolSink<S> {
+    inner: Arc<S>,
+    spool: Arc<Mutex<Spool>>,
+    depth: Arc<AtomicU64>,
+    failures: AtomicU32, // Consecutive retryable errors of the inner sink
+}
+
+impl<S: Event + Send + Sync + 'static> SpoolSink<S> {
+    /// Opens the spool in `path` and starts replaying it into `inner` in a background task.
+    ///
+    /// `max_bytes` caps the disk usage, events are rejected with a retryable error past it.
+    pub fn new(
+        inner: S,
+        liveness: HealthHandle,
+        path: impl AsRef<Path>,
+        max_bytes: u64,
+        segment_bytes: u64,
+    ) -> anyhow::Result<SpoolSink<S>> {
+        let spool = Spool::open(path.as_ref().to_path_buf(), max_bytes, segment_bytes)?;
+        let sink = SpoolSink {
+            inner: Arc::new(inner),
+            depth: spool.depth.clone(),
+            spool: Arc::new(Mutex::new(spool)),
+            failures: AtomicU32::new(0),
+        };
+
+        let inner = sink.inner.clone();
+        let spool = sink.spool.clone();
+        tokio::spawn(async move { replay(inner, spool, liveness).await });
+
+        Ok(sink)
+    }
+
+    fn is_spooling(&self) -> bool {
+        self.depth.load(Ordering::Relaxed) > 0
+    }
+
+    async fn spool(&self, events: Vec<ProcessedEvent>) -> Result<(), CaptureError> {
+        let spool = self.spool.clone();
+        tokio::task::spawn_blocking(move || lock(&spool).append(&events))
+            .await
+            .map_err(|e| {
+                error!("spool write task failed: {}", e);
+                CaptureError::RetryableSinkError
+            })?
+    }
+
+    /// Sends the events to the inner sink, spooling them if it failed too many times in a row.
+    /// Events are only cloned when the next failure would open the circuit.
+    async fn send_or_spool(&self, events: Vec<ProcessedEvent>) -> Result<(), CaptureError> {
+        if self.is_spooling() {
+            return self.spool(events).await;
+        }
+
+        let failures = self.failures.load(Ordering::Relaxed);
+        let (result, fallback) = if failures + 1 >= SPOOL_FAILURE_THRESHOLD {
+            (self.send_inner(events.clone()).await, Some(events))
+        } else {
+            (self.send_inner(events).await, None)
+        };
+
+        match result {
+            Err(CaptureError::RetryableSinkError) => {
+                let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                match fallback {
+                    Some(events) if failures >= SPOOL_FAILURE_THRESHOLD => self.spool(events).await,
+                    _ => Err(CaptureError::RetryableSinkError),
+                }
+            }
+            result => {
+                self.failures.store(0, Ordering::Relaxed);
+                result
+            }
+        }
+    }
+
+    async fn send_inner(&self, mut events: Vec<ProcessedEvent>) -> Result<(), CaptureError> {
+        // Single events keep going through the single-event path of the inner sink
+        match events.len() {
+            1 => self.inner.send(events.pop().expect("one event")).await,
+            _ => self.inner.send_batch(events).await,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Event + Send + Sync + 'static> Event for SpoolSink<S> {
+    #[instrument(skip_all)]
+    async fn send(&self, event: ProcessedEvent) -> Result<(), CaptureError> {
+        self.send_or_spool(vec![event]).await
+    }
+
+    #[instrument(skip_all)]
+    async fn send_batch(&self, events: Vec<ProcessedEvent>) -> Result<(), CaptureError> {
+        self.send_or_spool(events).await
+    }
+
+    fn queue_usage(&self) -> Option<f64> {
+        self.inner.queue_usage()
+    }
+}
+
+/// Locks the spool, which stays usable if a writer panicked: segments are append-only.
+fn lock(spool: &Mutex<Spool>) -> std::sync::MutexGuard<'_, Spool> {
+    spool
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Replays the spool segments in order, retrying with a backoff while the inner sink fails.
+async fn replay<S: Event + Send + Sync>(
+    inner: Arc<S>,
+    spool: Arc<Mutex<Spool>>,
+    liveness: HealthHandle,
+) {
+    loop {
+        liveness.report_healthy().await;
+
+        let next = {
+            let mut spool = lock(&spool);
+            spool.report_age();
+            spool.seal_oldest()
+        };
+        let Some((id, path)) = next else {
+            tokio::time::sleep(REPLAY_IDLE_INTERVAL).await;
+            continue;
+        };
+
+        let events = match tokio::fs::read(&path).await {
+            Ok(content) => parse_segment(&content),
+            Err(e) => {
+                error!("failed to read spool segment {:?}: {}", path, e);
+                Vec::new()
+            }
+        };
+
+        for chunk in events.chunks(REPLAY_BATCH_SIZE) {
+            replay_chunk(&inner, &spool, chunk, &liveness).await;
+            counter!("capture_spool_events_replayed_total").increment(chunk.len() as u64);
+        }
+
+        let spool = spool.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || lock(&spool).complete(id)).await {
+            error!("failed to complete spool segment {}: {}", id, e);
+        }
+    }
+}
+
+fn parse_segment(content: &[u8]) -> Vec<ProcessedEvent> {
+    content
+        .split(|c| *c == b'\n')
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| match serde_json::from_slice::<SpooledEvent>(line) {
+            Ok(spooled) => Some(spooled.event),
+            Err(e) => {
+                // Most likely a partial write, before a crash or a full disk
+                warn!("skipping invalid spooled event: {}", e);
+                report_dropped_events("spool_invalid_event", 1);
+                None
+            }
+        })
+        .collect()
+}
+
+async fn replay_chunk<S: Event + Send + Sync>(
+    inner: &Arc<S>,
+    spool: &Mutex<Spool>,
+    chunk: &[ProcessedEvent],
+    liveness: &HealthHandle,
+) {
+    let mut backoff = Duration::from_millis(100);
+    loop {
+        match inner.send_batch(chunk.to_vec()).await {
+            Ok(()) => return,
+            Err(CaptureError::RetryableSinkError) => {
+                liveness.report_healthy().await;
+                lock(spool).report_age();
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(REPLAY_MAX_BACKOFF);
+            }
+            Err(_) => break,
+        }
+    }
+
+    // Find and skip the events that can't be produced, retrying the others
+    for event in chunk {
+        loop {
+            match inner.send(event.clone()).await {
+                Ok(()) => break,
+                Err(CaptureError::RetryableSinkError) => {
+                    liveness.report_healthy().await;
+                    lock(spool).report_age();
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(REPLAY_MAX_BACKOFF);
+                }
+                Err(e) => {
+                    error!("dropping spooled event {}: {}", event.event.uuid, e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use health::HealthRegistry;
+    use uuid::Uuid;
+
+    use crate::api::CaptureError;
+    use crate::sinks::spool::{SpoolSink, SPOOL_FAILURE_THRESHOLD};
+    use crate::sinks::test_utils::event;
+    use crate::sinks::Event;
+    use crate::v0_request::ProcessedEvent;
+
+    #[derive(Default)]
+    struct FlakySink {
+        failing: AtomicBool,
+        received: Mutex<Vec<Uuid>>,
+    }
+
+    #[async_trait]
+    impl Event for Arc<FlakySink> {
+        async fn send(&self, event: ProcessedEvent) -> Result<(), CaptureError> {
+            self.send_batch(vec![event]).await
+        }
+        async fn send_batch(&self, events: Vec<ProcessedEvent>) -> Result<(), CaptureError> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(CaptureError::RetryableSinkError);
+            }
+            let mut received = self.received.lock().unwrap();
+            received.extend(events.iter().map(|e| e.event.uuid));
+            Ok(())
+        }
+    }
+
+    fn spool_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("capture-spool-{}", Uuid::now_v7()))
+    }
+
+    async fn start(inner: Arc<FlakySink>, dir: &Path, max_bytes: u64) -> SpoolSink<Arc<FlakySink>> {
+        let registry = HealthRegistry::new("liveness");
+        let handle = registry
+            .register("spool".to_string(), time::Duration::seconds(30))
+            .await;
+        SpoolSink::new(inner, handle, dir, max_bytes, 1024).expect("failed to open spool")
+    }
+
+    async fn wait_for(inner: &Arc<FlakySink>, count: usize) -> Vec<Uuid> {
+        for _ in 0..100 {
+            let received = inner.received.lock().unwrap().clone();
+            if received.len() >= count {
+                return received;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("timed out waiting for {count} events");
+    }
+
+    fn segment_count(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    /// Fails enough sends for the next failure to open the circuit and start spooling.
+    async fn trip(sink: &SpoolSink<Arc<FlakySink>>) {
+        for _ in 1..SPOOL_FAILURE_THRESHOLD {
+            assert!(matches!(
+                sink.send(event("token1")).await,
+                Err(CaptureError::RetryableSinkError)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn pass_through_when_healthy() {
+        let dir = spool_dir();
+        let inner = Arc::new(FlakySink::default());
+        let sink = start(inner.clone(), &dir, 1_000_000).await;
+
+        let events = vec![event("token1"), event("token1")];
+        let uuids: Vec<Uuid> = events.iter().map(|e| e.event.uuid).collect();
+        sink.send_batch(events).await.expect("failed to send");
+
+        assert_eq!(uuids, *inner.received.lock().unwrap());
+        assert_eq!(0, segment_count(&dir));
+    }
+
+    #[tokio::test]
+    async fn only_spool_after_consecutive_failures() {
+        let dir = spool_dir();
+        let inner = Arc::new(FlakySink::default());
+        let sink = start(inner.clone(), &dir, 1_000_000).await;
+
+        // A success in between resets the failure count
+        inner.failing.store(true, Ordering::SeqCst);
+        trip(&sink).await;
+        inner.failing.store(false, Ordering::SeqCst);
+        sink.send(event("token1")).await.expect("failed to send");
+        inner.failing.store(true, Ordering::SeqCst);
+        trip(&sink).await;
+        assert_eq!(0, segment_count(&dir));
+
+        sink.send(event("token1")).await.expect("failed to spool");
+        assert_eq!(1, segment_count(&dir));
+    }
+
+    #[tokio::test]
+    async fn spool_and_replay_in_order() {
+        let dir = spool_dir();
+        let inner = Arc::new(FlakySink::default());
+        inner.failing.store(true, Ordering::SeqCst);
+        let sink = start(inner.clone(), &dir, 1_000_000).await;
+        trip(&sink).await;
+
+        // Enough events to rotate segments
+        let events: Vec<ProcessedEvent> = (0..20).map(|_| event("token1")).collect();
+        let uuids: Vec<Uuid> = events.iter().map(|e| e.event.uuid).collect();
+        for event in events {
+            sink.send(event).await.expect("failed to spool");
+        }
+        assert!(segment_count(&dir) > 1);
+
+        inner.failing.store(false, Ordering::SeqCst);
+        assert_eq!(uuids, wait_for(&inner, uuids.len()).await);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(0, segment_count(&dir));
+
+        // Back to direct sends once the spool is drained
+        let event = event("token1");
+        let uuid = event.event.uuid;
+        sink.send(event).await.expect("failed to send");
+        assert_eq!(Some(&uuid), inner.received.lock().unwrap().last());
+    }
+
+    #[tokio::test]
+    async fn reject_when_full() {
+        let dir = spool_dir();
+        let inner = Arc::new(FlakySink::default());
+        inner.failing.store(true, Ordering::SeqCst);
+        let sink = start(inner.clone(), &dir, 1024).await;
+        trip(&sink).await;
+
+        let mut result = Ok(());
+        for _ in 0..20 {
+            result = sink.send(event("token1")).await;
+        }
+        assert!(matches!(result, Err(CaptureError::RetryableSinkError)));
+    }
+
+    #[tokio::test]
+    async fn replay_after_restart() {
+        let dir = spool_dir();
+        let failing = Arc::new(FlakySink::default());
+        failing.failing.store(true, Ordering::SeqCst);
+        let sink = start(failing.clone(), &dir, 1_000_000).await;
+        trip(&sink).await;
+
+        let event = event("token1");
+        let uuid = event.event.uuid;
+        sink.send(event).await.expect("failed to spool");
+        drop(sink);
+
+        let inner = Arc::new(FlakySink::default());
+        let _sink = start(inner.clone(), &dir, 1_000_000).await;
+        assert_eq!(vec![uuid], wait_for(&inner, 1).await);
+    }
+}
@@ -0,0 +1,206 @@
+/// Composite sink that mirrors a sample of the traffic to a secondary sink, to test new
+/// consumers or clusters against real traffic.
+///
+/// The primary sink is the source of truth: its result is the one returned to the handler.
+/// Mirrored events are sent in background tasks, so that the secondary sink cannot slow down
+/// nor fail requests. If too many mirrored sends are in flight, new events are not mirrored.
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use metrics::counter;
+use rand::Rng;
+use tokio::sync::Semaphore;
+use tracing::instrument;
+use tracing::log::warn;
+
+use crate::api::CaptureError;
+use crate::sinks::Event;
+use crate::v0_request::ProcessedEvent;
+
+const MAX_MIRRORS_IN_FLIGHT: usize = 1000;
+
+pub struct TeeSink<P> {
+    primary: P,
+    secondary: Arc<dyn Event + Send + Sync>,
+    sample_rate: f64,
+    tokens: Option<HashSet<String>>,
+    in_flight: Arc<Semaphore>,
+}
+
+impl<P: Event + Send + Sync> TeeSink<P> {
+    /// Mirrors `sample_rate` (between 0 and 1) of the events to `secondary`.
+    /// If `tokens` is set (comma-delimited), only events for these tokens are mirrored.
+    pub fn new<S: Event + Send + Sync + 'static>(
+        primary: P,
+        secondary: S,
+        sample_rate: f64,
+        tokens: Option<String>,
+    ) -> Self {
+        TeeSink {
+            primary,
+            secondary: Arc::new(secondary),
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            tokens: tokens.map(|values| values.split(',').map(|t| t.trim().to_string()).collect()),
+            in_flight: Arc::new(Semaphore::new(MAX_MIRRORS_IN_FLIGHT)),
+        }
+    }
+
+    fn is_mirrored(&self, event: &ProcessedEvent) -> bool {
+        if let Some(tokens) = &self.tokens {
+            if !tokens.contains(&event.event.token) {
+                return false;
+            }
+        }
+        self.sample_rate > 0.0 && rand::thread_rng().gen_bool(self.sample_rate)
+    }
+
+    fn mirror(&self, events: Vec<ProcessedEvent>) {
+        if events.is_empty() {
+            return;
+        }
+        let Ok(permit) = self.in_flight.clone().try_acquire_owned() else {
+            counter!("capture_tee_events_skipped_total").increment(events.len() as u64);
+            return;
+        };
+
+        let secondary = self.secondary.clone();
+        tokio::spawn(async move {
+            let count = events.len() as u64;
+            let result = match events.len() {
+                1 => secondary.send(events.into_iter().next().unwrap()).await,
+                _ => secondary.send_batch(events).await,
+            };
+            match result {
+                Ok(()) => counter!("capture_tee_events_mirrored_total").increment(count),
+                Err(err) => {
+                    warn!("failed to mirror events: {}", err);
+                    counter!("capture_tee_events_failed_total").increment(count);
+                }
+            }
+            drop(permit);
+        });
+    }
+}
+
+#[async_trait]
+impl<P: Event + Send + Sync> Event for TeeSink<P> {
+    #[instrument(skip_all)]
+    async fn send(&self, event: ProcessedEvent) -> Result<(), CaptureError> {
+        if self.is_mirrored(&event) {
+            self.mirror(vec![event.clone()]);
+        }
+        self.primary.send(event).await
+    }
+
+    #[instrument(skip_all)]
+    async fn send_batch(&self, events: Vec<ProcessedEvent>) -> Result<(), CaptureError> {
+        let mirrored: Vec<ProcessedEvent> = events
+            .iter()
+            .filter(|event| self.is_mirrored(event))
+            .cloned()
+            .collect();
+        self.mirror(mirrored);
+        self.primary.send_batch(events).await
+    }
+
+    fn queue_usage(&self) -> Option<f64> {
+        self.primary.queue_usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use crate::api::CaptureError;
+    use crate::sinks::tee::TeeSink;
+    use crate::sinks::test_utils::event;
+    use crate::sinks::Event;
+    use crate::v0_request::ProcessedEvent;
+
+    #[derive(Default)]
+    struct MemorySink {
+        failing: bool,
+        received: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Event for Arc<MemorySink> {
+        async fn send(&self, event: ProcessedEvent) -> Result<(), CaptureError> {
+            self.send_batch(vec![event]).await
+        }
+        async fn send_batch(&self, events: Vec<ProcessedEvent>) -> Result<(), CaptureError> {
+            if self.failing {
+                return Err(CaptureError::NonRetryableSinkError);
+            }
+            let mut received = self.received.lock().unwrap();
+            received.extend(events.into_iter().map(|e| e.event.token));
+            Ok(())
+        }
+    }
+
+    fn received(sink: &Arc<MemorySink>) -> Vec<String> {
+        sink.received.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn mirror_filtered_tokens() {
+        let primary = Arc::new(MemorySink::default());
+        let secondary = Arc::new(MemorySink::default());
+        let sink = TeeSink::new(
+            primary.clone(),
+            secondary.clone(),
+            1.0,
+            Some("one, two".to_string()),
+        );
+
+        sink.send(event("one")).await.expect("failed to send");
+        sink.send_batch(vec![event("two"), event("three")])
+            .await
+            .expect("failed to send");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(vec!["one", "two", "three"], received(&primary));
+        assert_eq!(vec!["one", "two"], received(&secondary));
+    }
+
+    #[tokio::test]
+    async fn sample_traffic() {
+        let primary = Arc::new(MemorySink::default());
+        let secondary = Arc::new(MemorySink::default());
+        let sink = TeeSink::new(primary.clone(), secondary.clone(), 0.0, None);
+
+        sink.send_batch(vec![event("one"), event("two")])
+            .await
+            .expect("failed to send");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(2, received(&primary).len());
+        assert!(received(&secondary).is_empty());
+    }
+
+    #[tokio::test]
+    async fn ignore_secondary_errors() {
+        let primary = Arc::new(MemorySink::default());
+        let secondary = Arc::new(MemorySink {
+            failing: true,
+            ..Default::default()
+        });
+        let sink = TeeSink::new(primary.clone(), secondary, 1.0, None);
+
+        sink.send(event("one")).await.expect("failed to send");
+        assert_eq!(vec!["one"], received(&primary));
+
+        // Primary errors are still returned
+        let primary = Arc::new(MemorySink {
+            failing: true,
+            ..Default::default()
+        });
+        let sink = TeeSink::new(primary, Arc::new(MemorySink::default()), 1.0, None);
+        assert!(sink.send(event("one")).await.is_err());
+    }
+}
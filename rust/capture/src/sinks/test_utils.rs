@@ -0,0 +1,24 @@
+use common_types::CapturedEvent;
+
+use crate::utils::uuid_v7;
+use crate::v0_request::{DataType, ProcessedEvent, ProcessedEventMetadata};
+
+/// Builds a minimal analytics event for the given token, with a fresh UUID.
+pub fn event(token: &str) -> ProcessedEvent {
+    ProcessedEvent {
+        metadata: ProcessedEventMetadata {
+            data_type: DataType::AnalyticsMain,
+            session_id: None,
+            event_name: "event".to_string(),
+        },
+        event: CapturedEvent {
+            uuid: uuid_v7(),
+            distinct_id: "id1".to_string(),
+            ip: "".to_string(),
+            data: "{}".to_string(),
+            now: "".to_string(),
+            sent_at: None,
+            token: token.to_string(),
+        },
+    }
+}
@@ -15,7 +15,7 @@ use tracing::error;
 use crate::{
     api::{CaptureError, CaptureResponse, CaptureResponseCode},
     router,
-    v0_request::{Compression, EventFormData, EventQuery, RawRequest, GZIP_MAGIC_NUMBERS},
+    v0_request::{EventFormData, EventQuery, RawRequest, GZIP_MAGIC_NUMBERS},
 };
 
 // These metrics are only used in the test paths below
@@ -40,11 +40,9 @@ pub async fn test_black_hole(
     body: Bytes,
 ) -> Result<Json<CaptureResponse>, CaptureError> {
     metrics::counter!(REQUEST_SEEN).increment(1);
-    let comp = match meta.compression {
-        None => String::from("unknown"),
-        Some(Compression::Gzip) => String::from("gzip"),
-        Some(Compression::Unsupported) => String::from("unsupported"),
-    };
+    let comp = meta
+        .compression
+        .map_or(String::from("unknown"), |c| c.as_str().to_string());
 
     metrics::counter!(COMPRESSION_TYPE, "type" => comp.clone()).increment(1);
 
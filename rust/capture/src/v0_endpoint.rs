@@ -1,11 +1,10 @@
 use std::ops::Deref;
 use std::sync::Arc;
 
-use axum::{debug_handler, Json};
-use bytes::Bytes;
-// TODO: stream this instead
+use axum::body::Body;
 use axum::extract::{MatchedPath, Query, State};
-use axum::http::{HeaderMap, Method};
+use axum::http::{HeaderMap, Method, Uri};
+use axum::{debug_handler, Json};
 use axum_client_ip::InsecureClientIp;
 use base64::Engine;
 use common_types::CapturedEvent;
@@ -13,7 +12,14 @@ use metrics::counter;
 use serde_json::json;
 use serde_json::Value;
 use tracing::instrument;
-
+use uuid::Uuid;
+
+use crate::body::{compression_hint, decode_body, decompress_lz64, form_body_limit, read_body};
+use crate::bots::{BotAction, BotMatch};
+use crate::debug_capture::{debug_request, DebugRequest};
+use crate::dedup::find_duplicates;
+use crate::limiters::redis::QuotaResource;
+use crate::privacy::PrivacySettings;
 use crate::prometheus::report_dropped_events;
 use crate::v0_request::{
     Compression, DataType, ProcessedEvent, ProcessedEventMetadata, ProcessingContext, RawRequest,
@@ -25,12 +31,16 @@ use crate::{
     v0_request::{EventFormData, EventQuery, RawEvent},
 };
 
+// Room left for the kafka record key, headers and framing when splitting replay batches
+const KAFKA_MESSAGE_OVERHEAD_BYTES: usize = 1024;
+
 /// Flexible endpoint that targets wide compatibility with the wide range of requests
 /// currently processed by posthog-events (analytics events capture). Replay is out
 /// of scope and should be processed on a separate endpoint.
 ///
-/// Because it must accommodate several shapes, it is inefficient in places. A v1
-/// endpoint should be created, that only accepts the BatchedRequest payload shape.
+/// Because it must accommodate several shapes, it is inefficient in places. The v1
+/// endpoint in `v1_endpoint` only accepts the BatchedRequest payload shape.
+#[allow(clippy::too_many_arguments)]
 async fn handle_common(
     state: &State<router::State>,
     InsecureClientIp(ip): &InsecureClientIp,
@@ -38,8 +48,9 @@ async fn handle_common(
     headers: &HeaderMap,
     method: &Method,
     path: &MatchedPath,
-    body: Bytes,
-) -> Result<(ProcessingContext, Vec<RawEvent>), CaptureError> {
+    debug: &mut Option<DebugRequest>,
+    body: Body,
+) -> Result<(ProcessingContext, Vec<RawEvent>, Vec<QuotaResource>), CaptureError> {
     let user_agent = headers
         .get("user-agent")
         .map_or("unknown", |v| v.to_str().unwrap_or("unknown"));
@@ -47,44 +58,17 @@ async fn handle_common(
         .get("content-encoding")
         .map_or("unknown", |v| v.to_str().unwrap_or("unknown"));
 
-    let comp = match meta.compression {
-        None => String::from("unknown"),
-        Some(Compression::Gzip) => String::from("gzip"),
-        Some(Compression::Unsupported) => String::from("unsupported"),
-    };
+    let comp = meta.compression.map_or("unknown", |c| c.as_str());
+    let hint = compression_hint(meta.compression, headers);
 
     tracing::Span::current().record("user_agent", user_agent);
     tracing::Span::current().record("content_encoding", content_encoding);
     tracing::Span::current().record("version", meta.lib_version.clone());
-    tracing::Span::current().record("compression", comp.as_str());
+    tracing::Span::current().record("compression", comp);
     tracing::Span::current().record("method", method.as_str());
     tracing::Span::current().record("path", path.as_str().trim_end_matches('/'));
 
-    let request = match headers
-        .get("content-type")
-        .map_or("", |v| v.to_str().unwrap_or(""))
-    {
-        "application/x-www-form-urlencoded" => {
-            tracing::Span::current().record("content_type", "application/x-www-form-urlencoded");
-
-            let input: EventFormData = serde_urlencoded::from_bytes(body.deref()).map_err(|e| {
-                tracing::error!("failed to decode body: {}", e);
-                CaptureError::RequestDecodingError(String::from("invalid form data"))
-            })?;
-            let payload = base64::engine::general_purpose::STANDARD
-                .decode(input.data)
-                .map_err(|e| {
-                    tracing::error!("failed to decode form data: {}", e);
-                    CaptureError::RequestDecodingError(String::from("missing data field"))
-                })?;
-            RawRequest::from_bytes(payload.into(), state.event_size_limit)
-        }
-        ct => {
-            tracing::Span::current().record("content_type", ct);
-
-            RawRequest::from_bytes(body, state.event_size_limit)
-        }
-    }?;
+    let request = decode_request(state, hint, headers, body).await?;
 
     let sent_at = request.sent_at().or(meta.sent_at());
     let token = match request.extract_and_verify_token() {
@@ -94,6 +78,9 @@ async fn handle_common(
             return Err(err);
         }
     };
+    if let Some(debug) = debug {
+        debug.set_token(&token);
+    }
     let historical_migration = request.historical_migration();
     let events = request.events(); // Takes ownership of request
 
@@ -108,7 +95,7 @@ async fn handle_common(
 
     counter!("capture_events_received_total").increment(events.len() as u64);
 
-    let context = ProcessingContext {
+    let mut context = ProcessingContext {
         lib_version: meta.lib_version.clone(),
         sent_at,
         token,
@@ -117,20 +104,202 @@ async fn handle_common(
         historical_migration,
     };
 
-    let billing_limited = state
+    let events = filter_bots(state, &context, headers, events).await;
+    if events.is_empty() {
+        return Ok((context, events, Vec::new()));
+    }
+
+    // Before the limits, uuids recorded for deduplication would not be forgotten otherwise
+    let privacy = privacy_settings(state, &mut context).await?;
+    let (mut events, quota_limited) = apply_limits(state, &context, events).await?;
+    if let Some(settings) = privacy {
+        events.iter_mut().for_each(|event| settings.apply(event));
+    }
+
+    tracing::debug!(context=?context, events=?events, "decoded request");
+
+    Ok((context, events, quota_limited))
+}
+
+/// Decodes the body of a v0 request: either a form holding the base64 or lz64 encoded
+/// payload in its `data` field, or a JSON payload, optionally compressed.
+pub(crate) async fn decode_request(
+    state: &router::State,
+    hint: Option<Compression>,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<RawRequest, CaptureError> {
+    match headers
+        .get("content-type")
+        .map_or("", |v| v.to_str().unwrap_or(""))
+    {
+        "application/x-www-form-urlencoded" => {
+            tracing::Span::current().record("content_type", "application/x-www-form-urlencoded");
+
+            // Form payloads hold base64 data in a field, they cannot be decoded incrementally
+            let body = read_body(body, form_body_limit(state.event_size_limit)).await?;
+            let input: EventFormData = serde_urlencoded::from_bytes(body.deref()).map_err(|e| {
+                tracing::error!("failed to decode body: {}", e);
+                CaptureError::RequestDecodingError(String::from("invalid form data"))
+            })?;
+            let payload = match hint {
+                Some(Compression::Lz64) => {
+                    decompress_lz64(&input.data, state.event_size_limit)?.into_bytes()
+                }
+                _ => base64::engine::general_purpose::STANDARD
+                    .decode(input.data)
+                    .map_err(|e| {
+                        tracing::error!("failed to decode form data: {}", e);
+                        CaptureError::RequestDecodingError(String::from("missing data field"))
+                    })?,
+            };
+            RawRequest::from_bytes(payload.into(), state.event_size_limit)
+        }
+        ct => {
+            tracing::Span::current().record("content_type", ct);
+
+            decode_body::<RawRequest>(body, hint, state.event_size_limit).await
+        }
+    }
+}
+
+/// Applies the billing and rate limits to decoded events, and drops duplicates if enabled.
+/// Returns the events left to process, that can be empty if all of them were duplicates
+/// or over quota, and the resources we dropped events of because of billing limits.
+pub(crate) async fn apply_limits(
+    state: &router::State,
+    context: &ProcessingContext,
+    mut events: Vec<RawEvent>,
+) -> Result<(Vec<RawEvent>, Vec<QuotaResource>), CaptureError> {
+    let limited = state
         .billing_limiter
-        .is_limited(context.token.as_str())
+        .limited_resources(&context.token)
         .await;
 
-    if billing_limited {
-        report_dropped_events("over_quota", events.len() as u64);
+    let mut quota_limited = Vec::new();
+    if !limited.is_empty() {
+        let count = events.len();
+        events.retain(|event| {
+            match state
+                .billing_limiter
+                .resource(event, context.historical_migration)
+            {
+                Some(resource) if limited.contains(&resource) => {
+                    if !quota_limited.contains(&resource) {
+                        quota_limited.push(resource);
+                    }
+                    false
+                }
+                _ => true,
+            }
+        });
+        if events.len() < count {
+            report_dropped_events("over_quota", (count - events.len()) as u64);
+        }
+        if events.is_empty() {
+            return Ok((events, quota_limited));
+        }
+    }
 
-        return Err(CaptureError::BillingLimit);
+    if let Some(rate_limiter) = &state.rate_limiter {
+        if rate_limiter.check_token(&context.token).await.is_err() {
+            report_dropped_events("rate_limited", events.len() as u64);
+            return Err(CaptureError::RateLimited);
+        }
+
+        // Events we can't get a distinct_id for are rejected later on
+        let count = events.len();
+        events.retain(|event| match event.extract_distinct_id() {
+            Ok(distinct_id) => rate_limiter
+                .check_distinct_id(&context.token, &distinct_id)
+                .is_ok(),
+            Err(_) => true,
+        });
+        if events.len() < count {
+            report_dropped_events("rate_limited", (count - events.len()) as u64);
+        }
+        if events.is_empty() {
+            return Err(CaptureError::RateLimited);
+        }
     }
 
-    tracing::debug!(context=?context, events=?events, "decoded request");
+    if let Some(deduplicator) = &state.deduplicator {
+        let uuids: Vec<Option<Uuid>> = events.iter().map(|event| event.uuid).collect();
+        let mut duplicates = find_duplicates(deduplicator.as_ref(), &context.token, &uuids)
+            .await
+            .into_iter();
+        let count = events.len();
+        events.retain(|_| !duplicates.next().unwrap_or(false));
+        if events.len() < count {
+            report_dropped_events("duplicate_event", (count - events.len()) as u64);
+        }
+        // If only duplicates are left, the client retried a batch we already ingested
+    }
+
+    Ok((events, quota_limited))
+}
 
-    Ok((context, events))
+/// Returns how to handle the request if it comes from a bot, and the team filters bots.
+pub(crate) async fn check_bot(
+    state: &router::State,
+    context: &ProcessingContext,
+    headers: &HeaderMap,
+) -> Option<BotMatch> {
+    let user_agent = headers.get("user-agent").and_then(|v| v.to_str().ok());
+    state
+        .bot_filter
+        .as_ref()?
+        .check(&context.token, user_agent, &context.client_ip)
+        .await
+}
+
+/// Drops or tags the events of bot requests, depending on the team settings.
+pub(crate) async fn filter_bots(
+    state: &router::State,
+    context: &ProcessingContext,
+    headers: &HeaderMap,
+    mut events: Vec<RawEvent>,
+) -> Vec<RawEvent> {
+    match check_bot(state, context, headers).await {
+        None => events,
+        Some(bot) if bot.action == BotAction::Drop => {
+            report_dropped_events("bot_traffic", events.len() as u64);
+            Vec::new()
+        }
+        Some(bot) => {
+            events.iter_mut().for_each(|event| bot.tag(event));
+            events
+        }
+    }
+}
+
+/// Returns the privacy settings of the team, after applying them to the request context.
+/// Events must go through `PrivacySettings::apply` before being processed.
+pub(crate) async fn privacy_settings(
+    state: &router::State,
+    context: &mut ProcessingContext,
+) -> Result<Option<Arc<PrivacySettings>>, CaptureError> {
+    let settings = match &state.privacy {
+        None => None,
+        Some(privacy) => privacy.settings(&context.token).await?,
+    };
+    if let Some(settings) = &settings {
+        settings.apply_context(context);
+    }
+    Ok(settings)
+}
+
+/// Lists the resources we dropped events of in the response, so that SDKs stop sending them.
+pub(crate) fn quota_limited_response(quota_limited: &[QuotaResource]) -> Option<Vec<String>> {
+    match quota_limited.is_empty() {
+        true => None,
+        false => Some(
+            quota_limited
+                .iter()
+                .map(|resource| resource.as_str().to_string())
+                .collect(),
+        ),
+    }
 }
 
 #[instrument(
@@ -148,6 +317,7 @@ async fn handle_common(
     )
 )]
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn event(
     state: State<router::State>,
     ip: InsecureClientIp,
@@ -155,15 +325,21 @@ pub async fn event(
     headers: HeaderMap,
     method: Method,
     path: MatchedPath,
-    body: Bytes,
+    uri: Uri,
+    body: Body,
 ) -> Result<Json<CaptureResponse>, CaptureError> {
-    match handle_common(&state, &ip, &meta, &headers, &method, &path, body).await {
-        Err(CaptureError::BillingLimit) => {
+    let (body, mut debug) = debug_request(&state, &method, &uri, &headers, body, None).await;
+    let result = match handle_common(
+        &state, &ip, &meta, &headers, &method, &path, &mut debug, body,
+    )
+    .await
+    {
+        Err(CaptureError::RateLimited) => {
             // for v0 we want to just return ok 🙃
             // this is because the clients are pretty dumb and will just retry over and over and
             // over...
             //
-            // for v1, we'll return a meaningful error code and error, so that the clients can do
+            // v1 returns a meaningful error code and error, so that the clients can do
             // something meaningful with that error
             Ok(Json(CaptureResponse {
                 status: CaptureResponseCode::Ok,
@@ -171,25 +347,49 @@ pub async fn event(
             }))
         }
         Err(err) => Err(err),
-        Ok((context, events)) => {
-            if let Err(err) = process_events(state.sink.clone(), &events, &context).await {
-                let cause = match err {
-                    CaptureError::EmptyDistinctId => "empty_distinct_id",
-                    CaptureError::MissingDistinctId => "missing_distinct_id",
-                    CaptureError::MissingEventName => "missing_event_name",
-                    _ => "process_events_error",
-                };
-                report_dropped_events(cause, events.len() as u64);
-                tracing::log::warn!("rejected invalid payload: {}", err);
-                return Err(err);
-            }
+        Ok((_, events, quota_limited)) if events.is_empty() => Ok(Json(CaptureResponse {
+            status: CaptureResponseCode::Ok,
+            quota_limited: quota_limited_response(&quota_limited),
+        })),
+        Ok((context, events, quota_limited)) => {
+            send_events(&state, &context, &events).await.map(|()| {
+                Json(CaptureResponse {
+                    status: CaptureResponseCode::Ok,
+                    quota_limited: quota_limited_response(&quota_limited),
+                })
+            })
+        }
+    };
 
-            Ok(Json(CaptureResponse {
-                status: CaptureResponseCode::Ok,
-                quota_limited: None,
-            }))
+    if let Some(debug) = debug {
+        debug.finish(&result);
+    }
+    result
+}
+
+/// Processes and produces analytics events, reporting them as dropped if that fails.
+pub(crate) async fn send_events(
+    state: &router::State,
+    context: &ProcessingContext,
+    events: &[RawEvent],
+) -> Result<(), CaptureError> {
+    if let Err(err) = process_events(state.sink.clone(), events, context).await {
+        // The events were not ingested, don't drop them as duplicates when sent again
+        if let Some(deduplicator) = &state.deduplicator {
+            let uuids: Vec<Uuid> = events.iter().filter_map(|e| e.uuid).collect();
+            deduplicator.forget(&context.token, &uuids).await;
         }
+        let cause = match err {
+            CaptureError::EmptyDistinctId => "empty_distinct_id",
+            CaptureError::MissingDistinctId => "missing_distinct_id",
+            CaptureError::MissingEventName => "missing_event_name",
+            _ => "process_events_error",
+        };
+        report_dropped_events(cause, events.len() as u64);
+        tracing::log::warn!("rejected invalid payload: {}", err);
+        return Err(err);
     }
+    Ok(())
 }
 
 #[instrument(
@@ -207,6 +407,7 @@ pub async fn event(
     )
 )]
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn recording(
     state: State<router::State>,
     ip: InsecureClientIp,
@@ -214,17 +415,55 @@ pub async fn recording(
     headers: HeaderMap,
     method: Method,
     path: MatchedPath,
-    body: Bytes,
+    uri: Uri,
+    body: Body,
 ) -> Result<Json<CaptureResponse>, CaptureError> {
-    match handle_common(&state, &ip, &meta, &headers, &method, &path, body).await {
-        Err(CaptureError::BillingLimit) => Ok(Json(CaptureResponse {
+    let (body, mut debug) = debug_request(&state, &method, &uri, &headers, body, None).await;
+    let result = handle_recording(
+        &state, &ip, &meta, &headers, &method, &path, &mut debug, body,
+    )
+    .await;
+    if let Some(debug) = debug {
+        debug.finish(&result);
+    }
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_recording(
+    state: &State<router::State>,
+    ip: &InsecureClientIp,
+    meta: &EventQuery,
+    headers: &HeaderMap,
+    method: &Method,
+    path: &MatchedPath,
+    debug: &mut Option<DebugRequest>,
+    body: Body,
+) -> Result<Json<CaptureResponse>, CaptureError> {
+    match handle_common(state, ip, meta, headers, method, path, debug, body).await {
+        Err(CaptureError::RateLimited) => Ok(Json(CaptureResponse {
             status: CaptureResponseCode::Ok,
-            quota_limited: Some(vec!["recordings".to_string()]),
+            quota_limited: None,
         })),
         Err(err) => Err(err),
-        Ok((context, events)) => {
+        Ok((_, events, quota_limited)) if events.is_empty() => Ok(Json(CaptureResponse {
+            status: CaptureResponseCode::Ok,
+            quota_limited: quota_limited_response(&quota_limited),
+        })),
+        Ok((context, events, _)) => {
             let count = events.len() as u64;
-            if let Err(err) = process_replay_events(state.sink.clone(), events, &context).await {
+            let uuids: Vec<Uuid> = events.iter().filter_map(|e| e.uuid).collect();
+            if let Err(err) = process_replay_events(
+                state.sink.clone(),
+                events,
+                &context,
+                state.replay_message_max_bytes,
+            )
+            .await
+            {
+                if let Some(deduplicator) = &state.deduplicator {
+                    deduplicator.forget(&context.token, &uuids).await;
+                }
                 let cause = match err {
                     CaptureError::EmptyDistinctId => "empty_distinct_id",
                     CaptureError::MissingDistinctId => "missing_distinct_id",
@@ -267,13 +506,7 @@ pub fn process_single_event(
         return Err(CaptureError::MissingEventName);
     }
 
-    let data_type = match (event.event.as_str(), context.historical_migration) {
-        ("$$client_ingestion_warning", _) => DataType::ClientIngestionWarning,
-        ("$exception", _) => DataType::ExceptionMain,
-        ("$$heatmap", _) => DataType::HeatmapMain,
-        (_, true) => DataType::AnalyticsHistorical,
-        (_, false) => DataType::AnalyticsMain,
-    };
+    let data_type = event.data_type(context.historical_migration);
 
     let data = serde_json::to_string(&event).map_err(|e| {
         tracing::error!("failed to encode data field: {}", e);
@@ -283,6 +516,7 @@ pub fn process_single_event(
     let metadata = ProcessedEventMetadata {
         data_type,
         session_id: None,
+        event_name: event.event.clone(),
     };
 
     let event = CapturedEvent {
@@ -317,76 +551,261 @@ pub async fn process_events<'a>(
     }
 }
 
+/// Snapshot items of a batch belonging to the same session and window,
+/// with the metadata of the first event of the group.
+struct ReplayGroup {
+    session_id: String,
+    window_id: Value,
+    uuid: Uuid,
+    distinct_id: String,
+    snapshot_source: Value,
+    items: Vec<Value>,
+}
+
 #[instrument(skip_all, fields(events = events.len()))]
 pub async fn process_replay_events<'a>(
     sink: Arc<dyn sinks::Event + Send + Sync>,
-    mut events: Vec<RawEvent>,
+    events: Vec<RawEvent>,
     context: &'a ProcessingContext,
+    max_message_bytes: usize,
+) -> Result<(), CaptureError> {
+    // SDKs can flush snapshots across a session or window rotation, group them to send
+    // each snapshot under the right session. Invalid events are dropped on their own.
+    let mut groups: Vec<ReplayGroup> = Vec::with_capacity(1);
+    let mut rejected: Vec<CaptureError> = Vec::new();
+    for event in events {
+        if let Err(err) = add_to_replay_group(&mut groups, event) {
+            rejected.push(err);
+        }
+    }
+
+    if groups.is_empty() {
+        // The whole batch is invalid, let the handler report it
+        return Err(rejected
+            .into_iter()
+            .next()
+            .unwrap_or(CaptureError::EmptyBatch));
+    }
+    for err in rejected {
+        tracing::log::warn!("rejected invalid snapshot event: {}", err);
+        report_dropped_events(err.reason(), 1);
+    }
+
+    let mut messages: Vec<ProcessedEvent> = Vec::with_capacity(groups.len());
+    for group in groups {
+        messages.extend(snapshot_messages(group, context, max_message_bytes)?);
+    }
+
+    // Messages are keyed by session_id, so chunks of a group are produced in order to the same partition
+    if messages.len() == 1 {
+        sink.send(messages.remove(0)).await
+    } else {
+        sink.send_batch(messages).await
+    }
+}
+
+/// Builds the messages of a single snapshot event, as `process_replay_events` would produce them.
+pub fn process_single_replay_event(
+    event: RawEvent,
+    context: &ProcessingContext,
+    max_message_bytes: usize,
+) -> Result<Vec<ProcessedEvent>, CaptureError> {
+    let mut groups: Vec<ReplayGroup> = Vec::with_capacity(1);
+    add_to_replay_group(&mut groups, event)?;
+    let group = groups.pop().ok_or(CaptureError::EmptyBatch)?;
+    snapshot_messages(group, context, max_message_bytes)
+}
+
+fn add_to_replay_group(
+    groups: &mut Vec<ReplayGroup>,
+    mut event: RawEvent,
 ) -> Result<(), CaptureError> {
-    // Grab metadata about the whole batch from the first event before
-    // we drop all the events as we rip out the snapshot data
-    let session_id = events[0]
+    let session_id = event
         .properties
         .remove("$session_id")
         .ok_or(CaptureError::MissingSessionId)?;
-    let window_id = events[0]
+    let session_id = session_id
+        .as_str()
+        .ok_or(CaptureError::InvalidSessionId)?
+        .to_string();
+    if Uuid::parse_str(&session_id).is_err() {
+        return Err(CaptureError::InvalidSessionId);
+    }
+    let window_id = event
         .properties
         .remove("$window_id")
-        .unwrap_or(session_id.clone());
-    let uuid = events[0].uuid.unwrap_or_else(uuid_v7);
-    let distinct_id = events[0].extract_distinct_id()?;
-    let snapshot_source = events[0]
-        .properties
-        .remove("$snapshot_source")
-        .unwrap_or(Value::String(String::from("web")));
+        .unwrap_or(Value::String(session_id.clone()));
+    let distinct_id = event.extract_distinct_id()?;
 
-    let mut snapshot_items: Vec<Value> = Vec::with_capacity(events.len());
-    for mut event in events {
-        let Some(snapshot_data) = event.properties.remove("$snapshot_data") else {
-            return Err(CaptureError::MissingSnapshotData);
-        };
-        match snapshot_data {
-            Value::Array(value) => {
-                snapshot_items.extend(value);
-            }
-            Value::Object(value) => {
-                snapshot_items.push(Value::Object(value));
-            }
-            _ => {
-                return Err(CaptureError::MissingSnapshotData);
-            }
-        }
+    let items = match event.properties.remove("$snapshot_data") {
+        Some(Value::Array(value)) => value,
+        Some(Value::Object(value)) => vec![Value::Object(value)],
+        _ => return Err(CaptureError::MissingSnapshotData),
+    };
+
+    match groups
+        .iter_mut()
+        .find(|group| group.session_id == session_id && group.window_id == window_id)
+    {
+        Some(group) => group.items.extend(items),
+        None => groups.push(ReplayGroup {
+            session_id,
+            window_id,
+            uuid: event.uuid.unwrap_or_else(uuid_v7),
+            distinct_id,
+            snapshot_source: event
+                .properties
+                .remove("$snapshot_source")
+                .unwrap_or(Value::String(String::from("web"))),
+            items,
+        }),
     }
+    Ok(())
+}
 
+/// Builds the `$snapshot_items` messages for a group, split to fit in `max_message_bytes`.
+fn snapshot_messages(
+    group: ReplayGroup,
+    context: &ProcessingContext,
+    max_message_bytes: usize,
+) -> Result<Vec<ProcessedEvent>, CaptureError> {
+    let ReplayGroup {
+        session_id,
+        window_id,
+        uuid,
+        distinct_id,
+        snapshot_source,
+        items,
+    } = group;
     let metadata = ProcessedEventMetadata {
         data_type: DataType::SnapshotMain,
-        session_id: Some(
-            session_id
-                .as_str()
-                .ok_or(CaptureError::InvalidSessionId)?
-                .to_string(),
-        ),
+        session_id: Some(session_id.clone()),
+        event_name: String::from("$snapshot_items"),
     };
 
-    let event = CapturedEvent {
-        uuid,
-        distinct_id: distinct_id.clone(),
-        ip: context.client_ip.clone(),
-        data: json!({
+    let snapshot_event = |uuid: Uuid, items: Vec<Value>, chunk: Option<(usize, usize)>| {
+        let mut data = json!({
             "event": "$snapshot_items",
             "properties": {
                 "distinct_id": distinct_id,
                 "$session_id": session_id,
                 "$window_id": window_id,
                 "$snapshot_source": snapshot_source,
-                "$snapshot_items": snapshot_items,
+                "$snapshot_items": items,
             }
-        })
-        .to_string(),
-        now: context.now.clone(),
-        sent_at: context.sent_at,
-        token: context.token.clone(),
+        });
+        // Only set on split batches, so that the consumer knows it has to reassemble them
+        if let Some((index, count)) = chunk {
+            data["properties"]["$snapshot_chunk_index"] = json!(index);
+            data["properties"]["$snapshot_chunk_count"] = json!(count);
+        }
+        ProcessedEvent {
+            metadata: metadata.clone(),
+            event: CapturedEvent {
+                uuid,
+                distinct_id: distinct_id.clone(),
+                ip: context.client_ip.clone(),
+                data: data.to_string(),
+                now: context.now.clone(),
+                sent_at: context.sent_at,
+                token: context.token.clone(),
+            },
+        }
     };
 
-    sink.send(ProcessedEvent { metadata, event }).await
+    // Measure the envelope with the chunk fields to know how much room is left for the items
+    let envelope = snapshot_event(uuid, Vec::new(), Some((0, 0)));
+    let envelope_bytes = serde_json::to_string(&envelope.event)
+        .map_err(|_| CaptureError::NonRetryableSinkError)?
+        .len();
+    let chunks = split_snapshot_items(
+        items,
+        max_message_bytes.saturating_sub(envelope_bytes + KAFKA_MESSAGE_OVERHEAD_BYTES),
+    );
+
+    if chunks.len() == 1 {
+        let items = chunks.into_iter().next().unwrap_or_default();
+        return Ok(vec![snapshot_event(uuid, items, None)]);
+    }
+
+    let count = chunks.len();
+    counter!("capture_replay_batches_split_total").increment(1);
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, items)| {
+            let uuid = if index == 0 { uuid } else { uuid_v7() };
+            snapshot_event(uuid, items, Some((index, count)))
+        })
+        .collect())
+}
+
+/// Splits snapshot items in chunks holding at most `max_bytes` once serialized in the
+/// `data` field of a CapturedEvent. Items larger than `max_bytes` get a chunk of their own,
+/// and will be rejected by the sink. Always returns at least one, maybe empty, chunk.
+fn split_snapshot_items(items: Vec<Value>, max_bytes: usize) -> Vec<Vec<Value>> {
+    let mut chunks = vec![];
+    let mut current = vec![];
+    let mut current_bytes = 0;
+    for item in items {
+        let item_bytes = escaped_json_size(&item) + 1; // Separating comma
+        if !current.is_empty() && current_bytes + item_bytes > max_bytes {
+            chunks.push(std::mem::take(&mut current));
+            current_bytes = 0;
+        }
+        current_bytes += item_bytes;
+        current.push(item);
+    }
+    chunks.push(current);
+    chunks
+}
+
+/// Size of a value once serialized to JSON and then embedded as a JSON string.
+fn escaped_json_size(value: &Value) -> usize {
+    let serialized = value.to_string();
+    let escaped = serialized
+        .bytes()
+        .filter(|c| *c == b'"' || *c == b'\\')
+        .count();
+    serialized.len() + escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::v0_endpoint::{escaped_json_size, split_snapshot_items};
+
+    #[test]
+    fn measure_escaped_items() {
+        let item = json!({"type": 2, "data": "a\"b"});
+        let embedded = serde_json::to_string(&item.to_string()).unwrap();
+        assert_eq!(embedded.len() - 2, escaped_json_size(&item));
+    }
+
+    #[test]
+    fn split_items_in_chunks() {
+        let item = json!({"data": "x".repeat(100)});
+        let item_bytes = escaped_json_size(&item) + 1;
+        let items: Vec<Value> = (0..10).map(|_| item.clone()).collect();
+
+        // Everything fits
+        let chunks = split_snapshot_items(items.clone(), 10 * item_bytes);
+        assert_eq!(vec![items.clone()], chunks);
+
+        // Three items per chunk, order is kept
+        let chunks = split_snapshot_items(items.clone(), 3 * item_bytes + 1);
+        assert_eq!(
+            vec![3, 3, 3, 1],
+            chunks.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+        assert_eq!(items, chunks.concat());
+
+        // Oversized items get their own chunk
+        let chunks = split_snapshot_items(items.clone(), 10);
+        assert_eq!(10, chunks.len());
+
+        // Empty batches still send one message
+        assert_eq!(vec![Vec::<Value>::new()], split_snapshot_items(vec![], 10));
+    }
 }
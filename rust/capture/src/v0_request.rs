@@ -1,9 +1,7 @@
 use std::collections::{HashMap, HashSet};
-use std::io::prelude::*;
 
 use bytes::{Buf, Bytes};
 use common_types::CapturedEvent;
-use flate2::read::GzDecoder;
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 use time::format_description::well_known::Iso8601;
@@ -12,16 +10,49 @@ use tracing::instrument;
 use uuid::Uuid;
 
 use crate::api::CaptureError;
-use crate::prometheus::report_dropped_events;
+use crate::body::decode_json;
 use crate::token::validate_token;
 
-#[derive(Deserialize, Default)]
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Compression {
     #[default]
     Unsupported,
 
     #[serde(rename = "gzip", alias = "gzip-js")]
     Gzip,
+    #[serde(rename = "zstd")]
+    Zstd,
+    #[serde(rename = "br", alias = "brotli")]
+    Brotli,
+    #[serde(rename = "deflate")]
+    Deflate,
+    #[serde(rename = "lz64", alias = "lz-string")]
+    Lz64,
+}
+
+impl Compression {
+    /// Maps a Content-Encoding header value to the matching compression, if supported.
+    pub fn from_content_encoding(value: &str) -> Option<Compression> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" | "gzip-js" => Some(Compression::Gzip),
+            "zstd" => Some(Compression::Zstd),
+            "br" => Some(Compression::Brotli),
+            "deflate" => Some(Compression::Deflate),
+            "lz64" => Some(Compression::Lz64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::Unsupported => "unsupported",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Brotli => "brotli",
+            Compression::Deflate => "deflate",
+            Compression::Lz64 => "lz64",
+        }
+    }
 }
 
 #[derive(Deserialize, Default)]
@@ -123,61 +154,12 @@ impl RawRequest {
     /// Takes a request payload and tries to decompress and unmarshall it.
     /// While posthog-js sends a compression query param, a sizable portion of requests
     /// fail due to it being missing when the body is compressed.
-    /// Instead of trusting the parameter, we peek at the payload's first three bytes to
-    /// detect gzip, fallback to uncompressed utf8 otherwise.
+    /// Instead of trusting the parameter, we peek at the payload's first bytes to detect
+    /// gzip, zstd or deflate, fallback to uncompressed utf8 otherwise.
     #[instrument(skip_all)]
     pub fn from_bytes(bytes: Bytes, limit: usize) -> Result<RawRequest, CaptureError> {
         tracing::debug!(len = bytes.len(), "decoding new event");
-
-        let payload = if bytes.starts_with(&GZIP_MAGIC_NUMBERS) {
-            let len = bytes.len();
-            let mut zipstream = GzDecoder::new(bytes.reader());
-            let chunk = &mut [0; 1024];
-            let mut buf = Vec::with_capacity(len);
-            loop {
-                let got = match zipstream.read(chunk) {
-                    Ok(got) => got,
-                    Err(e) => {
-                        tracing::error!("failed to read gzip stream: {}", e);
-                        return Err(CaptureError::RequestDecodingError(String::from(
-                            "invalid gzip data",
-                        )));
-                    }
-                };
-                if got == 0 {
-                    break;
-                }
-                buf.extend_from_slice(&chunk[..got]);
-                if buf.len() > limit {
-                    tracing::error!("GZIP decompression limit reached");
-                    report_dropped_events("event_too_big", 1);
-                    return Err(CaptureError::EventTooBig);
-                }
-            }
-            match String::from_utf8(buf) {
-                Ok(s) => s,
-                Err(e) => {
-                    tracing::error!("failed to decode gzip: {}", e);
-                    return Err(CaptureError::RequestDecodingError(String::from(
-                        "invalid gzip data",
-                    )));
-                }
-            }
-        } else {
-            let s = String::from_utf8(bytes.into()).map_err(|e| {
-                tracing::error!("failed to decode body: {}", e);
-                CaptureError::RequestDecodingError(String::from("invalid body encoding"))
-            })?;
-            if s.len() > limit {
-                tracing::error!("Request size limit reached");
-                report_dropped_events("event_too_big", 1);
-                return Err(CaptureError::EventTooBig);
-            }
-            s
-        };
-
-        tracing::debug!(json = payload, "decoded event data");
-        Ok(serde_json::from_str::<RawRequest>(&payload)?)
+        decode_json(bytes.reader(), None, limit)
     }
 
     pub fn events(self) -> Vec<RawEvent> {
@@ -237,6 +219,18 @@ pub fn extract_token(events: &[RawEvent]) -> Result<String, CaptureError> {
 }
 
 impl RawEvent {
+    /// Returns the type of data this event holds, that decides its destination topic
+    /// and the quota it counts against.
+    pub fn data_type(&self, historical_migration: bool) -> DataType {
+        match (self.event.as_str(), historical_migration) {
+            ("$$client_ingestion_warning", _) => DataType::ClientIngestionWarning,
+            ("$exception", _) => DataType::ExceptionMain,
+            ("$$heatmap", _) => DataType::HeatmapMain,
+            (_, true) => DataType::AnalyticsHistorical,
+            (_, false) => DataType::AnalyticsMain,
+        }
+    }
+
     pub fn extract_token(&self) -> Option<String> {
         match &self.token {
             Some(value) => Some(value.clone()),
@@ -284,7 +278,7 @@ pub struct ProcessingContext {
     pub historical_migration: bool,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum DataType {
     AnalyticsMain,
     AnalyticsHistorical,
@@ -294,16 +288,18 @@ pub enum DataType {
     SnapshotMain,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProcessedEvent {
     pub metadata: ProcessedEventMetadata,
     pub event: CapturedEvent,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProcessedEventMetadata {
     pub data_type: DataType,
     pub session_id: Option<String>,
+    #[serde(default)]
+    pub event_name: String, // Used for topic routing
 }
 
 #[cfg(test)]
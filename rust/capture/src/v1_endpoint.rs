@@ -0,0 +1,307 @@
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::{MatchedPath, Query, State};
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::{debug_handler, Json};
+use axum_client_ip::InsecureClientIp;
+use futures::future::join_all;
+use metrics::counter;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::api::{CaptureError, CaptureResponseCode, CaptureV1Response, RejectedEvent};
+use crate::body::{compression_hint, decode_body};
+use crate::bots::BotAction;
+use crate::debug_capture::{debug_request, DebugRequest};
+use crate::dedup::find_duplicates;
+use crate::limiters::rate::retry_after_secs;
+use crate::limiters::redis::QuotaResource;
+use crate::prometheus::report_dropped_events;
+use crate::router;
+use crate::v0_endpoint::{check_bot, privacy_settings, process_single_event};
+use crate::v0_request::{EventQuery, ProcessingContext};
+use crate::v1_request::{parse_event, peek_uuid, BatchRequest};
+
+/// Strict batch endpoint, only accepting the `BatchRequest` payload shape.
+///
+/// Events are validated and produced individually: invalid events are reported in the
+/// response instead of failing the whole batch, so that SDKs can drop or retry them
+/// without having to split batches. Billing limits only reject the events of the resources
+/// over quota, listed in `quota_limited`. Batches fully over quota or rate limited get a 429
+/// status, rate limits come with a `Retry-After` header.
+#[instrument(
+    skip_all,
+    fields(
+        path,
+        token,
+        batch_size,
+        user_agent,
+        content_encoding,
+        version,
+        compression,
+        historical_migration
+    )
+)]
+#[debug_handler]
+#[allow(clippy::too_many_arguments)]
+pub async fn batch(
+    state: State<router::State>,
+    ip: InsecureClientIp,
+    meta: Query<EventQuery>,
+    headers: HeaderMap,
+    method: Method,
+    uri: Uri,
+    path: MatchedPath,
+    body: Body,
+) -> Result<Response, CaptureError> {
+    let (body, mut debug) = debug_request(&state, &method, &uri, &headers, body, None).await;
+    let result = handle_batch(state, ip, meta, headers, path, &mut debug, body).await;
+    if let Some(debug) = debug {
+        debug.finish(&result);
+    }
+    result
+}
+
+async fn handle_batch(
+    state: State<router::State>,
+    InsecureClientIp(ip): InsecureClientIp,
+    meta: Query<EventQuery>,
+    headers: HeaderMap,
+    path: MatchedPath,
+    debug: &mut Option<DebugRequest>,
+    body: Body,
+) -> Result<Response, CaptureError> {
+    let user_agent = headers
+        .get("user-agent")
+        .map_or("unknown", |v| v.to_str().unwrap_or("unknown"));
+    let content_encoding = headers
+        .get("content-encoding")
+        .map_or("unknown", |v| v.to_str().unwrap_or("unknown"));
+
+    tracing::Span::current().record("user_agent", user_agent);
+    tracing::Span::current().record("content_encoding", content_encoding);
+    tracing::Span::current().record("version", meta.lib_version.clone());
+    tracing::Span::current().record(
+        "compression",
+        meta.compression.map_or("unknown", |c| c.as_str()),
+    );
+    tracing::Span::current().record("path", path.as_str().trim_end_matches('/'));
+
+    let hint = compression_hint(meta.compression, &headers);
+    let request = decode_body::<BatchRequest>(body, hint, state.event_size_limit).await?;
+    if let Err(err) = request.verify_token() {
+        report_dropped_events("token_shape_invalid", request.batch.len() as u64);
+        return Err(err);
+    }
+    if let Some(debug) = debug {
+        debug.set_token(&request.token);
+    }
+
+    tracing::Span::current().record("token", &request.token);
+    tracing::Span::current().record("historical_migration", request.historical_migration);
+    tracing::Span::current().record("batch_size", request.batch.len());
+
+    if request.batch.is_empty() {
+        tracing::log::warn!("rejected empty batch");
+        return Err(CaptureError::EmptyBatch);
+    }
+
+    let batch_size = request.batch.len();
+    counter!("capture_events_received_total").increment(batch_size as u64);
+
+    let mut context = ProcessingContext {
+        lib_version: meta.lib_version.clone(),
+        sent_at: request.sent_at().or(meta.sent_at()),
+        token: request.token.clone(),
+        now: state.timesource.current_time(),
+        client_ip: ip.to_string(),
+        historical_migration: request.historical_migration,
+    };
+
+    let mut response = CaptureV1Response {
+        status: CaptureResponseCode::Ok,
+        accepted: Vec::with_capacity(batch_size),
+        rejected: Vec::new(),
+        quota_limited: Vec::new(),
+    };
+
+    // Bot traffic is dropped silently, its events are reported as accepted
+    let bot = check_bot(&state, &context, &headers).await;
+    if bot
+        .as_ref()
+        .is_some_and(|bot| bot.action == BotAction::Drop)
+    {
+        report_dropped_events("bot_traffic", batch_size as u64);
+        response.accepted.extend(0..batch_size);
+        return Ok((StatusCode::OK, Json(response)).into_response());
+    }
+
+    // Only the events of the resources over quota are rejected
+    let limited = state
+        .billing_limiter
+        .limited_resources(&context.token)
+        .await;
+
+    if let Some(rate_limiter) = &state.rate_limiter {
+        if let Err(wait) = rate_limiter.check_token(&context.token).await {
+            report_dropped_events("rate_limited", batch_size as u64);
+            for (index, value) in request.batch.iter().enumerate() {
+                response.reject(index, peek_uuid(value), &CaptureError::RateLimited);
+            }
+            return Ok((
+                StatusCode::TOO_MANY_REQUESTS,
+                retry_after_header(wait),
+                Json(response),
+            )
+                .into_response());
+        }
+    }
+
+    let privacy = privacy_settings(&state, &mut context).await?;
+
+    // Duplicates were already ingested, they are reported as accepted
+    let duplicates = match &state.deduplicator {
+        None => vec![false; batch_size],
+        Some(deduplicator) => {
+            let uuids: Vec<Option<Uuid>> = request.batch.iter().map(peek_uuid).collect();
+            find_duplicates(deduplicator.as_ref(), &context.token, &uuids).await
+        }
+    };
+
+    // Longest wait across the events rejected by the per-distinct_id limit
+    let mut retry_after = None;
+    let mut over_quota = 0;
+    let mut events = Vec::with_capacity(batch_size);
+    for (index, value) in request.batch.into_iter().enumerate() {
+        if duplicates[index] {
+            report_dropped_events("duplicate_event", 1);
+            response.accepted.push(index);
+            continue;
+        }
+        let uuid = peek_uuid(&value);
+        let result = parse_event(value)
+            .map(|mut event| {
+                if let Some(bot) = &bot {
+                    bot.tag(&mut event);
+                }
+                if let Some(settings) = &privacy {
+                    settings.apply(&mut event);
+                }
+                event
+            })
+            .and_then(|event| {
+                match state
+                    .billing_limiter
+                    .resource(&event, context.historical_migration)
+                {
+                    Some(resource) if limited.contains(&resource) => {
+                        response.quota_limit(resource);
+                        Err(CaptureError::BillingLimit)
+                    }
+                    _ => Ok(event),
+                }
+            })
+            .and_then(|event| process_single_event(&event, &context))
+            .and_then(|event| match &state.rate_limiter {
+                None => Ok(event),
+                Some(rate_limiter) => rate_limiter
+                    .check_distinct_id(&context.token, &event.event.distinct_id)
+                    .map(|_| event)
+                    .map_err(|wait| {
+                        retry_after = retry_after.max(Some(wait));
+                        CaptureError::RateLimited
+                    }),
+            });
+        match result {
+            Ok(event) => events.push((index, event)),
+            Err(CaptureError::BillingLimit) => {
+                report_dropped_events("over_quota", 1);
+                response.reject(index, uuid, &CaptureError::BillingLimit);
+                over_quota += 1;
+            }
+            Err(err) => {
+                report_dropped_events(err.reason(), 1);
+                response.reject(index, uuid, &err);
+            }
+        }
+    }
+
+    if over_quota == batch_size {
+        return Ok((StatusCode::TOO_MANY_REQUESTS, Json(response)).into_response());
+    }
+
+    tracing::debug!(context=?context, events=?events, "processed {} events", events.len());
+
+    // Produce events individually to get a result for each of them. join_all polls the
+    // futures in order, so events are still enqueued in the producer in batch order.
+    let sink = &state.sink;
+    let results = join_all(events.into_iter().map(|(index, event)| async move {
+        let uuid = event.event.uuid;
+        (index, uuid, sink.send(event).await)
+    }))
+    .await;
+
+    for (index, uuid, result) in results {
+        match result {
+            Ok(()) => response.accepted.push(index),
+            Err(err) => {
+                report_dropped_events(err.reason(), 1);
+                response.reject(index, Some(uuid), &err);
+            }
+        }
+    }
+    response.accepted.sort_unstable();
+    response.rejected.sort_by_key(|r| r.index);
+
+    // Rejected events were not ingested, don't drop them as duplicates when sent again
+    if let Some(deduplicator) = &state.deduplicator {
+        let rejected: Vec<Uuid> = response
+            .rejected
+            .iter()
+            .filter_map(|rejected| rejected.uuid)
+            .collect();
+        if !rejected.is_empty() {
+            deduplicator.forget(&context.token, &rejected).await;
+        }
+    }
+
+    if !response.rejected.is_empty() {
+        tracing::log::warn!(
+            "rejected {} out of {} events",
+            response.rejected.len(),
+            batch_size
+        );
+    }
+
+    match retry_after {
+        None => Ok((StatusCode::OK, Json(response)).into_response()),
+        Some(wait) => {
+            Ok((StatusCode::OK, retry_after_header(wait), Json(response)).into_response())
+        }
+    }
+}
+
+fn retry_after_header(wait: Duration) -> [(HeaderName, HeaderValue); 1] {
+    [(RETRY_AFTER, HeaderValue::from(retry_after_secs(wait)))]
+}
+
+impl CaptureV1Response {
+    fn reject(&mut self, index: usize, uuid: Option<Uuid>, err: &CaptureError) {
+        self.rejected.push(RejectedEvent {
+            index,
+            uuid,
+            reason: err.reason().to_string(),
+            retryable: err.is_retryable(),
+        });
+    }
+
+    fn quota_limit(&mut self, resource: QuotaResource) {
+        let resource = resource.as_str().to_string();
+        if !self.quota_limited.contains(&resource) {
+            self.quota_limited.push(resource);
+        }
+    }
+}
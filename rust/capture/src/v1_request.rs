@@ -0,0 +1,129 @@
+use bytes::{Buf, Bytes};
+use serde::Deserialize;
+use serde_json::Value;
+use time::format_description::well_known::Iso8601;
+use time::OffsetDateTime;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::api::CaptureError;
+use crate::body::decode_json;
+use crate::token::validate_token;
+use crate::v0_request::RawEvent;
+
+/// The only payload shape accepted by the v1 endpoint.
+/// Events are kept as raw JSON values until they are validated one by one, so that
+/// a single malformed event does not fail the whole batch.
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    #[serde(alias = "api_key")]
+    pub token: String,
+    #[serde(default)]
+    pub historical_migration: bool,
+    pub sent_at: Option<String>,
+    pub batch: Vec<Value>,
+}
+
+impl BatchRequest {
+    /// Takes a request payload and tries to decompress and unmarshall it.
+    /// Unlike v0, we only accept a JSON body, optionally compressed.
+    #[instrument(skip_all)]
+    pub fn from_bytes(bytes: Bytes, limit: usize) -> Result<BatchRequest, CaptureError> {
+        tracing::debug!(len = bytes.len(), "decoding new batch");
+        decode_json(bytes.reader(), None, limit)
+    }
+
+    pub fn verify_token(&self) -> Result<(), CaptureError> {
+        validate_token(&self.token)?;
+        Ok(())
+    }
+
+    pub fn sent_at(&self) -> Option<OffsetDateTime> {
+        self.sent_at
+            .as_ref()
+            .and_then(|value| OffsetDateTime::parse(value, &Iso8601::DEFAULT).ok())
+    }
+}
+
+/// Deserializes one event of a v1 batch.
+pub fn parse_event(value: Value) -> Result<RawEvent, CaptureError> {
+    Ok(serde_json::from_value::<RawEvent>(value)?)
+}
+
+/// Best-effort lookup of the event uuid, to help clients correlate rejections
+/// for events that could not be deserialized.
+pub fn peek_uuid(value: &Value) -> Option<Uuid> {
+    value
+        .get("uuid")
+        .and_then(Value::as_str)
+        .and_then(|s| Uuid::parse_str(s).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::{parse_event, peek_uuid, BatchRequest};
+    use crate::api::CaptureError;
+    use crate::token::InvalidTokenReason;
+
+    #[test]
+    fn decode_batch_request() {
+        let input = json!({
+            "api_key": "my_token",
+            "historical_migration": true,
+            "sent_at": "2024-01-01T00:00:00Z",
+            "batch": [
+                {"event": "e1", "distinct_id": "id1"},
+                {"not_an_event": true},
+            ]
+        });
+        let request =
+            BatchRequest::from_bytes(input.to_string().into(), 2048).expect("failed to parse");
+
+        assert_eq!("my_token", request.token);
+        assert!(request.historical_migration);
+        assert!(request.sent_at().is_some());
+        assert_eq!(2, request.batch.len());
+
+        // Events are validated individually
+        let mut events = request.batch.into_iter();
+        let first = parse_event(events.next().unwrap()).expect("failed to parse first event");
+        assert_eq!("e1", first.event);
+        assert!(matches!(
+            parse_event(events.next().unwrap()),
+            Err(CaptureError::RequestParsingError(_))
+        ));
+    }
+
+    #[test]
+    fn reject_other_payload_shapes() {
+        let input = json!([{"event": "e1", "distinct_id": "id1", "token": "my_token"}]);
+        assert!(matches!(
+            BatchRequest::from_bytes(input.to_string().into(), 2048),
+            Err(CaptureError::RequestParsingError(_))
+        ));
+    }
+
+    #[test]
+    fn verify_token() {
+        let input = json!({"token": "phx_hellothere", "batch": []});
+        let request =
+            BatchRequest::from_bytes(input.to_string().into(), 2048).expect("failed to parse");
+        assert!(matches!(
+            request.verify_token(),
+            Err(CaptureError::TokenValidationError(
+                InvalidTokenReason::PersonalApiKey
+            ))
+        ));
+    }
+
+    #[test]
+    fn peek_uuid_on_invalid_events() {
+        let uuid = Uuid::now_v7();
+        assert_eq!(Some(uuid), peek_uuid(&json!({"uuid": uuid.to_string()})));
+        assert_eq!(None, peek_uuid(&json!({"uuid": "not-a-uuid"})));
+        assert_eq!(None, peek_uuid(&json!("not an object")));
+    }
+}
@@ -0,0 +1,248 @@
+use axum::body::Body;
+use axum::extract::{MatchedPath, Query, State};
+use axum::http::HeaderMap;
+use axum::{debug_handler, Json};
+use axum_client_ip::InsecureClientIp;
+use metrics::counter;
+use tracing::instrument;
+
+use crate::api::{CaptureError, EventValidation, ValidationReport};
+use crate::body::{compression_hint, detect_compression, form_body_limit, read_body};
+use crate::router;
+use crate::v0_endpoint::{
+    decode_request, privacy_settings, process_single_event, process_single_replay_event,
+};
+use crate::v0_request::{Compression, DataType, EventQuery, ProcessingContext, RawEvent};
+
+/// Dry-run endpoint for SDK authors and customers debugging their integration.
+///
+/// Accepts the same payloads as the v0 endpoints and runs them through the same decoding,
+/// token validation and event processing, but nothing is produced to Kafka, and limits are
+/// not applied. Instead of an error status, the response reports how the payload and each
+/// of its events would have been processed, and what would have been rejected.
+#[instrument(skip_all, fields(path, token, user_agent, content_type))]
+#[debug_handler]
+pub async fn validate(
+    state: State<router::State>,
+    InsecureClientIp(ip): InsecureClientIp,
+    meta: Query<EventQuery>,
+    headers: HeaderMap,
+    path: MatchedPath,
+    body: Body,
+) -> Json<ValidationReport> {
+    let user_agent = headers
+        .get("user-agent")
+        .map_or("unknown", |v| v.to_str().unwrap_or("unknown"));
+    tracing::Span::current().record("user_agent", user_agent);
+    tracing::Span::current().record("path", path.as_str().trim_end_matches('/'));
+    counter!("capture_validation_requests_total").increment(1);
+
+    let hint = compression_hint(meta.compression, &headers);
+    let mut report = ValidationReport::default();
+
+    // Buffer the body to report its compression, validated payloads are small
+    // Form bodies are larger than the payload they hold, see `form_body_limit`
+    let body = match read_body(body, form_body_limit(state.event_size_limit)).await {
+        Ok(body) => body,
+        Err(err) => return Json(report.failed(&err)),
+    };
+    report.compression = body_compression(&headers, &body, hint).to_string();
+
+    let request = match decode_request(&state, hint, &headers, Body::from(body)).await {
+        Ok(request) => request,
+        Err(err) => return Json(report.failed(&err)),
+    };
+
+    report.historical_migration = request.historical_migration();
+    let token = request.extract_and_verify_token();
+    let sent_at = request.sent_at().or(meta.sent_at());
+    let events = request.events();
+    let token = match token {
+        Ok(token) => token,
+        Err(err) => {
+            report.events = events
+                .iter()
+                .enumerate()
+                .map(|(index, event)| EventValidation::new(index, event))
+                .collect();
+            return Json(report.failed(&err));
+        }
+    };
+    tracing::Span::current().record("token", &token);
+    report.token = Some(token.clone());
+
+    if events.is_empty() {
+        return Json(report.failed(&CaptureError::EmptyBatch));
+    }
+
+    let mut context = ProcessingContext {
+        lib_version: meta.lib_version.clone(),
+        sent_at,
+        token,
+        now: state.timesource.current_time(),
+        client_ip: ip.to_string(),
+        historical_migration: report.historical_migration,
+    };
+
+    // Report the events as they would be after the team privacy settings
+    let mut events = events;
+    match privacy_settings(&state, &mut context).await {
+        Ok(Some(settings)) => events.iter_mut().for_each(|event| settings.apply(event)),
+        Ok(None) => {}
+        Err(err) => return Json(report.failed(&err)),
+    }
+
+    report.events = events
+        .into_iter()
+        .enumerate()
+        .map(|(index, event)| validate_event(&state, &context, index, event))
+        .collect();
+
+    Json(report)
+}
+
+/// Returns the compression we would decode the body with, as reported to the client.
+fn body_compression(headers: &HeaderMap, body: &[u8], hint: Option<Compression>) -> &'static str {
+    let is_form = headers
+        .get("content-type")
+        .is_some_and(|v| v.as_bytes() == b"application/x-www-form-urlencoded");
+    match (is_form, hint) {
+        (true, Some(Compression::Lz64)) => Compression::Lz64.as_str(),
+        (true, _) => "base64",
+        (false, _) => {
+            let head = &body[..body.len().min(4)];
+            detect_compression(head, hint).map_or("none", |c| c.as_str())
+        }
+    }
+}
+
+fn validate_event(
+    state: &router::State,
+    context: &ProcessingContext,
+    index: usize,
+    event: RawEvent,
+) -> EventValidation {
+    let mut validation = EventValidation::new(index, &event);
+    if event.event == "$snapshot" {
+        validate_snapshot(state, context, event, &mut validation);
+        return validation;
+    }
+
+    let processed = match process_single_event(&event, context) {
+        Ok(processed) => processed,
+        Err(err) => {
+            validation.error = Some((&err).into());
+            return validation;
+        }
+    };
+
+    validation.uuid = Some(processed.event.uuid);
+    validation.data_type = Some(processed.metadata.data_type);
+    validation.partition_key = Some(processed.event.key());
+    match serde_json::to_string(&processed.event) {
+        Ok(payload) => {
+            validation.size = Some(payload.len());
+            if payload.len() > state.replay_message_max_bytes {
+                validation.error = Some((&CaptureError::EventTooBig).into());
+            }
+        }
+        Err(e) => {
+            tracing::error!("failed to serialize event: {}", e);
+            validation.error = Some((&CaptureError::NonRetryableSinkError).into());
+        }
+    }
+    validation
+}
+
+/// Snapshots are validated and keyed by session like on the `/s` endpoint, and might be split
+/// in several messages: the reported size is the one of the largest.
+fn validate_snapshot(
+    state: &router::State,
+    context: &ProcessingContext,
+    event: RawEvent,
+    validation: &mut EventValidation,
+) {
+    let messages = match process_single_replay_event(event, context, state.replay_message_max_bytes)
+    {
+        Ok(messages) => messages,
+        Err(err) => {
+            validation.error = Some((&err).into());
+            return;
+        }
+    };
+
+    let Some(first) = messages.first() else {
+        return;
+    };
+    validation.uuid = Some(first.event.uuid);
+    validation.data_type = Some(DataType::SnapshotMain);
+    validation.partition_key = first.metadata.session_id.clone();
+    let mut largest = 0;
+    for message in &messages {
+        match serde_json::to_string(&message.event) {
+            Ok(payload) => largest = largest.max(payload.len()),
+            Err(e) => {
+                tracing::error!("failed to serialize event: {}", e);
+                validation.error = Some((&CaptureError::NonRetryableSinkError).into());
+                return;
+            }
+        }
+    }
+    validation.size = Some(largest);
+    if largest > state.replay_message_max_bytes {
+        validation.error = Some((&CaptureError::EventTooBig).into());
+    }
+}
+
+impl ValidationReport {
+    fn failed(mut self, err: &CaptureError) -> Self {
+        self.error = Some(err.into());
+        self
+    }
+}
+
+impl EventValidation {
+    fn new(index: usize, event: &RawEvent) -> Self {
+        EventValidation {
+            index,
+            event: event.event.clone(),
+            uuid: event.uuid,
+            distinct_id: event.extract_distinct_id().ok(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderMap;
+
+    use crate::v0_request::Compression;
+    use crate::validate_endpoint::body_compression;
+
+    #[test]
+    fn report_body_compression() {
+        let json = HeaderMap::new();
+        let mut form = HeaderMap::new();
+        form.insert(
+            "content-type",
+            "application/x-www-form-urlencoded".parse().unwrap(),
+        );
+
+        assert_eq!("none", body_compression(&json, b"{}", None));
+        assert_eq!(
+            "none",
+            body_compression(&json, b"", Some(Compression::Gzip))
+        );
+        assert_eq!("gzip", body_compression(&json, &[0x1f, 0x8b, 8, 0], None));
+        assert_eq!(
+            "brotli",
+            body_compression(&json, b"abcd", Some(Compression::Brotli))
+        );
+        assert_eq!("base64", body_compression(&form, b"data=e30=", None));
+        assert_eq!(
+            "lz64",
+            body_compression(&form, b"data=abc", Some(Compression::Lz64))
+        );
+    }
+}
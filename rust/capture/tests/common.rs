@@ -25,10 +25,13 @@ use tokio::sync::Notify;
 use tokio::time::timeout;
 use tracing::{debug, warn};
 
+use capture::bots::BOT_FILTERING_CACHE_KEY;
 use capture::config::{CaptureMode, Config, KafkaConfig};
+use capture::debug_capture::DEBUG_CAPTURE_CACHE_KEY;
 use capture::limiters::redis::{
     QuotaResource, OVERFLOW_LIMITER_CACHE_KEY, QUOTA_LIMITER_CACHE_KEY,
 };
+use capture::privacy::PRIVACY_SETTINGS_CACHE_KEY;
 use capture::server::serve;
 
 pub static DEFAULT_CONFIG: Lazy<Config> = Lazy::new(|| Config {
@@ -39,6 +42,35 @@ pub static DEFAULT_CONFIG: Lazy<Config> = Lazy::new(|| Config {
     overflow_burst_limit: NonZeroU32::new(5).unwrap(),
     overflow_per_second_limit: NonZeroU32::new(10).unwrap(),
     overflow_forced_keys: None,
+    overflow_redis_enabled: false,
+    rate_limit_enabled: false,
+    rate_limit_per_token_per_second: NonZeroU32::new(100).unwrap(),
+    rate_limit_per_token_burst: NonZeroU32::new(1000).unwrap(),
+    rate_limit_per_distinct_id_per_second: None,
+    rate_limit_per_distinct_id_burst: NonZeroU32::new(100).unwrap(),
+    rate_limit_global_per_second: None,
+    rate_limit_global_burst: NonZeroU32::new(10000).unwrap(),
+    import_events_per_second: NonZeroU32::new(1000).unwrap(),
+    dedup_backend: None,
+    dedup_window_secs: 60,
+    dedup_memory_max_entries: 1_000_000,
+    routing_enabled: false,
+    routing_rules: None,
+    privacy_enabled: false,
+    bot_filtering_enabled: false,
+    bot_ip_ranges: String::new(),
+    load_shedding_enabled: false,
+    load_shedding_low_priority_watermark: 0.6,
+    load_shedding_reject_watermark: 0.85,
+    shutdown_drain_delay_secs: 0,
+    shutdown_requests_timeout_secs: 5,
+    shutdown_flush_timeout_secs: 5,
+    debug_capture_destination: None,
+    debug_capture_sample_rate: 1.0,
+    debug_capture_max_body_kib: 512,
+    debug_capture_kafka_topic: "capture_debug_requests".to_string(),
+    debug_capture_file_path: "capture-debug.jsonl".to_string(),
+    debug_capture_file_max_mib: 100,
     kafka: KafkaConfig {
         kafka_producer_linger_ms: 0, // Send messages as soon as possible
         kafka_producer_queue_mib: 10,
@@ -57,6 +89,14 @@ pub static DEFAULT_CONFIG: Lazy<Config> = Lazy::new(|| Config {
         kafka_metadata_max_age_ms: 60000,
         kafka_producer_max_retries: 2,
     },
+    spool_enabled: false,
+    spool_path: "/tmp/capture-spool".to_string(),
+    spool_max_mib: 64,
+    tee_destination: None,
+    tee_sample_rate: 0.0,
+    tee_tokens: None,
+    tee_kafka_hosts: None,
+    tee_file_path: "/tmp/capture-tee.jsonl".to_string(),
     otel_url: None,
     otel_sampling_rate: 0.0,
     otel_service_name: "capture-testing".to_string(),
@@ -104,6 +144,28 @@ impl ServerHandle {
         Self { addr, shutdown }
     }
 
+    /// Starts a graceful shutdown, the server stops once the drain delay is over.
+    pub fn stop(&self) {
+        self.shutdown.notify_one()
+    }
+
+    pub async fn readiness(&self) -> reqwest::Response {
+        reqwest::get(format!("http://{:?}/_readiness", self.addr))
+            .await
+            .expect("failed to send request")
+    }
+
+    /// Waits for the background loaders the server needs before accepting requests.
+    pub async fn wait_until_ready(&self) {
+        for _ in 0..50 {
+            if self.readiness().await.status().is_success() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("server did not get ready");
+    }
+
     pub async fn capture_events<T: Into<reqwest::Body>>(&self, body: T) -> reqwest::Response {
         let client = reqwest::Client::new();
         client
@@ -114,6 +176,21 @@ impl ServerHandle {
             .expect("failed to send request")
     }
 
+    pub async fn capture_events_as<T: Into<reqwest::Body>>(
+        &self,
+        user_agent: &str,
+        body: T,
+    ) -> reqwest::Response {
+        let client = reqwest::Client::new();
+        client
+            .post(format!("http://{:?}/i/v0/e", self.addr))
+            .header("user-agent", user_agent)
+            .body(body)
+            .send()
+            .await
+            .expect("failed to send request")
+    }
+
     pub async fn capture_to_batch<T: Into<reqwest::Body>>(&self, body: T) -> reqwest::Response {
         let client = reqwest::Client::new();
         client
@@ -124,6 +201,57 @@ impl ServerHandle {
             .expect("failed to send request")
     }
 
+    pub async fn capture_to_v1_batch<T: Into<reqwest::Body>>(&self, body: T) -> reqwest::Response {
+        let client = reqwest::Client::new();
+        client
+            .post(format!("http://{:?}/i/v1/batch", self.addr))
+            .body(body)
+            .send()
+            .await
+            .expect("failed to send request")
+    }
+
+    pub async fn validate<T: Into<reqwest::Body>>(&self, body: T) -> reqwest::Response {
+        let client = reqwest::Client::new();
+        client
+            .post(format!("http://{:?}/i/v0/validate", self.addr))
+            .body(body)
+            .send()
+            .await
+            .expect("failed to send request")
+    }
+
+    pub async fn capture_import<T: Into<reqwest::Body>>(
+        &self,
+        token: &str,
+        body: T,
+    ) -> reqwest::Response {
+        let client = reqwest::Client::new();
+        client
+            .post(format!("http://{:?}/i/v0/import", self.addr))
+            .query(&[("token", token)])
+            .body(body)
+            .send()
+            .await
+            .expect("failed to send request")
+    }
+
+    pub async fn capture_segment<T: Into<reqwest::Body>>(
+        &self,
+        call: &str,
+        write_key: &str,
+        body: T,
+    ) -> reqwest::Response {
+        let client = reqwest::Client::new();
+        client
+            .post(format!("http://{:?}/v1/{}", self.addr, call))
+            .basic_auth(write_key, Some(""))
+            .body(body)
+            .send()
+            .await
+            .expect("failed to send request")
+    }
+
     pub async fn capture_recording<T: Into<reqwest::Body>>(&self, body: T) -> reqwest::Response {
         let client = reqwest::Client::new();
         client
@@ -309,6 +437,52 @@ impl PrefixedRedis {
             .zadd::<String, i64, &str, i64>(key, token, score)
             .expect("failed to insert in redis");
     }
+
+    pub fn set_bot_filtering(&self, token: &str, action: &str) {
+        let key = format!("{}{}", self.key_prefix, BOT_FILTERING_CACHE_KEY);
+        self.client
+            .get_connection()
+            .expect("failed to get connection")
+            .hset::<String, &str, &str, i64>(key, token, action)
+            .expect("failed to insert in redis");
+    }
+
+    pub fn set_debug_capture(&self, token: &str, until: i64) {
+        let key = format!("{}{}", self.key_prefix, DEBUG_CAPTURE_CACHE_KEY);
+        self.client
+            .get_connection()
+            .expect("failed to get connection")
+            .hset::<String, &str, i64, i64>(key, token, until)
+            .expect("failed to insert in redis");
+    }
+
+    pub fn set_privacy_settings(&self, token: &str, settings: serde_json::Value) {
+        let key = format!("{}{}", self.key_prefix, PRIVACY_SETTINGS_CACHE_KEY);
+        self.client
+            .get_connection()
+            .expect("failed to get connection")
+            .hset::<String, &str, String, i64>(key, token, settings.to_string())
+            .expect("failed to insert in redis");
+    }
+
+    /// Stores a value that can't be loaded as privacy settings, until `repair_privacy_settings`.
+    pub fn break_privacy_settings(&self) {
+        let key = format!("{}{}", self.key_prefix, PRIVACY_SETTINGS_CACHE_KEY);
+        self.client
+            .get_connection()
+            .expect("failed to get connection")
+            .set::<String, &str, ()>(key, "broken")
+            .expect("failed to insert in redis");
+    }
+
+    pub fn repair_privacy_settings(&self) {
+        let key = format!("{}{}", self.key_prefix, PRIVACY_SETTINGS_CACHE_KEY);
+        self.client
+            .get_connection()
+            .expect("failed to get connection")
+            .del::<String, i64>(key)
+            .expect("failed to delete from redis");
+    }
 }
 
 pub fn random_string(prefix: &str, length: usize) -> String {
@@ -6,7 +6,7 @@ use base64::engine::general_purpose;
 use base64::Engine;
 use capture::api::{CaptureError, CaptureResponse, CaptureResponseCode};
 use capture::config::CaptureMode;
-use capture::limiters::redis::{QuotaResource, RedisLimiter, QUOTA_LIMITER_CACHE_KEY};
+use capture::limiters::billing::BillingLimiter;
 use capture::redis::MockRedisClient;
 use capture::router::router;
 use capture::sinks::Event;
@@ -17,6 +17,8 @@ use serde::Deserialize;
 use serde_json::{json, Value};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::num::NonZeroU32;
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 use time::format_description::well_known::{Iso8601, Rfc3339};
 use time::{Duration, OffsetDateTime};
@@ -102,25 +104,29 @@ async fn it_matches_django_capture_behaviour() -> anyhow::Result<()> {
         let timesource = FixedTime { time: case.now };
 
         let redis = Arc::new(MockRedisClient::new());
-        let billing_limiter = RedisLimiter::new(
-            Duration::weeks(1),
-            redis.clone(),
-            QUOTA_LIMITER_CACHE_KEY.to_string(),
-            None,
-            QuotaResource::Events,
-        )
-        .expect("failed to create billing limiter");
+        let billing_limiter =
+            BillingLimiter::new(CaptureMode::Events, Duration::weeks(1), redis.clone(), None)
+                .expect("failed to create billing limiter");
 
         let app = router(
             timesource,
             liveness.clone(),
+            Arc::new(AtomicBool::new(true)),
             sink.clone(),
             redis,
             billing_limiter,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            NonZeroU32::new(1000).unwrap(),
             false,
             CaptureMode::Events,
             None,
             25 * 1024 * 1024,
+            1024 * 1024,
         );
 
         let client = TestClient::new(app);
@@ -4,7 +4,13 @@ use time::Duration;
 use crate::common::*;
 use anyhow::Result;
 use assert_json_diff::assert_json_include;
+use capture::api::{
+    CaptureResponse, CaptureResponseCode, CaptureV1Response, ImportSummary, RejectedEvent,
+    RejectedLine, ValidationReport,
+};
+use capture::config::{DebugCaptureDestination, DedupBackend};
 use capture::limiters::redis::QuotaResource;
+use capture::v0_request::DataType;
 use reqwest::StatusCode;
 use serde_json::json;
 use uuid::Uuid;
@@ -165,6 +171,71 @@ async fn it_captures_a_historical_batch() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn it_imports_ndjson_to_the_historical_topic() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let distinct_id1 = random_string("id", 16);
+    let distinct_id2 = random_string("id", 16);
+
+    let main_topic = EphemeralTopic::new().await;
+    let histo_topic = EphemeralTopic::new().await;
+    let server = ServerHandle::for_topics(&main_topic, &histo_topic).await;
+
+    let lines = [
+        json!({"event": "event1", "distinct_id": distinct_id1}).to_string(),
+        String::new(),
+        json!({"event": "event2"}).to_string(),
+        "not json".to_string(),
+        json!({"event": "event3", "distinct_id": distinct_id2, "token": token}).to_string(),
+    ];
+    let res = server.capture_import(&token, lines.join("\n")).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let summary: ImportSummary = res.json().await?;
+    assert_eq!(2, summary.accepted_count);
+    assert_eq!(vec![(1, 1), (5, 5)], summary.accepted);
+    assert_eq!(2, summary.rejected_count);
+    assert_eq!(
+        vec![
+            RejectedLine {
+                line: 3,
+                reason: "missing_distinct_id".to_string(),
+                retryable: false,
+            },
+            RejectedLine {
+                line: 4,
+                reason: "request_parsing_error".to_string(),
+                retryable: false,
+            }
+        ],
+        summary.rejected
+    );
+    assert!(summary.error.is_none());
+
+    assert_json_include!(
+        actual: histo_topic.next_event()?,
+        expected: json!({
+            "token": token,
+            "distinct_id": distinct_id1
+        })
+    );
+    assert_json_include!(
+        actual: histo_topic.next_event()?,
+        expected: json!({
+            "token": token,
+            "distinct_id": distinct_id2
+        })
+    );
+    histo_topic.assert_empty();
+    main_topic.assert_empty();
+
+    // The token is required
+    let res = server.capture_import("", lines.join("\n")).await;
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn it_overflows_events_on_burst() -> Result<()> {
     setup_tracing();
@@ -412,6 +483,428 @@ async fn it_applies_billing_limits() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn it_reports_per_event_results_on_v1_batch() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+    let invalid_uuid = Uuid::now_v7();
+
+    let main_topic = EphemeralTopic::new().await;
+    let histo_topic = EphemeralTopic::new().await;
+    let server = ServerHandle::for_topics(&main_topic, &histo_topic).await;
+
+    let payload = json!({
+        "token": token,
+        "batch": [{
+            "event": "event1",
+            "distinct_id": distinct_id
+        },{
+            "uuid": invalid_uuid,
+            "event": "missing_distinct_id"
+        },{
+            "event": "event3",
+            "distinct_id": distinct_id
+        }]
+    });
+    let res = server.capture_to_v1_batch(payload.to_string()).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(
+        CaptureV1Response {
+            status: CaptureResponseCode::Ok,
+            accepted: vec![0, 2],
+            rejected: vec![RejectedEvent {
+                index: 1,
+                uuid: Some(invalid_uuid),
+                reason: "missing_distinct_id".to_string(),
+                retryable: false,
+            }],
+            quota_limited: vec![],
+        },
+        res.json().await?
+    );
+
+    // Valid events are produced in order
+    assert_json_include!(
+        actual: main_topic.next_event()?,
+        expected: json!({
+            "token": token,
+            "distinct_id": distinct_id
+        })
+    );
+    assert_json_include!(
+        actual: main_topic.next_event()?,
+        expected: json!({
+            "token": token,
+            "distinct_id": distinct_id
+        })
+    );
+    main_topic.assert_empty();
+
+    // Only the batch payload shape is accepted
+    let payload = json!([{
+        "token": token,
+        "event": "event1",
+        "distinct_id": distinct_id
+    }]);
+    let res = server.capture_to_v1_batch(payload.to_string()).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_returns_quota_errors_on_v1_batch() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+
+    let topic = EphemeralTopic::new().await;
+    let redis = PrefixedRedis::new().await;
+    redis.add_billing_limit(QuotaResource::Events, &token, Duration::seconds(60));
+
+    let mut config = DEFAULT_CONFIG.clone();
+    config.redis_key_prefix = redis.key_prefix();
+    config.kafka.kafka_topic = topic.topic_name().to_string();
+    let server = ServerHandle::for_config(config).await;
+
+    let payload = json!({
+        "token": token,
+        "batch": [{"event": "to drop","distinct_id": distinct_id}]
+    });
+    let res = server.capture_to_v1_batch(payload.to_string()).await;
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, res.status());
+    let response: CaptureV1Response = res.json().await?;
+    assert!(response.accepted.is_empty());
+    assert_eq!(1, response.rejected.len());
+    assert_eq!("quota_limited", response.rejected[0].reason);
+    assert_eq!(vec!["events".to_string()], response.quota_limited);
+    topic.assert_empty();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_applies_billing_limits_per_resource() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+
+    let main_topic = EphemeralTopic::new().await;
+    let exceptions_topic = EphemeralTopic::new().await;
+    let heatmaps_topic = EphemeralTopic::new().await;
+    let redis = PrefixedRedis::new().await;
+    redis.add_billing_limit(QuotaResource::Events, &token, Duration::seconds(60));
+
+    let mut config = DEFAULT_CONFIG.clone();
+    config.redis_key_prefix = redis.key_prefix();
+    config.kafka.kafka_topic = main_topic.topic_name().to_string();
+    config.kafka.kafka_exceptions_topic = exceptions_topic.topic_name().to_string();
+    config.kafka.kafka_heatmaps_topic = heatmaps_topic.topic_name().to_string();
+    let server = ServerHandle::for_config(config).await;
+
+    // Events are over quota, exceptions and heatmaps of the same batch go through
+    let payload = json!({
+        "token": token,
+        "batch": [
+            {"event": "to drop", "distinct_id": distinct_id},
+            {"event": "$exception", "distinct_id": distinct_id},
+            {"event": "$$heatmap", "distinct_id": distinct_id}
+        ]
+    });
+    let res = server.capture_events(payload.to_string()).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let response: CaptureResponse = res.json().await?;
+    assert_eq!(Some(vec!["events".to_string()]), response.quota_limited);
+
+    let res = server.capture_to_v1_batch(payload.to_string()).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let response: CaptureV1Response = res.json().await?;
+    assert_eq!(vec![1, 2], response.accepted);
+    assert_eq!(1, response.rejected.len());
+    assert_eq!(0, response.rejected[0].index);
+    assert_eq!("quota_limited", response.rejected[0].reason);
+    assert_eq!(vec!["events".to_string()], response.quota_limited);
+
+    for _ in 0..2 {
+        assert_json_include!(
+            actual: exceptions_topic.next_event()?,
+            expected: json!({"token": token, "distinct_id": distinct_id})
+        );
+        assert_json_include!(
+            actual: heatmaps_topic.next_event()?,
+            expected: json!({"token": token, "distinct_id": distinct_id})
+        );
+    }
+    main_topic.assert_empty();
+    exceptions_topic.assert_empty();
+    heatmaps_topic.assert_empty();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_returns_rate_limit_errors_on_v1_batch() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+
+    let topic = EphemeralTopic::new().await;
+    let redis = PrefixedRedis::new().await;
+
+    let mut config = DEFAULT_CONFIG.clone();
+    config.redis_key_prefix = redis.key_prefix();
+    config.kafka.kafka_topic = topic.topic_name().to_string();
+    config.rate_limit_enabled = true;
+    config.rate_limit_per_token_per_second = NonZeroU32::new(1).unwrap();
+    config.rate_limit_per_token_burst = NonZeroU32::new(1).unwrap();
+    let server = ServerHandle::for_config(config).await;
+
+    let payload = json!({
+        "token": token,
+        "batch": [{"event": "event1","distinct_id": distinct_id}]
+    });
+    let res = server.capture_to_v1_batch(payload.to_string()).await;
+    assert_eq!(StatusCode::OK, res.status());
+    topic.next_event()?;
+
+    let res = server.capture_to_v1_batch(payload.to_string()).await;
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, res.status());
+    assert!(res.headers().contains_key("retry-after"));
+    let response: CaptureV1Response = res.json().await?;
+    assert!(response.accepted.is_empty());
+    assert_eq!("rate_limited", response.rejected[0].reason);
+    assert!(response.rejected[0].retryable);
+    topic.assert_empty();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_captures_segment_messages() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let user_id = random_string("id", 16);
+    let anonymous_id = random_string("anon", 16);
+
+    let main_topic = EphemeralTopic::new().await;
+    let histo_topic = EphemeralTopic::new().await;
+    let server = ServerHandle::for_topics(&main_topic, &histo_topic).await;
+
+    let track = json!({
+        "userId": user_id,
+        "anonymousId": anonymous_id,
+        "event": "Order Completed",
+        "properties": {"total": 10}
+    });
+    let res = server
+        .capture_segment("track", &token, track.to_string())
+        .await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let batch = json!({
+        "batch": [
+            {"type": "identify", "userId": user_id, "traits": {"email": "a@example.com"}},
+            {"type": "alias", "userId": user_id, "previousId": anonymous_id},
+        ]
+    });
+    let res = server
+        .capture_segment("batch", &token, batch.to_string())
+        .await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    // The write key is required
+    let res = server.capture_segment("track", "", track.to_string()).await;
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+    let res = server
+        .capture_segment("unknown", &token, track.to_string())
+        .await;
+    assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+    let event = main_topic.next_event()?;
+    assert_json_include!(
+        actual: event,
+        expected: json!({
+            "token": token,
+            "distinct_id": user_id
+        })
+    );
+    let data: serde_json::Value = serde_json::from_str(event["data"].as_str().unwrap())?;
+    assert_json_include!(
+        actual: data,
+        expected: json!({
+            "event": "Order Completed",
+            "properties": {"total": 10, "$anon_distinct_id": anonymous_id}
+        })
+    );
+
+    let data: serde_json::Value =
+        serde_json::from_str(main_topic.next_event()?["data"].as_str().unwrap())?;
+    assert_json_include!(
+        actual: data,
+        expected: json!({
+            "event": "$identify",
+            "$set": {"email": "a@example.com"}
+        })
+    );
+    let data: serde_json::Value =
+        serde_json::from_str(main_topic.next_event()?["data"].as_str().unwrap())?;
+    assert_json_include!(
+        actual: data,
+        expected: json!({
+            "event": "$create_alias",
+            "properties": {"alias": anonymous_id}
+        })
+    );
+    main_topic.assert_empty();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_drops_duplicate_events() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+    let uuid = Uuid::now_v7();
+
+    let topic = EphemeralTopic::new().await;
+    let mut config = DEFAULT_CONFIG.clone();
+    config.kafka.kafka_topic = topic.topic_name().to_string();
+    config.dedup_backend = Some(DedupBackend::Memory);
+    let server = ServerHandle::for_config(config).await;
+
+    let event = json!({
+        "token": token,
+        "event": "testing",
+        "uuid": uuid,
+        "distinct_id": distinct_id
+    });
+    for _ in 0..2 {
+        let res = server.capture_events(event.to_string()).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    // Duplicates are reported as accepted on v1
+    let payload = json!({
+        "token": token,
+        "batch": [{"event": "testing", "uuid": uuid, "distinct_id": distinct_id}]
+    });
+    let res = server.capture_to_v1_batch(payload.to_string()).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let response: CaptureV1Response = res.json().await?;
+    assert_eq!(vec![0], response.accepted);
+
+    assert_json_include!(
+        actual: topic.next_event()?,
+        expected: json!({
+            "token": token,
+            "uuid": uuid,
+            "distinct_id": distinct_id
+        })
+    );
+    topic.assert_empty();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_does_not_dedup_rejected_events() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+    let uuid = Uuid::now_v7();
+
+    let topic = EphemeralTopic::new().await;
+    let mut config = DEFAULT_CONFIG.clone();
+    config.kafka.kafka_topic = topic.topic_name().to_string();
+    config.dedup_backend = Some(DedupBackend::Memory);
+    let server = ServerHandle::for_config(config).await;
+
+    // Rejected for the missing distinct_id, the fixed event must not be dropped as a duplicate
+    let invalid = json!({
+        "token": token,
+        "event": "testing",
+        "uuid": uuid
+    });
+    let res = server.capture_events(invalid.to_string()).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let event = json!({
+        "token": token,
+        "event": "testing",
+        "uuid": uuid,
+        "distinct_id": distinct_id
+    });
+    let res = server.capture_events(event.to_string()).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    assert_json_include!(
+        actual: topic.next_event()?,
+        expected: json!({
+            "token": token,
+            "uuid": uuid,
+            "distinct_id": distinct_id
+        })
+    );
+    topic.assert_empty();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_routes_events_with_routing_rules() -> Result<()> {
+    setup_tracing();
+
+    let token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+
+    let main_topic = EphemeralTopic::new().await;
+    let routed_topic = EphemeralTopic::new().await;
+
+    let mut config = DEFAULT_CONFIG.clone();
+    config.kafka.kafka_topic = main_topic.topic_name().to_string();
+    config.routing_enabled = true;
+    config.routing_rules = Some(
+        json!({
+            (token.clone()): [{
+                "event": "$feature_flag_*",
+                "topic": routed_topic.topic_name(),
+                "partition_key": "token"
+            }]
+        })
+        .to_string(),
+    );
+    let server = ServerHandle::for_config(config).await;
+
+    let event = json!([{
+        "token": token,
+        "event": "$feature_flag_called",
+        "distinct_id": distinct_id
+    },{
+        "token": token,
+        "event": "$pageview",
+        "distinct_id": distinct_id
+    }]);
+    let res = server.capture_events(event.to_string()).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    assert_json_include!(
+        actual: main_topic.next_event()?,
+        expected: json!({
+            "token": token,
+            "distinct_id": distinct_id
+        })
+    );
+    main_topic.assert_empty();
+
+    // Routed events are keyed by token
+    assert_eq!(Some(token.clone()), routed_topic.next_message_key()?);
+    routed_topic.assert_empty();
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn it_routes_exceptions_and_heapmaps_to_separate_topics() -> Result<()> {
     setup_tracing();
@@ -591,3 +1084,358 @@ async fn it_limits_batch_endpoints_to_20mb() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn it_validates_payloads_without_producing() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+    let uuid = Uuid::now_v7();
+
+    let main_topic = EphemeralTopic::new().await;
+    let histo_topic = EphemeralTopic::new().await;
+    let server = ServerHandle::for_topics(&main_topic, &histo_topic).await;
+
+    let payload = json!({
+        "token": token,
+        "batch": [
+            {"event": "event1", "uuid": uuid, "distinct_id": distinct_id},
+            {"event": "$exception", "distinct_id": distinct_id},
+            {"event": "no distinct_id"}
+        ]
+    });
+    let res = server.validate(payload.to_string()).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let report: ValidationReport = res.json().await?;
+    assert_eq!("none", report.compression);
+    assert_eq!(Some(token.clone()), report.token);
+    assert!(report.error.is_none());
+    assert_eq!(3, report.events.len());
+
+    let event = &report.events[0];
+    assert_eq!(Some(uuid), event.uuid);
+    assert_eq!(Some(distinct_id.clone()), event.distinct_id);
+    assert_eq!(Some(DataType::AnalyticsMain), event.data_type);
+    assert_eq!(
+        Some(format!("{}:{}", token, distinct_id)),
+        event.partition_key
+    );
+    assert!(event.size.is_some_and(|size| size > 0));
+    assert!(event.error.is_none());
+
+    assert_eq!(Some(DataType::ExceptionMain), report.events[1].data_type);
+    assert_eq!(
+        "missing_distinct_id",
+        report.events[2].error.as_ref().unwrap().reason
+    );
+
+    // Request-level errors are reported too
+    let res = server
+        .validate(json!({"batch": [{"event": "e", "distinct_id": "d"}]}).to_string())
+        .await;
+    assert_eq!(StatusCode::OK, res.status());
+    let report: ValidationReport = res.json().await?;
+    assert_eq!("no_token", report.error.unwrap().reason);
+    assert_eq!(1, report.events.len());
+
+    main_topic.assert_empty();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_validates_snapshots_by_session() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+    let session_id = Uuid::now_v7().to_string();
+
+    let main_topic = EphemeralTopic::new().await;
+    let histo_topic = EphemeralTopic::new().await;
+    let server = ServerHandle::for_topics(&main_topic, &histo_topic).await;
+
+    let payload = json!({
+        "token": token,
+        "batch": [
+            {"event": "$snapshot", "distinct_id": distinct_id, "properties": {
+                "$session_id": session_id, "$snapshot_data": [{"type": 2}]
+            }},
+            {"event": "$snapshot", "distinct_id": distinct_id, "properties": {
+                "$snapshot_data": [{"type": 2}]
+            }},
+            {"event": "$snapshot", "distinct_id": distinct_id, "properties": {
+                "$session_id": "not a uuid", "$snapshot_data": [{"type": 2}]
+            }}
+        ]
+    });
+    let res = server.validate(payload.to_string()).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let report: ValidationReport = res.json().await?;
+    assert_eq!(3, report.events.len());
+
+    let event = &report.events[0];
+    assert_eq!(Some(DataType::SnapshotMain), event.data_type);
+    assert_eq!(Some(session_id), event.partition_key);
+    assert!(event.size.is_some_and(|size| size > 0));
+    assert!(event.error.is_none());
+
+    assert_eq!(
+        "missing_session_id",
+        report.events[1].error.as_ref().unwrap().reason
+    );
+    assert_eq!(
+        "invalid_session_id",
+        report.events[2].error.as_ref().unwrap().reason
+    );
+
+    main_topic.assert_empty();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_applies_team_privacy_settings() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+
+    let main_topic = EphemeralTopic::new().await;
+    let redis = PrefixedRedis::new().await;
+    redis.set_privacy_settings(
+        &token,
+        json!({
+            "ip": "truncate",
+            "strip_properties": ["email"],
+            "hash_properties": ["name"],
+            "salt": "salt"
+        }),
+    );
+
+    let mut config = DEFAULT_CONFIG.clone();
+    config.redis_key_prefix = redis.key_prefix();
+    config.kafka.kafka_topic = main_topic.topic_name().to_string();
+    config.privacy_enabled = true;
+    let server = ServerHandle::for_config(config).await;
+    server.wait_until_ready().await;
+
+    let event = json!({
+        "token": token,
+        "event": "event1",
+        "distinct_id": distinct_id,
+        "properties": {"email": "a@b.c", "name": "alice", "plan": "free"}
+    });
+    let res = server.capture_events(event.to_string()).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let captured = main_topic.next_event()?;
+    // Test requests come from 127.0.0.1
+    assert_eq!("127.0.0.0", captured["ip"]);
+    let data: serde_json::Value = serde_json::from_str(captured["data"].as_str().unwrap())?;
+    let properties = data["properties"].as_object().unwrap();
+    assert!(!properties.contains_key("email"));
+    assert_eq!("free", properties["plan"]);
+    assert_eq!(64, properties["name"].as_str().unwrap().len());
+    main_topic.assert_empty();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_does_not_dedup_segment_events_retried_until_privacy_settings_load() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let user_id = random_string("id", 16);
+    let uuid = Uuid::now_v7();
+
+    let main_topic = EphemeralTopic::new().await;
+    let redis = PrefixedRedis::new().await;
+    redis.break_privacy_settings();
+
+    let mut config = DEFAULT_CONFIG.clone();
+    config.redis_key_prefix = redis.key_prefix();
+    config.kafka.kafka_topic = main_topic.topic_name().to_string();
+    config.privacy_enabled = true;
+    config.dedup_backend = Some(DedupBackend::Memory);
+    let server = ServerHandle::for_config(config).await;
+
+    let track = json!({
+        "userId": user_id,
+        "messageId": uuid.to_string(),
+        "event": "Order Completed",
+        "properties": {"email": "a@b.c"}
+    });
+    let res = server
+        .capture_segment("track", &token, track.to_string())
+        .await;
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+
+    // The retry goes through once the settings are loaded, instead of being dropped as a duplicate
+    redis.repair_privacy_settings();
+    redis.set_privacy_settings(&token, json!({"strip_properties": ["email"]}));
+    server.wait_until_ready().await;
+    let res = server
+        .capture_segment("track", &token, track.to_string())
+        .await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let event = main_topic.next_event()?;
+    assert_eq!(uuid.to_string(), event["uuid"]);
+    let data: serde_json::Value = serde_json::from_str(event["data"].as_str().unwrap())?;
+    assert!(!data["properties"]
+        .as_object()
+        .unwrap()
+        .contains_key("email"));
+    main_topic.assert_empty();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_filters_bot_traffic() -> Result<()> {
+    setup_tracing();
+    let tagged_token = random_string("token", 16);
+    let dropped_token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+    let googlebot = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+
+    let main_topic = EphemeralTopic::new().await;
+    let redis = PrefixedRedis::new().await;
+    redis.set_bot_filtering(&tagged_token, "tag");
+    redis.set_bot_filtering(&dropped_token, "drop");
+
+    let mut config = DEFAULT_CONFIG.clone();
+    config.redis_key_prefix = redis.key_prefix();
+    config.kafka.kafka_topic = main_topic.topic_name().to_string();
+    config.bot_filtering_enabled = true;
+    let server = ServerHandle::for_config(config).await;
+
+    for token in [&dropped_token, &tagged_token] {
+        let event = json!({
+            "token": token,
+            "event": "$pageview",
+            "distinct_id": distinct_id
+        });
+        let res = server.capture_events_as(googlebot, event.to_string()).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    // Only the event of the tagging team goes through
+    let captured = main_topic.next_event()?;
+    assert_eq!(tagged_token, captured["token"]);
+    let data: serde_json::Value = serde_json::from_str(captured["data"].as_str().unwrap())?;
+    assert_eq!(true, data["properties"]["$bot"]);
+    assert_eq!("googlebot", data["properties"]["$bot_name"]);
+    main_topic.assert_empty();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_keeps_serving_while_draining_on_shutdown() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+
+    let main_topic = EphemeralTopic::new().await;
+    let mut config = DEFAULT_CONFIG.clone();
+    config.kafka.kafka_topic = main_topic.topic_name().to_string();
+    config.shutdown_drain_delay_secs = 2;
+    let server = ServerHandle::for_config(config).await;
+    assert_eq!(StatusCode::OK, server.readiness().await.status());
+
+    server.stop();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(
+        StatusCode::SERVICE_UNAVAILABLE,
+        server.readiness().await.status()
+    );
+
+    // Requests are still accepted until the drain delay is over
+    let event = json!({
+        "token": token,
+        "event": "testing",
+        "distinct_id": distinct_id
+    });
+    let res = server.capture_events(event.to_string()).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(token, main_topic.next_event()?["token"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_records_requests_of_debugged_tokens() -> Result<()> {
+    setup_tracing();
+    let debugged_token = random_string("token", 16);
+    let other_token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+
+    let main_topic = EphemeralTopic::new().await;
+    let redis = PrefixedRedis::new().await;
+    let until = time::OffsetDateTime::now_utc().unix_timestamp() + 3600;
+    redis.set_debug_capture(&debugged_token, until);
+
+    let path = std::env::temp_dir().join(random_string("capture-debug", 16));
+    let mut config = DEFAULT_CONFIG.clone();
+    config.redis_key_prefix = redis.key_prefix();
+    config.kafka.kafka_topic = main_topic.topic_name().to_string();
+    config.debug_capture_destination = Some(DebugCaptureDestination::File);
+    config.debug_capture_file_path = path.to_string_lossy().to_string();
+    let server = ServerHandle::for_config(config).await;
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    for token in [&debugged_token, &other_token] {
+        let event = json!({
+            "token": token,
+            "event": "testing",
+            "distinct_id": distinct_id
+        });
+        let res = server.capture_events(event.to_string()).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    // Requests failing after decoding are recorded with their error
+    let event = json!({
+        "token": debugged_token,
+        "event": "testing"
+    });
+    let res = server.capture_events(event.to_string()).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    // Other endpoints are recorded too
+    let payload = json!({
+        "token": debugged_token,
+        "batch": [{"event": "testing", "distinct_id": distinct_id}]
+    });
+    let res = server.capture_to_v1_batch(payload.to_string()).await;
+    assert_eq!(StatusCode::OK, res.status());
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let records: Vec<serde_json::Value> = std::fs::read_to_string(&path)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    std::fs::remove_file(&path).ok();
+    assert_eq!(3, records.len());
+    for record in &records {
+        assert_eq!(debugged_token, record["token"]);
+    }
+    let mut outcomes: Vec<(&str, &str)> = records
+        .iter()
+        .map(|record| {
+            (
+                record["path"].as_str().unwrap(),
+                record["outcome"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    outcomes.sort();
+    assert_eq!(
+        vec![
+            ("/i/v0/e", "missing_distinct_id"),
+            ("/i/v0/e", "ok"),
+            ("/i/v1/batch", "ok")
+        ],
+        outcomes
+    );
+
+    Ok(())
+}
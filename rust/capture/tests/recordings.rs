@@ -6,6 +6,7 @@ use capture::limiters::redis::QuotaResource;
 use reqwest::StatusCode;
 use serde_json::{json, value::Value};
 use time::Duration;
+use uuid::Uuid;
 
 mod common;
 
@@ -14,7 +15,7 @@ async fn it_captures_one_recording() -> Result<()> {
     setup_tracing();
     let token = random_string("token", 16);
     let distinct_id = random_string("id", 16);
-    let session_id = random_string("id", 16);
+    let session_id = Uuid::now_v7().to_string();
     let window_id = random_string("id", 16);
 
     let main_topic = EphemeralTopic::new().await;
@@ -51,7 +52,7 @@ async fn it_fails_no_session_id() -> Result<()> {
     setup_tracing();
     let token = random_string("token", 16);
     let distinct_id = random_string("id", 16);
-    let session_id = random_string("id", 16);
+    let session_id = Uuid::now_v7().to_string();
     let window_id = random_string("id", 16);
 
     let main_topic = EphemeralTopic::new().await;
@@ -103,7 +104,7 @@ async fn it_defaults_window_id_to_session_id() -> Result<()> {
     setup_tracing();
     let token = random_string("token", 16);
     let distinct_id = random_string("id", 16);
-    let session_id = random_string("id", 16);
+    let session_id = Uuid::now_v7().to_string();
 
     let main_topic = EphemeralTopic::new().await;
     let server = ServerHandle::for_recordings(&main_topic).await;
@@ -122,13 +123,129 @@ async fn it_defaults_window_id_to_session_id() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn it_splits_large_recordings() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+    let session_id = Uuid::now_v7().to_string();
+
+    let main_topic = EphemeralTopic::new().await;
+    let server = ServerHandle::for_recordings(&main_topic).await;
+
+    // 2.5MB of snapshot data, over the 1MB message size limit
+    let snapshot_data: Vec<Value> = (0..25)
+        .map(|i| json!({"type": 2, "index": i, "data": random_string("data", 100_000)}))
+        .collect();
+    let event = json!({
+        "token": token,
+        "event": "testing",
+        "distinct_id": distinct_id,
+        "properties": {
+            "$session_id": session_id,
+            "$snapshot_data": snapshot_data,
+        }
+    });
+    let res = server.capture_recording(event.to_string()).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let mut items = vec![];
+    let mut count = None;
+    for index in 0.. {
+        let data: Value = serde_json::from_str(
+            main_topic
+                .next_event()?
+                .get("data")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+        )?;
+        let properties = &data["properties"];
+        assert_eq!(session_id, properties["$session_id"]);
+        assert_eq!(session_id, properties["$window_id"]);
+        assert_eq!(index, properties["$snapshot_chunk_index"]);
+        let chunk_count = properties["$snapshot_chunk_count"].as_u64().unwrap();
+        assert_eq!(*count.get_or_insert(chunk_count), chunk_count);
+        items.extend(properties["$snapshot_items"].as_array().unwrap().clone());
+        if index + 1 == chunk_count {
+            break;
+        }
+    }
+    assert!(count.unwrap() >= 3);
+    assert_eq!(snapshot_data, items);
+    main_topic.assert_empty();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_groups_recordings_by_session_and_window() -> Result<()> {
+    setup_tracing();
+    let token = random_string("token", 16);
+    let distinct_id = random_string("id", 16);
+    let session1 = Uuid::now_v7().to_string();
+    let session2 = Uuid::now_v7().to_string();
+    let window1 = random_string("window", 16);
+    let window2 = random_string("window", 16);
+
+    let main_topic = EphemeralTopic::new().await;
+    let server = ServerHandle::for_recordings(&main_topic).await;
+
+    let snapshot = |session_id: &str, window_id: &str, index: u32| {
+        json!({
+            "token": token,
+            "event": "$snapshot",
+            "distinct_id": distinct_id,
+            "properties": {
+                "$session_id": session_id,
+                "$window_id": window_id,
+                "$snapshot_data": [{"index": index}],
+            }
+        })
+    };
+    let batch = json!([
+        snapshot(&session1, &window1, 0),
+        snapshot(&session1, &window2, 1),
+        snapshot(&session1, &window1, 2),
+        snapshot("not-a-uuid", &window1, 3),
+        snapshot(&session2, &window1, 4),
+    ]);
+    let res = server.capture_recording(batch.to_string()).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    // One message per group, in order of first appearance, the invalid event is dropped
+    for (session_id, window_id, items) in [
+        (&session1, &window1, json!([{"index": 0}, {"index": 2}])),
+        (&session1, &window2, json!([{"index": 1}])),
+        (&session2, &window1, json!([{"index": 4}])),
+    ] {
+        let event = main_topic.next_event()?;
+        let data: Value = serde_json::from_str(event.get("data").unwrap().as_str().unwrap())?;
+        assert_json_include!(
+            actual: data,
+            expected: json!({
+                "event": "$snapshot_items",
+                "properties": {
+                    "$session_id": session_id,
+                    "$window_id": window_id,
+                    "distinct_id": distinct_id,
+                    "$snapshot_items": items,
+                },
+            })
+        );
+    }
+    main_topic.assert_empty();
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn it_applies_overflow_limits() -> Result<()> {
     setup_tracing();
     let token = random_string("token", 16);
-    let session1 = random_string("session1", 16);
-    let session2 = random_string("session2", 16);
-    let session3 = random_string("session3", 16);
+    let session1 = Uuid::now_v7().to_string();
+    let session2 = Uuid::now_v7().to_string();
+    let session3 = Uuid::now_v7().to_string();
     let distinct_id = random_string("id", 16);
 
     let topic = EphemeralTopic::new().await;
@@ -15,6 +15,7 @@ pub struct CapturedEvent {
     pub now: String,
     #[serde(
         with = "time::serde::rfc3339::option",
+        default,
         skip_serializing_if = "Option::is_none"
     )]
     pub sent_at: Option<OffsetDateTime>,
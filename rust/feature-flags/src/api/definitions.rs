@@ -0,0 +1,325 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use axum::{
+    extract::State,
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api::{
+        errors::{ClientFacingError, FlagError},
+        types::FlagDefinitionsResponse,
+    },
+    cohort::cohort_models::{Cohort, CohortId, CohortProperty},
+    flags::{
+        flag_matching::{GroupTypeMappingCache, ProjectId},
+        flag_models::FeatureFlag,
+        flag_request::{FlagRequest, FlagRequestType},
+    },
+    router,
+    team::team_models::Team,
+};
+
+#[derive(Deserialize, Default)]
+pub struct DefinitionsQueryParams {
+    /// The project token of the team, required when authenticating with a personal API key,
+    /// since those can access every project of the user's organizations.
+    #[serde(alias = "api_key")]
+    pub token: Option<String>,
+}
+
+pub struct DefinitionsRequestContext {
+    pub state: State<router::State>,
+    pub meta: DefinitionsQueryParams,
+    pub headers: HeaderMap,
+}
+
+/// Serves the team's flag definitions, or a 304 if they match the ETag the SDK already has.
+pub async fn process_definitions_request(
+    context: DefinitionsRequestContext,
+) -> Result<Response, FlagError> {
+    let DefinitionsRequestContext {
+        state,
+        meta,
+        headers,
+    } = context;
+
+    let team = authenticate(&state, &meta, &headers).await?;
+    let definitions = get_flag_definitions(&state, &team).await?;
+    state
+        .flag_request_counter
+        .increment(team.id, FlagRequestType::LocalEvaluation);
+
+    let body = serde_json::to_vec(&definitions)
+        .map_err(|e| FlagError::Internal(format!("failed to serialize flag definitions: {}", e)))?;
+    let etag = definitions_etag(&body);
+    let etag_header = HeaderValue::from_str(&etag)
+        .map_err(|e| FlagError::Internal(format!("invalid etag: {}", e)))?;
+
+    if etag_matches(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag_header)]).into_response());
+    }
+
+    Ok((
+        [
+            (CONTENT_TYPE, HeaderValue::from_static("application/json")),
+            (ETAG, etag_header),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Resolves the team from the personal API key in the `Authorization: Bearer` header and the
+/// `token` query parameter.
+async fn authenticate(
+    state: &router::State,
+    meta: &DefinitionsQueryParams,
+    headers: &HeaderMap,
+) -> Result<Team, FlagError> {
+    let key = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .ok_or(FlagError::NoPersonalApiKeyError)?;
+
+    let token = meta.token.as_deref().ok_or_else(|| {
+        FlagError::ClientFacing(ClientFacingError::BadRequest(
+            "The token query parameter is required when using a personal API key.".to_string(),
+        ))
+    })?;
+    state
+        .personal_api_key_cache
+        .get_team(state.reader.clone(), key, token)
+        .await
+}
+
+/// Collects the team's flags along with the cohorts and group types they need to be evaluated.
+pub async fn get_flag_definitions(
+    state: &router::State,
+    team: &Team,
+) -> Result<FlagDefinitionsResponse, FlagError> {
+    let flags: Vec<FeatureFlag> = FlagRequest::default()
+        .get_flags_from_cache_or_pg(team.id, &state.redis, &state.reader)
+        .await?
+        .flags
+        .into_iter()
+        .filter(|flag| !flag.deleted)
+        .collect();
+
+    let cohorts = state.cohort_cache_manager.get_cohorts(team.id).await?;
+    let cohorts = referenced_cohorts(&flags, cohorts)
+        .into_iter()
+        .filter_map(
+            |cohort| match serde_json::from_value::<CohortProperty>(cohort.filters) {
+                Ok(filters) => Some((cohort.id, filters.properties)),
+                Err(e) => {
+                    // Flags using this cohort can't be evaluated locally, and SDKs fall back to `/flags` for them
+                    tracing::warn!("Failed to parse filters for cohort {}: {}", cohort.id, e);
+                    None
+                }
+            },
+        )
+        .collect();
+
+    let mut group_type_mapping_cache =
+        GroupTypeMappingCache::new(team.project_id as ProjectId, state.reader.clone());
+    let group_type_mapping = match group_type_mapping_cache
+        .group_type_index_to_group_type_map()
+        .await
+    {
+        Ok(mapping) => mapping.into_iter().collect(),
+        Err(FlagError::NoGroupTypeMappings) => BTreeMap::new(),
+        Err(e) => return Err(e),
+    };
+
+    Ok(FlagDefinitionsResponse {
+        flags,
+        cohorts,
+        group_type_mapping,
+    })
+}
+
+/// Returns the cohorts the flags filter on, along with the cohorts those depend on.
+/// Static cohorts are left out, since their members are only known to the server.
+fn referenced_cohorts(flags: &[FeatureFlag], cohorts: Vec<Cohort>) -> Vec<Cohort> {
+    let mut cohorts_by_id: HashMap<CohortId, Cohort> = cohorts
+        .into_iter()
+        .filter(|cohort| !cohort.deleted && !cohort.is_static)
+        .map(|cohort| (cohort.id, cohort))
+        .collect();
+
+    let mut to_visit: Vec<CohortId> = flags
+        .iter()
+        .flat_map(|flag| flag.get_conditions())
+        .flat_map(|condition| condition.properties.iter().flatten())
+        .filter_map(|filter| filter.get_cohort_id())
+        .collect();
+    let mut visited = HashSet::new();
+    let mut referenced = Vec::new();
+
+    while let Some(cohort_id) = to_visit.pop() {
+        if !visited.insert(cohort_id) {
+            continue;
+        }
+        if let Some(cohort) = cohorts_by_id.remove(&cohort_id) {
+            match cohort.extract_dependencies() {
+                Ok(dependencies) => to_visit.extend(dependencies),
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to extract dependencies of cohort {}: {}",
+                        cohort_id,
+                        e
+                    )
+                }
+            }
+            referenced.push(cohort);
+        }
+    }
+
+    referenced
+}
+
+fn definitions_etag(body: &[u8]) -> String {
+    format!("\"{:x}\"", Sha256::digest(body))
+}
+
+/// Whether the `If-None-Match` header lists the given ETag, ignoring weak validator prefixes.
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::utils::test_utils::create_flag_from_json;
+
+    fn cohort(id: CohortId, filters: serde_json::Value, is_static: bool) -> Cohort {
+        Cohort {
+            id,
+            name: format!("cohort {id}"),
+            description: None,
+            team_id: 1,
+            deleted: false,
+            filters,
+            query: None,
+            version: None,
+            pending_version: None,
+            count: None,
+            is_calculating: false,
+            is_static,
+            errors_calculating: 0,
+            groups: json!([]),
+            created_by_id: None,
+        }
+    }
+
+    fn cohort_filters(values: serde_json::Value) -> serde_json::Value {
+        json!({"properties": {"type": "OR", "values": [{"type": "AND", "values": values}]}})
+    }
+
+    #[test]
+    fn test_referenced_cohorts_include_dependencies() {
+        let flags = create_flag_from_json(Some(
+            json!([{
+                "id": 1,
+                "key": "flag",
+                "active": true,
+                "deleted": false,
+                "team_id": 1,
+                "filters": {
+                    "groups": [{
+                        "properties": [{"key": "id", "value": 1, "type": "cohort"}],
+                        "rollout_percentage": 100
+                    }]
+                }
+            }])
+            .to_string(),
+        ));
+        let cohorts = vec![
+            cohort(
+                1,
+                cohort_filters(json!([{"key": "id", "value": 2, "type": "cohort"}])),
+                false,
+            ),
+            cohort(
+                2,
+                cohort_filters(json!([{"key": "email", "value": "a@b.com", "type": "person"}])),
+                false,
+            ),
+            cohort(
+                3,
+                cohort_filters(json!([{"key": "email", "value": "c@d.com", "type": "person"}])),
+                false,
+            ),
+        ];
+
+        let mut referenced: Vec<CohortId> = referenced_cohorts(&flags, cohorts)
+            .into_iter()
+            .map(|cohort| cohort.id)
+            .collect();
+        referenced.sort();
+
+        assert_eq!(referenced, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_referenced_cohorts_skip_static_cohorts() {
+        let flags = create_flag_from_json(Some(
+            json!([{
+                "id": 1,
+                "key": "flag",
+                "active": true,
+                "deleted": false,
+                "team_id": 1,
+                "filters": {
+                    "groups": [{
+                        "properties": [{"key": "id", "value": 1, "type": "cohort"}],
+                        "rollout_percentage": 100
+                    }]
+                }
+            }])
+            .to_string(),
+        ));
+        let cohorts = vec![cohort(1, json!({}), true)];
+
+        assert!(referenced_cohorts(&flags, cohorts).is_empty());
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let etag = definitions_etag(b"{}");
+        let headers_with = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(IF_NONE_MATCH, value.parse().unwrap());
+            headers
+        };
+
+        assert!(!etag_matches(&HeaderMap::new(), &etag));
+        assert!(etag_matches(&headers_with(&etag), &etag));
+        assert!(etag_matches(&headers_with(&format!("W/{etag}")), &etag));
+        assert!(etag_matches(
+            &headers_with(&format!("\"other\", {etag}")),
+            &etag
+        ));
+        assert!(etag_matches(&headers_with("*"), &etag));
+        assert!(!etag_matches(&headers_with("\"other\""), &etag));
+        assert_ne!(etag, definitions_etag(b"[]"));
+    }
+}
@@ -1,6 +1,9 @@
 use std::net::IpAddr;
 
 use crate::{
+    api::definitions::{
+        process_definitions_request, DefinitionsQueryParams, DefinitionsRequestContext,
+    },
     api::errors::FlagError,
     api::handler::{process_request, FlagsQueryParams, RequestContext},
     api::types::FlagsResponse,
@@ -9,6 +12,7 @@ use crate::{
 // TODO: stream this instead
 use axum::extract::{MatchedPath, Query, State};
 use axum::http::{HeaderMap, Method};
+use axum::response::Response;
 use axum::{debug_handler, Json};
 use axum_client_ip::InsecureClientIp;
 use bytes::Bytes;
@@ -54,6 +58,24 @@ pub async fn flags(
     Ok(Json(process_request(context).await?))
 }
 
+/// Flag definitions endpoint for local evaluation in server-side SDKs.
+/// Requires a personal API key, and supports conditional requests through ETags.
+#[instrument(skip_all)]
+#[debug_handler]
+pub async fn flag_definitions(
+    state: State<router::State>,
+    meta: Query<DefinitionsQueryParams>,
+    headers: HeaderMap,
+) -> Result<Response, FlagError> {
+    let context = DefinitionsRequestContext {
+        state,
+        meta: meta.0,
+        headers,
+    };
+
+    process_definitions_request(context).await
+}
+
 fn record_request_metadata(
     headers: &HeaderMap,
     method: &Method,
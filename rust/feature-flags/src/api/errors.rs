@@ -35,6 +35,10 @@ pub enum FlagError {
     NoTokenError,
     #[error("API key is not valid")]
     TokenValidationError,
+    #[error("No personal API key in request")]
+    NoPersonalApiKeyError,
+    #[error("Personal API key is not valid")]
+    PersonalApiKeyValidationError,
     #[error("Row not found in postgres")]
     RowNotFound,
     #[error("failed to parse redis cache data")]
@@ -95,6 +99,12 @@ impl IntoResponse for FlagError {
             FlagError::TokenValidationError => {
                 (StatusCode::UNAUTHORIZED, "The provided API key is invalid or has expired. Please check your API key and try again.".to_string())
             }
+            FlagError::NoPersonalApiKeyError => {
+                (StatusCode::UNAUTHORIZED, "No personal API key provided. Please include a personal API key in the Authorization header.".to_string())
+            }
+            FlagError::PersonalApiKeyValidationError => {
+                (StatusCode::UNAUTHORIZED, "The provided personal API key is invalid, or doesn't have access to this project's feature flags.".to_string())
+            }
             FlagError::RedisDataParsingError => {
                 tracing::error!("Data parsing error: {:?}", self);
                 (
@@ -184,6 +194,10 @@ impl From<CustomRedisError> for FlagError {
                 tracing::error!("Unknown redis error: {}", e);
                 FlagError::RedisUnavailable
             }
+            CustomRedisError::ConnectionError(e) => {
+                tracing::error!("failed to connect to redis: {}", e);
+                FlagError::RedisUnavailable
+            }
         }
     }
 }
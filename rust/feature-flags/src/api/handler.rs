@@ -6,7 +6,7 @@ use crate::{
     cohort::cohort_cache_manager::CohortCacheManager,
     flags::flag_matching::{FeatureFlagMatcher, GroupTypeMappingCache},
     flags::flag_models::FeatureFlagList,
-    flags::flag_request::FlagRequest,
+    flags::flag_request::{FlagRequest, FlagRequestType},
     router,
 };
 use axum::{extract::State, http::HeaderMap};
@@ -55,6 +55,18 @@ pub struct FlagsQueryParams {
     pub sent_at: Option<i64>,
 }
 
+impl FlagsQueryParams {
+    /// The response version requested by the client, as in `/decide`: `v=3` adds flag payloads
+    /// and `v=4` adds per-flag details. Missing or unparseable versions get the base response.
+    pub fn response_version(&self) -> u32 {
+        self.version
+            .as_deref()
+            .and_then(|version| version.parse::<f64>().ok())
+            .filter(|version| version.is_finite() && *version >= 0.0)
+            .map_or(1, |version| version as u32)
+    }
+}
+
 pub struct RequestContext {
     pub state: State<router::State>,
     pub ip: IpAddr,
@@ -86,7 +98,7 @@ pub async fn process_request(context: RequestContext) -> Result<FlagsResponse, F
     let RequestContext {
         state,
         ip,
-        meta: _, // TODO use this
+        meta,
         headers,
         body,
     } = context;
@@ -115,6 +127,11 @@ pub async fn process_request(context: RequestContext) -> Result<FlagsResponse, F
         .get_flags_from_cache_or_pg(team_id, &state.redis, &state.reader)
         .await?;
 
+    // Requests that failed before this point are not billed
+    state
+        .flag_request_counter
+        .increment(team_id, FlagRequestType::Decide);
+
     let evaluation_context = FeatureFlagEvaluationContextBuilder::default()
         .team_id(team_id)
         .distinct_id(distinct_id)
@@ -131,7 +148,7 @@ pub async fn process_request(context: RequestContext) -> Result<FlagsResponse, F
 
     let flags_response = evaluate_feature_flags(evaluation_context).await;
 
-    Ok(flags_response)
+    Ok(flags_response.for_version(meta.response_version()))
 }
 
 /// Get person property overrides based on the request
@@ -591,6 +608,124 @@ mod tests {
         assert_eq!(params.sent_at, Some(1234567890));
     }
 
+    #[test]
+    fn test_flags_query_params_response_version() {
+        let version = |v: Option<&str>| {
+            FlagsQueryParams {
+                version: v.map(str::to_string),
+                ..Default::default()
+            }
+            .response_version()
+        };
+
+        assert_eq!(version(None), 1);
+        assert_eq!(version(Some("2")), 2);
+        assert_eq!(version(Some("3")), 3);
+        assert_eq!(version(Some("4.0")), 4);
+        assert_eq!(version(Some("latest")), 1);
+        assert_eq!(version(Some("-3")), 1);
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_feature_flags_with_payloads_and_details() {
+        let reader: Arc<dyn Client + Send + Sync> = setup_pg_reader_client(None).await;
+        let writer: Arc<dyn Client + Send + Sync> = setup_pg_writer_client(None).await;
+        let cohort_cache = Arc::new(CohortCacheManager::new(reader.clone(), None, None));
+        let team = insert_new_team_in_pg(reader.clone(), None).await.unwrap();
+        let flag = |id: i32, key: &str, rollout_percentage: f64| FeatureFlag {
+            name: Some(format!("{key} description")),
+            id,
+            key: key.to_string(),
+            active: true,
+            deleted: false,
+            team_id: team.id,
+            filters: FlagFilters {
+                groups: vec![FlagGroupType {
+                    properties: Some(vec![]),
+                    rollout_percentage: Some(rollout_percentage),
+                    variant: None,
+                }],
+                multivariate: None,
+                aggregation_group_type_index: None,
+                payloads: Some(json!({"true": {"color": "blue"}})),
+                super_groups: None,
+            },
+            ensure_experience_continuity: false,
+        };
+
+        let evaluation_context = FeatureFlagEvaluationContextBuilder::default()
+            .team_id(team.id)
+            .distinct_id("user123".to_string())
+            .feature_flags(FeatureFlagList {
+                flags: vec![
+                    flag(1, "enabled_flag", 100.0),
+                    flag(2, "disabled_flag", 0.0),
+                ],
+            })
+            .reader(reader)
+            .writer(writer)
+            .cohort_cache(cohort_cache)
+            .person_property_overrides(Some(HashMap::new()))
+            .build()
+            .expect("Failed to build FeatureFlagEvaluationContext");
+
+        let result = evaluate_feature_flags(evaluation_context).await;
+
+        assert!(!result.error_while_computing_flags);
+        // Payloads are only returned for enabled flags
+        assert_eq!(
+            result.feature_flag_payloads,
+            Some(HashMap::from([(
+                "enabled_flag".to_string(),
+                json!({"color": "blue"})
+            )]))
+        );
+
+        let details = result.flags.as_ref().expect("missing flag details");
+        let enabled = &details["enabled_flag"];
+        assert!(enabled.enabled);
+        assert_eq!(enabled.variant, None);
+        assert_eq!(enabled.reason.code, "condition_match");
+        assert_eq!(enabled.reason.condition_index, Some(0));
+        assert_eq!(enabled.reason.description, "Matched condition set 1");
+        assert_eq!(enabled.metadata.id, 1);
+        assert_eq!(
+            enabled.metadata.description,
+            Some("enabled_flag description".to_string())
+        );
+        assert_eq!(enabled.metadata.payload, Some(json!({"color": "blue"})));
+
+        let disabled = &details["disabled_flag"];
+        assert!(!disabled.enabled);
+        assert_eq!(disabled.reason.code, "out_of_rollout_bound");
+        assert_eq!(disabled.reason.condition_index, Some(0));
+
+        let v3 = serde_json::to_value(result.for_version(3)).unwrap();
+        assert!(v3.get("featureFlagPayloads").is_some());
+        assert!(v3.get("flags").is_none());
+    }
+
+    #[test]
+    fn test_flags_response_for_version() {
+        let response = || FlagsResponse {
+            error_while_computing_flags: false,
+            feature_flags: HashMap::from([("flag".to_string(), FlagValue::Boolean(true))]),
+            feature_flag_payloads: Some(HashMap::new()),
+            flags: Some(HashMap::new()),
+        };
+
+        assert_eq!(
+            serde_json::to_value(response().for_version(1)).unwrap(),
+            json!({"errorWhileComputingFlags": false, "featureFlags": {"flag": true}})
+        );
+        let v3 = response().for_version(3);
+        assert!(v3.feature_flag_payloads.is_some());
+        assert!(v3.flags.is_none());
+        let v4 = response().for_version(4);
+        assert!(v4.feature_flag_payloads.is_some());
+        assert!(v4.flags.is_some());
+    }
+
     #[test]
     fn test_compression_deserialization() {
         assert_eq!(
@@ -1,3 +1,4 @@
+pub mod definitions;
 pub mod endpoint;
 pub mod errors;
 pub mod handler;
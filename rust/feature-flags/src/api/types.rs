@@ -1,5 +1,20 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    cohort::cohort_models::{CohortId, InnerCohortProperty},
+    flags::{
+        flag_match_reason::FeatureFlagMatchReason,
+        flag_matching::{FeatureFlagMatch, GroupTypeIndex},
+        flag_models::FeatureFlag,
+    },
+};
+
+/// The first response version that includes `featureFlagPayloads`.
+pub const PAYLOADS_RESPONSE_VERSION: u32 = 3;
+/// The first response version that includes the per-flag `flags` details.
+pub const FLAG_DETAILS_RESPONSE_VERSION: u32 = 4;
 
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum FlagsResponseCode {
@@ -18,4 +33,91 @@ pub enum FlagValue {
 pub struct FlagsResponse {
     pub error_while_computing_flags: bool,
     pub feature_flags: HashMap<String, FlagValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feature_flag_payloads: Option<HashMap<String, Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<HashMap<String, FlagDetails>>,
+}
+
+impl FlagsResponse {
+    /// Drops the fields that the requested response version doesn't include, so that
+    /// older clients keep getting the response shape they know.
+    pub fn for_version(mut self, version: u32) -> Self {
+        if version < PAYLOADS_RESPONSE_VERSION {
+            self.feature_flag_payloads = None;
+        }
+        if version < FLAG_DETAILS_RESPONSE_VERSION {
+            self.flags = None;
+        }
+        self
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FlagDetails {
+    pub key: String,
+    pub enabled: bool,
+    pub variant: Option<String>,
+    pub reason: FlagEvaluationReason,
+    pub metadata: FlagDetailsMetadata,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FlagEvaluationReason {
+    pub code: String,
+    pub condition_index: Option<usize>,
+    pub description: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FlagDetailsMetadata {
+    pub id: i32,
+    pub description: Option<String>,
+    pub payload: Option<Value>,
+}
+
+impl FlagDetails {
+    pub fn create(flag: &FeatureFlag, flag_match: &FeatureFlagMatch) -> Self {
+        FlagDetails {
+            key: flag.key.clone(),
+            enabled: flag_match.matches,
+            variant: flag_match.variant.clone(),
+            reason: FlagEvaluationReason {
+                code: flag_match.reason.to_string(),
+                condition_index: flag_match.condition_index,
+                description: reason_description(&flag_match.reason, flag_match.condition_index),
+            },
+            metadata: FlagDetailsMetadata {
+                id: flag.id,
+                // The flag name is what the UI shows as its description
+                description: flag.name.clone(),
+                payload: flag_match.payload.clone(),
+            },
+        }
+    }
+}
+
+/// Everything a server-side SDK needs to evaluate the team's flags locally.
+/// Maps are ordered so that the serialized definitions, and hence their ETag, are stable.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FlagDefinitionsResponse {
+    pub flags: Vec<FeatureFlag>,
+    pub cohorts: BTreeMap<CohortId, InnerCohortProperty>,
+    pub group_type_mapping: BTreeMap<GroupTypeIndex, String>,
+}
+
+fn reason_description(reason: &FeatureFlagMatchReason, condition_index: Option<usize>) -> String {
+    match reason {
+        FeatureFlagMatchReason::SuperConditionValue => "Super condition value".to_string(),
+        FeatureFlagMatchReason::ConditionMatch => match condition_index {
+            Some(index) => format!("Matched condition set {}", index + 1),
+            None => "Matched conditions".to_string(),
+        },
+        FeatureFlagMatchReason::NoConditionMatch => "No matching condition set".to_string(),
+        FeatureFlagMatchReason::OutOfRolloutBound => match condition_index {
+            Some(index) => format!("Out of rollout bound for condition set {}", index + 1),
+            None => "Out of rollout bound".to_string(),
+        },
+        FeatureFlagMatchReason::NoGroupType => "No group type".to_string(),
+    }
 }
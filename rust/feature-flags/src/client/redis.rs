@@ -2,12 +2,16 @@ use std::time::Duration;
 
 use anyhow::Result;
 use async_trait::async_trait;
-use redis::{AsyncCommands, RedisError};
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, ErrorKind, RedisError};
 use thiserror::Error;
+use tokio::sync::Mutex;
 use tokio::time::timeout;
 
 // average for all commands is <10ms, check grafana
 const REDIS_TIMEOUT_MILLISECS: u64 = 10;
+// flushes increment many fields at once, and are not retried once sent
+const REDIS_FLUSH_TIMEOUT_MILLISECS: u64 = 2000;
 
 #[derive(Error, Debug)]
 pub enum CustomRedisError {
@@ -22,10 +26,14 @@ pub enum CustomRedisError {
 
     #[error("Timeout error")]
     Timeout(#[from] tokio::time::error::Elapsed),
+
+    /// Nothing was sent, as we could not connect to redis
+    #[error("Connection error: {0}")]
+    ConnectionError(RedisError),
 }
 /// A simple redis wrapper
 /// Copied from capture/src/redis.rs.
-/// Supports get, set, del, zrangebyscore, and hincrby operations
+/// Supports get, set, del, zrangebyscore, hincrby, and hincrby_many operations
 
 #[async_trait]
 pub trait Client {
@@ -37,6 +45,12 @@ pub trait Client {
         v: String,
         count: Option<i32>,
     ) -> Result<(), CustomRedisError>;
+    /// Applies every `(key, field, count)` increment in a single transaction. Only a
+    /// `ConnectionError` guarantees that none of them were applied.
+    async fn hincrby_many(
+        &self,
+        increments: Vec<(String, String, i32)>,
+    ) -> Result<(), CustomRedisError>;
     async fn get(&self, k: String) -> Result<String, CustomRedisError>;
     async fn set(&self, k: String, v: String) -> Result<()>;
     async fn del(&self, k: String) -> Result<(), CustomRedisError>;
@@ -45,13 +59,35 @@ pub trait Client {
 
 pub struct RedisClient {
     client: redis::Client,
+    // Shared by the flushes, that run concurrently with the requests
+    multiplexed: Mutex<Option<MultiplexedConnection>>,
 }
 
 impl RedisClient {
     pub fn new(addr: String) -> Result<RedisClient> {
         let client = redis::Client::open(addr)?;
 
-        Ok(RedisClient { client })
+        Ok(RedisClient {
+            client,
+            multiplexed: Mutex::new(None),
+        })
+    }
+
+    /// Returns the shared multiplexed connection, connecting on first use or after it was dropped.
+    async fn multiplexed_connection(&self) -> Result<MultiplexedConnection, RedisError> {
+        let mut multiplexed = self.multiplexed.lock().await;
+        if let Some(conn) = multiplexed.as_ref() {
+            return Ok(conn.clone());
+        }
+
+        let conn = timeout(
+            Duration::from_millis(REDIS_FLUSH_TIMEOUT_MILLISECS),
+            self.client.get_multiplexed_tokio_connection(),
+        )
+        .await
+        .map_err(|_| RedisError::from((ErrorKind::IoError, "connection timed out")))??;
+        *multiplexed = Some(conn.clone());
+        Ok(conn)
     }
 }
 
@@ -81,6 +117,41 @@ impl Client for RedisClient {
         fut.map_err(CustomRedisError::from)
     }
 
+    async fn hincrby_many(
+        &self,
+        increments: Vec<(String, String, i32)>,
+    ) -> Result<(), CustomRedisError> {
+        if increments.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self
+            .multiplexed_connection()
+            .await
+            .map_err(CustomRedisError::ConnectionError)?;
+
+        // All or nothing, so that a failed flush never leaves some of the counts applied
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (k, v, count) in &increments {
+            pipe.hincr(k, v, *count).ignore();
+        }
+
+        let results = pipe.query_async::<_, ()>(&mut conn);
+        let fut = timeout(
+            Duration::from_millis(REDIS_FLUSH_TIMEOUT_MILLISECS),
+            results,
+        )
+        .await?;
+
+        if let Err(e) = &fut {
+            if e.is_connection_dropped() || e.is_io_error() {
+                // Reconnect on the next flush
+                *self.multiplexed.lock().await = None;
+            }
+        }
+        fut.map_err(CustomRedisError::from)
+    }
+
     async fn get(&self, k: String) -> Result<String, CustomRedisError> {
         let mut conn = self.client.get_async_connection().await?;
 
@@ -104,6 +104,9 @@ pub struct Config {
 
     #[envconfig(from = "CACHE_TTL_SECONDS", default = "300")]
     pub cache_ttl_seconds: u64,
+
+    #[envconfig(from = "FLAG_REQUEST_COUNTS_FLUSH_INTERVAL_SECS", default = "10")]
+    pub flag_request_counts_flush_interval_secs: u64,
 }
 
 impl Config {
@@ -122,6 +125,7 @@ impl Config {
             team_ids_to_track: TeamIdsToTrack::All,
             cache_max_cohort_entries: 100_000,
             cache_ttl_seconds: 300,
+            flag_request_counts_flush_interval_secs: 10,
         }
     }
 
@@ -1,9 +1,13 @@
 use anyhow::Result;
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use common_metrics::inc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::MissedTickBehavior;
 
 use crate::client::redis::{Client as RedisClient, CustomRedisError};
 use crate::flags::flag_request::FlagRequestType;
+use crate::metrics::metrics_consts::FLAG_REQUEST_COUNT_FLUSH_ERRORS_COUNTER;
 
 const CACHE_BUCKET_SIZE: u64 = 60 * 2; // duration in seconds
 
@@ -16,17 +20,21 @@ pub fn get_team_request_key(team_id: i32, request_type: FlagRequestType) -> Stri
     }
 }
 
+fn current_time_bucket() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / CACHE_BUCKET_SIZE
+}
+
 pub async fn increment_request_count(
     redis_client: Arc<dyn RedisClient + Send + Sync>,
     team_id: i32,
     count: i32,
     request_type: FlagRequestType,
 ) -> Result<(), CustomRedisError> {
-    let time_bucket = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-        / CACHE_BUCKET_SIZE;
+    let time_bucket = current_time_bucket();
     let key_name = get_team_request_key(team_id, request_type);
     redis_client
         .hincrby(key_name, time_bucket.to_string(), Some(count))
@@ -34,10 +42,104 @@ pub async fn increment_request_count(
     Ok(())
 }
 
+type RequestCounts = HashMap<(i32, FlagRequestType, u64), i32>;
+
+/// Aggregates flag request counts in memory, so that billing usage is written to Redis with one
+/// transaction on every flush, instead of one `HINCRBY` per request. Counts are keyed by the time bucket
+/// the requests were made in, so they land in that bucket however late they are flushed.
+#[derive(Clone, Default)]
+pub struct FlagRequestCounter {
+    counts: Arc<Mutex<RequestCounts>>,
+}
+
+impl FlagRequestCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn increment(&self, team_id: i32, request_type: FlagRequestType) {
+        self.add(team_id, request_type, current_time_bucket(), 1);
+    }
+
+    fn add(&self, team_id: i32, request_type: FlagRequestType, time_bucket: u64, count: i32) {
+        let mut counts = self.counts.lock().expect("poisoned request counts lock");
+        *counts
+            .entry((team_id, request_type, time_bucket))
+            .or_default() += count;
+    }
+
+    /// Writes the aggregated counts to Redis. Counts are only kept for the next flush if they were
+    /// not sent, as retrying counts Redis may have applied could bill them twice.
+    pub async fn flush(&self, redis_client: Arc<dyn RedisClient + Send + Sync>) {
+        let counts =
+            std::mem::take(&mut *self.counts.lock().expect("poisoned request counts lock"));
+
+        if counts.is_empty() {
+            return;
+        }
+
+        let increments = counts
+            .iter()
+            .map(|(&(team_id, request_type, time_bucket), &count)| {
+                (
+                    get_team_request_key(team_id, request_type),
+                    time_bucket.to_string(),
+                    count,
+                )
+            })
+            .collect();
+
+        match redis_client.hincrby_many(increments).await {
+            Ok(()) => (),
+            Err(CustomRedisError::ConnectionError(e)) => {
+                tracing::warn!(
+                    "Failed to connect to flush request counts for {} teams and buckets, keeping them: {}",
+                    counts.len(),
+                    e
+                );
+                inc(
+                    FLAG_REQUEST_COUNT_FLUSH_ERRORS_COUNTER,
+                    &[("outcome".to_string(), "kept".to_string())],
+                    1,
+                );
+                for ((team_id, request_type, time_bucket), count) in counts {
+                    self.add(team_id, request_type, time_bucket, count);
+                }
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Failed to flush request counts for {} teams and buckets, dropping them: {}",
+                    counts.len(),
+                    e
+                );
+                inc(
+                    FLAG_REQUEST_COUNT_FLUSH_ERRORS_COUNTER,
+                    &[("outcome".to_string(), "dropped".to_string())],
+                    1,
+                );
+            }
+        }
+    }
+
+    /// Flushes the counts to Redis on every interval, forever.
+    pub async fn run_flush_loop(
+        self,
+        redis_client: Arc<dyn RedisClient + Send + Sync>,
+        interval: Duration,
+    ) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            self.flush(redis_client.clone()).await;
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::utils::test_utils::setup_redis_client;
+    use crate::utils::test_utils::{setup_redis_client, MockRedisClient};
 
     #[tokio::test]
     async fn test_get_team_request_key() {
@@ -109,4 +211,117 @@ mod tests {
         redis_client.del(decide_key).await.unwrap();
         redis_client.del(local_eval_key).await.unwrap();
     }
+
+    #[tokio::test]
+    async fn test_flag_request_counter_aggregates_counts_until_flushed() {
+        let redis_client = setup_redis_client(None);
+        let counter = FlagRequestCounter::new();
+
+        let team_id = 790;
+        let decide_key = get_team_request_key(team_id, FlagRequestType::Decide);
+        let local_eval_key = get_team_request_key(team_id, FlagRequestType::LocalEvaluation);
+        redis_client.del(decide_key.clone()).await.unwrap();
+        redis_client.del(local_eval_key.clone()).await.unwrap();
+
+        for _ in 0..3 {
+            counter.increment(team_id, FlagRequestType::Decide);
+        }
+        counter.increment(team_id, FlagRequestType::LocalEvaluation);
+
+        // Nothing is written until the counter is flushed
+        let time_bucket = current_time_bucket();
+        assert!(redis_client
+            .hget(decide_key.clone(), time_bucket.to_string())
+            .await
+            .is_err());
+
+        counter.flush(redis_client.clone()).await;
+        // Flushing again doesn't count the same requests twice
+        counter.flush(redis_client.clone()).await;
+
+        let decide_count: i32 = redis_client
+            .hget(decide_key.clone(), time_bucket.to_string())
+            .await
+            .unwrap()
+            .parse()
+            .unwrap();
+        let local_eval_count: i32 = redis_client
+            .hget(local_eval_key.clone(), time_bucket.to_string())
+            .await
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        assert_eq!(decide_count, 3);
+        assert_eq!(local_eval_count, 1);
+
+        redis_client.del(decide_key).await.unwrap();
+        redis_client.del(local_eval_key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flag_request_counter_keeps_counts_on_flush_errors() {
+        let redis_client = setup_redis_client(Some("redis://localhost:1111/".to_string()));
+        let counter = FlagRequestCounter::new();
+
+        counter.add(791, FlagRequestType::Decide, 100, 1);
+        counter.flush(redis_client).await;
+
+        // The count is kept in the bucket its requests were made in
+        let counts = counter.counts.lock().unwrap();
+        assert_eq!(counts.get(&(791, FlagRequestType::Decide, 100)), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_flag_request_counter_drops_counts_that_may_have_been_sent() {
+        let redis_client = Arc::new(MockRedisClient::new().timing_out_transactions());
+        let counter = FlagRequestCounter::new();
+
+        counter.add(791, FlagRequestType::Decide, 100, 1);
+        counter.flush(redis_client.clone()).await;
+
+        // Retrying could count the requests twice
+        assert!(counter.counts.lock().unwrap().is_empty());
+
+        // The timed out transaction was applied, and is not sent again
+        counter.flush(redis_client.clone()).await;
+        let key = get_team_request_key(791, FlagRequestType::Decide);
+        assert_eq!(
+            redis_client.hget(key, "100".to_string()).await.unwrap(),
+            "1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flag_request_counter_flushes_counts_to_their_time_buckets() {
+        let redis_client = setup_redis_client(None);
+        let counter = FlagRequestCounter::new();
+
+        let team_id = 792;
+        let decide_key = get_team_request_key(team_id, FlagRequestType::Decide);
+        redis_client.del(decide_key.clone()).await.unwrap();
+
+        let time_bucket = current_time_bucket();
+        counter.add(team_id, FlagRequestType::Decide, time_bucket - 1, 2);
+        counter.add(team_id, FlagRequestType::Decide, time_bucket, 3);
+        counter.flush(redis_client.clone()).await;
+
+        let previous_count: i32 = redis_client
+            .hget(decide_key.clone(), (time_bucket - 1).to_string())
+            .await
+            .unwrap()
+            .parse()
+            .unwrap();
+        let current_count: i32 = redis_client
+            .hget(decide_key.clone(), time_bucket.to_string())
+            .await
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        assert_eq!(previous_count, 2);
+        assert_eq!(current_count, 3);
+
+        redis_client.del(decide_key).await.unwrap();
+    }
 }
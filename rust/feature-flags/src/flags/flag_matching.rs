@@ -1,5 +1,5 @@
 use crate::api::errors::FlagError;
-use crate::api::types::{FlagValue, FlagsResponse};
+use crate::api::types::{FlagDetails, FlagValue, FlagsResponse};
 use crate::client::database::Client as DatabaseClient;
 use crate::cohort::cohort_cache_manager::CohortCacheManager;
 use crate::cohort::cohort_models::{Cohort, CohortId};
@@ -307,7 +307,7 @@ impl FeatureFlagMatcher {
         FlagsResponse {
             error_while_computing_flags: initial_error
                 || flags_response.error_while_computing_flags,
-            feature_flags: flags_response.feature_flags,
+            ..flags_response
         }
     }
 
@@ -405,6 +405,8 @@ impl FeatureFlagMatcher {
         hash_key_overrides: Option<HashMap<String, String>>,
     ) -> FlagsResponse {
         let mut result = HashMap::new();
+        let mut payloads = HashMap::new();
+        let mut details = HashMap::new();
         let mut error_while_computing_flags = false;
         let mut flags_needing_db_properties = Vec::new();
 
@@ -426,6 +428,12 @@ impl FeatureFlagMatcher {
                 Ok(Some(flag_match)) => {
                     let flag_value = self.flag_match_to_value(&flag_match);
                     result.insert(flag.key.clone(), flag_value);
+                    if let Some(payload) =
+                        flag_match.payload.as_ref().filter(|_| flag_match.matches)
+                    {
+                        payloads.insert(flag.key.clone(), payload.clone());
+                    }
+                    details.insert(flag.key.clone(), FlagDetails::create(flag, &flag_match));
                 }
                 Ok(None) => {
                     flags_needing_db_properties.push(flag.clone());
@@ -498,6 +506,12 @@ impl FeatureFlagMatcher {
                     Ok(flag_match) => {
                         let flag_value = self.flag_match_to_value(&flag_match);
                         result.insert(flag.key.clone(), flag_value);
+                        if let Some(payload) =
+                            flag_match.payload.as_ref().filter(|_| flag_match.matches)
+                        {
+                            payloads.insert(flag.key.clone(), payload.clone());
+                        }
+                        details.insert(flag.key.clone(), FlagDetails::create(&flag, &flag_match));
                     }
                     Err(e) => {
                         error_while_computing_flags = true;
@@ -520,6 +534,8 @@ impl FeatureFlagMatcher {
         FlagsResponse {
             error_while_computing_flags,
             feature_flags: result,
+            feature_flag_payloads: Some(payloads),
+            flags: Some(details),
         }
     }
 
@@ -448,6 +448,14 @@ mod tests {
             ("is_date_exact", OperatorType::IsDateExact),
             ("is_date_after", OperatorType::IsDateAfter),
             ("is_date_before", OperatorType::IsDateBefore),
+            ("semver_eq", OperatorType::SemverEq),
+            ("semver_gt", OperatorType::SemverGt),
+            ("semver_gte", OperatorType::SemverGte),
+            ("semver_lt", OperatorType::SemverLt),
+            ("semver_lte", OperatorType::SemverLte),
+            ("semver_tilde", OperatorType::SemverTilde),
+            ("semver_caret", OperatorType::SemverCaret),
+            ("semver_wildcard", OperatorType::SemverWildcard),
         ];
 
         for (op_str, op_type) in operators {
@@ -18,7 +18,7 @@ use crate::{
     team::team_models::Team,
 };
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum FlagRequestType {
     Decide,
     LocalEvaluation,
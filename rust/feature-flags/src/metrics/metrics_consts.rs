@@ -24,3 +24,4 @@ pub const DB_PERSON_AND_GROUP_PROPERTIES_READS_COUNTER: &str =
     "db_person_and_group_properties_reads_total";
 pub const DB_PERSON_PROPERTIES_READS_COUNTER: &str = "db_person_properties_reads_total";
 pub const DB_GROUP_PROPERTIES_READS_COUNTER: &str = "db_group_properties_reads_total";
+pub const FLAG_REQUEST_COUNT_FLUSH_ERRORS_COUNTER: &str = "flag_request_count_flush_errors_total";
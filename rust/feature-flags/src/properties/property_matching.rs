@@ -1,9 +1,38 @@
 use std::collections::HashMap;
 
 use crate::properties::property_models::{OperatorType, PropertyFilter};
+use crate::properties::semver::{SemanticVersion, VersionRange};
+use chrono::{DateTime, Duration, Months, NaiveDate, NaiveDateTime, Utc};
+use once_cell::sync::Lazy;
 use regex::Regex;
 use serde_json::Value;
 
+/// Relative dates of flag filters, `-7d` meaning seven days ago
+static RELATIVE_DATE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^-?(?P<number>[0-9]+)(?P<interval>[a-z])$").unwrap());
+
+/// Date formats with an offset, tried in order after RFC 3339
+const OFFSET_DATE_FORMATS: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S%.f %:z",
+    "%Y-%m-%d %H:%M:%S%.f%:z",
+    "%Y-%m-%d %H:%M:%S%.f %z",
+    "%Y-%m-%dT%H:%M:%S%.f%z",
+];
+
+/// Date formats without offset, taken as UTC
+const NAIVE_DATE_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M",
+];
+
+/// Dates without time, taken as midnight UTC
+const DAY_FORMATS: &[&str] = &["%Y-%m-%d", "%Y%m%d"];
+
+/// Shorter digit strings are compact dates like 20220501 rather than timestamps before 1973
+const MIN_TIMESTAMP_STRING_DIGITS: usize = 9;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum FlagMatchingError {
     ValidationError(String),
@@ -169,29 +198,75 @@ pub fn match_property(
                 ))
             }
         }
+        // Deliberately diverges from python, that only matches string dates with before and after:
+        // we also take unix timestamps, and match the day of `is_date_exact`
         OperatorType::IsDateExact | OperatorType::IsDateAfter | OperatorType::IsDateBefore => {
-            // TODO: Handle date operators
-            Ok(false)
-            // let parsed_date = determine_parsed_date_for_property_matching(match_value);
-
-            // if parsed_date.is_none() {
-            //     return Ok(false);
-            // }
-
-            // if let Some(override_value) = value.as_str() {
-            //     let override_date = match parser::parse(override_value) {
-            //         Ok(override_date) => override_date,
-            //         Err(_) => return Ok(false),
-            //     };
-
-            //     match operator {
-            //         OperatorType::IsDateBefore => Ok(override_date < parsed_date.unwrap()),
-            //         OperatorType::IsDateAfter => Ok(override_date > parsed_date.unwrap()),
-            //         _ => Ok(false),
-            //     }
-            // } else {
-            //     Ok(false)
-            // }
+            // Like python, invalid dates on either side are not a match
+            let Some(filter_date) = determine_parsed_date_for_property_matching(value) else {
+                return Ok(false);
+            };
+            let Some(property_date) = match_value.and_then(parse_property_date) else {
+                return Ok(false);
+            };
+
+            match operator {
+                OperatorType::IsDateBefore => Ok(property_date < filter_date),
+                OperatorType::IsDateAfter => Ok(property_date > filter_date),
+                _ => Ok(property_date.date_naive() == filter_date.date_naive()),
+            }
+        }
+        OperatorType::SemverEq
+        | OperatorType::SemverGt
+        | OperatorType::SemverGte
+        | OperatorType::SemverLt
+        | OperatorType::SemverLte => {
+            let Some(match_value) = match_value else {
+                return Ok(false);
+            };
+            let Some(version) = SemanticVersion::parse(&to_string_representation(match_value))
+            else {
+                return Err(FlagMatchingError::ValidationError(
+                    "value is not a semantic version".to_string(),
+                ));
+            };
+            let Some(filter_version) = SemanticVersion::parse(&to_string_representation(value))
+            else {
+                return Err(FlagMatchingError::ValidationError(
+                    "override value is not a semantic version".to_string(),
+                ));
+            };
+
+            match operator {
+                OperatorType::SemverEq => Ok(version == filter_version),
+                OperatorType::SemverGt => Ok(version > filter_version),
+                OperatorType::SemverGte => Ok(version >= filter_version),
+                OperatorType::SemverLt => Ok(version < filter_version),
+                _ => Ok(version <= filter_version),
+            }
+        }
+        OperatorType::SemverTilde | OperatorType::SemverCaret | OperatorType::SemverWildcard => {
+            let Some(match_value) = match_value else {
+                return Ok(false);
+            };
+            let Some(version) = SemanticVersion::parse(&to_string_representation(match_value))
+            else {
+                return Err(FlagMatchingError::ValidationError(
+                    "value is not a semantic version".to_string(),
+                ));
+            };
+            let filter_value = to_string_representation(value);
+            let range = match operator {
+                OperatorType::SemverTilde => VersionRange::tilde(&filter_value),
+                OperatorType::SemverCaret => VersionRange::caret(&filter_value),
+                _ => VersionRange::wildcard(&filter_value),
+            };
+
+            match range {
+                Some(range) => Ok(range.contains(&version)),
+                None => Err(FlagMatchingError::ValidationError(
+                    "override value is not a version range".to_string(),
+                )),
+            }
         }
         OperatorType::In | OperatorType::NotIn => {
             // TODO: we handle these in cohort matching, so we can just return false here
@@ -202,6 +277,85 @@ pub fn match_property(
     }
 }
 
+/// Parses the date of a flag filter, either relative to now like `-7d`, or absolute.
+/// Filter dates are always strings, numbers are not valid filter dates.
+fn determine_parsed_date_for_property_matching(value: &Value) -> Option<DateTime<Utc>> {
+    let value = value.as_str()?.trim();
+    relative_date_parse_for_feature_flag_matching(value).or_else(|| parse_date_string(value))
+}
+
+/// Parses relative dates like python: a number of hours, days, weeks, months or years ago.
+fn relative_date_parse_for_feature_flag_matching(value: &str) -> Option<DateTime<Utc>> {
+    let captures = RELATIVE_DATE_REGEX.captures(value)?;
+    let number: u32 = captures["number"].parse().ok()?;
+    if number >= 10_000 {
+        // Guard against overflow, and dates way out of range anyway
+        return None;
+    }
+
+    let now = Utc::now();
+    match &captures["interval"] {
+        "h" => now.checked_sub_signed(Duration::hours(number.into())),
+        "d" => now.checked_sub_signed(Duration::days(number.into())),
+        "w" => now.checked_sub_signed(Duration::weeks(number.into())),
+        "m" => now.checked_sub_months(Months::new(number)),
+        "y" => now.checked_sub_months(Months::new(number * 12)),
+        _ => None,
+    }
+}
+
+/// Parses the date of a property: an ISO or compact date or datetime, taken as UTC without
+/// offset, or a unix timestamp in seconds, or in milliseconds as sent by the JS SDKs.
+fn parse_property_date(value: &Value) -> Option<DateTime<Utc>> {
+    let timestamp = match value {
+        Value::Number(number) => number.as_f64()?,
+        Value::String(value) => {
+            let value = value.trim();
+            let digits = value
+                .split('.')
+                .next()
+                .map_or(0, |integer| integer.trim_start_matches(['-', '+']).len());
+            match value.parse::<f64>() {
+                Ok(timestamp) if digits >= MIN_TIMESTAMP_STRING_DIGITS => timestamp,
+                _ => return parse_date_string(value),
+            }
+        }
+        _ => return None,
+    };
+    if !timestamp.is_finite() {
+        return None;
+    }
+
+    // Seconds timestamps only reach 1e11 in year 5138, larger values are milliseconds
+    let millis = if timestamp.abs() >= 1e11 {
+        timestamp
+    } else {
+        timestamp * 1000.0
+    };
+    DateTime::from_timestamp_millis(millis as i64)
+}
+
+fn parse_date_string(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Some(date.with_timezone(&Utc));
+    }
+    for format in OFFSET_DATE_FORMATS {
+        if let Ok(date) = DateTime::parse_from_str(value, format) {
+            return Some(date.with_timezone(&Utc));
+        }
+    }
+    for format in NAIVE_DATE_FORMATS {
+        if let Ok(date) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(date.and_utc());
+        }
+    }
+    DAY_FORMATS
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|date| date.and_utc())
+}
+
 fn is_truthy_or_falsy_property_value(value: &Value) -> bool {
     if value.is_boolean() {
         return true;
@@ -1357,7 +1511,6 @@ mod test_match_properties {
         )
         .expect("Expected no errors with full props mode"));
 
-        // TODO: Handle date operators
         let property_is_date_before = PropertyFilter {
             key: "key".to_string(),
             value: json!("2021-01-01"),
@@ -1374,4 +1527,269 @@ mod test_match_properties {
         )
         .expect("Expected no errors with full props mode"));
     }
+
+    fn date_filter(operator: OperatorType, value: Value) -> PropertyFilter {
+        PropertyFilter {
+            key: "key".to_string(),
+            value,
+            operator: Some(operator),
+            prop_type: "person".to_string(),
+            group_type_index: None,
+            negation: None,
+        }
+    }
+
+    fn matches_date(filter: &PropertyFilter, value: Value) -> bool {
+        match_property(filter, &HashMap::from([("key".to_string(), value)]), true)
+            .expect("expected match to exist")
+    }
+
+    #[test]
+    fn test_match_properties_date_operators() {
+        let property_a = date_filter(OperatorType::IsDateBefore, json!("2022-05-01"));
+        assert!(matches_date(&property_a, json!("2022-03-01")));
+        assert!(matches_date(&property_a, json!("2022-04-30")));
+        assert!(matches_date(&property_a, json!("2022-04-30T01:02:03")));
+        assert!(matches_date(
+            &property_a,
+            json!("2022-04-30T01:02:03+02:00")
+        ));
+        assert!(matches_date(&property_a, json!("2022-04-30 01:02:03")));
+        assert!(!matches_date(&property_a, json!("2022-05-30")));
+        // Invalid dates never match
+        assert!(!matches_date(&property_a, json!("abcdef")));
+        assert!(!matches_date(&property_a, json!(null)));
+        assert!(!matches_date(&property_a, json!(true)));
+
+        let property_b = date_filter(OperatorType::IsDateAfter, json!("2022-05-01"));
+        assert!(matches_date(&property_b, json!("2022-05-02")));
+        assert!(matches_date(&property_b, json!("2022-05-30")));
+        assert!(matches_date(&property_b, json!("2022-05-30T00:00:00Z")));
+        assert!(!matches_date(&property_b, json!("2022-04-30")));
+        assert!(!matches_date(&property_b, json!("abcdef")));
+
+        // Invalid flag property
+        let property_c = date_filter(OperatorType::IsDateBefore, json!(1234));
+        assert!(!matches_date(&property_c, json!(1)));
+        assert!(!matches_date(&property_c, json!("2022-05-30")));
+
+        // Timezone aware property
+        let property_d = date_filter(
+            OperatorType::IsDateBefore,
+            json!("2022-04-05 12:34:12 +01:00"),
+        );
+        assert!(!matches_date(&property_d, json!("2022-05-30")));
+        assert!(matches_date(&property_d, json!("2022-03-30")));
+        assert!(matches_date(
+            &property_d,
+            json!("2022-04-05 12:34:11 +01:00")
+        ));
+        assert!(matches_date(
+            &property_d,
+            json!("2022-04-05 11:34:11 +00:00")
+        ));
+        assert!(!matches_date(
+            &property_d,
+            json!("2022-04-05 11:34:13 +00:00")
+        ));
+
+        let property_e = date_filter(OperatorType::IsDateExact, json!("2022-05-01"));
+        assert!(matches_date(&property_e, json!("2022-05-01")));
+        assert!(matches_date(&property_e, json!("2022-05-01T23:59:59Z")));
+        assert!(!matches_date(&property_e, json!("2022-05-02T00:00:00Z")));
+    }
+
+    #[test]
+    fn test_match_properties_date_operators_with_unix_timestamps() {
+        // 2022-05-01T00:00:00Z
+        let property_a = date_filter(OperatorType::IsDateBefore, json!("2022-05-01"));
+        assert!(matches_date(&property_a, json!(1651363199)));
+        assert!(matches_date(&property_a, json!(1651363199.5)));
+        assert!(matches_date(&property_a, json!("1651363199")));
+        assert!(matches_date(&property_a, json!(1651363199000_i64)));
+        assert!(!matches_date(&property_a, json!(1651363201)));
+        assert!(!matches_date(&property_a, json!(1651363201000_i64)));
+        // Too short to be a timestamp, this is a compact date
+        assert!(matches_date(&property_a, json!("20220430")));
+        assert!(!matches_date(&property_a, json!("20220501")));
+
+        let property_b = date_filter(OperatorType::IsDateExact, json!("2022-05-01"));
+        assert!(matches_date(&property_b, json!(1651363200)));
+        assert!(!matches_date(&property_b, json!(1651363199)));
+    }
+
+    #[test]
+    fn test_match_properties_relative_date_operators() {
+        let now = Utc::now();
+        let property_a = date_filter(OperatorType::IsDateBefore, json!("-6h"));
+        assert!(matches_date(&property_a, json!("2022-03-01")));
+        assert!(matches_date(
+            &property_a,
+            json!((now - Duration::hours(7)).to_rfc3339())
+        ));
+        assert!(!matches_date(
+            &property_a,
+            json!((now - Duration::hours(5)).to_rfc3339())
+        ));
+
+        let property_b = date_filter(OperatorType::IsDateAfter, json!("-7d"));
+        assert!(matches_date(
+            &property_b,
+            json!((now - Duration::days(6)).to_rfc3339())
+        ));
+        assert!(!matches_date(
+            &property_b,
+            json!((now - Duration::days(8)).to_rfc3339())
+        ));
+        assert!(matches_date(&property_b, json!(now.timestamp())));
+
+        let property_c = date_filter(OperatorType::IsDateAfter, json!("-2w"));
+        assert!(matches_date(
+            &property_c,
+            json!((now - Duration::days(13)).to_rfc3339())
+        ));
+        assert!(!matches_date(
+            &property_c,
+            json!((now - Duration::days(15)).to_rfc3339())
+        ));
+
+        let property_d = date_filter(OperatorType::IsDateBefore, json!("-1m"));
+        assert!(matches_date(
+            &property_d,
+            json!((now - Duration::days(32)).to_rfc3339())
+        ));
+        assert!(!matches_date(
+            &property_d,
+            json!((now - Duration::days(27)).to_rfc3339())
+        ));
+
+        let property_e = date_filter(OperatorType::IsDateAfter, json!("-1y"));
+        assert!(matches_date(
+            &property_e,
+            json!((now - Duration::days(364)).to_rfc3339())
+        ));
+        assert!(!matches_date(
+            &property_e,
+            json!((now - Duration::days(367)).to_rfc3339())
+        ));
+
+        // Unknown intervals and overflowing numbers are invalid dates
+        let property_f = date_filter(OperatorType::IsDateBefore, json!("-1x"));
+        assert!(!matches_date(&property_f, json!("2022-03-01")));
+        let property_g = date_filter(OperatorType::IsDateBefore, json!("-10000y"));
+        assert!(!matches_date(&property_g, json!("2022-03-01")));
+    }
+
+    fn semver_filter(operator: OperatorType, value: &str) -> PropertyFilter {
+        PropertyFilter {
+            key: "$app_version".to_string(),
+            value: json!(value),
+            operator: Some(operator),
+            prop_type: "person".to_string(),
+            group_type_index: None,
+            negation: None,
+        }
+    }
+
+    fn matches_version(filter: &PropertyFilter, version: Value) -> Result<bool, FlagMatchingError> {
+        match_property(
+            filter,
+            &HashMap::from([("$app_version".to_string(), version)]),
+            false,
+        )
+    }
+
+    #[test]
+    fn test_match_properties_semver_operators() {
+        let property_gte = semver_filter(OperatorType::SemverGte, "2.10.0");
+        // Numbers are compared one by one, unlike floats and strings
+        assert_eq!(matches_version(&property_gte, json!("2.10.0")), Ok(true));
+        assert_eq!(matches_version(&property_gte, json!("2.10.1")), Ok(true));
+        assert_eq!(matches_version(&property_gte, json!("v10.0")), Ok(true));
+        assert_eq!(matches_version(&property_gte, json!("2.9.9")), Ok(false));
+        assert_eq!(
+            matches_version(&property_gte, json!("2.10.0-beta.1")),
+            Ok(false)
+        );
+        assert_eq!(matches_version(&property_gte, json!(3)), Ok(true));
+
+        let property_gt = semver_filter(OperatorType::SemverGt, "1.0.0-alpha.1");
+        assert_eq!(
+            matches_version(&property_gt, json!("1.0.0-alpha.beta")),
+            Ok(true)
+        );
+        assert_eq!(
+            matches_version(&property_gt, json!("1.0.0-alpha")),
+            Ok(false)
+        );
+        assert_eq!(matches_version(&property_gt, json!("1.0.0")), Ok(true));
+
+        let property_lt = semver_filter(OperatorType::SemverLt, "1.2");
+        assert_eq!(matches_version(&property_lt, json!("1.1.99")), Ok(true));
+        assert_eq!(matches_version(&property_lt, json!("1.2.0-rc.1")), Ok(true));
+        assert_eq!(matches_version(&property_lt, json!("1.2.0")), Ok(false));
+
+        let property_lte = semver_filter(OperatorType::SemverLte, "1.2.0");
+        assert_eq!(matches_version(&property_lte, json!("1.2.0")), Ok(true));
+        assert_eq!(matches_version(&property_lte, json!("1.2.1")), Ok(false));
+
+        // Build metadata is ignored
+        let property_eq = semver_filter(OperatorType::SemverEq, "1.2.3");
+        assert_eq!(matches_version(&property_eq, json!("1.2.3+456")), Ok(true));
+        assert_eq!(
+            matches_version(&property_eq, json!("1.2.3-beta")),
+            Ok(false)
+        );
+
+        assert_eq!(
+            matches_version(&property_eq, json!("not-a-version")),
+            Err(FlagMatchingError::ValidationError(
+                "value is not a semantic version".to_string()
+            ))
+        );
+        assert!(!match_property(&property_eq, &HashMap::new(), false).unwrap());
+        assert!(matches_version(
+            &semver_filter(OperatorType::SemverEq, "abc"),
+            json!("1.2.3")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_match_properties_semver_ranges() {
+        let property_tilde = semver_filter(OperatorType::SemverTilde, "~1.2.3");
+        assert_eq!(matches_version(&property_tilde, json!("1.2.9")), Ok(true));
+        assert_eq!(matches_version(&property_tilde, json!("1.3.0")), Ok(false));
+
+        let property_caret = semver_filter(OperatorType::SemverCaret, "^1.2.3");
+        assert_eq!(matches_version(&property_caret, json!("1.9.0")), Ok(true));
+        assert_eq!(matches_version(&property_caret, json!("2.0.0")), Ok(false));
+        assert_eq!(matches_version(&property_caret, json!("1.2.2")), Ok(false));
+
+        let property_caret_zero = semver_filter(OperatorType::SemverCaret, "0.2.3");
+        assert_eq!(
+            matches_version(&property_caret_zero, json!("0.2.5")),
+            Ok(true)
+        );
+        assert_eq!(
+            matches_version(&property_caret_zero, json!("0.3.0")),
+            Ok(false)
+        );
+
+        let property_wildcard = semver_filter(OperatorType::SemverWildcard, "2.*");
+        assert_eq!(
+            matches_version(&property_wildcard, json!("2.10.1")),
+            Ok(true)
+        );
+        assert_eq!(
+            matches_version(&property_wildcard, json!("3.0.0")),
+            Ok(false)
+        );
+
+        assert!(matches_version(
+            &semver_filter(OperatorType::SemverWildcard, "a.*"),
+            json!("1.0.0")
+        )
+        .is_err());
+    }
 }
@@ -20,6 +20,14 @@ pub enum OperatorType {
     IsDateBefore,
     In,
     NotIn,
+    SemverEq,
+    SemverGt,
+    SemverGte,
+    SemverLt,
+    SemverLte,
+    SemverTilde,
+    SemverCaret,
+    SemverWildcard,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
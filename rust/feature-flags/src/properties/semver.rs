@@ -0,0 +1,323 @@
+use std::cmp::Ordering;
+
+/// A semantic version, compared following the semver 2.0 precedence rules:
+/// pre-release versions have a lower precedence than the release, and build
+/// metadata is ignored.
+///
+/// Parsing is lenient to match how apps report their versions: a leading `v` is
+/// allowed, and missing minor and patch numbers default to 0, so `v2.10` is `2.10.0`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SemanticVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub pre_release: Vec<PreReleaseIdentifier>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreReleaseIdentifier {
+    Numeric(u64),
+    AlphaNumeric(String),
+}
+
+impl Ord for PreReleaseIdentifier {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Numeric(lhs), Self::Numeric(rhs)) => lhs.cmp(rhs),
+            (Self::AlphaNumeric(lhs), Self::AlphaNumeric(rhs)) => lhs.cmp(rhs),
+            // Numeric identifiers always have lower precedence than alphanumeric ones
+            (Self::Numeric(_), Self::AlphaNumeric(_)) => Ordering::Less,
+            (Self::AlphaNumeric(_), Self::Numeric(_)) => Ordering::Greater,
+        }
+    }
+}
+
+impl PartialOrd for PreReleaseIdentifier {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SemanticVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| {
+                match (self.pre_release.is_empty(), other.pre_release.is_empty()) {
+                    (true, true) => Ordering::Equal,
+                    (true, false) => Ordering::Greater,
+                    (false, true) => Ordering::Less,
+                    // Identifiers are compared in order, a larger set of fields wins if all
+                    // the preceding identifiers are equal
+                    (false, false) => self.pre_release.cmp(&other.pre_release),
+                }
+            })
+    }
+}
+
+impl PartialOrd for SemanticVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl SemanticVersion {
+    fn new(major: u64, minor: u64, patch: u64) -> Self {
+        SemanticVersion {
+            major,
+            minor,
+            patch,
+            pre_release: Vec::new(),
+        }
+    }
+
+    /// The lowest version with these numbers, lower than all their pre-releases, used as
+    /// the exclusive upper bound of ranges so that `~1.2` does not match `1.3.0-beta`.
+    fn lowest(major: u64, minor: u64, patch: u64) -> Self {
+        SemanticVersion {
+            major,
+            minor,
+            patch,
+            pre_release: vec![PreReleaseIdentifier::Numeric(0)],
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::parse_partial(value).map(|(version, _)| version)
+    }
+
+    /// Parses a version that can have missing numbers, returning how many were set.
+    fn parse_partial(value: &str) -> Option<(Self, usize)> {
+        let value = value.trim();
+        let value = value
+            .strip_prefix('v')
+            .or_else(|| value.strip_prefix('V'))
+            .unwrap_or(value);
+        // Build metadata does not take part in precedence
+        let value = value.split_once('+').map_or(value, |(version, _)| version);
+        let (core, pre_release) = match value.split_once('-') {
+            Some((core, pre_release)) => (core, Some(pre_release)),
+            None => (value, None),
+        };
+
+        let numbers = core
+            .split('.')
+            .map(|number| match number.is_empty() {
+                true => None,
+                false => number.parse::<u64>().ok(),
+            })
+            .collect::<Option<Vec<u64>>>()?;
+        if numbers.len() > 3 {
+            return None;
+        }
+
+        let pre_release = match pre_release {
+            None => Vec::new(),
+            Some(pre_release) => pre_release
+                .split('.')
+                .map(|identifier| {
+                    if identifier.is_empty()
+                        || !identifier
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+                    {
+                        None
+                    } else if let Ok(number) = identifier.parse::<u64>() {
+                        Some(PreReleaseIdentifier::Numeric(number))
+                    } else {
+                        Some(PreReleaseIdentifier::AlphaNumeric(identifier.to_string()))
+                    }
+                })
+                .collect::<Option<Vec<_>>>()?,
+        };
+
+        let number = |index: usize| numbers.get(index).copied().unwrap_or(0);
+        Some((
+            SemanticVersion {
+                major: number(0),
+                minor: number(1),
+                patch: number(2),
+                pre_release,
+            },
+            numbers.len(),
+        ))
+    }
+}
+
+/// A range of versions, from an inclusive lower bound to an exclusive upper bound.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VersionRange {
+    lower: Option<SemanticVersion>,
+    upper: Option<SemanticVersion>,
+}
+
+impl VersionRange {
+    pub fn contains(&self, version: &SemanticVersion) -> bool {
+        self.lower.as_ref().map_or(true, |lower| version >= lower)
+            && self.upper.as_ref().map_or(true, |upper| version < upper)
+    }
+
+    /// Tilde ranges allow patch changes, or minor changes if only the major is set:
+    /// `~1.2.3` is `>=1.2.3 <1.3.0`, and `~1` is `>=1.0.0 <2.0.0`.
+    pub fn tilde(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (version, numbers) =
+            SemanticVersion::parse_partial(value.strip_prefix('~').unwrap_or(value))?;
+        let upper = match numbers {
+            1 => SemanticVersion::lowest(version.major.saturating_add(1), 0, 0),
+            _ => SemanticVersion::lowest(version.major, version.minor.saturating_add(1), 0),
+        };
+        Some(VersionRange {
+            lower: Some(version),
+            upper: Some(upper),
+        })
+    }
+
+    /// Caret ranges allow changes that do not modify the left-most non-zero number:
+    /// `^1.2.3` is `>=1.2.3 <2.0.0`, `^0.2.3` is `>=0.2.3 <0.3.0`, and `^0.0.3` is
+    /// `>=0.0.3 <0.0.4`.
+    pub fn caret(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (version, numbers) =
+            SemanticVersion::parse_partial(value.strip_prefix('^').unwrap_or(value))?;
+        let upper = match (numbers, version.major, version.minor) {
+            (1, major, _) => SemanticVersion::lowest(major.saturating_add(1), 0, 0),
+            (2, 0, 0) => SemanticVersion::lowest(0, 1, 0),
+            (_, 0, 0) => SemanticVersion::lowest(0, 0, version.patch.saturating_add(1)),
+            (_, 0, minor) => SemanticVersion::lowest(0, minor.saturating_add(1), 0),
+            (_, major, _) => SemanticVersion::lowest(major.saturating_add(1), 0, 0),
+        };
+        Some(VersionRange {
+            lower: Some(version),
+            upper: Some(upper),
+        })
+    }
+
+    /// Wildcard ranges match any value for the `*`, `x` or `X` numbers and the ones after:
+    /// `1.2.*` is `>=1.2.0 <1.3.0`, `1.*` is `>=1.0.0 <2.0.0`, and `*` matches everything.
+    pub fn wildcard(value: &str) -> Option<Self> {
+        let mut numbers = Vec::with_capacity(3);
+        for part in value.trim().split('.') {
+            match part {
+                "*" | "x" | "X" => break,
+                part => numbers.push(part.parse::<u64>().ok()?),
+            }
+        }
+        let (lower, upper) = match numbers[..] {
+            [] => return Some(VersionRange::default()),
+            [major] => (
+                SemanticVersion::new(major, 0, 0),
+                SemanticVersion::lowest(major.saturating_add(1), 0, 0),
+            ),
+            [major, minor] => (
+                SemanticVersion::new(major, minor, 0),
+                SemanticVersion::lowest(major, minor.saturating_add(1), 0),
+            ),
+            // No wildcard, only matches this exact version
+            [major, minor, patch] => (
+                SemanticVersion::new(major, minor, patch),
+                SemanticVersion::lowest(major, minor, patch.saturating_add(1)),
+            ),
+            _ => return None,
+        };
+        Some(VersionRange {
+            lower: Some(lower),
+            upper: Some(upper),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(value: &str) -> SemanticVersion {
+        SemanticVersion::parse(value).expect("invalid version")
+    }
+
+    #[test]
+    fn test_parse_versions() {
+        assert_eq!(version("1.2.3"), SemanticVersion::new(1, 2, 3));
+        assert_eq!(version("v2.10"), SemanticVersion::new(2, 10, 0));
+        assert_eq!(version(" 3 "), SemanticVersion::new(3, 0, 0));
+        assert_eq!(version("1.2.3+build.5"), SemanticVersion::new(1, 2, 3));
+        assert_eq!(
+            version("1.2.3-beta.1").pre_release,
+            vec![
+                PreReleaseIdentifier::AlphaNumeric("beta".to_string()),
+                PreReleaseIdentifier::Numeric(1)
+            ]
+        );
+
+        assert_eq!(SemanticVersion::parse(""), None);
+        assert_eq!(SemanticVersion::parse("abc"), None);
+        assert_eq!(SemanticVersion::parse("1.2.3.4"), None);
+        assert_eq!(SemanticVersion::parse("1..3"), None);
+        assert_eq!(SemanticVersion::parse("1.2.3-"), None);
+        assert_eq!(SemanticVersion::parse("1.2.3-beta..1"), None);
+    }
+
+    #[test]
+    fn test_semver_precedence() {
+        // Example from https://semver.org/#spec-item-11
+        let ordered = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+            "1.9.0",
+            "1.10.0",
+            "2.0.0",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(version(pair[0]) < version(pair[1]), "{:?}", pair);
+        }
+        assert_eq!(version("1.0.0+build.1"), version("1.0.0+build.2"));
+    }
+
+    #[test]
+    fn test_version_ranges() {
+        let tilde = VersionRange::tilde("~1.2.3").unwrap();
+        assert!(tilde.contains(&version("1.2.3")));
+        assert!(tilde.contains(&version("1.2.10")));
+        assert!(!tilde.contains(&version("1.2.2")));
+        assert!(!tilde.contains(&version("1.3.0")));
+        assert!(!tilde.contains(&version("1.3.0-beta")));
+        let tilde = VersionRange::tilde("1").unwrap();
+        assert!(tilde.contains(&version("1.9.0")));
+        assert!(!tilde.contains(&version("2.0.0")));
+
+        let caret = VersionRange::caret("^1.2.3").unwrap();
+        assert!(caret.contains(&version("1.9.0")));
+        assert!(!caret.contains(&version("2.0.0")));
+        assert!(!caret.contains(&version("1.2.3-beta")));
+        let caret = VersionRange::caret("^0.2.3").unwrap();
+        assert!(caret.contains(&version("0.2.9")));
+        assert!(!caret.contains(&version("0.3.0")));
+        let caret = VersionRange::caret("^0.0.3").unwrap();
+        assert!(caret.contains(&version("0.0.3")));
+        assert!(!caret.contains(&version("0.0.4")));
+        let caret = VersionRange::caret("^0.0").unwrap();
+        assert!(caret.contains(&version("0.0.9")));
+        assert!(!caret.contains(&version("0.1.0")));
+
+        let wildcard = VersionRange::wildcard("1.2.*").unwrap();
+        assert!(wildcard.contains(&version("1.2.0")));
+        assert!(wildcard.contains(&version("1.2.99")));
+        assert!(!wildcard.contains(&version("1.3.0")));
+        let wildcard = VersionRange::wildcard("1.x").unwrap();
+        assert!(wildcard.contains(&version("1.99.0")));
+        assert!(!wildcard.contains(&version("2.0.0")));
+        assert!(VersionRange::wildcard("*")
+            .unwrap()
+            .contains(&version("42.0.0")));
+
+        assert_eq!(VersionRange::tilde("~abc"), None);
+        assert_eq!(VersionRange::caret("^"), None);
+        assert_eq!(VersionRange::wildcard("1.a.*"), None);
+    }
+}
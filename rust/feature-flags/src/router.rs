@@ -15,7 +15,9 @@ use crate::{
     },
     cohort::cohort_cache_manager::CohortCacheManager,
     config::{Config, TeamIdsToTrack},
+    flags::flag_analytics::FlagRequestCounter,
     metrics::metrics_utils::team_id_label_filter,
+    team::personal_api_key_cache::PersonalApiKeyCache,
 };
 
 #[derive(Clone)]
@@ -24,16 +26,20 @@ pub struct State {
     pub reader: Arc<dyn DatabaseClient + Send + Sync>,
     pub writer: Arc<dyn DatabaseClient + Send + Sync>,
     pub cohort_cache_manager: Arc<CohortCacheManager>,
+    pub personal_api_key_cache: Arc<PersonalApiKeyCache>,
     pub geoip: Arc<GeoIpClient>,
     pub team_ids_to_track: TeamIdsToTrack,
+    pub flag_request_counter: FlagRequestCounter,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn router<R, D>(
     redis: Arc<R>,
     reader: Arc<D>,
     writer: Arc<D>,
     cohort_cache: Arc<CohortCacheManager>,
     geoip: Arc<GeoIpClient>,
+    flag_request_counter: FlagRequestCounter,
     liveness: HealthRegistry,
     config: Config,
 ) -> Router
@@ -46,8 +52,10 @@ where
         reader,
         writer,
         cohort_cache_manager: cohort_cache,
+        personal_api_key_cache: Arc::new(PersonalApiKeyCache::default()),
         geoip,
         team_ids_to_track: config.team_ids_to_track.clone(),
+        flag_request_counter,
     };
 
     let status_router = Router::new()
@@ -57,6 +65,7 @@ where
 
     let flags_router = Router::new()
         .route("/flags", post(endpoint::flags).get(endpoint::flags))
+        .route("/flags/definitions", get(endpoint::flag_definitions))
         .layer(ConcurrencyLimitLayer::new(config.max_concurrency))
         .with_state(state);
 
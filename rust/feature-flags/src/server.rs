@@ -11,6 +11,7 @@ use crate::client::geoip::GeoIpClient;
 use crate::client::redis::RedisClient;
 use crate::cohort::cohort_cache_manager::CohortCacheManager;
 use crate::config::Config;
+use crate::flags::flag_analytics::FlagRequestCounter;
 use crate::router;
 
 pub async fn serve<F>(config: Config, listener: TcpListener, shutdown: F)
@@ -60,6 +61,12 @@ where
         Some(config.cache_ttl_seconds),
     ));
 
+    let flag_request_counter = FlagRequestCounter::new();
+    tokio::spawn(flag_request_counter.clone().run_flush_loop(
+        redis_client.clone(),
+        Duration::from_secs(config.flag_request_counts_flush_interval_secs),
+    ));
+
     let health = HealthRegistry::new("liveness");
 
     // TODO - we don't have a more complex health check yet, but we should add e.g. some around DB operations
@@ -70,11 +77,12 @@ where
 
     // You can decide which client to pass to the router, or pass both if needed
     let app = router::router(
-        redis_client,
+        redis_client.clone(),
         reader,
         writer,
         cohort_cache,
         geoip_service,
+        flag_request_counter.clone(),
         health,
         config,
     );
@@ -86,7 +94,10 @@ where
     )
     .with_graceful_shutdown(shutdown)
     .await
-    .unwrap()
+    .unwrap();
+
+    // Don't lose the usage of the requests served since the last flush
+    flag_request_counter.flush(redis_client).await;
 }
 
 async fn liveness_loop(handle: HealthHandle) {
@@ -1,2 +1,3 @@
+pub mod personal_api_key_cache;
 pub mod team_models;
 pub mod team_operations;
@@ -0,0 +1,159 @@
+use moka::future::Cache;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::api::errors::FlagError;
+use crate::client::database::Client as DatabaseClient;
+use crate::team::team_models::Team;
+use crate::team::team_operations::hash_personal_api_key;
+
+/// Revoked keys and removed memberships are picked up after at most this long
+const PERSONAL_API_KEY_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Keys created after being rejected are accepted after at most this long
+const REJECTED_PERSONAL_API_KEY_CACHE_TTL: Duration = Duration::from_secs(30);
+const PERSONAL_API_KEY_CACHE_MAX_ENTRIES: u64 = 10_000;
+
+/// Caches the teams personal API keys were validated for, so that SDKs polling the flag
+/// definitions don't query Postgres on every request, and the keys that were rejected, so that
+/// retried bad keys aren't hashed again with the slow legacy modes.
+///
+/// Entries are keyed by the sha256 hash of the key, never by the key itself, and the project
+/// token. Lookups failing for other reasons than the key being invalid are not cached.
+#[derive(Clone)]
+pub struct PersonalApiKeyCache {
+    teams: Cache<(String, String), Team>,
+    rejected: Cache<(String, String), ()>,
+}
+
+impl PersonalApiKeyCache {
+    pub fn new(max_capacity: Option<u64>, ttl: Option<Duration>) -> Self {
+        let max_capacity = max_capacity.unwrap_or(PERSONAL_API_KEY_CACHE_MAX_ENTRIES);
+        let teams = Cache::builder()
+            .time_to_live(ttl.unwrap_or(PERSONAL_API_KEY_CACHE_TTL))
+            .max_capacity(max_capacity)
+            .build();
+        let rejected = Cache::builder()
+            .time_to_live(REJECTED_PERSONAL_API_KEY_CACHE_TTL)
+            .max_capacity(max_capacity)
+            .build();
+
+        Self { teams, rejected }
+    }
+
+    /// Returns the team with the given project token if the personal API key can read its feature flags,
+    /// see `Team::from_personal_api_key`.
+    pub async fn get_team(
+        &self,
+        client: Arc<dyn DatabaseClient + Send + Sync>,
+        personal_api_key: &str,
+        token: &str,
+    ) -> Result<Team, FlagError> {
+        let cache_key = (hash_personal_api_key(personal_api_key), token.to_string());
+        if let Some(team) = self.teams.get(&cache_key).await {
+            return Ok(team);
+        }
+        if self.rejected.contains_key(&cache_key) {
+            return Err(FlagError::PersonalApiKeyValidationError);
+        }
+
+        match Team::from_personal_api_key(client, personal_api_key, token).await {
+            Ok(team) => {
+                self.teams.insert(cache_key, team.clone()).await;
+                Ok(team)
+            }
+            Err(FlagError::PersonalApiKeyValidationError) => {
+                self.rejected.insert(cache_key, ()).await;
+                Err(FlagError::PersonalApiKeyValidationError)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Default for PersonalApiKeyCache {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_utils::{
+        insert_new_team_in_pg, setup_invalid_pg_client, setup_pg_reader_client,
+    };
+
+    fn team(token: &str) -> Team {
+        Team {
+            id: 1,
+            name: "team".to_string(),
+            api_token: token.to_string(),
+            project_id: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_personal_api_key_skips_database() {
+        let client = setup_invalid_pg_client().await;
+        let cache = PersonalApiKeyCache::default();
+        cache
+            .teams
+            .insert(
+                (hash_personal_api_key("phx_key"), "phc_token".to_string()),
+                team("phc_token"),
+            )
+            .await;
+
+        let cached = cache
+            .get_team(client.clone(), "phx_key", "phc_token")
+            .await
+            .expect("cached team should be returned without the database");
+        assert_eq!(cached.api_token, "phc_token");
+
+        // Keys are only valid for the teams they were validated for
+        match cache.get_team(client.clone(), "phx_key", "phc_other").await {
+            Err(FlagError::DatabaseUnavailable) => (),
+            other => panic!("Expected DatabaseUnavailable, got {:?}", other),
+        };
+        match cache.get_team(client, "phx_other", "phc_token").await {
+            Err(FlagError::DatabaseUnavailable) => (),
+            other => panic!("Expected DatabaseUnavailable, got {:?}", other),
+        };
+    }
+
+    #[tokio::test]
+    async fn test_failed_personal_api_key_lookups_are_not_cached() {
+        let client = setup_invalid_pg_client().await;
+        let cache = PersonalApiKeyCache::default();
+
+        assert!(cache
+            .get_team(client, "phx_key", "phc_token")
+            .await
+            .is_err());
+        cache.teams.run_pending_tasks().await;
+        cache.rejected.run_pending_tasks().await;
+        assert_eq!(cache.teams.entry_count(), 0);
+        assert_eq!(cache.rejected.entry_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_personal_api_key_is_not_hashed_again() {
+        let client = setup_pg_reader_client(None).await;
+        let team = insert_new_team_in_pg(client.clone(), None)
+            .await
+            .expect("Failed to insert team in pg");
+        let cache = PersonalApiKeyCache::default();
+
+        match cache.get_team(client, "phx_invalid", &team.api_token).await {
+            Err(FlagError::PersonalApiKeyValidationError) => (),
+            other => panic!("Expected PersonalApiKeyValidationError, got {:?}", other),
+        };
+
+        // Neither the database nor the legacy hashes are needed to reject the key again
+        let client = setup_invalid_pg_client().await;
+        match cache.get_team(client, "phx_invalid", &team.api_token).await {
+            Err(FlagError::PersonalApiKeyValidationError) => (),
+            other => panic!("Expected PersonalApiKeyValidationError, got {:?}", other),
+        };
+    }
+}
@@ -1,8 +1,11 @@
+use base64::{engine::general_purpose, Engine as _};
+use sha2::{Digest, Sha256};
 use std::sync::Arc;
+use tokio::sync::Semaphore;
 use tracing::instrument;
 
 use crate::{
-    api::errors::FlagError,
+    api::errors::{ClientFacingError, FlagError},
     client::database::Client as DatabaseClient,
     client::redis::Client as RedisClient,
     team::team_models::{Team, TEAM_TOKEN_CACHE_PREFIX},
@@ -71,6 +74,113 @@ impl Team {
 
         Ok(row)
     }
+
+    /// Returns the team with the given project token if the personal API key can read its feature flags:
+    /// the key's user must be an active member of the team's organization, and the key's scopes
+    /// (when set) must allow reading feature flags of this team and organization.
+    #[instrument(skip_all)]
+    pub async fn from_personal_api_key(
+        client: Arc<dyn DatabaseClient + Send + Sync>,
+        personal_api_key: &str,
+        token: &str,
+    ) -> Result<Team, FlagError> {
+        // Current keys are stored under their sha256 hash, only compute the legacy hashes on a miss
+        let hash = hash_personal_api_key(personal_api_key);
+        if let Some(team) =
+            Self::from_personal_api_key_hashes(client.clone(), vec![hash], token).await?
+        {
+            return Ok(team);
+        }
+
+        // Any string is hashed, bound the blocking threads bogus keys can keep busy
+        let _permit = LEGACY_PERSONAL_API_KEY_HASHING
+            .try_acquire()
+            .map_err(|_| FlagError::ClientFacing(ClientFacingError::RateLimited))?;
+        let key = personal_api_key.to_string();
+        // The legacy PBKDF2 hashes are deliberately slow to compute, so keep them off the runtime threads
+        let hashes = tokio::task::spawn_blocking(move || legacy_personal_api_key_hashes(&key))
+            .await
+            .map_err(|e| FlagError::Internal(format!("failed to hash personal API key: {}", e)))?;
+
+        Self::from_personal_api_key_hashes(client, hashes, token)
+            .await?
+            .ok_or(FlagError::PersonalApiKeyValidationError)
+    }
+
+    async fn from_personal_api_key_hashes(
+        client: Arc<dyn DatabaseClient + Send + Sync>,
+        hashes: Vec<String>,
+        token: &str,
+    ) -> Result<Option<Team>, FlagError> {
+        let mut conn = client.get_connection().await?;
+
+        let query = r#"
+            SELECT t.id, t.name, t.api_token, t.project_id
+            FROM posthog_personalapikey k
+            JOIN posthog_user u ON u.id = k.user_id
+            JOIN posthog_organizationmembership m ON m.user_id = k.user_id
+            JOIN posthog_team t ON t.organization_id = m.organization_id
+            WHERE k.secure_value = ANY($1)
+              AND t.api_token = $2
+              AND u.is_active
+              AND (k.scopes IS NULL OR k.scopes && ARRAY['*', 'feature_flag:read', 'feature_flag:write']::varchar[])
+              AND (k.scoped_teams IS NULL OR cardinality(k.scoped_teams) = 0 OR t.id = ANY(k.scoped_teams))
+              AND (k.scoped_organizations IS NULL OR cardinality(k.scoped_organizations) = 0 OR m.organization_id::text = ANY(k.scoped_organizations))
+        "#;
+        let row = sqlx::query_as::<_, Team>(query)
+            .bind(hashes)
+            .bind(token)
+            .fetch_optional(&mut *conn)
+            .await?;
+
+        Ok(row)
+    }
+}
+
+/// Salt Django uses when hashing personal API keys with PBKDF2.
+const PERSONAL_API_KEY_SALT: &str = "posthog_personal_api_key";
+
+/// PBKDF2 iteration counts of Django's legacy `PERSONAL_API_KEY_MODES_TO_TRY`, still stored for older keys.
+const LEGACY_PERSONAL_API_KEY_ITERATIONS: [u32; 2] = [260000, 390000];
+
+/// How many keys can be hashed with the legacy modes at once, lookups fail fast past it.
+static LEGACY_PERSONAL_API_KEY_HASHING: Semaphore = Semaphore::const_new(2);
+
+/// Personal API keys are only stored hashed, in the same format as Django's `hash_key_value`.
+pub fn hash_personal_api_key(personal_api_key: &str) -> String {
+    format!("sha256${:x}", Sha256::digest(personal_api_key.as_bytes()))
+}
+
+/// Hashes a personal API key the way Django's `make_password` does with the PBKDF2 hasher.
+fn hash_personal_api_key_pbkdf2(personal_api_key: &str, iterations: u32) -> String {
+    let mut hash = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(
+        personal_api_key.as_bytes(),
+        PERSONAL_API_KEY_SALT.as_bytes(),
+        iterations,
+        &mut hash,
+    );
+    format!(
+        "pbkdf2_sha256${}${}${}",
+        iterations,
+        PERSONAL_API_KEY_SALT,
+        general_purpose::STANDARD.encode(hash)
+    )
+}
+
+/// The PBKDF2 hashes older personal API keys may still be stored under.
+fn legacy_personal_api_key_hashes(personal_api_key: &str) -> Vec<String> {
+    LEGACY_PERSONAL_API_KEY_ITERATIONS
+        .iter()
+        .map(|&iterations| hash_personal_api_key_pbkdf2(personal_api_key, iterations))
+        .collect()
+}
+
+/// Every hash a personal API key may be stored under, matching Django's `PERSONAL_API_KEY_MODES_TO_TRY`.
+pub fn personal_api_key_hashes(personal_api_key: &str) -> Vec<String> {
+    std::iter::once(hash_personal_api_key(personal_api_key))
+        .chain(legacy_personal_api_key_hashes(personal_api_key))
+        .collect()
 }
 
 #[cfg(test)]
@@ -217,4 +327,38 @@ mod tests {
             _ => panic!("Expected RowNotFound"),
         };
     }
+
+    #[tokio::test]
+    async fn test_fetch_team_from_pg_with_invalid_personal_api_key() {
+        let client = setup_pg_reader_client(None).await;
+
+        let team = insert_new_team_in_pg(client.clone(), None)
+            .await
+            .expect("Failed to insert team in pg");
+
+        match Team::from_personal_api_key(client.clone(), "phx_invalid", &team.api_token).await {
+            Err(FlagError::PersonalApiKeyValidationError) => (),
+            _ => panic!("Expected PersonalApiKeyValidationError"),
+        };
+    }
+
+    #[test]
+    fn test_hash_personal_api_key() {
+        assert_eq!(
+            hash_personal_api_key("phx_test_key"),
+            "sha256$02d61fdf5d0a70144c364b90d297231b4d43d4643013455d815d7f97bce49520"
+        );
+    }
+
+    #[test]
+    fn test_personal_api_key_hashes_include_legacy_modes() {
+        assert_eq!(
+            personal_api_key_hashes("phx_test_key"),
+            vec![
+                "sha256$02d61fdf5d0a70144c364b90d297231b4d43d4643013455d815d7f97bce49520".to_string(),
+                "pbkdf2_sha256$260000$posthog_personal_api_key$1Ur9k1di397O4BVJ9QBTEaONIP2qFRYtpPviLWq3zBc=".to_string(),
+                "pbkdf2_sha256$390000$posthog_personal_api_key$AyX4V9DsB0aJttIj5cX/rK2pXoE2CeFYzuQ5ToRbmTM=".to_string(),
+            ]
+        );
+    }
 }
@@ -2,13 +2,16 @@ use anyhow::Error;
 use axum::async_trait;
 use serde_json::{json, Value};
 use sqlx::{pool::PoolConnection, postgres::PgRow, Error as SqlxError, Postgres, Row};
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::timeout;
 use uuid::Uuid;
 
 use crate::{
     client::{
         database::{get_pool, Client, CustomDatabaseError},
-        redis::{Client as RedisClientTrait, RedisClient},
+        redis::{Client as RedisClientTrait, CustomRedisError, RedisClient},
     },
     cohort::cohort_models::Cohort,
     config::{Config, DEFAULT_TEST_CONFIG},
@@ -96,6 +99,98 @@ pub fn setup_redis_client(url: Option<String>) -> Arc<dyn RedisClientTrait + Sen
     Arc::new(client)
 }
 
+/// In-memory redis holding strings and hashes, for tests that need it to misbehave
+#[derive(Default)]
+pub struct MockRedisClient {
+    strings: Mutex<HashMap<String, String>>,
+    hashes: Mutex<HashMap<String, HashMap<String, i32>>>,
+    timing_out_transactions: bool,
+}
+
+impl MockRedisClient {
+    pub fn new() -> MockRedisClient {
+        MockRedisClient::default()
+    }
+
+    /// Transactions are applied, but report a timeout as if redis was too slow to reply
+    pub fn timing_out_transactions(mut self) -> MockRedisClient {
+        self.timing_out_transactions = true;
+        self
+    }
+}
+
+#[async_trait]
+impl RedisClientTrait for MockRedisClient {
+    async fn zrangebyscore(
+        &self,
+        _k: String,
+        _min: String,
+        _max: String,
+    ) -> Result<Vec<String>, Error> {
+        // No sorted sets are stored
+        Ok(Vec::new())
+    }
+
+    async fn hincrby(
+        &self,
+        k: String,
+        v: String,
+        count: Option<i32>,
+    ) -> Result<(), CustomRedisError> {
+        let mut hashes = self.hashes.lock().expect("poisoned mock redis lock");
+        *hashes.entry(k).or_default().entry(v).or_default() += count.unwrap_or(1);
+        Ok(())
+    }
+
+    async fn hincrby_many(
+        &self,
+        increments: Vec<(String, String, i32)>,
+    ) -> Result<(), CustomRedisError> {
+        for (k, v, count) in increments {
+            self.hincrby(k, v, Some(count)).await?;
+        }
+        if self.timing_out_transactions {
+            let elapsed = timeout(Duration::ZERO, std::future::pending::<()>())
+                .await
+                .expect_err("pending future should time out");
+            return Err(CustomRedisError::Timeout(elapsed));
+        }
+        Ok(())
+    }
+
+    async fn get(&self, k: String) -> Result<String, CustomRedisError> {
+        let strings = self.strings.lock().expect("poisoned mock redis lock");
+        strings.get(&k).cloned().ok_or(CustomRedisError::NotFound)
+    }
+
+    async fn set(&self, k: String, v: String) -> Result<(), Error> {
+        let mut strings = self.strings.lock().expect("poisoned mock redis lock");
+        strings.insert(k, v);
+        Ok(())
+    }
+
+    async fn del(&self, k: String) -> Result<(), CustomRedisError> {
+        self.strings
+            .lock()
+            .expect("poisoned mock redis lock")
+            .remove(&k);
+        self.hashes
+            .lock()
+            .expect("poisoned mock redis lock")
+            .remove(&k);
+        Ok(())
+    }
+
+    async fn hget(&self, k: String, field: String) -> Result<String, CustomRedisError> {
+        let hashes = self.hashes.lock().expect("poisoned mock redis lock");
+        hashes
+            .get(&k)
+            .and_then(|hash| hash.get(&field))
+            .map(|count| count.to_string())
+            .ok_or(CustomRedisError::NotFound)
+    }
+}
+
 pub fn create_flag_from_json(json_value: Option<String>) -> Vec<FeatureFlag> {
     let payload = match json_value {
         Some(value) => value,
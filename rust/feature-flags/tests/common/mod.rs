@@ -1,7 +1,7 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
 
-use reqwest::header::CONTENT_TYPE;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
 use tokio::net::TcpListener;
 use tokio::sync::Notify;
 
@@ -37,6 +37,37 @@ impl ServerHandle {
             .expect("failed to send request")
     }
 
+    pub async fn send_flags_request_with_version<T: Into<reqwest::Body>>(
+        &self,
+        body: T,
+        version: &str,
+    ) -> reqwest::Response {
+        let client = reqwest::Client::new();
+        client
+            .post(format!("http://{:?}/flags?v={}", self.addr, version))
+            .body(body)
+            .header(CONTENT_TYPE, "application/json")
+            .send()
+            .await
+            .expect("failed to send request")
+    }
+
+    pub async fn send_definitions_request(
+        &self,
+        key: Option<&str>,
+        token: Option<&str>,
+    ) -> reqwest::Response {
+        let client = reqwest::Client::new();
+        let mut request = client.get(format!("http://{:?}/flags/definitions", self.addr));
+        if let Some(key) = key {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", key));
+        }
+        if let Some(token) = token {
+            request = request.query(&[("token", token)]);
+        }
+        request.send().await.expect("failed to send request")
+    }
+
     pub async fn send_invalid_header_for_flags_request<T: Into<reqwest::Body>>(
         &self,
         body: T,
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 /// These tests are common between all libraries doing local evaluation of feature flags.
@@ -10,7 +11,7 @@ use feature_flags::{
     },
     utils::test_utils::{create_flag_from_json, setup_pg_reader_client, setup_pg_writer_client},
 };
-use serde_json::json;
+use serde_json::{json, Value};
 
 #[tokio::test]
 async fn it_is_consistent_with_rollout_calculation_for_simple_flags() {
@@ -1241,3 +1242,133 @@ async fn it_is_consistent_with_rollout_calculation_for_multivariate_flags() {
         }
     }
 }
+
+async fn matches_with_properties(
+    operator: &str,
+    filter_value: &str,
+    property_value: Value,
+) -> FeatureFlagMatch {
+    let flags = create_flag_from_json(Some(
+        json!([{
+            "id": 1,
+            "key": "date-flag",
+            "name": "Date flag",
+            "active": true,
+            "deleted": false,
+            "team_id": 1,
+            "filters": {
+                "groups": [
+                    {
+                        "properties": [
+                            {
+                                "key": "signup_date",
+                                "type": "person",
+                                "value": filter_value,
+                                "operator": operator,
+                            },
+                        ],
+                        "rollout_percentage": 100,
+                    },
+                ],
+            },
+        }])
+        .to_string(),
+    ));
+
+    let reader = setup_pg_reader_client(None).await;
+    let writer = setup_pg_writer_client(None).await;
+    let cohort_cache = Arc::new(CohortCacheManager::new(reader.clone(), None, None));
+    let overrides = HashMap::from([("signup_date".to_string(), property_value)]);
+
+    FeatureFlagMatcher::new(
+        "distinct_id".to_string(),
+        1,
+        reader,
+        writer,
+        cohort_cache,
+        None,
+        None,
+    )
+    .get_match(&flags[0], Some(overrides), None)
+    .await
+    .unwrap()
+}
+
+// Unix timestamps and `is_date_exact` are not supported in python, they are covered by the
+// property matching tests instead
+#[tokio::test]
+async fn it_is_consistent_with_date_operators() {
+    let now = chrono::Utc::now();
+    let cases = vec![
+        ("is_date_before", "2022-05-01", json!("2022-03-01"), true),
+        (
+            "is_date_before",
+            "2022-05-01",
+            json!("2022-04-30T01:02:03"),
+            true,
+        ),
+        ("is_date_before", "2022-05-01", json!("2022-05-30"), false),
+        ("is_date_before", "2022-05-01", json!("abcdef"), false),
+        ("is_date_before", "2022-05-01", json!("20220501"), false),
+        ("is_date_after", "2022-04-30", json!("20220501"), true),
+        ("is_date_after", "2022-05-01", json!("2022-05-02"), true),
+        ("is_date_after", "2022-05-01", json!("2022-04-30"), false),
+        (
+            "is_date_before",
+            "2022-04-05 12:34:12 +01:00",
+            json!("2022-04-05 11:34:11 +00:00"),
+            true,
+        ),
+        (
+            "is_date_before",
+            "2022-04-05 12:34:12 +01:00",
+            json!("2022-04-05 11:34:13 +00:00"),
+            false,
+        ),
+        (
+            "is_date_after",
+            "-7d",
+            json!((now - chrono::Duration::days(6)).to_rfc3339()),
+            true,
+        ),
+        (
+            "is_date_after",
+            "-7d",
+            json!((now - chrono::Duration::days(8)).to_rfc3339()),
+            false,
+        ),
+        (
+            "is_date_before",
+            "-2w",
+            json!((now - chrono::Duration::days(15)).to_rfc3339()),
+            true,
+        ),
+        (
+            "is_date_after",
+            "-1m",
+            json!((now - chrono::Duration::days(27)).to_rfc3339()),
+            true,
+        ),
+        (
+            "is_date_after",
+            "-1y",
+            json!((now - chrono::Duration::days(367)).to_rfc3339()),
+            false,
+        ),
+    ];
+
+    for (operator, filter_value, property_value, matches) in cases {
+        let feature_flag_match =
+            matches_with_properties(operator, filter_value, property_value.clone()).await;
+        assert_eq!(
+            feature_flag_match.matches, matches,
+            "{operator} {filter_value} with {property_value}"
+        );
+        if matches {
+            assert_eq!(
+                feature_flag_match.reason,
+                FeatureFlagMatchReason::ConditionMatch
+            );
+        }
+    }
+}
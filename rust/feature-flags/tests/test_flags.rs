@@ -7,9 +7,10 @@ use serde_json::{json, Value};
 use crate::common::*;
 
 use feature_flags::config::DEFAULT_TEST_CONFIG;
+use feature_flags::flags::flag_models::FeatureFlagRow;
 use feature_flags::utils::test_utils::{
-    insert_flags_for_team_in_redis, insert_new_team_in_pg, insert_new_team_in_redis,
-    setup_pg_reader_client, setup_redis_client,
+    insert_flag_for_team_in_pg, insert_flags_for_team_in_redis, insert_new_team_in_pg,
+    insert_new_team_in_redis, setup_pg_reader_client, setup_redis_client,
 };
 
 pub mod common;
@@ -69,6 +70,179 @@ async fn it_sends_flag_request() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn it_returns_payloads_and_flag_details_for_versioned_requests() -> Result<()> {
+    let config = DEFAULT_TEST_CONFIG.clone();
+
+    let distinct_id = "user_distinct_id".to_string();
+
+    let client = setup_redis_client(Some(config.redis_url.clone()));
+    let team = insert_new_team_in_redis(client.clone()).await.unwrap();
+    let token = team.api_token;
+
+    let flag_json = json!([{
+        "id": 1,
+        "key": "test-flag",
+        "name": "Test Flag",
+        "active": true,
+        "deleted": false,
+        "team_id": team.id,
+        "filters": {
+            "groups": [
+                {
+                    "properties": [],
+                    "rollout_percentage": 100
+                }
+            ],
+            "payloads": {
+                "true": "{\"color\": \"blue\"}"
+            }
+        },
+    }]);
+
+    insert_flags_for_team_in_redis(client, team.id, Some(flag_json.to_string())).await?;
+
+    let server = ServerHandle::for_config(config).await;
+
+    let payload = json!({
+        "token": token,
+        "distinct_id": distinct_id,
+    });
+
+    let res = server
+        .send_flags_request_with_version(payload.to_string(), "3")
+        .await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let json_data = res.json::<Value>().await?;
+    assert_json_include!(
+        actual: json_data,
+        expected: json!({
+            "errorWhileComputingFlags": false,
+            "featureFlags": {
+                "test-flag": true
+            },
+            "featureFlagPayloads": {
+                "test-flag": "{\"color\": \"blue\"}"
+            }
+        })
+    );
+    assert!(json_data.get("flags").is_none());
+
+    let res = server
+        .send_flags_request_with_version(payload.to_string(), "4")
+        .await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let json_data = res.json::<Value>().await?;
+    assert_json_include!(
+        actual: json_data,
+        expected: json!({
+            "errorWhileComputingFlags": false,
+            "featureFlags": {
+                "test-flag": true
+            },
+            "flags": {
+                "test-flag": {
+                    "key": "test-flag",
+                    "enabled": true,
+                    "variant": null,
+                    "reason": {
+                        "code": "condition_match",
+                        "condition_index": 0,
+                        "description": "Matched condition set 1"
+                    },
+                    "metadata": {
+                        "id": 1,
+                        "description": "Test Flag",
+                        "payload": "{\"color\": \"blue\"}"
+                    }
+                }
+            }
+        })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_returns_flag_details_for_flags_loaded_from_pg() -> Result<()> {
+    let config = DEFAULT_TEST_CONFIG.clone();
+
+    let distinct_id = "user_distinct_id".to_string();
+
+    let client = setup_redis_client(Some(config.redis_url.clone()));
+    let pg_client = setup_pg_reader_client(None).await;
+    let team = insert_new_team_in_redis(client.clone()).await.unwrap();
+    insert_new_team_in_pg(pg_client.clone(), Some(team.id))
+        .await
+        .unwrap();
+    let token = team.api_token;
+
+    // The flags are not cached in redis, so they are loaded from postgres
+    let flag = insert_flag_for_team_in_pg(
+        pg_client,
+        team.id,
+        Some(FeatureFlagRow {
+            id: 0,
+            team_id: team.id,
+            name: Some("PG Flag".to_string()),
+            key: "pg-flag".to_string(),
+            filters: json!({
+                "groups": [
+                    {
+                        "properties": [],
+                        "rollout_percentage": 100
+                    }
+                ],
+                "payloads": {
+                    "true": "{\"color\": \"blue\"}"
+                }
+            }),
+            deleted: false,
+            active: true,
+            ensure_experience_continuity: false,
+        }),
+    )
+    .await?;
+
+    let server = ServerHandle::for_config(config).await;
+
+    let payload = json!({
+        "token": token,
+        "distinct_id": distinct_id,
+    });
+
+    let res = server
+        .send_flags_request_with_version(payload.to_string(), "4")
+        .await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let json_data = res.json::<Value>().await?;
+    assert_json_include!(
+        actual: json_data,
+        expected: json!({
+            "errorWhileComputingFlags": false,
+            "featureFlags": {
+                "pg-flag": true
+            },
+            "flags": {
+                "pg-flag": {
+                    "key": "pg-flag",
+                    "enabled": true,
+                    "metadata": {
+                        "id": flag.id,
+                        "description": "PG Flag",
+                        "payload": "{\"color\": \"blue\"}"
+                    }
+                }
+            }
+        })
+    );
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn it_rejects_invalid_headers_flag_request() -> Result<()> {
     let config = DEFAULT_TEST_CONFIG.clone();
@@ -500,3 +674,30 @@ async fn it_handles_flag_with_group_properties() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn it_rejects_unauthenticated_flag_definitions_requests() -> Result<()> {
+    let config = DEFAULT_TEST_CONFIG.clone();
+
+    let pg_client = setup_pg_reader_client(None).await;
+    let team = insert_new_team_in_pg(pg_client, None).await?;
+
+    let server = ServerHandle::for_config(config).await;
+
+    let res = server
+        .send_definitions_request(None, Some(&team.api_token))
+        .await;
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+    let res = server
+        .send_definitions_request(Some("phx_invalid"), Some(&team.api_token))
+        .await;
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+    let res = server
+        .send_definitions_request(Some("phx_invalid"), None)
+        .await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    Ok(())
+}